@@ -0,0 +1,61 @@
+//! Hot paths of the protocol: sealing a message into a frame, opening one,
+//! and fanning one message out to a room of 100 clients.
+//!
+//! Run with `cargo bench`.
+
+use std::collections::VecDeque;
+use antimpeu::crypto::{decrypt_frame, encrypt_frame, Framing, Payload, SessionCipher, Side};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::hint::black_box;
+
+const TEXT: &str = "The quick brown fox jumps over the lazy dog, twice, to make a message of typical length.";
+
+fn payload() -> Payload<'static> {
+    Payload { user: Some("key:0123456789abcdef".into()), serial: Some(42), ..Payload::chat("alice", TEXT, None) }
+}
+
+fn encrypt(c: &mut Criterion) {
+    let cipher = SessionCipher::new(&[7; 32], Side::Server);
+    let payload = payload();
+    c.bench_function("encrypt_frame", |b| b.iter(|| encrypt_frame(black_box(&payload), &cipher, 1, Framing::default())));
+    c.bench_function("encrypt_frame_padded", |b| b.iter(|| encrypt_frame(black_box(&payload), &cipher, 1, Framing { pad: true, base64: false })));
+    c.bench_function("encrypt_frame_base64", |b| b.iter(|| encrypt_frame(black_box(&payload), &cipher, 1, Framing { pad: false, base64: true })));
+}
+
+fn decrypt(c: &mut Criterion) {
+    let (server, client) = (SessionCipher::new(&[7; 32], Side::Server), SessionCipher::new(&[7; 32], Side::Client));
+    let frame = encrypt_frame(&payload(), &server, 1, Framing::default());
+    c.bench_function("decrypt_frame", |b| b.iter(|| decrypt_frame(black_box(&frame), &client, &mut 0).unwrap()));
+    let frame = encrypt_frame(&payload(), &server, 1, Framing { pad: false, base64: true });
+    c.bench_function("decrypt_frame_base64", |b| b.iter(|| decrypt_frame(black_box(&frame), &client, &mut 0).unwrap()));
+}
+
+/// One chat message relayed to 100 clients: the server seals it with every
+/// client's session key and queues the frames, and each client opens its
+/// own.
+fn broadcast(c: &mut Criterion) {
+    let keys: Vec<[u8; 32]> = (0..100u8).map(|i| [i; 32]).collect();
+    let servers: Vec<SessionCipher> = keys.iter().map(|key| SessionCipher::new(key, Side::Server)).collect();
+    let clients: Vec<SessionCipher> = keys.iter().map(|key| SessionCipher::new(key, Side::Client)).collect();
+    let payload = payload();
+    c.bench_function("broadcast_100_clients", |b| {
+        b.iter_batched_ref(
+            || vec![VecDeque::<Bytes>::new(); 100],
+            |queues| {
+                for (queue, cipher) in queues.iter_mut().zip(&servers) {
+                    queue.push_back(encrypt_frame(&payload, cipher, 1, Framing::default()));
+                }
+                for (queue, cipher) in queues.iter_mut().zip(&clients) {
+                    while let Some(frame) = queue.pop_front() {
+                        black_box(decrypt_frame(&frame, cipher, &mut 0).unwrap());
+                    }
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, encrypt, decrypt, broadcast);
+criterion_main!(benches);
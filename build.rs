@@ -0,0 +1,33 @@
+//! With the `ffi` feature, generate the C header for `src/ffi.rs` into
+//! `OUT_DIR` and warn when the copy checked in as `include/antimpeu.h`
+//! differs from it. The build never writes to the source tree.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=include/antimpeu.h");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+        let out = std::env::var("OUT_DIR").expect("set by cargo");
+        let config = cbindgen::Config {
+            language: cbindgen::Language::C,
+            include_guard: Some("ANTIMPEU_H".to_string()),
+            header: Some("/* Generated from src/ffi.rs by build.rs; do not edit. */".to_string()),
+            cpp_compat: true,
+            ..Default::default()
+        };
+        let generated = format!("{}/antimpeu.h", out);
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{}/src/ffi.rs", dir))
+            .generate()
+            .expect("Cannot generate the C header")
+            .write_to_file(&generated);
+        let checked_in = std::fs::read(format!("{}/include/antimpeu.h", dir)).unwrap_or_default();
+        if std::fs::read(&generated).unwrap_or_default() != checked_in {
+            println!("cargo:warning=include/antimpeu.h is out of date; copy {} over it", generated);
+        }
+    }
+    #[cfg(not(feature = "ffi"))]
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use antimpeu::frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Must never panic, whatever the input
+    let _ = frame::frame_len(data);
+    if let Ok(decoded) = frame::decode_frame(data) {
+        assert!(decoded.sealed.len() >= 16);
+    }
+});
@@ -0,0 +1,100 @@
+/* Generated from src/ffi.rs by build.rs; do not edit. */
+
+#ifndef ANTIMPEU_H
+#define ANTIMPEU_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A connection to a room, from `antimpeu_connect`.
+ */
+typedef struct AntimpeuClient AntimpeuClient;
+
+/**
+ * A message from `antimpeu_poll_message`, freed with
+ * `antimpeu_message_free`.
+ */
+typedef struct AntimpeuMessage {
+  /**
+   * Sender's name; "Server" for notices from the server.
+   */
+  char *sender;
+  /**
+   * Sender's stable ID, or NULL.
+   */
+  char *user;
+  char *text;
+  /**
+   * Message ID, or NULL for notices.
+   */
+  char *id;
+  /**
+   * Unix time of a message replayed from the server's history; 0 for
+   * live ones.
+   */
+  int64_t time;
+} AntimpeuMessage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Connect to `addr` (`host:port`) with the room's 32-byte group key and
+ * join as `name`, or under the local user name if `name` is NULL.
+ * Returns NULL on failure.
+ *
+ * # Safety
+ * `addr` and `name` are NULL or NUL-terminated strings and `key` points
+ * to 32 bytes.
+ */
+struct AntimpeuClient *antimpeu_connect(const char *addr, const uint8_t *key, const char *name);
+
+/**
+ * Send a chat message. Returns 0, or -1 on failure.
+ *
+ * # Safety
+ * `client` comes from `antimpeu_connect` and `text` is a NUL-terminated
+ * string.
+ */
+int antimpeu_send(struct AntimpeuClient *client, const char *text);
+
+/**
+ * The next message received, or NULL if there is none yet. Does not
+ * block.
+ *
+ * # Safety
+ * `client` comes from `antimpeu_connect`.
+ */
+struct AntimpeuMessage *antimpeu_poll_message(struct AntimpeuClient *client);
+
+/**
+ * Free a message from `antimpeu_poll_message`. NULL is ignored.
+ *
+ * # Safety
+ * `message` comes from `antimpeu_poll_message` and is not used again.
+ */
+void antimpeu_message_free(struct AntimpeuMessage *message);
+
+/**
+ * Leave the room and free the client. NULL is ignored.
+ *
+ * # Safety
+ * `client` comes from `antimpeu_connect` and is not used again.
+ */
+void antimpeu_free(struct AntimpeuClient *client);
+
+/**
+ * Why the last call on this thread failed, or NULL. The string stays
+ * valid until the next failure on the thread.
+ */
+const char *antimpeu_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ANTIMPEU_H */
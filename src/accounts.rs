@@ -0,0 +1,85 @@
+//! Server-side user accounts.
+//!
+//! Accounts live in `$HOME/key/accounts`, one `name <argon2 PHC hash>` line
+//! each, and are managed with `antimpeu server user add|del <name>`. When
+//! `require_login` is set, clients must log in with one of them after the
+//! handshake, so holding the DEK alone is no longer enough to join.
+
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+/// Longest accepted password, in bytes; keeps a login frame from making the
+/// server hash megabytes.
+const MAX_PASSWORD_LEN: usize = 1024;
+
+/// Location of the account store.
+pub fn accounts_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/accounts", home)
+}
+
+fn load(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?.to_string(), parts.next()?.to_string()))
+        })
+        .collect())
+}
+
+fn save(path: &str, accounts: &[(String, String)]) -> Result<(), String> {
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let text: String = accounts.iter().map(|(name, hash)| format!("{} {}\n", name, hash)).collect();
+    std::fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+/// Create or replace the account `name`.
+pub fn add(path: &str, name: &str, password: &str) -> Result<(), String> {
+    if name.is_empty() || name.chars().any(char::is_whitespace) {
+        return Err("Account names must be non-empty and contain no whitespace".to_string());
+    }
+    if password.is_empty() || password.len() > MAX_PASSWORD_LEN {
+        return Err(format!("Passwords must be 1 to {} bytes", MAX_PASSWORD_LEN));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("Failed to hash password: {}", e))?
+        .to_string();
+    let mut accounts = load(path)?;
+    accounts.retain(|(n, _)| n != name);
+    accounts.push((name.to_string(), hash));
+    save(path, &accounts)
+}
+
+/// Delete the account `name`.
+pub fn remove(path: &str, name: &str) -> Result<(), String> {
+    let mut accounts = load(path)?;
+    let before = accounts.len();
+    accounts.retain(|(n, _)| n != name);
+    if accounts.len() == before {
+        return Err(format!("No account named {}", name));
+    }
+    save(path, &accounts)
+}
+
+/// Check a login. The store is read on every call so account changes apply
+/// without restarting the server.
+pub fn verify(path: &str, name: &str, password: &str) -> Result<bool, String> {
+    if password.len() > MAX_PASSWORD_LEN {
+        return Ok(false);
+    }
+    let Some((_, hash)) = load(path)?.into_iter().find(|(n, _)| n == name) else {
+        return Ok(false);
+    };
+    let hash = PasswordHash::new(&hash).map_err(|e| format!("Corrupt hash for {} in {}: {}", name, path, e))?;
+    Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
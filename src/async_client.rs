@@ -0,0 +1,173 @@
+//! Async chat client for tokio applications, behind the `async` feature.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), String> {
+//! let dek = antimpeu::auth::load_dek_with_kek("/home/bot/key/dek.bin", Some("passphrase".to_string()))?;
+//! let mut client = antimpeu::AsyncChatClient::connect("chat.example.org:7878", dek).await?;
+//! client.send("hello").await?;
+//! while let Some(m) = client.recv().await {
+//!     println!("{}: {}", m.sender, m.text);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `AsyncChatClient` is also a `Stream` of messages and a `Sink` for the
+//! text of messages to send, so it works with the `futures` combinators
+//! and can be split into the two halves. Messages are those of
+//! `ChatClient`, without our own coming back. The handshake runs on
+//! tokio's blocking pool; after it the connection is read and written by
+//! whichever task polls the client.
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use crate::chat_client::{ClientOptions, Joined, Message};
+use crate::crypto::{Framing, Payload, SessionCipher};
+
+/// A connection to a room. Dropping it closes the connection.
+pub struct AsyncChatClient {
+    stream: TcpStream,
+    cipher: SessionCipher,
+    framing: Framing,
+    /// Name we speak under; the server may give us another, see
+    /// `Kind::Nick`.
+    name: String,
+    /// Sequence number of the last frame sent.
+    seq: u64,
+    /// Highest sequence number received, see `decrypt_frame`.
+    last_seq: u64,
+    /// IDs of our messages not back from the server yet.
+    sent: HashSet<String>,
+    /// Bytes read that do not make a whole frame yet.
+    read_buf: BytesMut,
+    /// What is left to write of the frame being sent.
+    write_buf: Bytes,
+    /// See `identity_notice`.
+    notice: Option<String>,
+}
+
+impl AsyncChatClient {
+    /// Connect to `addr` (`host:port`) with the room's group key and join
+    /// under the local user name.
+    pub async fn connect(addr: &str, key: [u8; 32]) -> Result<Self, String> {
+        Self::connect_with(addr, key, ClientOptions::default()).await
+    }
+
+    /// Connect to `addr` (`host:port`) with the room's group key.
+    pub async fn connect_with(addr: &str, key: [u8; 32], options: ClientOptions) -> Result<Self, String> {
+        let addr = addr.to_string();
+        let joined = tokio::task::spawn_blocking(move || crate::chat_client::join(&addr, key, &options)).await.map_err(|e| e.to_string())?;
+        let Joined { stream, cipher, framing, name, seq, notice } = joined?;
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        let stream = TcpStream::from_std(stream).map_err(|e| e.to_string())?;
+        Ok(Self { stream, cipher, framing, name, seq, last_seq: 0, sent: HashSet::new(), read_buf: BytesMut::new(), write_buf: Bytes::new(), notice })
+    }
+
+    /// Send a chat message and return its ID. The server may still refuse
+    /// it, which arrives as a notice.
+    pub async fn send(&mut self, text: &str) -> Result<String, String> {
+        std::future::poll_fn(|cx| self.poll_write_buf(cx)).await?;
+        let id = self.queue(text);
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_flush(cx)).await?;
+        Ok(id)
+    }
+
+    /// The next message, or None once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Message> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Name we speak under, which the server may have changed.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What the check of the server's identity key found worth telling the
+    /// user, see `ChatClient::identity_notice`.
+    pub fn identity_notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    /// Encrypt a chat message into `write_buf`, which must be empty, and
+    /// return its ID.
+    fn queue(&mut self, text: &str) -> String {
+        let payload = Payload::chat(&self.name, text, None);
+        let id = payload.id.as_deref().unwrap_or_default().to_string();
+        self.sent.insert(id.clone());
+        self.seq += 1;
+        self.write_buf = crate::crypto::encrypt_frame(&payload, &self.cipher, self.seq, self.framing);
+        id
+    }
+
+    /// Write out what is left of the frame being sent.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        while !self.write_buf.is_empty() {
+            match ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf)) {
+                Ok(0) => return Poll::Ready(Err("Failed to send: connection closed".to_string())),
+                Ok(n) => self.write_buf.advance(n),
+                Err(e) => return Poll::Ready(Err(format!("Failed to send: {}", e))),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for AsyncChatClient {
+    type Item = Message;
+
+    /// Messages as they arrive. The stream ends when the connection is
+    /// closed or a frame does not decrypt.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let this = self.get_mut();
+        loop {
+            match crate::frame::frame_len(&this.read_buf) {
+                Err(_) => return Poll::Ready(None),
+                Ok(Some(total)) if this.read_buf.len() >= total => {
+                    let frame = this.read_buf.split_to(total);
+                    let Ok(m) = crate::crypto::decrypt_frame(&frame, &this.cipher, &mut this.last_seq) else { return Poll::Ready(None) };
+                    if let Some(message) = crate::chat_client::to_message(m, &mut this.sent, &mut this.name) {
+                        return Poll::Ready(Some(message));
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+            }
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            match ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk)) {
+                Ok(()) if !chunk.filled().is_empty() => this.read_buf.extend_from_slice(chunk.filled()),
+                _ => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Sink<String> for AsyncChatClient {
+    type Error = String;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        self.get_mut().poll_write_buf(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, text: String) -> Result<(), String> {
+        self.get_mut().queue(&text);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx).map_err(|e| format!("Failed to send: {}", e))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), String>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx).map_err(|e| e.to_string())
+    }
+}
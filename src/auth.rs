@@ -1,15 +1,67 @@
 use aes_gcm::{Aes256Gcm, aead::Aead, KeyInit};
 use pbkdf2::pbkdf2;
-use hmac::Hmac;
+use hmac::{Hmac, Mac};
 use sha2::Sha256;
+#[cfg(not(target_arch = "wasm32"))]
 use rpassword::read_password;
 
+/// Location of a DEK file in `$HOME/key` with the given extension (`key`
+/// for the plain key, `bin` for the encrypted one): `dek.<ext>`, or
+/// `dek-<slot>.<ext>` for a key slot, so one user can hold the keys of
+/// several rooms.
+pub fn dek_path(slot: Option<&str>, extension: &str) -> Result<String, String> {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    match slot {
+        None => Ok(format!("{}/key/dek.{}", home, extension)),
+        Some(slot) if !slot.is_empty() && slot.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => Ok(format!("{}/key/dek-{}.{}", home, slot, extension)),
+        Some(slot) => Err(format!("Invalid key slot {:?}: use letters, digits, '-' and '_'", slot)),
+    }
+}
+
+/// Names of the key slots with a DEK file in `$HOME/key`.
+pub fn key_slots() -> Vec<String> {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    let Ok(entries) = std::fs::read_dir(format!("{}/key", home)) else { return Vec::new() };
+    let mut slots: Vec<String> = entries
+        .filter_map(|e| {
+            let name = e.ok()?.file_name().into_string().ok()?;
+            let slot = name.strip_prefix("dek-")?;
+            Some(slot.strip_suffix(".bin").or_else(|| slot.strip_suffix(".key"))?.to_string())
+        })
+        .collect();
+    slots.sort();
+    slots.dedup();
+    slots
+}
+
 /// Load and decrypt a 32-byte Data Encryption Key (DEK) saved in the
 /// binary format: [16 byte salt][12 byte nonce][ciphertext].
 ///
 /// The function prompts the user for the KEK (password) on stdin.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn load_dek_from_encrypted(path: &str) -> Result<[u8; 32], String> {
+    load_dek_with_kek(path, None)
+}
+
+/// Like `load_dek_from_encrypted`, but with the KEK given by the caller
+/// when it is `Some`, for non-interactive use.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_dek_with_kek(path: &str, kek: Option<String>) -> Result<[u8; 32], String> {
     let dek_blob = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let kek = match kek {
+        Some(kek) => kek,
+        None => {
+            use std::io::{self, Write};
+            print!("Enter KEK (password) to decrypt DEK: ");
+            io::stdout().flush().ok();
+            read_password().map_err(|_| "Failed to read KEK".to_string())?
+        }
+    };
+    decrypt_dek(&dek_blob, &kek)
+}
+
+/// Decrypt the contents of a `dek.bin` with the KEK.
+pub fn decrypt_dek(dek_blob: &[u8], kek: &str) -> Result<[u8; 32], String> {
     if dek_blob.len() < 16 + 12 + 16 {
     return Err("Encrypted DEK file is too small or malformed".to_string());
     }
@@ -17,10 +69,6 @@ pub fn load_dek_from_encrypted(path: &str) -> Result<[u8; 32], String> {
     let nonce = &dek_blob[16..28];
     let ciphertext = &dek_blob[28..];
 
-    use std::io::{self, Write};
-    print!("Enter KEK (password) to decrypt DEK: ");
-    io::stdout().flush().ok();
-    let kek = read_password().map_err(|_| "Failed to read KEK".to_string())?;
     let mut kek_derived = [0u8; 32];
     pbkdf2::<Hmac<Sha256>>(kek.as_bytes(), salt, 100_000, &mut kek_derived);
     let kek_cipher = Aes256Gcm::new_from_slice(&kek_derived).map_err(|_| "Invalid KEK-derived key".to_string())?;
@@ -31,3 +79,63 @@ pub fn load_dek_from_encrypted(path: &str) -> Result<[u8; 32], String> {
     arr.copy_from_slice(&dek_bytes);
     Ok(arr)
 }
+
+/// Derive the key used to answer handshake challenges from the DEK. Keeping
+/// it separate means the message key never encrypts attacker-chosen data
+/// during the handshake.
+pub fn derive_handshake_key(dek: &[u8; 32]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(None, dek)
+        .expand(b"antimpeu handshake v1", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// Derive the key a client's local log is sealed with from the DEK, so the
+/// message key never encrypts anything that stays on disk.
+pub fn derive_storage_key(dek: &[u8; 32]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(None, dek)
+        .expand(b"antimpeu local history v1", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn handshake_hmac(key: &[u8; 32], challenge: &[u8], role: &str, peer: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(challenge);
+    mac.update(role.as_bytes());
+    mac.update(peer.as_bytes());
+    mac
+}
+
+/// Handshake response: HMAC-SHA256(key, challenge || role || peer).
+///
+/// `role` names the side answering ("client" or "server") so a response can
+/// not be reflected back at its sender, and `peer` is the client address as
+/// the server sees it, binding the response to one connection.
+pub fn handshake_mac(key: &[u8; 32], challenge: &[u8], role: &str, peer: &str) -> [u8; 32] {
+    handshake_hmac(key, challenge, role, peer).finalize().into_bytes().into()
+}
+
+/// Check a handshake response in constant time.
+pub fn verify_handshake_mac(key: &[u8; 32], challenge: &[u8], role: &str, peer: &str, response: &[u8]) -> bool {
+    handshake_hmac(key, challenge, role, peer).verify_slice(response).is_ok()
+}
+
+/// Derive the message key of one connection from the DEK and both sides'
+/// handshake challenges, so a frame recorded on one connection never
+/// authenticates on another. `peer` is the client address as the server
+/// sees it, as in `handshake_mac`.
+pub fn derive_session_key(dek: &[u8; 32], server_challenge: &[u8], client_challenge: &[u8], peer: &str) -> [u8; 32] {
+    let mut info = b"antimpeu session v1".to_vec();
+    for part in [server_challenge, client_challenge, peer.as_bytes()] {
+        info.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        info.extend_from_slice(part);
+    }
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<Sha256>::new(None, dek)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
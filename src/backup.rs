@@ -0,0 +1,60 @@
+//! `antimpeu key backup|restore`: a copy of the DEK to keep in a password
+//! manager.
+//!
+//! The backup holds the DEK encrypted with a passphrase of its own, in the
+//! `dek.bin` layout, base64 encoded between `BEGIN`/`END` lines so it
+//! survives being pasted into a notes field. Restoring it writes a fresh
+//! `dek.bin` under a KEK chosen at that point.
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+const BEGIN: &str = "-----BEGIN ANTIMPEU KEY BACKUP-----";
+const END: &str = "-----END ANTIMPEU KEY BACKUP-----";
+/// Width of the base64 lines between `BEGIN` and `END`.
+const LINE_WIDTH: usize = 64;
+
+/// Armor the DEK in `path`, asking for its KEK and a backup passphrase.
+pub fn backup(path: &str) -> Result<String, String> {
+    let dek = crate::auth::load_dek_from_encrypted(path)?;
+    let passphrase = crate::setup::choose_secret("Choose a passphrase for the backup: ")?;
+    Ok(armor(&crate::utils::encrypt_dek(&dek, &passphrase)?))
+}
+
+/// Write `path` from an armored backup, asking for its passphrase and a KEK
+/// for the new file.
+pub fn restore(path: &str, armored: &str) -> Result<(), String> {
+    if std::path::Path::new(path).exists() {
+        return Err(format!("{} already exists; delete it first to restore a backup", path));
+    }
+    let blob = dearmor(armored)?;
+    let passphrase = crate::setup::ask_secret("Passphrase of the backup: ")?;
+    let dek = crate::auth::decrypt_dek(&blob, &passphrase).map_err(|_| "Wrong passphrase, or the backup is damaged".to_string())?;
+    let kek = crate::setup::choose_secret("Choose a KEK (password) to protect the key: ")?;
+    let blob = crate::utils::encrypt_dek(&dek, &kek)?;
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    crate::identity::write_private(path, &blob).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+fn armor(blob: &[u8]) -> String {
+    let encoded = STANDARD.encode(blob);
+    let mut out = format!("{}\n", BEGIN);
+    for line in encoded.as_bytes().chunks(LINE_WIDTH) {
+        out.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        out.push('\n');
+    }
+    out.push_str(END);
+    out
+}
+
+/// The blob between the armor lines; text around them is ignored, so a
+/// whole note can be pasted.
+fn dearmor(text: &str) -> Result<Vec<u8>, String> {
+    let not_a_backup = || format!("No key backup found; it starts with {}", BEGIN);
+    let start = text.find(BEGIN).ok_or_else(not_a_backup)? + BEGIN.len();
+    let end = start + text[start..].find(END).ok_or_else(not_a_backup)?;
+    let encoded: String = text[start..end].chars().filter(|c| !c.is_whitespace()).collect();
+    STANDARD.decode(encoded).map_err(|_| "The key backup is damaged: invalid base64".to_string())
+}
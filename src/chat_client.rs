@@ -0,0 +1,164 @@
+//! Embeddable chat client, for programs that join a room without the
+//! terminal UI.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), String> {
+//! let dek = antimpeu::auth::load_dek_with_kek("/home/bot/key/dek.bin", Some("passphrase".to_string()))?;
+//! let client = antimpeu::ChatClient::connect("chat.example.org:7878", dek)?;
+//! client.on_message(|m| println!("{}: {}", m.sender, m.text));
+//! client.send("hello")?;
+//! client.close();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Messages arrive on a reader thread, which runs the callbacks in the
+//! order they were added. Our own messages coming back from the server are
+//! not passed on. Messages are not signed, so in signed rooms others show
+//! them as unverified.
+
+use std::collections::HashSet;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use crate::crypto::{Framing, Kind, Payload, SessionCipher, Side};
+pub use crate::client_state::Message;
+pub(crate) use crate::client_state::to_message;
+
+/// Settings for `ChatClient::connect_with`.
+#[derive(Clone, Default)]
+pub struct ClientOptions {
+    /// Name to chat under; the local user name when unset.
+    pub name: Option<String>,
+    /// Password for servers that require a login; the account is `name`.
+    pub password: Option<String>,
+    pub connection: crate::handshake::Options,
+}
+
+type Callbacks = Arc<Mutex<Vec<Box<dyn FnMut(&Message) + Send>>>>;
+
+/// A connection to a room. Dropping it closes the connection.
+pub struct ChatClient {
+    /// The stream and the sequence number of the last frame sent on it.
+    writer: Arc<Mutex<(TcpStream, u64)>>,
+    cipher: SessionCipher,
+    framing: Framing,
+    /// Name we speak under; the server may give us another, see
+    /// `Kind::Nick`.
+    name: Arc<Mutex<String>>,
+    /// IDs of our messages not back from the server yet.
+    sent: Arc<Mutex<HashSet<String>>>,
+    callbacks: Callbacks,
+    reader: Option<thread::JoinHandle<()>>,
+    /// See `identity_notice`.
+    notice: Option<String>,
+}
+
+impl ChatClient {
+    /// Connect to `addr` (`host:port`) with the room's group key and join
+    /// under the local user name.
+    pub fn connect(addr: &str, key: [u8; 32]) -> Result<Self, String> {
+        Self::connect_with(addr, key, &ClientOptions::default())
+    }
+
+    /// Connect to `addr` (`host:port`) with the room's group key.
+    pub fn connect_with(addr: &str, key: [u8; 32], options: &ClientOptions) -> Result<Self, String> {
+        let Joined { stream, cipher, framing, name, seq, notice } = join(addr, key, options)?;
+        let mut reader = stream.try_clone().map_err(|e| e.to_string())?;
+        let name = Arc::new(Mutex::new(name));
+        let (sent, callbacks): (Arc<Mutex<HashSet<String>>>, Callbacks) = Default::default();
+        let (cipher_reader, name_reader, sent_reader, callbacks_reader) = (cipher.clone(), name.clone(), sent.clone(), callbacks.clone());
+        let reader = thread::spawn(move || {
+            let mut frame_buf = bytes::BytesMut::new();
+            let mut last_seq = 0;
+            while let Ok(m) = crate::crypto::read_one_encrypted(&mut reader, &cipher_reader, &mut frame_buf, &mut last_seq) {
+                let Some(message) = to_message(m, &mut sent_reader.lock().unwrap(), &mut name_reader.lock().unwrap()) else { continue };
+                for callback in callbacks_reader.lock().unwrap().iter_mut() {
+                    callback(&message);
+                }
+            }
+        });
+        Ok(Self { writer: Arc::new(Mutex::new((stream, seq))), cipher, framing, name, sent, callbacks, reader: Some(reader), notice })
+    }
+
+    /// Send a chat message and return its ID. The server may still refuse
+    /// it, which arrives as a notice.
+    pub fn send(&self, text: &str) -> Result<String, String> {
+        let name = self.name();
+        let payload = Payload::chat(&name, text, None);
+        let id = payload.id.as_deref().unwrap_or_default().to_string();
+        self.sent.lock().unwrap().insert(id.clone());
+        let mut writer = self.writer.lock().unwrap();
+        let (stream, seq) = &mut *writer;
+        *seq += 1;
+        crate::crypto::send_encrypted(stream, &payload, &self.cipher, *seq, self.framing).map_err(|e| format!("Failed to send: {}", e))?;
+        Ok(id)
+    }
+
+    /// Call `callback` with every message from now on. Callbacks run on the
+    /// reader thread and must not add further callbacks.
+    pub fn on_message(&self, callback: impl FnMut(&Message) + Send + 'static) {
+        self.callbacks.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Name we speak under, which the server may have changed.
+    pub fn name(&self) -> String {
+        self.name.lock().unwrap().clone()
+    }
+
+    /// What the check of the server's identity key found worth telling the
+    /// user: that the key was pinned on this first connection, or that it
+    /// changed and `strict_server_keys` is off.
+    pub fn identity_notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    /// Leave the room and wait for the reader thread to finish.
+    pub fn close(self) {}
+}
+
+impl Drop for ChatClient {
+    fn drop(&mut self) {
+        let _ = self.writer.lock().unwrap().0.shutdown(Shutdown::Both);
+        // a callback may drop the client, on the reader thread itself
+        if let Some(reader) = self.reader.take().filter(|r| r.thread().id() != thread::current().id()) {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// A connection that got through the handshake and joined the room.
+pub(crate) struct Joined {
+    pub stream: TcpStream,
+    pub cipher: SessionCipher,
+    pub framing: Framing,
+    pub name: String,
+    /// Sequence number of the last frame sent.
+    pub seq: u64,
+    /// See `handshake::Accepted::notice`.
+    pub notice: Option<String>,
+}
+
+/// Connect to `addr` (`host:port`), log in if the server asks, and join.
+pub(crate) fn join(addr: &str, key: [u8; 32], options: &ClientOptions) -> Result<Joined, String> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host.trim_start_matches('[').trim_end_matches(']'), port.parse().ok()?)))
+        .ok_or_else(|| format!("{} is not a host:port address", addr))?;
+    let (mut stream, mut accepted, _) = crate::handshake::connect(host, port, Some(key), &options.connection)?;
+    let cipher = SessionCipher::new(&accepted.session_key, Side::Client);
+    let name = options.name.clone().unwrap_or_else(whoami::username);
+    let mut seq = 0;
+    if accepted.login {
+        let password = options.password.as_deref().ok_or("The server requires a login; set ClientOptions::password")?;
+        crate::handshake::login(&mut stream, &cipher, &name, accepted.framing, password, options.connection.timeouts.login())?;
+        seq += 1;
+    }
+    // signed, so the server gives us back the name it remembers for our key
+    let signature = crate::identity::load_or_create(&crate::identity::user_key_path()).ok().map(|key| crate::identity::sign_join(&key, &accepted.challenge, &name));
+    seq += 1;
+    let join = Payload { signature: signature.map(Into::into), ..Payload::control(Kind::Join, &name, "") };
+    crate::crypto::send_encrypted(&mut stream, &join, &cipher, seq, accepted.framing).map_err(|e| format!("Failed to join: {}", e))?;
+    Ok(Joined { stream, cipher, framing: accepted.framing, name, seq, notice: accepted.notice.take() })
+}
+
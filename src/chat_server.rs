@@ -0,0 +1,162 @@
+//! Embeddable chat server, for programs that host a room without the
+//! terminal UI.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), String> {
+//! let dek = antimpeu::auth::load_dek_with_kek("/srv/chat/key/dek.bin", Some("passphrase".to_string()))?;
+//! let server = antimpeu::ChatServer::builder()
+//!     .port(7878)
+//!     .key(dek)
+//!     .max_clients(50)
+//!     .on_event(|event| println!("{:?}", event))
+//!     .start()?;
+//! server.send("welcome")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each server keeps its own state and runs until the process exits. Its
+//! identity key, history and the account, role and nickname files are
+//! those of `antimpeu server`, under `$HOME/key`, so two servers in one
+//! process share them.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use crate::server::{ServerConfig, JOIN_NOTICE, LEAVE_NOTICE};
+use crate::types::{MessageBuffer, SharedMessages, SharedStatus};
+
+/// Something that happened in the room.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// A client's chat message, relayed to the others.
+    Message {
+        sender: String,
+        /// Sender's stable ID, see `Payload::user`.
+        user: Option<String>,
+        text: String,
+    },
+    /// A client connected from this address. A `Notice` follows if it then
+    /// fails the handshake or login.
+    Joined(String),
+    /// The client at this address disconnected.
+    Left(String),
+    /// Anything else the server reports, such as the address it listens
+    /// on, refused connections, lagging clients and the output of commands
+    /// sent with `ChatServer::send`.
+    Notice(String),
+}
+
+impl Event {
+    /// The event for a line the server TUI shows, in its event pane when
+    /// `event` is set, else in the chat.
+    pub(crate) fn from_message(m: &crate::types::Message, event: bool) -> Self {
+        let notice = || Event::Notice(m.text.clone());
+        match (event, m.sender.as_str()) {
+            (true, _) => m.text.strip_prefix(JOIN_NOTICE).map(|addr| Event::Joined(addr.to_string()))
+                .or_else(|| m.text.strip_prefix(LEAVE_NOTICE).map(|addr| Event::Left(addr.to_string())))
+                .unwrap_or_else(notice),
+            (false, "System") => notice(),
+            (false, _) => Event::Message { sender: m.sender.clone(), user: m.user.clone(), text: m.text.clone() },
+        }
+    }
+}
+
+type Callback = Box<dyn FnMut(Event) + Send>;
+
+/// Settings for a `ChatServer`, from `ChatServer::builder`.
+pub struct ChatServerBuilder {
+    port: Option<u16>,
+    key: Option<[u8; 32]>,
+    config: ServerConfig,
+    on_event: Option<Callback>,
+}
+
+impl ChatServerBuilder {
+    /// Port to listen on, on all interfaces. Required.
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// The room's group key. Required.
+    pub fn key(mut self, key: [u8; 32]) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Most clients connected at once; 0, the default, for no limit.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    /// Socket options for accepted connections, as in the `[socket]`
+    /// section of the config file.
+    pub fn socket(mut self, socket: crate::net::SocketConfig) -> Self {
+        self.config.socket = socket;
+        self
+    }
+
+    /// Everything else, as in the `[server]` section of the config file.
+    /// This replaces `max_clients` and `socket`, so set those afterwards.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Call `f` with each event, in order, on a thread of its own. Without
+    /// it the events are read from `ChatServer::events`.
+    pub fn on_event(mut self, f: impl FnMut(Event) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(f));
+        self
+    }
+
+    /// Start listening. Errors say why the server could not start, such as
+    /// the port being in use.
+    pub fn start(self) -> Result<ChatServer, String> {
+        let port = self.port.ok_or("ChatServer needs a port; set it with ChatServerBuilder::port")?;
+        let dek = self.key.ok_or("ChatServer needs the group key; set it with ChatServerBuilder::key")?;
+        let identity = crate::identity::load_or_create(&crate::identity::server_key_path())?;
+        let keys = crate::server::ServerKeys { dek, handshake: crate::auth::derive_handshake_key(&dek), identity, room: None };
+        // the server sends every event on the channel as it happens, so
+        // the buffer the TUI would read can stay small
+        let messages: SharedMessages<crate::types::Message> = Arc::new(Mutex::new(MessageBuffer::new(1)));
+        let status: SharedStatus = Arc::default();
+        let (console, console_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+        crate::server::run_server_with_tui(port, keys, messages, status, console_rx, &self.config, Some(event_tx))?;
+        let events = match self.on_event {
+            Some(on_event) => {
+                thread::spawn(move || event_rx.into_iter().for_each(on_event));
+                None
+            }
+            None => Some(event_rx),
+        };
+        Ok(ChatServer { console, events })
+    }
+}
+
+/// A running server.
+pub struct ChatServer {
+    console: mpsc::Sender<String>,
+    events: Option<mpsc::Receiver<Event>>,
+}
+
+impl ChatServer {
+    /// Settings for a server with the default configuration.
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder { port: None, key: None, config: ServerConfig::default(), on_event: None }
+    }
+
+    /// Send `text` to every client, under the local user name. Commands
+    /// such as `/who` or `/kick <name>` work as in the server TUI, their
+    /// output coming back as `Event::Notice`.
+    pub fn send(&self, text: &str) -> Result<(), String> {
+        self.console.send(text.to_string()).map_err(|_| "The server has stopped".to_string())
+    }
+
+    /// Events, unless they go to the `on_event` callback.
+    pub fn events(&self) -> Option<&mpsc::Receiver<Event>> {
+        self.events.as_ref()
+    }
+}
@@ -2,58 +2,841 @@ use std::sync::{Arc, Mutex};
 use std::net::TcpStream;
 use std::thread;
 use std::time::Duration;
-use aes_gcm::Aes256Gcm;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use crate::crypto::{Framing, Kind, Payload, SessionCipher, Side};
+use crate::handshake::Accepted;
+use crate::transfer::FileMeta;
+use crate::types::{Delivery, MessageBuffer, SeenIds, SharedMessages, SharedStatus, Transfer};
 
-/// Start a client connection, run the handshake and launch the TUI.
-/// The function blocks and runs the TUI in the current thread.
-pub fn run_client_with_tui(ip: String, port: u16, cipher: Aes256Gcm) {
-    let addr = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&addr).expect("Could not establish connection");
-    println!("Connected to {}", addr);
+/// Connect to `host:port` with the `[client]` settings, see
+/// `handshake::connect`. With `heartbeat`, offer to exchange pings.
+fn connect(host: &str, port: u16, dek: Option<[u8; 32]>, observe: bool, heartbeat: bool, config: &crate::config::ClientConfig) -> Result<(TcpStream, Accepted, [u8; 32]), String> {
+    let options = crate::handshake::Options { pad: config.pad_messages, observe, heartbeat, socks_proxy: config.socks_proxy.clone(), strict_server_keys: config.strict_server_keys, timeouts: config.timeouts.clone() };
+    let (stream, mut accepted, dek) = crate::handshake::connect(host, port, dek, &options)?;
+    if let Some(notice) = accepted.notice.take() {
+        eprintln!("{}", notice);
+    }
+    Ok((stream, accepted, dek))
+}
 
-    // Send HELLO token immediately so server's HELLO-first check succeeds.
-    if let Err(e) = crate::net::write_plain(&mut stream, b"HELLO-ANTIMPEU") {
-        eprintln!("Failed to send HELLO to server: {}", e);
-        return;
+/// Log in to `account` on a server that requires it, with the remembered
+/// `password` or else prompting for it, and return the password.
+fn login(stream: &mut TcpStream, cipher: &SessionCipher, account: &str, server: &str, framing: Framing, password: Option<String>, timeout: Duration) -> Result<String, String> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            use std::io::Write;
+            print!("Password for {}@{}: ", account, server);
+            std::io::stdout().flush().ok();
+            rpassword::read_password().map_err(|_| "Failed to read password".to_string())?
+        }
+    };
+    crate::handshake::login(stream, cipher, account, framing, &password, timeout)?;
+    Ok(password)
+}
+
+/// Slash commands the client handles itself, for the F1 help; the rest go
+/// to the server.
+const COMMANDS: &[(&str, &str)] = &[
+    ("/ephemeral <duration> <text>", "send a message that disappears after 30s, 5m, 2h..."),
+    ("/send <path>", "share a file through the server"),
+    ("/get <name>", "download a shared file"),
+    ("/search <term>", "search the local log (needs keep_log)"),
+    ("/qr", "show a QR code for joining this server from another device"),
+    ("/fingerprint [name]", "show the server's and your key fingerprints, or a member's in a signed room"),
+];
+
+/// Output of `/fingerprint`: the fingerprints of the server at `server`
+/// and our personal key, or with a `name` the key pinned for that member.
+fn fingerprints(server: &str, server_key: Option<&str>, name: &str) -> Vec<String> {
+    if !name.is_empty() {
+        return match crate::identity::pinned(&crate::identity::known_users_path(), name) {
+            Ok(Some(key)) => crate::fingerprint::describe(name, &key),
+            Ok(None) => vec![format!("No key is pinned for {}; members' keys are pinned in signed rooms", name)],
+            Err(e) => vec![e],
+        };
     }
+    let mut lines = match server_key {
+        Some(key) => crate::fingerprint::describe(&format!("Server {}", server), key),
+        None => vec![format!("No key is pinned for {}", server)],
+    };
+    let path = crate::identity::user_key_path();
+    if std::path::Path::new(&path).exists() {
+        match crate::identity::load_or_create(&path) {
+            Ok(key) => lines.extend(crate::fingerprint::describe("You", &hex::encode(key.verifying_key().as_bytes()))),
+            Err(e) => lines.push(e),
+        }
+    }
+    lines
+}
 
-    // Client handshake: read plaintext challenge and reply encrypted
-    stream.set_read_timeout(Some(Duration::from_secs(5))).ok();
-    if let Ok(chal_bytes) = crate::net::read_plain(&mut stream) {
-        if let Ok(chal_str) = String::from_utf8(chal_bytes) {
-            if chal_str.starts_with("CHAL:") {
-                let challenge = chal_str.trim_start_matches("CHAL:").to_string();
-                let username = whoami::username();
-                let cipher_hand = cipher.clone();
-                // send encrypted reply containing the challenge as message
-                if let Err(e) = crate::crypto::send_encrypted(&mut stream, &challenge, &cipher_hand, &username) {
-                    eprintln!("Handshake reply failed: {}", e);
-                    return;
+/// Messages of the local log shown at startup, and loaded at a time when
+/// scrolling up past the oldest one.
+const SCROLLBACK_PAGE: usize = 200;
+
+/// A message of the local log as the TUI shows it.
+fn logged_message(payload: Payload) -> crate::tui::Message {
+    crate::tui::Message { sender: payload.username.into_owned(), text: payload.text.into_owned(), time: message_time(payload.time), verified: None, expires: None, user: payload.user.map(Cow::into_owned), id: payload.id.map(Cow::into_owned) }
+}
+
+/// Say what a page of the local log found wrong with it.
+fn report_page(messages: &SharedMessages<crate::tui::Message>, damaged: usize, missing: u64) {
+    if damaged > 0 {
+        system(messages, format!("{} entries of the local log failed the integrity check and are skipped", damaged));
+    }
+    if missing > 0 {
+        system(messages, format!("{} entries are missing from the local log: they were removed or the file was cut short", missing));
+    }
+}
+
+/// Show the newest page of the local log, its IDs remembered in `seen` so
+/// the server's replay does not show them twice, and give the TUI a loader
+/// for the pages before it.
+fn start_scrollback(log: Arc<crate::history::History>, messages: &SharedMessages<crate::tui::Message>, seen: &Mutex<SeenIds>, status: &SharedStatus) {
+    let page = match log.page_before(None, SCROLLBACK_PAGE) {
+        Ok(page) => page,
+        Err(e) => return system(messages, e),
+    };
+    let cursor = Mutex::new(page.before);
+    let (damaged, missing) = (page.damaged, page.missing);
+    {
+        let mut seen = seen.lock().unwrap();
+        let mut msgs = messages.lock().unwrap();
+        for payload in page.messages {
+            if let Some(id) = &payload.id {
+                seen.insert(id);
+            }
+            msgs.push(logged_message(payload));
+        }
+    }
+    // under the page rather than scrolled off above it
+    report_page(messages, damaged, missing);
+    let messages = messages.clone();
+    status.lock().unwrap().scrollback = Some(Arc::new(move || {
+        let mut cursor = cursor.lock().unwrap();
+        let Some(before) = *cursor else { return false };
+        if messages.lock().unwrap().is_full() {
+            return false;
+        }
+        let page = match log.page_before(Some(before), SCROLLBACK_PAGE) {
+            Ok(page) => page,
+            Err(e) => {
+                system(&messages, e);
+                return false;
+            }
+        };
+        report_page(&messages, page.damaged, page.missing);
+        *cursor = page.before;
+        let mut msgs = messages.lock().unwrap();
+        // a long replay may reach back past the first page
+        let shown: HashSet<String> = msgs.iter().filter_map(|m| m.id.clone()).collect();
+        msgs.prepend(page.messages.into_iter().filter(|p| p.id.as_deref().is_none_or(|id| !shown.contains(id))).map(logged_message).collect());
+        cursor.is_some() && !msgs.is_full()
+    }));
+}
+
+/// Most search results shown; older matches are left out.
+const SEARCH_LIMIT: usize = 500;
+
+/// Date and time of a logged message for search results.
+fn search_time(time: Option<i64>) -> String {
+    time.and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Send one frame on the shared writer, numbering it in write order.
+fn send_frame(writer: &Mutex<(TcpStream, u64)>, cipher: &SessionCipher, framing: Framing, payload: &Payload) {
+    if let Ok(mut guard) = writer.lock() {
+        let (s, seq) = &mut *guard;
+        *seq += 1;
+        let _ = crate::crypto::send_encrypted(s, payload, cipher, *seq, framing);
+    }
+}
+
+/// Our chat messages awaiting the server's `ack`, by ID.
+type Pending = Arc<Mutex<HashMap<String, PendingSend>>>;
+
+/// A chat message the server has not acknowledged yet.
+struct PendingSend {
+    payload: Payload<'static>,
+    /// When it was first sent, to keep the order when it is sent again on
+    /// the next connection.
+    queued: std::time::Instant,
+    /// When it is sent again, or flagged once out of retries.
+    deadline: std::time::Instant,
+    /// Times it was sent again so far.
+    retries: u32,
+}
+
+/// How long the server has to acknowledge a chat message before it is
+/// sent again; the wait doubles with every retry, up to `MAX_ACK_TIMEOUT`.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ACK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The wait for an `ack` after `retries` resends.
+fn ack_timeout(retries: u32) -> Duration {
+    ACK_TIMEOUT.saturating_mul(2u32.saturating_pow(retries)).min(MAX_ACK_TIMEOUT)
+}
+
+/// The start of a message, to name it in a notice.
+fn preview(text: &str) -> String {
+    match text.char_indices().nth(30) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Show a line from the client itself.
+fn system(messages: &SharedMessages<crate::tui::Message>, text: String) {
+    messages.lock().unwrap().push(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+}
+
+/// Files this client shares with `/send` and fetches with `/get`, see
+/// `transfer`.
+struct Files {
+    download_dir: PathBuf,
+    /// Files offered with `/send`, by hash.
+    uploads: Mutex<HashMap<String, (PathBuf, FileMeta)>>,
+    /// Files others shared, by name.
+    offers: Mutex<HashMap<String, FileMeta>>,
+}
+
+impl Files {
+    fn new(download_dir: Option<&str>) -> Self {
+        let download_dir = match download_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var("HOME").unwrap_or_else(|_| "~".to_string())).join("Downloads").join("antimpeu"),
+        };
+        Self { download_dir, uploads: Mutex::new(HashMap::new()), offers: Mutex::new(HashMap::new()) }
+    }
+
+    /// Get ready to upload `path`; returns the offer to send.
+    fn share(&self, path: &Path) -> Result<FileMeta, String> {
+        let meta = crate::transfer::describe(path)?;
+        if meta.size == 0 {
+            return Err(format!("{} is empty", path.display()));
+        }
+        self.uploads.lock().unwrap().insert(meta.hash.clone(), (path.to_path_buf(), meta.clone()));
+        Ok(meta)
+    }
+
+    /// Request for the next chunk of a shared file called `name`.
+    fn fetch(&self, name: &str) -> Result<FileMeta, String> {
+        let offers = self.offers.lock().unwrap();
+        let meta = offers.get(name).ok_or_else(|| format!("Nobody has shared a file called {}", name))?;
+        Ok(meta.at(crate::transfer::received(&crate::transfer::download_part(&self.download_dir, meta))))
+    }
+
+    /// Handle a `file-*` frame from the server; returns the frame to answer
+    /// with, if any.
+    fn handle(&self, messages: &SharedMessages<crate::tui::Message>, status: &SharedStatus, m: Payload) -> Option<Payload<'static>> {
+        let meta = m.file.clone().filter(|f| f.validate().is_ok())?;
+        match m.kind {
+            Kind::FileOffer => {
+                if self.uploads.lock().unwrap().remove(&meta.hash).is_some() {
+                    status.lock().unwrap().transfers.remove(&format!("up {}", meta.hash));
+                    system(messages, format!("Shared {}", meta.name));
+                } else {
+                    system(messages, format!("{} shared {} ({}); /get {} to download", m.username, meta.name, crate::transfer::format_size(meta.size), meta.name));
+                    self.offers.lock().unwrap().insert(meta.name.clone(), meta);
                 }
+                None
+            }
+            Kind::FileRequest => {
+                let (path, upload) = self.uploads.lock().unwrap().get(&meta.hash).cloned()?;
+                let key = format!("up {}", meta.hash);
+                let upload = upload.at(meta.offset);
+                let data = match crate::transfer::read_chunk(&path, &upload) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        status.lock().unwrap().transfers.remove(&key);
+                        system(messages, e);
+                        return None;
+                    }
+                };
+                let done = upload.offset + data.len() as u64;
+                status.lock().unwrap().transfers.insert(key, Transfer { name: upload.name.clone(), size: upload.size, done, upload: true });
+                let mut chunk = Payload { file: Some(upload), ..Payload::control(Kind::FileChunk, "", "") };
+                chunk.text = hex::encode(data).into();
+                Some(chunk)
             }
+            Kind::FileChunk => {
+                let part = crate::transfer::download_part(&self.download_dir, &meta);
+                let key = format!("down {}", meta.hash);
+                let data = hex::decode(m.text.as_bytes()).map_err(|_| "invalid chunk encoding".to_string());
+                let done = match data.and_then(|data| crate::transfer::append_chunk(&part, &meta, &data)) {
+                    Ok(done) => done,
+                    Err(e) => {
+                        status.lock().unwrap().transfers.remove(&key);
+                        system(messages, format!("Download of {} failed: {}", meta.name, e));
+                        return None;
+                    }
+                };
+                if done < meta.size {
+                    status.lock().unwrap().transfers.insert(key, Transfer { name: meta.name.clone(), size: meta.size, done, upload: false });
+                    return Some(Payload { file: Some(meta.at(done)), ..Payload::control(Kind::FileRequest, "", "") });
+                }
+                status.lock().unwrap().transfers.remove(&key);
+                let mut dest = self.download_dir.join(&meta.name);
+                if dest.exists() {
+                    dest = self.download_dir.join(format!("{}-{}", &meta.hash[..8], meta.name));
+                }
+                match crate::transfer::finish(&part, &meta, &dest) {
+                    Ok(()) => system(messages, format!("Saved {} to {}", meta.name, dest.display())),
+                    Err(e) => system(messages, format!("Download failed: {}", e)),
+                }
+                None
+            }
+            _ => None,
         }
     }
+}
+
+/// Time of a message: now for live messages, the original time for ones
+/// replayed from the server's history.
+fn message_time(time: Option<i64>) -> chrono::DateTime<chrono::Utc> {
+    time.and_then(|t| chrono::DateTime::from_timestamp(t, 0)).unwrap_or_else(chrono::Utc::now)
+}
+
+/// Exit status of `antimpeu send` when the server could not be reached or
+/// the handshake failed.
+pub const EXIT_CONNECT: i32 = 3;
+/// Exit status of `antimpeu send` when the server rejected the login.
+pub const EXIT_LOGIN: i32 = 4;
+/// Exit status of `antimpeu send` when the server did not relay the
+/// message, e.g. because of slow mode or a filter.
+pub const EXIT_NOT_DELIVERED: i32 = 5;
+/// How long `antimpeu send` waits for the server to relay the message.
+const SEND_CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection that went through the handshake and, when the server asks
+/// for it, the login.
+struct Connected {
+    stream: TcpStream,
+    accepted: Accepted,
+    cipher: SessionCipher,
+    /// Name we speak under: the account when logged in.
+    username: String,
+    /// Sequence number of the last frame we sent.
+    seq: u64,
+}
+
+/// Connect and log in without a terminal, for `send` and `tail`. Errors
+/// carry the exit status.
+fn connect_headless(ip: &str, port: u16, dek: Option<[u8; 32]>, observe: bool, password: Option<String>, config: &crate::config::Config) -> Result<Connected, (i32, String)> {
+    let addr = format!("{}:{}", ip, port);
+    let (mut stream, accepted, _) = connect(ip, port, dek, observe, false, &config.client).map_err(|e| (EXIT_CONNECT, e))?;
+    let cipher = SessionCipher::new(&accepted.session_key, Side::Client);
+    let mut username = config.client.nickname.clone().unwrap_or_else(whoami::username);
+    let mut seq = 0;
+    if accepted.login {
+        let account = config.client.account.clone().unwrap_or(username);
+        login(&mut stream, &cipher, &account, &addr, accepted.framing, password, config.client.timeouts.login()).map_err(|e| (EXIT_LOGIN, e))?;
+        username = account;
+        seq += 1;
+    }
+    Ok(Connected { stream, accepted, cipher, username, seq })
+}
+
+/// Connection of `antimpeu send`, which may deliver several messages. The
+/// server relays every message back to its sender too, so a message counts
+/// as delivered once it comes back; a notice instead means it was rejected.
+pub struct Sender {
+    stream: TcpStream,
+    cipher: SessionCipher,
+    framing: Framing,
+    username: String,
+    seq: u64,
+    room: Option<crate::identity::SignedRoom>,
+    /// IDs of the messages we sent. The server may relay them under another
+    /// name than ours, so they are recognised by ID.
+    sent: Arc<Mutex<std::collections::HashSet<String>>>,
+    /// Our own messages coming back and the server's notices. A reader
+    /// thread drains the connection so a busy room cannot fill the
+    /// server's queue for us while we wait for input.
+    replies: std::sync::mpsc::Receiver<Result<Payload<'static>, String>>,
+}
+
+impl Sender {
+    /// Connect and log in. Errors carry the exit status.
+    pub fn connect(ip: &str, port: u16, dek: Option<[u8; 32]>, password: Option<String>, config: &crate::config::Config) -> Result<Self, (i32, String)> {
+        let Connected { stream, accepted, cipher, username, seq } = connect_headless(ip, port, dek, false, password, config)?;
+        let room = if config.room.signed { Some(crate::identity::SignedRoom::load().map_err(|e| (2, e))?) } else { None };
+        stream.set_read_timeout(None).ok();
+        let mut reader = stream.try_clone().map_err(|e| (EXIT_CONNECT, e.to_string()))?;
+        let (tx, replies) = std::sync::mpsc::channel();
+        let sent: Arc<Mutex<std::collections::HashSet<String>>> = Arc::default();
+        let (cipher_reader, sent_reader) = (cipher.clone(), sent.clone());
+        thread::spawn(move || {
+            let mut frame_buf = bytes::BytesMut::new();
+            let mut last_seq = 0;
+            loop {
+                let reply = match crate::crypto::read_one_encrypted(&mut reader, &cipher_reader, &mut frame_buf, &mut last_seq) {
+                    Ok(m) if m.kind == Kind::Reply || (matches!(m.kind, Kind::Chat | Kind::Ack) && m.id.as_deref().is_some_and(|id| sent_reader.lock().unwrap().contains(id))) => Ok(m),
+                    Ok(_) => continue,
+                    Err(e) => Err(e.to_string()),
+                };
+                let failed = reply.is_err();
+                if tx.send(reply).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Self { stream, cipher, framing: accepted.framing, username, seq, room, sent, replies })
+    }
+
+    /// Send `text` and wait until the server relays it. Returns the message
+    /// ID; errors carry the exit status.
+    pub fn send(&mut self, text: &str) -> Result<String, (i32, String)> {
+        let signature = self.room.as_ref().map(|r| r.sign(&self.username, text));
+        let payload = Payload::chat(&self.username, text, signature.as_deref());
+        self.sent.lock().unwrap().insert(payload.id.as_deref().unwrap_or_default().to_string());
+        self.seq += 1;
+        crate::crypto::send_encrypted(&mut self.stream, &payload, &self.cipher, self.seq, self.framing).map_err(|e| (EXIT_CONNECT, format!("Failed to send: {}", e)))?;
+        let deadline = std::time::Instant::now() + SEND_CONFIRM_TIMEOUT;
+        loop {
+            let wait = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.replies.recv_timeout(wait) {
+                Ok(Ok(m)) if m.kind == Kind::Chat && m.id == payload.id => return Ok(m.id.unwrap_or_default().into_owned()),
+                Ok(Ok(m)) if m.kind == Kind::Ack && m.id == payload.id && !m.text.is_empty() => return Err((EXIT_NOT_DELIVERED, m.text.into_owned())),
+                Ok(Ok(m)) if m.kind == Kind::Reply => return Err((EXIT_NOT_DELIVERED, m.text.into_owned())),
+                // an earlier message that timed out
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err((EXIT_CONNECT, format!("Connection lost: {}", e))),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => return Err((EXIT_NOT_DELIVERED, "The server did not confirm the message".to_string())),
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Err((EXIT_CONNECT, "Connection lost".to_string())),
+            }
+        }
+    }
+}
+
+/// One line of `--json` output from `send` and `tail`. The event names
+/// and field names are an interface for other tools: add fields, do not
+/// rename them. Times are RFC 3339 with the local offset.
+#[derive(serde::Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A chat message; `replayed` ones come from the server's history.
+    /// `user` is the sender's stable ID, see `Payload::user`.
+    Message { time: String, sender: &'a str, user: Option<&'a str>, text: &'a str, id: Option<&'a str>, verified: Option<bool>, replayed: bool },
+    /// A client connected to the server.
+    Join { time: String, address: &'a str },
+    /// A client disconnected.
+    Leave { time: String, address: &'a str },
+    Topic { time: String, by: Option<&'a str>, text: &'a str },
+    SlowMode { time: String, seconds: u64 },
+    /// A notice from the server, such as a rejected message.
+    Notice { time: String, text: &'a str },
+    File { time: String, sender: &'a str, name: &'a str, size: u64 },
+    /// `send` delivered its message.
+    Sent { time: String, id: &'a str },
+    /// The server shut down.
+    Closed { time: String },
+    /// The command failed; `code` is its exit status.
+    Error { time: String, code: i32, text: &'a str },
+}
+
+/// `time` in the form `Event` uses; now when None.
+pub fn event_time(time: Option<i64>) -> String {
+    let time = time.and_then(|t| chrono::DateTime::from_timestamp(t, 0)).map(|t| t.with_timezone(&chrono::Local)).unwrap_or_else(chrono::Local::now);
+    time.to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+}
+
+/// Write `event` as one JSON line; false when stdout is gone.
+pub fn emit(out: &mut impl std::io::Write, event: &Event) -> bool {
+    serde_json::to_writer(&mut *out, event).is_ok() && writeln!(out).is_ok()
+}
+
+/// Print the room to stdout for `antimpeu tail` until the server goes
+/// away. By default one chat message per line as `[date time] sender:
+/// text`, with notices from the server on stderr so stdout carries nothing
+/// but chat; with `json`, every event as an `Event` on stdout. Ephemeral
+/// messages are skipped, as they are not meant to be kept. Asks to join
+/// read-only; servers without observers see a member that never speaks.
+/// Errors carry the exit status; a server shutdown is not one.
+pub fn tail(ip: &str, port: u16, dek: Option<[u8; 32]>, password: Option<String>, json: bool, config: &crate::config::Config) -> Result<(), (i32, String)> {
+    use std::io::Write;
+    let Connected { mut stream, accepted, cipher, username, mut seq } = connect_headless(ip, port, dek, true, password, config)?;
+    let room = if config.room.signed { Some(crate::identity::SignedRoom::load().map_err(|e| (2, e))?) } else { None };
+    seq += 1;
+    crate::crypto::send_encrypted(&mut stream, &Payload::control(Kind::Join, &username, ""), &cipher, seq, accepted.framing).map_err(|e| (EXIT_CONNECT, format!("Failed to join: {}", e)))?;
     stream.set_read_timeout(None).ok();
 
-    let messages: Arc<Mutex<Vec<crate::tui::Message>>> = Arc::new(Mutex::new(Vec::new()));
+    let mut out = std::io::stdout().lock();
+    let mut seen = SeenIds::default();
+    let mut frame_buf = bytes::BytesMut::new();
+    let mut last_seq = 0;
+    loop {
+        let m = match crate::crypto::read_one_encrypted(&mut stream, &cipher, &mut frame_buf, &mut last_seq) {
+            Ok(m) => m,
+            Err(crate::frame::ProtocolError::Eof) => {
+                if json {
+                    emit(&mut out, &Event::Closed { time: event_time(None) });
+                } else {
+                    eprintln!("Server has shut down");
+                }
+                return Ok(());
+            }
+            Err(e) => return Err((EXIT_CONNECT, format!("Connection lost: {}", e))),
+        };
+        let time = event_time(m.time);
+        let event = match m.kind {
+            Kind::Chat if m.ttl.is_some() || m.id.as_deref().is_some_and(|id| !seen.insert(id)) => continue,
+            Kind::Chat => {
+                let verified = room.as_ref().map(|r| r.verify(&m.username, &m.text, m.signature.as_deref()));
+                if !json {
+                    let unverified = if verified == Some(false) { " (unverified)" } else { "" };
+                    // the reader went away, e.g. `antimpeu tail ... | head`
+                    if writeln!(out, "[{}] {}: {}{}", &time[..19].replacen('T', " ", 1), m.username, m.text, unverified).is_err() {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                let server = m.username == "Server";
+                match m.text.strip_prefix(crate::server::JOIN_NOTICE).filter(|_| server) {
+                    Some(address) => Event::Join { time, address },
+                    None => match m.text.strip_prefix(crate::server::LEAVE_NOTICE).filter(|_| server) {
+                        Some(address) => Event::Leave { time, address },
+                        None => Event::Message { time, sender: &m.username, user: m.user.as_deref(), text: &m.text, id: m.id.as_deref(), verified, replayed: m.time.is_some() },
+                    },
+                }
+            }
+            Kind::Topic => Event::Topic { time, by: Some(m.username.as_ref()).filter(|by| !by.is_empty()), text: &m.text },
+            Kind::SlowMode => Event::SlowMode { time, seconds: m.text.parse().unwrap_or(0) },
+            Kind::Roster | Kind::Reply => Event::Notice { time, text: &m.text },
+            Kind::FileOffer => match m.file.as_ref().filter(|f| f.validate().is_ok()) {
+                Some(meta) => Event::File { time, sender: &m.username, name: &meta.name, size: meta.size },
+                None => continue,
+            },
+            Kind::FileRequest | Kind::FileChunk | Kind::Join | Kind::Who | Kind::Login | Kind::Command | Kind::Nick | Kind::Ack | Kind::Resend | Kind::Peers | Kind::Ping | Kind::Pong => continue,
+        };
+        if json {
+            if !emit(&mut out, &event) {
+                return Ok(());
+            }
+            continue;
+        }
+        match event {
+            Event::Topic { text, .. } => eprintln!("Topic: {}", text),
+            Event::SlowMode { seconds: 0, .. } => eprintln!("Slow mode off"),
+            Event::SlowMode { seconds, .. } => eprintln!("Slow mode: one message every {}s", seconds),
+            Event::Notice { text, .. } => eprintln!("{}", text),
+            Event::File { sender, name, size, .. } => eprintln!("{} shared {} ({})", sender, name, crate::transfer::format_size(size)),
+            _ => {}
+        }
+    }
+}
+
+/// Start a client connection, run the handshake and launch the TUI.
+/// The function blocks and runs the TUI in the current thread. With
+/// `observe`, ask to join read-only. `password` is a remembered login
+/// password, see `session`.
+pub fn run_client_with_tui(ip: String, port: u16, dek: Option<[u8; 32]>, observe: bool, password: Option<String>, config: &crate::config::Config) {
+    let addr = format!("{}:{}", ip, port);
+    let (mut stream, accepted, dek) = match connect(&ip, port, dek, observe, true, &config.client) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if accepted.observer {
+        println!("Connected to {} (read-only)", addr);
+    } else {
+        println!("Connected to {}", addr);
+        if observe {
+            println!("The server does not admit observers; joined as a member");
+        }
+    }
+    let framing = accepted.framing;
+    let cipher = SessionCipher::new(&accepted.session_key, Side::Client);
+    // drafts stay on disk, sealed with the DEK rather than this connection's key
+    let draft_cipher = Aes256Gcm::new_from_slice(&dek).expect("Invalid DEK");
+
+    // Local log of the chat for /search
+    let log = if config.client.keep_log {
+        let log_config = crate::history::HistoryConfig { enabled: true, ..Default::default() };
+        match crate::history::History::open_local(crate::history::local_history_path(), &dek, log_config) {
+            Ok(log) => Some(Arc::new(log)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    // On servers that require it, log in first; we then speak under the account name
+    let mut username = config.client.nickname.clone().unwrap_or_else(whoami::username);
+    let mut seq = 0;
+    let mut password = password;
+    if accepted.login {
+        let account = config.client.account.clone().unwrap_or(username);
+        let remembered = password.is_some();
+        match login(&mut stream, &cipher, &account, &addr, framing, password, config.client.timeouts.login()) {
+            Ok(used) => password = Some(used),
+            Err(e) if remembered => {
+                eprintln!("{} with the remembered password; run antimpeu logout to type it again", e);
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+        username = account;
+        seq += 1;
+    }
+    if config.client.remember_session {
+        let session = crate::session::Session { server: addr.clone(), dek, password: password.filter(|_| accepted.login) };
+        if let Err(e) = crate::session::save(&session) {
+            eprintln!("Could not remember the session: {}", e);
+        }
+    }
+
+    let room = if config.room.signed {
+        match crate::identity::SignedRoom::load() {
+            Ok(room) => Some(Arc::new(room)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let hooks = match crate::hooks::Hooks::new(&config.client.hooks, &username) {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let messages: SharedMessages<crate::tui::Message> = Arc::new(Mutex::new(MessageBuffer::new(config.tui.history_limit)));
     let messages_clone = messages.clone();
+    let seen = Arc::new(Mutex::new(SeenIds::default()));
+    let status: SharedStatus = Arc::default();
+    if let Some(log) = &log {
+        for notice in log.notices() {
+            system(&messages, notice.clone());
+        }
+        start_scrollback(log.clone(), &messages, &seen, &status);
+    }
+    {
+        let mut status = status.lock().unwrap();
+        status.name = Some(username.clone());
+        status.commands = COMMANDS.iter().chain(crate::server::COMMANDS).copied().collect();
+    }
+    match crate::drafts::take(&addr, &draft_cipher) {
+        Ok(draft) => status.lock().unwrap().draft = draft,
+        Err(e) => system(&messages, e),
+    }
+    let status_reader = status.clone();
     let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let shutdown_reader = shutdown.clone();
+    // Set when the connection broke rather than closed cleanly
+    let lost: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+    let lost_reader = lost.clone();
 
-    // Reader thread
+    // Announce our name so we show up in the roster, signed so the server
+    // gives us back the name it remembers for our key
+    let personal = match crate::identity::load_or_create(&crate::identity::user_key_path()) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let join_signature = crate::identity::sign_join(&personal, &accepted.challenge, &username);
+    seq += 1;
+    if let Err(e) = crate::crypto::send_encrypted(&mut stream, &Payload { signature: Some(join_signature.into()), ..Payload::control(Kind::Join, &username, "") }, &cipher, seq, framing) {
+        eprintln!("Failed to join: {}", e);
+        return;
+    }
+
+    // The TUI send closure and the reader, which answers file transfer
+    // requests, share the writer. The sequence number lives with the stream
+    // so frames are numbered in write order; the login and join frames came
+    // first.
     let mut stream_reader = stream.try_clone().expect("Could not clone stream for reader thread");
+    let stream_writer = Arc::new(Mutex::new((stream, seq)));
+    let files = Arc::new(Files::new(config.client.download_dir.as_deref()));
+
+    // Pick up downloads interrupted by an earlier disconnect
+    for meta in crate::transfer::pending_downloads(&files.download_dir) {
+        send_frame(&stream_writer, &cipher, framing, &Payload { file: Some(meta), ..Payload::control(Kind::FileRequest, &username, "") });
+    }
+
+    // our chat messages the server has not acknowledged yet, by ID
+    let pending: Pending = Arc::default();
+    // and those it had not acknowledged when the last connection closed,
+    // sent again under the same IDs so it relays them at most once
+    match crate::drafts::take_unsent(&addr, &draft_cipher) {
+        Ok(unsent) if !unsent.is_empty() => {
+            system(&messages, format!("Sending {} messages again that {} had not acknowledged", unsent.len(), addr));
+            for payload in unsent {
+                let Some(id) = payload.id.as_deref() else { continue };
+                seen.lock().unwrap().insert(id);
+                if accepted.acks {
+                    status.lock().unwrap().delivery.insert(id.to_string(), Delivery::Sending);
+                    let now = std::time::Instant::now();
+                    pending.lock().unwrap().insert(id.to_string(), PendingSend { payload: payload.clone(), queued: now, deadline: now + ACK_TIMEOUT, retries: 0 });
+                }
+                send_frame(&stream_writer, &cipher, framing, &payload);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => system(&messages, e),
+    }
+
+    // Reader thread
     let cipher_reader = cipher.clone();
+    let room_reader = room.clone();
+    let (writer_reader, files_reader, log_reader, seen_reader, pending_reader) = (stream_writer.clone(), files.clone(), log.clone(), seen.clone(), pending.clone());
+    let (addr_reader, draft_cipher_reader) = (addr.clone(), draft_cipher.clone());
+    let reader_span = tracing::info_span!("reader", server = %addr);
     thread::spawn(move || {
+        let _span = reader_span.entered();
+        let mut frame_buf = bytes::BytesMut::new();
+        let mut last_seq = 0;
+        // highest serial seen; resent messages come in below it
+        let mut last_serial: Option<u64> = None;
         loop {
-            match crate::crypto::read_one_encrypted(&mut stream_reader, &cipher_reader) {
-                Some((username, msg)) => {
-                    let mut msgs = messages_clone.lock().unwrap();
-                    msgs.push(crate::tui::Message { sender: username, text: msg, time: chrono::Local::now().format("%H:%M").to_string() });
+            match crate::crypto::read_one_encrypted(&mut stream_reader, &cipher_reader, &mut frame_buf, &mut last_seq) {
+                Ok(m) => {
+                    tracing::trace!(kind = ?m.kind, seq = last_seq, serial = ?m.serial, "frame");
+                    // a gap in the serials means relayed chat never reached us
+                    if let Some(serial) = m.serial {
+                        if let Some(last) = last_serial.filter(|last| serial > last + 1) {
+                            tracing::debug!(from = last + 1, to = serial - 1, "missed messages; asking for a resend");
+                            let range = format!("{} {}", last + 1, serial - 1);
+                            send_frame(&writer_reader, &cipher_reader, framing, &Payload::control(Kind::Resend, "", &range));
+                            let text = format!("Missed {} messages; asked the server for them again", serial - last - 1);
+                            messages_clone.lock().unwrap().push_event(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                        }
+                        last_serial = Some(last_serial.map_or(serial, |last| last.max(serial)));
+                    }
+                    match m.kind {
+                        // our own message coming back, or one we saw before a replay
+                        Kind::Chat if m.id.as_deref().is_some_and(|id| !seen_reader.lock().unwrap().insert(id)) => {}
+                        Kind::Chat if crate::server::is_connection_notice(&m.username, &m.text) => {
+                            messages_clone.lock().unwrap().push_event(crate::tui::Message { sender: "System".to_string(), text: m.text.into_owned(), time: message_time(m.time), verified: None, expires: None, user: None, id: None });
+                        }
+                        Kind::Chat => {
+                            // ephemeral messages are not kept, and replayed ones were
+                            // logged when they were live
+                            if let Some(log) = log_reader.as_ref().filter(|_| m.ttl.is_none() && m.time.is_none()) {
+                                if let Err(e) = log.append(&m) {
+                                    system(&messages_clone, e);
+                                }
+                            }
+                            let verified = room_reader.as_ref().map(|r| r.verify(&m.username, &m.text, m.signature.as_deref()));
+                            let message = crate::tui::Message { sender: m.username.into_owned(), text: m.text.into_owned(), time: message_time(m.time), verified, expires: None, user: m.user.map(Cow::into_owned), id: m.id.map(Cow::into_owned) };
+                            // live messages from others only: not our other sessions, not replays
+                            let own = {
+                                let status = status_reader.lock().unwrap();
+                                crate::tui::is_own(&message, status.name.as_deref().unwrap_or_default(), status.user_id.as_deref())
+                            };
+                            if m.time.is_none() && !own {
+                                for e in hooks.fire(&message.sender, &message.text) {
+                                    system(&messages_clone, e);
+                                }
+                            }
+                            match m.ttl {
+                                Some(ttl) => crate::tui::push_ephemeral(&messages_clone, message, std::time::Duration::from_secs(ttl)),
+                                None => messages_clone.lock().unwrap().push(message),
+                            }
+                        }
+                        Kind::Topic => {
+                            let text = match m.username.as_ref() {
+                                "" => format!("Topic: {}", m.text),
+                                by => format!("{} changed the topic to: {}", by, m.text),
+                            };
+                            status_reader.lock().unwrap().topic = Some(m.text.into_owned());
+                            messages_clone.lock().unwrap().push(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                        }
+                        Kind::SlowMode => {
+                            let secs: u64 = m.text.parse().unwrap_or(0);
+                            let mut status = status_reader.lock().unwrap();
+                            status.slow_mode = Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+                            if status.slow_mode.is_none() {
+                                status.next_send = None;
+                            }
+                            drop(status);
+                            let text = match (m.username.as_ref(), secs) {
+                                ("", _) => format!("Slow mode: one message every {}s", secs),
+                                (by, 0) => format!("{} turned slow mode off", by),
+                                (by, _) => format!("{} set slow mode to one message every {}s", by, secs),
+                            };
+                            messages_clone.lock().unwrap().push(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                        }
+                        Kind::Nick => {
+                            let mut status = status_reader.lock().unwrap();
+                            let renamed = status.name.as_deref() != Some(&m.username);
+                            status.name = Some(m.username.to_string());
+                            status.user_id = m.user.map(Cow::into_owned);
+                            drop(status);
+                            match m.text.as_ref() {
+                                "" if renamed => system(&messages_clone, format!("You are known here as {}; /nick changes it", m.username)),
+                                "" => {}
+                                notice => system(&messages_clone, notice.to_string()),
+                            }
+                        }
+                        Kind::Ack => {
+                            let Some(id) = m.id.as_deref().filter(|id| pending_reader.lock().unwrap().remove(*id).is_some()) else { continue };
+                            let delivery = if m.text.is_empty() { Delivery::Delivered } else { Delivery::Failed };
+                            status_reader.lock().unwrap().delivery.insert(id.to_string(), delivery);
+                            if delivery == Delivery::Failed {
+                                system(&messages_clone, m.text.into_owned());
+                            }
+                        }
+                        Kind::Roster | Kind::Reply => {
+                            let mut msgs = messages_clone.lock().unwrap();
+                            for line in m.text.lines() {
+                                msgs.push(crate::tui::Message { sender: "System".to_string(), text: line.to_string(), time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                            }
+                        }
+                        Kind::FileOffer | Kind::FileRequest | Kind::FileChunk => {
+                            let reply = files_reader.handle(&messages_clone, &status_reader, m);
+                            if let Some(reply) = reply {
+                                send_frame(&writer_reader, &cipher_reader, framing, &reply);
+                            }
+                        }
+                        Kind::Ping => send_frame(&writer_reader, &cipher_reader, framing, &Payload::control(Kind::Pong, "", &m.text)),
+                        Kind::Pong => {
+                            if let Some(rtt) = crate::net::pong_rtt(&m.text) {
+                                tracing::trace!(rtt_ms = rtt.as_millis() as u64, "pong");
+                                crate::types::sample_rtt(&mut status_reader.lock().unwrap().rtt, rtt);
+                            }
+                        }
+                        // client-to-server or peer mode only
+                        Kind::Join | Kind::Who | Kind::Login | Kind::Command | Kind::Resend | Kind::Peers => {}
+                    }
                 }
-                None => {
-                    // Inform TUI that the server shut down
+                Err(e) => {
+                    tracing::info!(error = %e, "connection ended");
+                    // Inform TUI that the server shut down or the connection broke
+                    let text = match e {
+                        crate::frame::ProtocolError::Eof => "Server has shut down".to_string(),
+                        e => {
+                            *lost_reader.lock().unwrap() = Some(e.to_string());
+                            format!("Connection lost: {}", e)
+                        }
+                    };
+                    // save the draft now rather than when the client exits
+                    let draft = status_reader.lock().unwrap().draft.clone();
+                    if let Some(Err(e)) = draft.map(|draft| crate::drafts::save(&addr_reader, &draft_cipher_reader, &draft)) {
+                        tracing::warn!(error = %e, "could not save the draft");
+                    }
                     let mut msgs = messages_clone.lock().unwrap();
-                    msgs.push(crate::tui::Message { sender: "System".to_string(), text: "Server has shut down".to_string(), time: chrono::Local::now().format("%H:%M").to_string() });
+                    msgs.push(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
                     shutdown_reader.store(true, std::sync::atomic::Ordering::SeqCst);
                     break;
                 }
@@ -61,19 +844,201 @@ pub fn run_client_with_tui(ip: String, port: u16, cipher: Aes256Gcm) {
         }
     });
 
-    // TUI send closure
-    let stream_writer = Arc::new(Mutex::new(stream));
+    // Send messages the server did not acknowledge again, under the same
+    // ID so nobody shows them twice, and flag them once out of retries.
+    // What is still pending when the connection closes is saved and sent
+    // again on the next connection to this server.
+    if accepted.acks {
+        let (pending, messages, status, shutdown) = (pending.clone(), messages.clone(), status.clone(), shutdown.clone());
+        let (writer, cipher, max_retries) = (stream_writer.clone(), cipher.clone(), config.client.send_retries);
+        thread::spawn(move || {
+            while !shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(1));
+                let now = std::time::Instant::now();
+                let mut failed = Vec::new();
+                let mut pending = pending.lock().unwrap();
+                pending.retain(|id, send| {
+                    if send.deadline > now {
+                        return true;
+                    }
+                    if send.retries == max_retries {
+                        failed.push((id.clone(), preview(&send.payload.text)));
+                        return false;
+                    }
+                    send.retries += 1;
+                    send.deadline = now + ack_timeout(send.retries);
+                    send_frame(&writer, &cipher, framing, &send.payload);
+                    true
+                });
+                drop(pending);
+                for (id, text) in failed {
+                    status.lock().unwrap().delivery.insert(id, Delivery::Failed);
+                    system(&messages, format!("The server did not acknowledge \"{}\"; it may not have been delivered", text));
+                }
+            }
+        });
+    }
+
+    // Ping the server for the round-trip time in the status bar
+    if accepted.ping {
+        let (writer, cipher, shutdown) = (stream_writer.clone(), cipher.clone(), shutdown.clone());
+        thread::spawn(move || {
+            while !shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+                send_frame(&writer, &cipher, framing, &Payload::control(Kind::Ping, "", &crate::net::ping_token()));
+                thread::sleep(crate::net::HEARTBEAT_INTERVAL);
+            }
+        });
+    }
+
     let cipher_writer = cipher.clone();
-    let username = whoami::username();
+    let status_writer = status.clone();
+    let unacknowledged = pending.clone();
+    let messages_writer = messages.clone();
+    // for /qr: this server, with the identity key pinned for it
+    let server_addr = addr.clone();
+    let pinned_key = crate::identity::pinned(&crate::identity::known_servers_path(), &addr).ok().flatten();
     let send_closure = move |msg: String| {
-        if let Ok(mut s) = stream_writer.lock() {
-            let _ = crate::crypto::send_encrypted(&mut s, &msg, &cipher_writer, &username);
+        // the server may have given us another name since
+        let username = status_writer.lock().unwrap().name.clone().unwrap_or_default();
+        // `/ephemeral` is chat with a lifetime; the TUI does not echo it
+        let (msg, ttl) = match msg.strip_prefix("/ephemeral").filter(|a| a.is_empty() || a.starts_with(char::is_whitespace)) {
+            Some(args) => match crate::tui::parse_ephemeral(args) {
+                Ok((ttl, text)) => {
+                    let user = status_writer.lock().unwrap().user_id.clone();
+                    let echo = crate::tui::Message { sender: username.clone(), text: text.to_string(), time: chrono::Utc::now(), verified: None, expires: None, user, id: None };
+                    crate::tui::push_ephemeral(&messages_writer, echo, ttl);
+                    (text.to_string(), Some(ttl.as_secs()))
+                }
+                Err(usage) => {
+                    messages_writer.lock().unwrap().push(crate::tui::Message { sender: "System".to_string(), text: usage, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                    return;
+                }
+            },
+            None => (msg, None),
+        };
+        // Lines starting with '/' are commands for the server, not chat
+        let command = msg.strip_prefix('/').filter(|_| ttl.is_none());
+        let signature = room.as_ref().filter(|_| command.is_none()).map(|r| r.sign(&username, &msg));
+        match command.map(|c| c.split_once(' ').unwrap_or((c, ""))) {
+            Some(("send", path)) => {
+                // Hashing a large file takes a while; keep the TUI responsive
+                let (writer, cipher, files, messages) = (stream_writer.clone(), cipher_writer.clone(), files.clone(), messages_writer.clone());
+                let path = std::path::PathBuf::from(path.trim());
+                thread::spawn(move || match files.share(&path) {
+                    Ok(meta) => send_frame(&writer, &cipher, framing, &Payload { file: Some(meta), ..Payload::control(Kind::FileOffer, "", "") }),
+                    Err(e) => system(&messages, e),
+                });
+                return;
+            }
+            Some(("search", term)) => {
+                let Some(log) = log.clone() else {
+                    system(&messages_writer, "Search needs a local log; set keep_log = true under [client]".to_string());
+                    return;
+                };
+                let (term, status, messages) = (term.trim().to_string(), status_writer.clone(), messages_writer.clone());
+                thread::spawn(move || match log.search(&term, SEARCH_LIMIT) {
+                    Ok(found) => {
+                        let title = format!("Search: {} ({} matches)", term, found.len());
+                        let lines = found.iter().map(|m| format!("[{}] {} ➢ {}", search_time(m.time), m.username, m.text)).collect();
+                        status.lock().unwrap().overlay = Some(crate::types::Overlay { title, lines });
+                    }
+                    Err(e) => system(&messages, e),
+                });
+                return;
+            }
+            Some(("qr", _)) => {
+                match pinned_key.as_deref().ok_or_else(|| "No key is pinned for this server".to_string()).and_then(|key| crate::qr::overlay(&ip, port, key)) {
+                    Ok(overlay) => status_writer.lock().unwrap().overlay = Some(overlay),
+                    Err(e) => system(&messages_writer, e),
+                }
+                return;
+            }
+            Some(("fingerprint", name)) => {
+                for line in fingerprints(&server_addr, pinned_key.as_deref(), name.trim()) {
+                    system(&messages_writer, line);
+                }
+                return;
+            }
+            Some(("get", name)) => {
+                match files.fetch(name.trim()) {
+                    Ok(meta) => send_frame(&stream_writer, &cipher_writer, framing, &Payload { file: Some(meta), ..Payload::control(Kind::FileRequest, "", "") }),
+                    Err(e) => system(&messages_writer, e),
+                }
+                return;
+            }
+            _ => {}
+        }
+        let payload = match command {
+            None => Payload { ttl, ..Payload::chat(&username, &msg, signature.as_deref()) },
+            Some(command) => match command.split_whitespace().next().unwrap_or_default() {
+                "who" => Payload::control(Kind::Who, &username, ""),
+                _ => Payload::control(Kind::Command, &username, command),
+            },
+        };
+        if let Some(id) = &payload.id {
+            seen.lock().unwrap().insert(id);
+            if accepted.acks && payload.kind == Kind::Chat {
+                let now = std::time::Instant::now();
+                let send = PendingSend { payload: payload.clone().into_owned(), queued: now, deadline: now + ACK_TIMEOUT, retries: 0 };
+                pending.lock().unwrap().insert(id.to_string(), send);
+                let mut status = status_writer.lock().unwrap();
+                status.delivery.insert(id.to_string(), Delivery::Sending);
+                // the TUI does not echo ephemeral messages
+                if payload.ttl.is_none() {
+                    status.sent_id = Some(id.to_string());
+                }
+            }
+        }
+        send_frame(&stream_writer, &cipher_writer, framing, &payload);
+        if let Some(log) = log.as_ref().filter(|_| payload.kind == Kind::Chat && payload.ttl.is_none()) {
+            if let Err(e) = log.append(&payload) {
+                system(&messages_writer, e);
+            }
+        }
+        // The TUI holds back chat until slow mode allows the next message
+        if payload.kind == Kind::Chat {
+            let mut status = status_writer.lock().unwrap();
+            status.next_send = status.slow_mode.map(|slow| std::time::Instant::now() + slow);
         }
     };
 
-    let _ = crate::tui::run_tui_with_sender(send_closure, messages, shutdown.clone());
+    let _ = crate::tui::run_tui_with_sender(send_closure, messages, status.clone(), shutdown.clone(), &config.tui);
+    // messages the server never acknowledged, for the next connection;
+    // ephemeral ones would be stale by then
+    let mut unsent: Vec<_> = unacknowledged.lock().unwrap().drain().map(|(_, send)| send).filter(|send| send.payload.ttl.is_none()).collect();
+    if !unsent.is_empty() {
+        unsent.sort_by_key(|send| send.queued);
+        let unsent: Vec<_> = unsent.into_iter().map(|send| send.payload).collect();
+        match crate::drafts::save_unsent(&addr, &draft_cipher, &unsent) {
+            Ok(()) => println!("Kept {} messages the server did not acknowledge; they are sent again on the next connection to {}", unsent.len(), addr),
+            Err(e) => eprintln!("Could not keep the unacknowledged messages: {}", e),
+        }
+    }
+    // kept for the next connection to this server
+    if let Some(draft) = status.lock().unwrap().draft.take() {
+        match crate::drafts::save(&addr, &draft_cipher, &draft) {
+            Ok(()) => println!("Saved the unsent draft; it is restored on the next connection to {}", addr),
+            Err(e) => eprintln!("Could not save the draft: {}", e),
+        }
+    }
     // After the TUI exits, if the reader signalled a server shutdown, print a single CLI notice.
     if shutdown.load(std::sync::atomic::Ordering::SeqCst) {
-        println!("Antimpeu server has been shut down");
+        match lost.lock().unwrap().take() {
+            Some(reason) => println!("Antimpeu connection lost: {}", reason),
+            None => println!("Antimpeu server has been shut down"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_ack_timeout_doubles_up_to_its_cap() {
+        assert_eq!(ack_timeout(0), ACK_TIMEOUT);
+        assert_eq!(ack_timeout(2), ACK_TIMEOUT * 4);
+        assert_eq!(ack_timeout(4), MAX_ACK_TIMEOUT);
+        assert_eq!(ack_timeout(u32::MAX), MAX_ACK_TIMEOUT);
     }
 }
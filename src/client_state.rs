@@ -0,0 +1,321 @@
+//! The client side of a connection as a state machine without I/O: bytes
+//! from the server go in with `receive`, bytes for the server come out of
+//! `take_output`. It opens no sockets, starts no threads and reads no
+//! files, so it can run over any transport that carries the frames in
+//! order. It is what the crate offers on wasm32, where a browser carries
+//! the frames, for instance over a WebSocket bridged to the server's TCP
+//! port.
+//!
+//! ```no_run
+//! # fn transport_read() -> Vec<u8> { Vec::new() }
+//! # fn transport_write(_: &[u8]) {}
+//! # fn main() -> Result<(), String> {
+//! # let dek = [0u8; 32];
+//! let mut state = antimpeu::client_state::ClientState::new(dek, "alice", None);
+//! state.start();
+//! transport_write(&state.take_output());
+//! loop {
+//!     for event in state.receive(&transport_read())? {
+//!         if let antimpeu::client_state::Event::Message(m) = event {
+//!             println!("{}: {}", m.sender, m.text);
+//!         }
+//!     }
+//!     transport_write(&state.take_output());
+//! }
+//! # }
+//! ```
+//!
+//! Compared with `ChatClient` the state machine leaves two things to its caller:
+//! pinning the server's identity key, which it reports with
+//! `Event::Identity`, and opening a fresh connection when the server hands
+//! out a cookie (`Event::Reconnect`). It cannot ask for the group key, and
+//! its join is not signed, so the server does not remember its name.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use bytes::{BufMut, BytesMut};
+use rand_core::RngCore;
+use crate::crypto::{Kind, Payload, SessionCipher, Side};
+use crate::handshake::Accepted;
+
+/// A chat message, or with `sender` "Server" and no `id`, a notice from the
+/// server such as the reason a message of ours was refused.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub sender: String,
+    /// Sender's stable ID, see `Payload::user`.
+    pub user: Option<String>,
+    pub text: String,
+    /// Message ID, see `Payload::id`.
+    pub id: Option<String>,
+    /// Unix time the server stored a message replayed from its history;
+    /// None for live ones.
+    pub time: Option<i64>,
+}
+
+impl Message {
+    fn notice(text: String) -> Self {
+        Self { sender: "Server".to_string(), user: None, text, id: None, time: None }
+    }
+}
+
+/// The message to pass on for a frame from the server, if any. Our own
+/// messages are taken out of `sent` as they come back, and a rename from
+/// the server updates `name`.
+pub(crate) fn to_message(m: Payload<'static>, sent: &mut HashSet<String>, name: &mut String) -> Option<Message> {
+    match m.kind {
+        Kind::Chat if m.id.as_deref().is_some_and(|id| sent.remove(id)) => None,
+        Kind::Chat => Some(Message { sender: m.username.into_owned(), user: m.user.map(Cow::into_owned), text: m.text.into_owned(), id: m.id.map(Cow::into_owned), time: m.time }),
+        Kind::Reply | Kind::Roster => Some(Message::notice(m.text.into_owned())),
+        // a refusal; the message is not coming back
+        Kind::Ack if !m.text.is_empty() => {
+            if let Some(id) = m.id.as_deref() {
+                sent.remove(id);
+            }
+            Some(Message::notice(m.text.into_owned()))
+        }
+        Kind::Nick => {
+            *name = m.username.into_owned();
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Something the caller has to act on.
+#[derive(Debug)]
+pub enum Event {
+    /// The server handed out a connection cookie and closes the
+    /// connection: open a new one and call `start` again.
+    Reconnect,
+    /// The server proved it knows the group key and signed the handshake
+    /// with this identity key, in hex. Compare it with the one pinned for
+    /// the server, if any, and drop the connection if they differ.
+    Identity(String),
+    /// We are in the room; `send` works from now on.
+    Joined,
+    /// A message or notice, as `ChatClient` passes them on.
+    Message(Message),
+}
+
+/// Where the handshake is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    /// `start` not called yet.
+    Idle,
+    /// HELLO sent, waiting for a cookie or the challenge.
+    Hello,
+    /// Challenge answered, waiting for the server's proof.
+    Proof,
+    /// Login sent, waiting for `LOGIN-OK`.
+    Login,
+    Joined,
+}
+
+/// One connection to a room.
+pub struct ClientState {
+    state: State,
+    dek: [u8; 32],
+    /// The connection's message key, once the server proved itself.
+    cipher: Option<SessionCipher>,
+    name: String,
+    /// Password for servers that require a login; the account is `name`.
+    password: Option<String>,
+    cookie: Option<String>,
+    /// What the server accepted, from its challenge.
+    accepted: Accepted,
+    /// Our address as the server sees it, from its challenge.
+    peer: String,
+    /// Our challenge to the server.
+    our_challenge: [u8; 32],
+    /// Sequence number of the last frame sent.
+    seq: u64,
+    /// Highest sequence number received, see `decrypt_frame`.
+    last_seq: u64,
+    /// IDs of our messages not back from the server yet.
+    sent: HashSet<String>,
+    /// Bytes received that do not make a whole frame yet.
+    input: BytesMut,
+    /// Bytes for the server.
+    output: Vec<u8>,
+}
+
+impl ClientState {
+    /// A connection that joins as `name` with the room's group key, logging
+    /// in with `password` if the server asks.
+    pub fn new(key: [u8; 32], name: &str, password: Option<String>) -> Self {
+        Self {
+            state: State::Idle,
+            dek: key,
+            cipher: None,
+            name: name.to_string(),
+            password,
+            cookie: None,
+            accepted: Accepted::default(),
+            peer: String::new(),
+            our_challenge: [0; 32],
+            seq: 0,
+            last_seq: 0,
+            sent: HashSet::new(),
+            input: BytesMut::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Begin the handshake on a new connection.
+    pub fn start(&mut self) {
+        let mut hello = crate::net::HELLO.to_string();
+        if let Some(cookie) = &self.cookie {
+            hello.push_str(" cookie=");
+            hello.push_str(cookie);
+        }
+        hello.push(' ');
+        hello.push_str(crate::net::CAP_ACK);
+        hello.push(' ');
+        hello.push_str(crate::net::CAP_BASE64);
+        self.state = State::Hello;
+        self.input.clear();
+        self.output.clear();
+        (self.seq, self.last_seq) = (0, 0);
+        self.write_plain(hello.as_bytes());
+    }
+
+    /// Bytes to send to the server, if any.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Whether we are in the room.
+    pub fn is_joined(&self) -> bool {
+        self.state == State::Joined
+    }
+
+    /// Name we speak under; the server may give us another, see
+    /// `Kind::Nick`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue a chat message and return its ID.
+    pub fn send(&mut self, text: &str) -> Result<String, String> {
+        if self.state != State::Joined {
+            return Err("Not in the room yet".to_string());
+        }
+        let name = self.name.clone();
+        let payload = Payload::chat(&name, text, None);
+        let id = payload.id.as_deref().unwrap_or_default().to_string();
+        self.sent.insert(id.clone());
+        self.write_encrypted(&payload);
+        Ok(id)
+    }
+
+    /// Take bytes from the server and return what they amount to. An error
+    /// means the connection is no use any more.
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<Vec<Event>, String> {
+        self.input.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(total) = crate::frame::frame_len(&self.input).map_err(|e| e.to_string())? {
+            if self.input.len() < total {
+                break;
+            }
+            let frame = self.input.split_to(total);
+            match self.state {
+                State::Idle => return Err("Call ClientState::start first".to_string()),
+                State::Hello => {
+                    let reply = String::from_utf8(frame[4..].to_vec()).map_err(|_| "Server sent an invalid challenge".to_string())?;
+                    match reply.strip_prefix("COOKIE:") {
+                        Some(cookie) => {
+                            self.cookie = Some(cookie.to_string());
+                            events.push(Event::Reconnect);
+                        }
+                        None => self.answer_challenge(&reply)?,
+                    }
+                }
+                State::Proof => {
+                    events.push(Event::Identity(self.check_proof(&frame[4..])?));
+                    self.accepted.session_key = crate::auth::derive_session_key(&self.dek, &self.accepted.challenge, &self.our_challenge, &self.peer);
+                    self.cipher = Some(SessionCipher::new(&self.accepted.session_key, Side::Client));
+                    if self.accepted.login {
+                        let password = self.password.clone().ok_or("The server requires a login; pass a password to ClientState::new")?;
+                        let name = self.name.clone();
+                        let login = Payload::control(Kind::Login, &name, &password);
+                        self.write_encrypted(&login);
+                        self.state = State::Login;
+                    } else {
+                        self.join();
+                        events.push(Event::Joined);
+                    }
+                }
+                State::Login if &frame[4..] == b"LOGIN-OK" => {
+                    self.join();
+                    events.push(Event::Joined);
+                }
+                State::Login => return Err(format!("Login as {} failed", self.name)),
+                State::Joined => {
+                    let cipher = self.cipher.as_ref().ok_or("The handshake did not finish")?;
+                    let m = crate::crypto::decrypt_frame(&frame, cipher, &mut self.last_seq).map_err(|e| e.to_string())?;
+                    if let Some(message) = to_message(m, &mut self.sent, &mut self.name) {
+                        events.push(Event::Message(message));
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Prove we know the group key and challenge the server in turn, see
+    /// `handshake::connect`.
+    fn answer_challenge(&mut self, reply: &str) -> Result<(), String> {
+        let mut parts = reply.strip_prefix("CHAL:").ok_or("Server sent an invalid challenge")?.split(' ');
+        let challenge = hex::decode(parts.next().unwrap_or_default()).map_err(|_| "Server sent an invalid challenge".to_string())?;
+        self.peer = parts.next().unwrap_or_default().to_string();
+        self.accepted = Accepted { challenge, ..Default::default() };
+        for cap in parts {
+            match cap {
+                crate::net::CAP_PAD => self.accepted.framing.pad = true,
+                crate::net::CAP_BASE64 => self.accepted.framing.base64 = true,
+                crate::net::CAP_LOGIN => self.accepted.login = true,
+                crate::net::CAP_OBSERVE => self.accepted.observer = true,
+                crate::net::CAP_ACK => self.accepted.acks = true,
+                _ => {}
+            }
+        }
+        aes_gcm::aead::OsRng.fill_bytes(&mut self.our_challenge);
+        let response = crate::auth::handshake_mac(&crate::auth::derive_handshake_key(&self.dek), &self.accepted.challenge, "client", &self.peer);
+        let reply = format!("{} {}", hex::encode(response), hex::encode(self.our_challenge));
+        self.write_plain(reply.as_bytes());
+        self.state = State::Proof;
+        Ok(())
+    }
+
+    /// Check the server's answer to our challenge and return its identity
+    /// key in hex.
+    fn check_proof(&self, proof: &[u8]) -> Result<String, String> {
+        let proof = String::from_utf8_lossy(proof);
+        let mut parts = proof.split(' ');
+        let mac = hex::decode(parts.next().unwrap_or_default()).unwrap_or_default();
+        if !crate::auth::verify_handshake_mac(&crate::auth::derive_handshake_key(&self.dek), &self.our_challenge, "server", &self.peer, &mac) {
+            return Err("Server failed to prove it knows the DEK; refusing to connect".to_string());
+        }
+        let identity = crate::identity::verify_handshake(parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), &self.our_challenge, &self.peer)?;
+        Ok(hex::encode(identity.as_bytes()))
+    }
+
+    fn join(&mut self) {
+        let name = self.name.clone();
+        let join = Payload::control(Kind::Join, &name, "");
+        self.write_encrypted(&join);
+        self.state = State::Joined;
+    }
+
+    fn write_plain(&mut self, data: &[u8]) {
+        self.output.put_u32(data.len() as u32);
+        self.output.put_slice(data);
+    }
+
+    fn write_encrypted(&mut self, payload: &Payload) {
+        self.seq += 1;
+        let cipher = self.cipher.as_ref().expect("frames are only written after the handshake");
+        self.output.extend_from_slice(&crate::crypto::encrypt_frame(payload, cipher, self.seq, self.accepted.framing));
+    }
+}
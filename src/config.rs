@@ -0,0 +1,233 @@
+//! User configuration read from `$HOME/.config/antimpeu/config.toml`.
+//!
+//! Every field has a default, so a missing file or a file that only sets a
+//! few keys is fine. Example:
+//!
+//! ```toml
+//! [tui]
+//! vi_mode = true
+//! history_limit = 10000
+//! theme = "light"
+//!
+//! [room]
+//! signed = true
+//!
+//! [client]
+//! pad_messages = true
+//! strict_server_keys = true
+//! account = "alice"
+//! socks_proxy = "127.0.0.1:9050"
+//!
+//! [socket]
+//! nodelay = true
+//! keepalive_secs = 60
+//!
+//! [log]
+//! file = "/var/log/antimpeu/server.log"
+//! max_size_mb = 10
+//! keep = 5
+//!
+//! [server]
+//! queue_capacity = 512
+//! overflow = "disconnect"
+//! lag_warn = 64
+//! write_timeout_secs = 10
+//! allow_padding = true
+//! require_login = false
+//! allow_observers = false
+//! tor_control = "127.0.0.1:9051"
+//!
+//! [server.filters]
+//! max_length = 2000
+//! wordlist = ["darn", "h[e3]ck"]
+//! wordlist_action = "censor"
+//! plugin = "/usr/local/bin/antimpeu-filter"
+//!
+//! [server.history]
+//! enabled = true
+//! replay = 50
+//! max_age_days = 7
+//! max_messages = 10000
+//!
+//! [profiles.friends]
+//! host = "chat.example.com"
+//! port = 6000
+//! key = "friends"
+//! nickname = "al"
+//! theme = "dark"
+//! ```
+
+use serde::Deserialize;
+pub use crate::server::ServerConfig;
+use crate::tui::{ColorSupport, Theme, Zone};
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub tui: TuiConfig,
+    pub room: RoomConfig,
+    pub client: ClientConfig,
+    pub server: ServerConfig,
+    /// TCP options for chat connections, client and server alike.
+    pub socket: crate::net::SocketConfig,
+    /// Log file for the `tracing` output, and its rotation.
+    pub log: crate::logfile::LogConfig,
+    /// Saved connections for `antimpeu client --profile <name>`.
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A saved connection. Anything left out falls back to the command line
+/// and the rest of the config.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Profile {
+    /// Server host or `antimpeu://` URI.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Key slot: the DEK in `$HOME/key/dek-<key>.bin` instead of `dek.bin`.
+    pub key: Option<String>,
+    /// Overrides `[client] nickname`.
+    pub nickname: Option<String>,
+    /// Overrides `[tui] theme`.
+    pub theme: Option<Theme>,
+}
+
+impl Config {
+    /// Apply the profile `name` to the client and TUI settings and return
+    /// it for the connection details.
+    pub fn use_profile(&mut self, name: &str) -> Result<Profile, String> {
+        let profile = self.profiles.get(name).cloned().ok_or_else(|| format!("No profile {:?} in {}", name, config_path()))?;
+        if let Some(nickname) = &profile.nickname {
+            self.client.nickname = Some(nickname.clone());
+        }
+        if let Some(theme) = profile.theme {
+            self.tui.theme = theme;
+        }
+        Ok(profile)
+    }
+}
+
+/// Settings for the terminal UI.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TuiConfig {
+    /// Use vi-style modal keybindings (normal mode for scrolling and
+    /// searching, insert mode for typing).
+    pub vi_mode: bool,
+    /// Number of messages kept in memory; older ones are dropped.
+    pub history_limit: usize,
+    /// Colour scheme: `dark` or `light`.
+    pub theme: Theme,
+    /// Colours the terminal can show: `auto`, `truecolor`, `256` or `16`.
+    pub colors: ColorSupport,
+    /// Show joins, leaves and other system events in a pane of their own
+    /// at start; F2 toggles it.
+    pub event_pane: bool,
+    /// Reorder Arabic, Hebrew and other right-to-left text for display;
+    /// turn off on terminals that apply the bidi algorithm themselves.
+    pub bidi: bool,
+    /// Print the chat as plain lines and read input at a prompt instead of
+    /// drawing the TUI, for screen readers and dumb terminals.
+    pub plain: bool,
+    /// Timezone message times are shown in: `local`, `utc`, an offset such
+    /// as `+05:30`, or a name such as `Europe/Berlin`.
+    pub timezone: Zone,
+    /// Show message times relative to now, such as `5m ago`.
+    pub relative_times: bool,
+    /// Slash-command aliases from `[tui.aliases]`, by name without the
+    /// slash, see `expand_alias`.
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl Default for TuiConfig {
+    fn default() -> Self {
+        Self { vi_mode: false, history_limit: 5000, theme: Theme::default(), colors: ColorSupport::default(), event_pane: true, bidi: true, plain: false, timezone: Zone::Local, relative_times: false, aliases: BTreeMap::new() }
+    }
+}
+
+/// `text` with an alias from `aliases` expanded: `/name args` becomes the
+/// alias's text with `{args}` replaced by the arguments, or with the
+/// arguments appended when it has no `{args}`. Expansions are not expanded
+/// again, so aliases cannot loop.
+pub fn expand_alias<'a>(aliases: &BTreeMap<String, String>, text: &'a str) -> std::borrow::Cow<'a, str> {
+    let Some(command) = text.strip_prefix('/') else { return text.into() };
+    let (name, args) = command.split_once(' ').map_or((command, ""), |(name, args)| (name, args.trim()));
+    let Some(expansion) = aliases.get(name) else { return text.into() };
+    if expansion.contains("{args}") {
+        expansion.replace("{args}", args).trim().to_string().into()
+    } else if args.is_empty() {
+        expansion.clone().into()
+    } else {
+        format!("{} {}", expansion, args).into()
+    }
+}
+
+/// Settings shared by everyone in the room, server included.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct RoomConfig {
+    /// Sign every message with a personal identity key and verify the
+    /// signatures of others, marking messages with ✔ or ✖.
+    pub signed: bool,
+}
+
+/// Settings for `antimpeu client`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// Ask the server to pad messages to fixed bucket sizes so their length
+    /// is hidden from eavesdroppers.
+    pub pad_messages: bool,
+    /// Refuse to connect when a server's identity key differs from the one
+    /// pinned in `known_servers`. When off, only warn.
+    pub strict_server_keys: bool,
+    /// Name to chat under; defaults to the local user name.
+    pub nickname: Option<String>,
+    /// Account to log in with when the server requires it; defaults to the
+    /// nickname.
+    pub account: Option<String>,
+    /// Where files fetched with `/get` are saved; defaults to
+    /// `$HOME/Downloads/antimpeu`.
+    pub download_dir: Option<String>,
+    /// Keep an encrypted log of the chat in `$HOME/key/local_history` for
+    /// `/search`.
+    pub keep_log: bool,
+    /// SOCKS5 proxy to connect through, such as Tor's `127.0.0.1:9050`.
+    /// `.onion` servers use Tor's default proxy when this is not set.
+    pub socks_proxy: Option<String>,
+    /// Remember the server, DEK and password in the OS keyring after
+    /// connecting, so the next `antimpeu client` needs neither the KEK nor
+    /// the password; see `session`.
+    pub remember_session: bool,
+    /// Commands to run when a message matches, see `hooks`.
+    pub hooks: Vec<crate::hooks::HookConfig>,
+    /// Times a chat message the server does not acknowledge is sent again
+    /// before it is flagged as not delivered.
+    pub send_retries: u32,
+    /// How long to wait for the server while connecting.
+    pub timeouts: crate::net::Timeouts,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self { pad_messages: false, strict_server_keys: true, nickname: None, account: None, download_dir: None, keep_log: false, socks_proxy: None, remember_session: false, hooks: Vec::new(), send_retries: 2, timeouts: crate::net::Timeouts::default() }
+    }
+}
+
+/// Location of the config file.
+pub fn config_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/.config/antimpeu/config.toml", home)
+}
+
+/// Load the config file, falling back to defaults when it does not exist.
+pub fn load() -> Result<Config, String> {
+    let path = config_path();
+    let text = match std::fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path, e))
+}
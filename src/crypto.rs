@@ -1,63 +1,446 @@
-use aes_gcm::{Aes256Gcm, aead::{Aead, OsRng}};
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{AeadInPlace, OsRng}};
+use bytes::Bytes;
+#[cfg(not(target_arch = "wasm32"))]
+use bytes::BytesMut;
 use rand_core::RngCore;
-use serde::{Serialize, Deserialize};
-use std::io::{Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::Write;
+#[cfg(not(target_arch = "wasm32"))]
 use std::net::TcpStream;
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use crate::frame::{ProtocolError, MAX_USERNAME_LEN};
 
-/// JSON-serializable envelope for encrypted messages sent over TCP.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct EncryptedMessage {
-    pub username: String,
-    pub nonce: String,
-    pub ciphertext: String,
-    pub tag: String,
+/// What a frame carries. Everything but `Chat` is a control frame between
+/// one client and the server (or two peers) and is never relayed.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// A chat message, relayed to everybody.
+    #[default]
+    Chat,
+    /// Sent by a client right after the handshake to announce its name,
+    /// optionally with its personal key's signature over the server's
+    /// challenge and the name in `signature` (see `identity::sign_join`).
+    Join,
+    /// Roster request (`/who`).
+    Who,
+    /// Roster reply: one member per line of `text`.
+    Roster,
+    /// Account login right after the handshake when the server requires
+    /// it: account name in `username`, password in `text`.
+    Login,
+    /// Any other slash command, without the slash, in `text`.
+    Command,
+    /// Server notice for one client, such as a command result: one line
+    /// per line of `text`.
+    Reply,
+    /// Room topic, server to client, in `text`: sent on join with an empty
+    /// `username`, and to everybody whenever it changes, with who changed it
+    /// in `username`.
+    Topic,
+    /// Slow mode, server to client: the minimum number of seconds between
+    /// two chat messages in `text` (`0` when off). Sent on join while it is
+    /// on and to everybody when it changes.
+    SlowMode,
+    /// A file is available, described by `file`: from a client that wants
+    /// to share it, and from the server to everybody once it has it, with
+    /// who shared it in `username`.
+    FileOffer,
+    /// Ask for the chunk of `file` at `file.offset`.
+    FileRequest,
+    /// The chunk of `file` at `file.offset`, hex-encoded in `text`.
+    FileChunk,
+    /// The name the server knows this client by, in `username`, and its
+    /// user ID, in `user`: sent on join and after `/nick`, only to clients
+    /// that signed their join. `text` says why, when the name differs from
+    /// the announced one because the server remembers another or the name
+    /// is taken.
+    Nick,
+    /// Ask the server to send relayed chat again, client to server: the
+    /// first and last missing `serial`, space-separated, in `text`.
+    Resend,
+    /// Server to client, with the `ack` capability: the chat message with
+    /// this `id` was relayed, or, with the reason in `text`, refused.
+    Ack,
+    /// Peer mode only: the other peers the sender is connected to, one
+    /// `node address` per line of `text`, see `peer`.
+    Peers,
+    /// Heartbeat, either way, with the `ping` capability: a token in
+    /// `text`, see `net::ping_token`, to send back in a `pong`.
+    Ping,
+    /// Answer to a `ping`, with its token in `text`.
+    Pong,
 }
 
-/// Encrypt and send a message. The serialized JSON is length-prefixed
-/// (u32 BE) so the receiver can read one complete frame at a time.
-pub fn send_encrypted(stream: &mut TcpStream, message: &str, cipher: &Aes256Gcm, username: &str) -> std::io::Result<()> {
-    // Generate random 12-byte nonce
+impl Kind {
+    fn is_chat(&self) -> bool {
+        *self == Kind::Chat
+    }
+}
+
+/// Plaintext sealed inside every frame. Only routing data (sequence number,
+/// nonce) stays visible on the wire; who is speaking is encrypted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Payload<'a> {
+    #[serde(default, skip_serializing_if = "Kind::is_chat")]
+    pub kind: Kind,
+    pub username: Cow<'a, str>,
+    pub text: Cow<'a, str>,
+    /// Sender's signature in signed rooms, see `identity::SignedRoom`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Cow<'a, str>>,
+    /// When a message replayed from the server's history was first sent,
+    /// in Unix seconds. Absent on live messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>,
+    /// Lifetime of an ephemeral message in seconds. Receivers remove it from
+    /// screen when it runs out and the server never stores it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// File and position for the `file-*` kinds, see `transfer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<crate::transfer::FileMeta>,
+    /// Stable ID of a chat message, chosen by whoever wrote it. The same
+    /// message reaches a client more than once when it is relayed back to
+    /// the sender's sessions or replayed from history; receivers show it
+    /// only the first time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Cow<'a, str>>,
+    /// Stable ID of the sender, stamped by the server on relayed chat and
+    /// given to a client with `nick`. Unlike `username` it survives renames;
+    /// see `nicknames::user_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<Cow<'a, str>>,
+    /// Position of a relayed chat message in the room, stamped by the
+    /// server and counting up by one per message, so a client notices a
+    /// message that never reached it and asks for it with `resend`. Frame
+    /// sequence numbers cannot tell: they are shared by all connections.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<u64>,
+}
+
+/// Longest message ID the server relays unchanged.
+pub const MAX_ID_LEN: usize = 64;
+
+/// A fresh random message ID.
+pub fn new_message_id() -> String {
+    let mut id = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+impl<'a> Payload<'a> {
+    pub fn chat(username: &'a str, text: &'a str, signature: Option<&'a str>) -> Self {
+        Self { kind: Kind::Chat, username: username.into(), text: text.into(), signature: signature.map(Into::into), time: None, ttl: None, file: None, id: Some(new_message_id().into()), user: None, serial: None }
+    }
+
+    pub fn control(kind: Kind, username: &'a str, text: &'a str) -> Self {
+        Self { kind, username: username.into(), text: text.into(), signature: None, time: None, ttl: None, file: None, id: None, user: None, serial: None }
+    }
+
+    /// Detach the payload from the buffer it was parsed from.
+    pub fn into_owned(self) -> Payload<'static> {
+        Payload {
+            kind: self.kind,
+            username: Cow::Owned(self.username.into_owned()),
+            text: Cow::Owned(self.text.into_owned()),
+            signature: self.signature.map(|s| Cow::Owned(s.into_owned())),
+            time: self.time,
+            ttl: self.ttl,
+            file: self.file,
+            id: self.id.map(|s| Cow::Owned(s.into_owned())),
+            user: self.user.map(|s| Cow::Owned(s.into_owned())),
+            serial: self.serial,
+        }
+    }
+}
+
+/// How frames to a peer are written, as negotiated in the handshake.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Framing {
+    /// Pad payloads to bucket sizes, see `net::CAP_PAD`.
+    pub pad: bool,
+    /// Base64 rather than hex in the envelope, see `net::CAP_BASE64`.
+    pub base64: bool,
+}
+
+/// Plaintext sizes that padded payloads are rounded up to. Larger payloads
+/// are rounded up to a multiple of the last bucket.
+const PAD_BUCKETS: [usize; 4] = [64, 256, 1024, 4096];
+
+/// Size a payload of `len` bytes is padded to.
+fn padded_len(len: usize) -> usize {
+    let last = PAD_BUCKETS[PAD_BUCKETS.len() - 1];
+    PAD_BUCKETS.iter().copied().find(|&b| b >= len).unwrap_or_else(|| len.div_ceil(last) * last)
+}
+
+/// The end of a connection that wrote a frame. Peers in `peer` mode count
+/// the one that dialed as the client.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn other(self) -> Self {
+        match self {
+            Side::Client => Side::Server,
+            Side::Server => Side::Client,
+        }
+    }
+}
+
+/// The message key of one connection, derived in the handshake by
+/// `auth::derive_session_key`, and the side this end writes as.
+#[derive(Clone)]
+pub struct SessionCipher {
+    cipher: Aes256Gcm,
+    side: Side,
+}
+
+impl SessionCipher {
+    pub fn new(key: &[u8; 32], side: Side) -> Self {
+        Self { cipher: Aes256Gcm::new_from_slice(key).expect("32-byte key"), side }
+    }
+
+    /// The side this end writes as.
+    pub fn side(&self) -> Side {
+        self.side
+    }
+}
+
+/// Associated data authenticated alongside every message: the side that
+/// wrote the frame and its sequence number, so a frame can be neither
+/// reflected back at its sender nor replayed. The connection itself is
+/// bound by the session key, and the sender is inside the ciphertext, so it
+/// is covered by the tag as well.
+fn associated_data(side: Side, seq: u64) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[0] = side as u8;
+    aad[1..].copy_from_slice(&seq.to_be_bytes());
+    aad
+}
+
+/// Encrypt a message and return the complete length-prefixed (u32 BE) JSON
+/// frame, ready to be written to one or many streams. The returned `Bytes`
+/// is reference counted, so fanning it out to many clients does not copy it.
+///
+/// `seq` must increase with every frame a sender writes on a connection;
+/// receivers reject frames that do not. With `framing.pad` the plaintext is
+/// padded to a bucket size so the ciphertext length hides the message
+/// length.
+pub fn encrypt_frame(payload: &Payload, cipher: &SessionCipher, seq: u64, framing: Framing) -> Bytes {
+    // Random 12-byte nonce, from the thread's CSPRNG rather than a system
+    // call per frame
     let mut nonce_bytes = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce_bytes);
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
     let nonce = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&nonce_bytes);
 
-    // AES-GCM returns ciphertext||tag. We split them to store the tag separately
-    let ciphertext_with_tag = cipher.encrypt(nonce, message.as_bytes()).expect("encryption failed");
-    let (ciphertext, tag) = ciphertext_with_tag.split_at(ciphertext_with_tag.len() - 16);
-
-    let encrypted_msg = EncryptedMessage {
-        username: username.to_string(),
-        nonce: hex::encode(nonce),
-        ciphertext: hex::encode(ciphertext),
-        tag: hex::encode(tag),
-    };
-
-    let serialized_msg = serde_json::to_string(&encrypted_msg).expect("serialization failed");
-    let msg_bytes = serialized_msg.as_bytes();
-    let len_bytes = (msg_bytes.len() as u32).to_be_bytes();
-    stream.write_all(&len_bytes)?;
-    stream.write_all(msg_bytes)?;
+    let mut buffer = Vec::with_capacity(64 + payload.username.len() + payload.text.len() + payload.signature.as_ref().map_or(0, |s| s.len()) + 16);
+    serde_json::to_writer(&mut buffer, payload).expect("serialization failed");
+    if framing.pad {
+        // JSON ignores trailing whitespace, so receivers strip the padding
+        // when parsing whether or not they asked for it.
+        buffer.resize(padded_len(buffer.len()), b' ');
+    }
+
+    // AES-GCM appends the tag to the buffer
+    cipher.cipher.encrypt_in_place(nonce, &associated_data(cipher.side, seq), &mut buffer).expect("encryption failed");
+    crate::frame::encode_frame(seq, &nonce_bytes, &buffer, framing.base64).expect("the tag was appended")
+}
+
+/// Encrypt and send a message. The serialized JSON is length-prefixed
+/// (u32 BE) so the receiver can read one complete frame at a time.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn send_encrypted(stream: &mut TcpStream, payload: &Payload, cipher: &SessionCipher, seq: u64, framing: Framing) -> std::io::Result<()> {
+    if crate::net::chaos_fate(false) == crate::net::Fate::Drop {
+        return Ok(());
+    }
+    stream.write_all(&encrypt_frame(payload, cipher, seq, framing))?;
     stream.flush()?;
     Ok(())
 }
 
-/// Read a single encrypted JSON frame, decrypt it with `cipher` and return
-/// (username, plaintext) on success. Returns None on any error or EOF.
-pub fn read_one_encrypted(stream: &mut TcpStream, cipher: &Aes256Gcm) -> Option<(String, String)> {
-    let mut len_buf = [0u8; 4];
-    if stream.read_exact(&mut len_buf).is_err() { return None; }
-    let msg_len = u32::from_be_bytes(len_buf) as usize;
-    let mut buffer = vec![0u8; msg_len];
-    if stream.read_exact(&mut buffer).is_err() { return None; }
+/// Read a single encrypted frame and decrypt it with `cipher`. A clean
+/// close between frames is reported as `ProtocolError::Eof`, so callers can
+/// tell it apart from corruption.
+///
+/// `buf` is scratch space reused across calls to avoid a fresh allocation
+/// per frame. `last_seq` tracks the connection's sequence numbers, see
+/// `decrypt_frame`.
+///
+/// Under `--chaos` frames are dropped here after decryption, and one held
+/// back is handed out on the call after the frame that overtook it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_one_encrypted(stream: &mut TcpStream, cipher: &SessionCipher, buf: &mut BytesMut, last_seq: &mut u64) -> Result<Payload<'static>, ProtocolError> {
+    if let Some(held) = HELD.with(|h| h.borrow_mut().1.take()) {
+        return Ok(held);
+    }
+    loop {
+        crate::net::read_frame(stream, buf)?;
+        let payload = decrypt_frame(buf, cipher, last_seq)?;
+        match crate::net::chaos_fate(true) {
+            crate::net::Fate::Drop => {}
+            crate::net::Fate::Hold if HELD.with(|h| h.borrow().0.is_none()) => HELD.with(|h| h.borrow_mut().0 = Some(payload)),
+            _ => {
+                HELD.with(|h| {
+                    let mut h = h.borrow_mut();
+                    h.1 = h.0.take();
+                });
+                return Ok(payload);
+            }
+        }
+    }
+}
 
-    let encrypted_msg: EncryptedMessage = serde_json::from_slice(&buffer).ok()?;
-    let nonce_bytes = hex::decode(&encrypted_msg.nonce).ok()?;
-    let nonce = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&nonce_bytes);
+#[cfg(not(target_arch = "wasm32"))]
+thread_local! {
+    /// `--chaos` reordering on this reader thread: a frame held back, and
+    /// one due on the next read.
+    static HELD: std::cell::RefCell<(Option<Payload<'static>>, Option<Payload<'static>>)> = const { std::cell::RefCell::new((None, None)) };
+}
+
+/// Decode and decrypt one complete wire frame.
+///
+/// `last_seq` is the highest sequence number accepted so far on this
+/// connection; frames that do not exceed it are rejected as replays and an
+/// accepted frame advances it.
+pub fn decrypt_frame(buffer: &[u8], cipher: &SessionCipher, last_seq: &mut u64) -> Result<Payload<'static>, ProtocolError> {
+    let frame = crate::frame::decode_frame(buffer)?;
+    let nonce = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&frame.nonce);
+    let mut sealed = frame.sealed;
+    cipher.cipher.decrypt_in_place(nonce, &associated_data(cipher.side.other(), frame.seq), &mut sealed).map_err(|_| ProtocolError::Decrypt)?;
+    if frame.seq <= *last_seq {
+        return Err(ProtocolError::Replayed(frame.seq));
+    }
+    *last_seq = frame.seq;
+    let payload: Payload = serde_json::from_slice(&sealed).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    if payload.username.len() > MAX_USERNAME_LEN {
+        return Err(ProtocolError::BadField("username"));
+    }
+    Ok(payload.into_owned())
+}
+
+/// AES key protecting a wrapped group key, derived from the X25519 shared
+/// secret and both public keys.
+fn wrap_cipher(shared: &x25519_dalek::SharedSecret, client_public: &[u8; 32], server_public: &[u8; 32]) -> Aes256Gcm {
+    let mut info = b"antimpeu keywrap v1".to_vec();
+    info.extend_from_slice(client_public);
+    info.extend_from_slice(server_public);
+    let mut key = [0u8; 32];
+    hkdf::Hkdf::<sha2::Sha256>::new(None, shared.as_bytes())
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Aes256Gcm::new_from_slice(&key).expect("32-byte key")
+}
+
+/// Wrap the group key to a client's ephemeral X25519 key. Returns the
+/// KEYWRAP body: `<hex server-public> <hex nonce> <hex ciphertext>`.
+pub fn wrap_key(dek: &[u8; 32], client_public: &[u8; 32]) -> String {
+    let secret = x25519_dalek::EphemeralSecret::random_from_rng(OsRng);
+    let server_public = x25519_dalek::PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*client_public));
+    let cipher = wrap_cipher(&shared, client_public, server_public.as_bytes());
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let nonce_ga = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&nonce);
+    let mut sealed = dek.to_vec();
+    cipher.encrypt_in_place(nonce_ga, b"", &mut sealed).expect("encryption failed");
+    format!("{} {} {}", hex::encode(server_public.as_bytes()), hex::encode(nonce), hex::encode(sealed))
+}
+
+/// Unwrap a group key sent in a KEYWRAP frame with the ephemeral secret
+/// whose public half went into the key request.
+pub fn unwrap_key(secret: x25519_dalek::EphemeralSecret, wrapped: &str) -> Result<[u8; 32], String> {
+    let invalid = || "Server sent an invalid wrapped key".to_string();
+    let client_public = x25519_dalek::PublicKey::from(&secret);
+    let mut parts = wrapped.split(' ');
+    let server_public: [u8; 32] = hex::decode(parts.next().unwrap_or_default()).ok().and_then(|b| b.try_into().ok()).ok_or_else(invalid)?;
+    let nonce: [u8; 12] = hex::decode(parts.next().unwrap_or_default()).ok().and_then(|b| b.try_into().ok()).ok_or_else(invalid)?;
+    let mut sealed = hex::decode(parts.next().unwrap_or_default()).map_err(|_| invalid())?;
+    let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(server_public));
+    let cipher = wrap_cipher(&shared, client_public.as_bytes(), &server_public);
+    let nonce_ga = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&nonce);
+    cipher.decrypt_in_place(nonce_ga, b"", &mut sealed).map_err(|_| "Failed to unwrap the group key".to_string())?;
+    sealed.try_into().map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(cipher: &SessionCipher, seq: u64) -> Bytes {
+        encrypt_frame(&Payload::chat("alice", "hello", None), cipher, seq, Framing::default())
+    }
+
+    #[test]
+    fn the_other_side_reads_a_frame() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[1; 32], Side::Server));
+        let mut last_seq = 0;
+        let payload = decrypt_frame(&frame(&client, 5), &server, &mut last_seq).unwrap();
+        assert_eq!((payload.username.as_ref(), payload.text.as_ref(), last_seq), ("alice", "hello", 5));
+    }
+
+    #[test]
+    fn a_frame_reflected_at_its_sender_fails() {
+        let client = SessionCipher::new(&[1; 32], Side::Client);
+        assert_eq!(decrypt_frame(&frame(&client, 1), &client, &mut 0).err(), Some(ProtocolError::Decrypt));
+    }
+
+    #[test]
+    fn a_frame_from_another_session_fails() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[2; 32], Side::Server));
+        assert_eq!(decrypt_frame(&frame(&client, 1), &server, &mut 0).err(), Some(ProtocolError::Decrypt));
+    }
+
+    #[test]
+    fn a_changed_sequence_number_fails() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[1; 32], Side::Server));
+        let decoded = crate::frame::decode_frame(&frame(&client, 1)).unwrap();
+        let renumbered = crate::frame::encode_frame(2, &decoded.nonce, &decoded.sealed, false).unwrap();
+        assert_eq!(decrypt_frame(&renumbered, &server, &mut 0).err(), Some(ProtocolError::Decrypt));
+    }
+
+    #[test]
+    fn a_replayed_frame_is_refused() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[1; 32], Side::Server));
+        let mut last_seq = 0;
+        let first = frame(&client, 3);
+        decrypt_frame(&first, &server, &mut last_seq).unwrap();
+        assert_eq!(decrypt_frame(&first, &server, &mut last_seq).err(), Some(ProtocolError::Replayed(3)));
+        assert_eq!(decrypt_frame(&frame(&client, 2), &server, &mut last_seq).err(), Some(ProtocolError::Replayed(2)));
+        assert_eq!(last_seq, 3);
+    }
+
+    #[test]
+    fn padded_frames_hide_the_message_length() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[1; 32], Side::Server));
+        let short = encrypt_frame(&Payload::chat("alice", "hi", None), &client, 1, Framing { pad: true, base64: false });
+        let long = encrypt_frame(&Payload::chat("alice", &"x".repeat(30), None), &client, 2, Framing { pad: true, base64: false });
+        assert_eq!(short.len(), long.len());
+        assert_ne!(encrypt_frame(&Payload::chat("alice", "hi", None), &client, 3, Framing::default()).len(), short.len());
+        // the padding is whitespace after the JSON, which the receiver skips
+        let mut last_seq = 0;
+        assert_eq!(decrypt_frame(&short, &server, &mut last_seq).unwrap().text, "hi");
+    }
+
+    #[test]
+    fn padding_rounds_up_to_a_bucket() {
+        assert_eq!((padded_len(1), padded_len(64), padded_len(65), padded_len(4096)), (64, 64, 256, 4096));
+        // past the last bucket, to a multiple of it
+        assert_eq!(padded_len(4097), 8192);
+    }
 
-    // reconstruct ciphertext||tag and decrypt
-    let mut combined_data = hex::decode(&encrypted_msg.ciphertext).ok()?;
-    combined_data.extend_from_slice(&hex::decode(&encrypted_msg.tag).ok()?);
-    let decrypted_bytes = cipher.decrypt(nonce, combined_data.as_ref()).ok()?;
-    let decrypted_message = String::from_utf8_lossy(&decrypted_bytes).to_string();
-    Some((encrypted_msg.username, decrypted_message))
+    #[test]
+    fn changed_padding_fails() {
+        let (client, server) = (SessionCipher::new(&[1; 32], Side::Client), SessionCipher::new(&[1; 32], Side::Server));
+        let mut padded = crate::frame::decode_frame(&encrypt_frame(&Payload::chat("alice", "hi", None), &client, 1, Framing { pad: true, base64: false })).unwrap();
+        // the last byte of padding, just before the 16-byte tag
+        let last = padded.sealed.len() - 17;
+        padded.sealed[last] ^= 1;
+        let changed = crate::frame::encode_frame(padded.seq, &padded.nonce, &padded.sealed, false).unwrap();
+        assert_eq!(decrypt_frame(&changed, &server, &mut 0).err(), Some(ProtocolError::Decrypt));
+    }
 }
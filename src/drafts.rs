@@ -0,0 +1,108 @@
+//! Unsent drafts, kept per server in `$HOME/key/drafts`, and chat the
+//! server never acknowledged, kept per server in `$HOME/key/unsent`.
+//!
+//! When the client's TUI closes, because the user quit or the connection
+//! dropped, a half-written message is saved and put back in the input box
+//! the next time the client connects to the same server. Messages still
+//! waiting for their `ack` are saved too and sent again, under the same
+//! IDs, on that next connection. Both are encrypted with the room's DEK,
+//! like the local history.
+
+use crate::crypto::Payload as Frame;
+use aes_gcm::{Aes256Gcm, aead::{Aead, OsRng, Payload}};
+use rand_core::RngCore;
+use std::collections::BTreeMap;
+
+/// Location of the drafts file.
+pub fn drafts_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/drafts", home)
+}
+
+/// Location of the file of unacknowledged messages.
+pub fn unsent_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/unsent", home)
+}
+
+/// Associated data of the entry for `server`, so an entry cannot be moved
+/// to another server or from one file to the other.
+fn associated_data(what: &str, server: &str) -> Vec<u8> {
+    format!("antimpeu {} v1 {}", what, server).into_bytes()
+}
+
+/// Sealed entries by server, as hex of nonce and ciphertext.
+fn read_all(path: &str) -> Result<BTreeMap<String, String>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|_| format!("{} is malformed", path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+    }
+}
+
+/// Take the entry saved for `server` in `path`, if any, removing it from
+/// the file.
+fn take_sealed(path: &str, what: &str, server: &str, cipher: &Aes256Gcm) -> Result<Option<Vec<u8>>, String> {
+    let mut entries = read_all(path)?;
+    let Some(sealed) = entries.remove(server) else { return Ok(None) };
+    write_all(path, &entries)?;
+    let data = hex::decode(sealed).map_err(|_| format!("{} is malformed", path))?;
+    if data.len() < 12 {
+        return Err(format!("{} is malformed", path));
+    }
+    let (nonce, sealed) = data.split_at(12);
+    let aad = associated_data(what, server);
+    cipher
+        .decrypt(nonce.into(), Payload { msg: sealed, aad: &aad })
+        .map(Some)
+        .map_err(|_| format!("Dropped the saved {}: it was encrypted with another key", what))
+}
+
+/// Save `plain` for `server` in `path`, replacing any earlier entry.
+fn save_sealed(path: &str, what: &str, server: &str, cipher: &Aes256Gcm, plain: &[u8]) -> Result<(), String> {
+    let mut entries = read_all(path)?;
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let aad = associated_data(what, server);
+    let sealed = cipher
+        .encrypt((&nonce).into(), Payload { msg: plain, aad: &aad })
+        .map_err(|_| format!("Failed to encrypt the {}", what))?;
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&sealed);
+    entries.insert(server.to_string(), hex::encode(data));
+    write_all(path, &entries)
+}
+
+/// Take the draft saved for `server`, if any, removing it from the file.
+pub fn take(server: &str, cipher: &Aes256Gcm) -> Result<Option<String>, String> {
+    let Some(plain) = take_sealed(&drafts_path(), "draft", server, cipher)? else { return Ok(None) };
+    String::from_utf8(plain).map(Some).map_err(|_| format!("{} is malformed", drafts_path()))
+}
+
+/// Save `draft` for `server`, replacing any earlier one.
+pub fn save(server: &str, cipher: &Aes256Gcm, draft: &str) -> Result<(), String> {
+    save_sealed(&drafts_path(), "draft", server, cipher, draft.as_bytes())
+}
+
+/// Take the messages `server` did not acknowledge, oldest first, removing
+/// them from the file.
+pub fn take_unsent(server: &str, cipher: &Aes256Gcm) -> Result<Vec<Frame<'static>>, String> {
+    let Some(plain) = take_sealed(&unsent_path(), "unsent messages", server, cipher)? else { return Ok(Vec::new()) };
+    serde_json::from_slice(&plain).map_err(|_| format!("{} is malformed", unsent_path()))
+}
+
+/// Save the messages `server` did not acknowledge, replacing any earlier
+/// ones.
+pub fn save_unsent(server: &str, cipher: &Aes256Gcm, unsent: &[Frame]) -> Result<(), String> {
+    let plain = serde_json::to_vec(unsent).map_err(|e| e.to_string())?;
+    save_sealed(&unsent_path(), "unsent messages", server, cipher, &plain)
+}
+
+fn write_all(path: &str, entries: &BTreeMap<String, String>) -> Result<(), String> {
+    let _ = std::fs::remove_file(path);
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let text = serde_json::to_string_pretty(entries).map_err(|e| e.to_string())?;
+    crate::identity::write_private(path, text.as_bytes()).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
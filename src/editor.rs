@@ -0,0 +1,213 @@
+//! The draft in the TUI input box: text, a cursor and an undo history.
+//!
+//! Positions are byte offsets into the text and always sit on a grapheme
+//! boundary, so the cursor steps over a whole emoji or accented letter.
+//! Word motion follows readline: a word is a run of letters and digits.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Undo steps kept; the oldest are dropped first.
+const UNDO_LIMIT: usize = 100;
+
+/// Kinds of edit, see `Editor::record`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EditKind {
+    Type,
+    Delete,
+    /// Pastes and kills, each a step of its own.
+    Other,
+}
+
+#[derive(Default)]
+pub struct Editor {
+    text: String,
+    cursor: usize,
+    /// Earlier states, with their cursor, for undo; the latest last.
+    undo: Vec<(String, usize)>,
+    /// States undone, for redo.
+    redo: Vec<(String, usize)>,
+    /// Kind of the last edit, so a run of typing is undone in one step.
+    last_edit: Option<EditKind>,
+}
+
+impl Editor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Byte offset of the cursor in `text`.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Replace the draft, such as a restored one, with the cursor at its
+    /// end and no history.
+    pub fn set(&mut self, text: String) {
+        self.cursor = text.len();
+        self.text = text;
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+    }
+
+    /// Empty the draft once it was sent, with its history.
+    pub fn clear(&mut self) {
+        self.set(String::new());
+    }
+
+    /// Type `c` at the cursor.
+    pub fn type_char(&mut self, c: char) {
+        self.record(EditKind::Type);
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Insert pasted text at the cursor, as one undo step.
+    pub fn paste(&mut self, text: &str) {
+        self.record(EditKind::Other);
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Backspace: delete the character before the cursor.
+    pub fn backspace(&mut self) {
+        let start = self.prev_grapheme();
+        if start < self.cursor {
+            self.record(EditKind::Delete);
+            self.remove(start, self.cursor);
+        }
+    }
+
+    /// Delete: delete the character under the cursor.
+    pub fn delete(&mut self) {
+        let end = self.next_grapheme();
+        if end > self.cursor {
+            self.record(EditKind::Delete);
+            self.remove(self.cursor, end);
+        }
+    }
+
+    /// Ctrl+W: delete the word before the cursor, with the spaces after it.
+    pub fn delete_word_before(&mut self) {
+        let start = self.word_start(|g| !g.trim().is_empty());
+        self.kill(start, self.cursor);
+    }
+
+    /// Ctrl+U: delete from the start of the draft to the cursor.
+    pub fn kill_to_start(&mut self) {
+        self.kill(0, self.cursor);
+    }
+
+    /// Ctrl+K: delete from the cursor to the end of the draft.
+    pub fn kill_to_end(&mut self) {
+        self.kill(self.cursor, self.text.len());
+    }
+
+    pub fn left(&mut self) {
+        self.move_to(self.prev_grapheme());
+    }
+
+    pub fn right(&mut self) {
+        self.move_to(self.next_grapheme());
+    }
+
+    pub fn home(&mut self) {
+        self.move_to(0);
+    }
+
+    pub fn end(&mut self) {
+        self.move_to(self.text.len());
+    }
+
+    /// Alt+B: to the start of the word before the cursor.
+    pub fn word_left(&mut self) {
+        self.move_to(self.word_start(is_word));
+    }
+
+    /// Alt+F: to the end of the word after the cursor.
+    pub fn word_right(&mut self) {
+        let end = self.text[self.cursor..]
+            .grapheme_indices(true)
+            .skip_while(|(_, g)| !is_word(g))
+            .find(|(_, g)| !is_word(g))
+            .map_or(self.text.len(), |(i, _)| self.cursor + i);
+        self.move_to(end);
+    }
+
+    /// Ctrl+Z: back to the draft before the last edit.
+    pub fn undo(&mut self) {
+        if let Some(state) = self.undo.pop() {
+            let current = (std::mem::replace(&mut self.text, state.0), std::mem::replace(&mut self.cursor, state.1));
+            self.redo.push(current);
+            self.last_edit = None;
+        }
+    }
+
+    /// Ctrl+Y: redo the last undone edit.
+    pub fn redo(&mut self) {
+        if let Some(state) = self.redo.pop() {
+            let current = (std::mem::replace(&mut self.text, state.0), std::mem::replace(&mut self.cursor, state.1));
+            self.undo.push(current);
+            self.last_edit = None;
+        }
+    }
+
+    /// Save the draft for undo before an edit of `kind`. Typing and
+    /// deleting are grouped: a run of either is one step, and typed words
+    /// are separate steps.
+    fn record(&mut self, kind: EditKind) {
+        let word_start = kind == EditKind::Type && self.text[..self.cursor].ends_with(' ');
+        if kind == EditKind::Other || self.last_edit != Some(kind) || word_start {
+            if self.undo.len() == UNDO_LIMIT {
+                self.undo.remove(0);
+            }
+            self.undo.push((self.text.clone(), self.cursor));
+        }
+        self.last_edit = Some(kind);
+        self.redo.clear();
+    }
+
+    fn kill(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.record(EditKind::Other);
+            self.remove(start, end);
+        }
+    }
+
+    fn remove(&mut self, start: usize, end: usize) {
+        self.text.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    /// Moving the cursor ends a run of typing, so the next edit starts a
+    /// new undo step.
+    fn move_to(&mut self, position: usize) {
+        self.cursor = position;
+        self.last_edit = None;
+    }
+
+    /// Start of the last run of graphemes `in_word` accepts before the
+    /// cursor, skipping those it rejects right before the cursor.
+    fn word_start(&self, in_word: impl Fn(&str) -> bool) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .rev()
+            .skip_while(|(_, g)| !in_word(g))
+            .take_while(|(_, g)| in_word(g))
+            .last()
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn prev_grapheme(&self) -> usize {
+        self.text[..self.cursor].grapheme_indices(true).next_back().map_or(0, |(i, _)| i)
+    }
+
+    fn next_grapheme(&self) -> usize {
+        self.text[self.cursor..].graphemes(true).next().map_or(self.cursor, |g| self.cursor + g.len())
+    }
+}
+
+/// Letters and digits make up words, with any accents on them.
+fn is_word(grapheme: &str) -> bool {
+    grapheme.chars().next().is_some_and(char::is_alphanumeric)
+}
@@ -0,0 +1,157 @@
+//! C bindings for `ChatClient`, behind the `ffi` feature, for programs in
+//! other languages, declared in `include/antimpeu.h`. Building with the
+//! feature checks that header against the bindings, see `build.rs`.
+//!
+//! Every function is called from one thread at a time per client. Strings
+//! are UTF-8 and NUL-terminated. A function that fails returns NULL or -1
+//! and leaves the reason for `antimpeu_last_error`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::sync::mpsc;
+use crate::{ChatClient, ClientOptions, Message};
+
+/// A connection to a room, from `antimpeu_connect`.
+pub struct AntimpeuClient {
+    client: ChatClient,
+    messages: mpsc::Receiver<Message>,
+}
+
+/// A message from `antimpeu_poll_message`, freed with
+/// `antimpeu_message_free`.
+#[repr(C)]
+pub struct AntimpeuMessage {
+    /// Sender's name; "Server" for notices from the server.
+    pub sender: *mut c_char,
+    /// Sender's stable ID, or NULL.
+    pub user: *mut c_char,
+    pub text: *mut c_char,
+    /// Message ID, or NULL for notices.
+    pub id: *mut c_char,
+    /// Unix time of a message replayed from the server's history; 0 for
+    /// live ones.
+    pub time: i64,
+}
+
+thread_local! {
+    /// Why the last call on this thread failed.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(e: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(c_string(e)));
+}
+
+/// `s` as a C string, with any NUL bytes taken out.
+fn c_string(s: String) -> CString {
+    CString::new(s.replace('\0', "")).expect("NUL bytes were removed")
+}
+
+/// # Safety
+/// `s` is NULL or points to a NUL-terminated string.
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<Option<&'a str>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s).to_str().map(Some).map_err(|_| format!("{} is not UTF-8", what))
+}
+
+/// Connect to `addr` (`host:port`) with the room's 32-byte group key and
+/// join as `name`, or under the local user name if `name` is NULL.
+/// Returns NULL on failure.
+///
+/// # Safety
+/// `addr` and `name` are NULL or NUL-terminated strings and `key` points
+/// to 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn antimpeu_connect(addr: *const c_char, key: *const u8, name: *const c_char) -> *mut AntimpeuClient {
+    let connect = || {
+        let addr = str_arg(addr, "addr")?.ok_or("addr is NULL")?;
+        if key.is_null() {
+            return Err("key is NULL".to_string());
+        }
+        let key: [u8; 32] = std::slice::from_raw_parts(key, 32).try_into().expect("32 bytes");
+        let options = ClientOptions { name: str_arg(name, "name")?.map(str::to_string), ..Default::default() };
+        let client = ChatClient::connect_with(addr, key, &options)?;
+        let (tx, messages) = mpsc::channel();
+        client.on_message(move |m| {
+            let _ = tx.send(m.clone());
+        });
+        Ok(AntimpeuClient { client, messages })
+    };
+    match connect() {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => {
+            set_error(e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Send a chat message. Returns 0, or -1 on failure.
+///
+/// # Safety
+/// `client` comes from `antimpeu_connect` and `text` is a NUL-terminated
+/// string.
+#[no_mangle]
+pub unsafe extern "C" fn antimpeu_send(client: *mut AntimpeuClient, text: *const c_char) -> c_int {
+    let Some(client) = client.as_ref() else {
+        set_error("client is NULL".to_string());
+        return -1;
+    };
+    let sent = str_arg(text, "text").and_then(|text| client.client.send(text.ok_or("text is NULL")?));
+    match sent {
+        Ok(_) => 0,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// The next message received, or NULL if there is none yet. Does not
+/// block.
+///
+/// # Safety
+/// `client` comes from `antimpeu_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn antimpeu_poll_message(client: *mut AntimpeuClient) -> *mut AntimpeuMessage {
+    let Some(m) = client.as_ref().and_then(|c| c.messages.try_recv().ok()) else { return std::ptr::null_mut() };
+    let optional = |s: Option<String>| s.map_or(std::ptr::null_mut(), |s| c_string(s).into_raw());
+    Box::into_raw(Box::new(AntimpeuMessage { sender: c_string(m.sender).into_raw(), user: optional(m.user), text: c_string(m.text).into_raw(), id: optional(m.id), time: m.time.unwrap_or(0) }))
+}
+
+/// Free a message from `antimpeu_poll_message`. NULL is ignored.
+///
+/// # Safety
+/// `message` comes from `antimpeu_poll_message` and is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn antimpeu_message_free(message: *mut AntimpeuMessage) {
+    if message.is_null() {
+        return;
+    }
+    let m = Box::from_raw(message);
+    for s in [m.sender, m.user, m.text, m.id] {
+        if !s.is_null() {
+            drop(CString::from_raw(s));
+        }
+    }
+}
+
+/// Leave the room and free the client. NULL is ignored.
+///
+/// # Safety
+/// `client` comes from `antimpeu_connect` and is not used again.
+#[no_mangle]
+pub unsafe extern "C" fn antimpeu_free(client: *mut AntimpeuClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Why the last call on this thread failed, or NULL. The string stays
+/// valid until the next failure on the thread.
+#[no_mangle]
+pub extern "C" fn antimpeu_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
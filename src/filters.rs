@@ -0,0 +1,307 @@
+//! Content moderation hooks.
+//!
+//! The server passes every decrypted chat message through a chain of
+//! filters before it is shown or relayed. Each filter may let the message
+//! through, censor it (replace its text) or drop it; the first drop wins.
+//! Built in are a length limit and a regex wordlist, configured under
+//! `[server.filters]`, and an external plugin: a long-running command that
+//! receives one JSON message per line on stdin and answers each with a line
+//! on stdout:
+//!
+//! ```text
+//! {"username":"alice","text":"hello"}   ->   allow
+//!                                            censor <new text>
+//!                                            drop <reason>
+//! ```
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+use regex::Regex;
+use serde::Deserialize;
+
+/// What a filter decided about a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// Relay the message with this text instead.
+    Censor(String),
+    /// Do not relay the message, for the given reason.
+    Drop(String),
+}
+
+/// A content filter. Filters run on the server's event loop, so they must
+/// answer quickly, unless they say they may wait.
+pub trait Filter: Send + Sync {
+    /// Name used when logging the filter's actions.
+    fn name(&self) -> &str;
+    fn check(&self, username: &str, text: &str) -> Verdict;
+
+    /// Whether `check` may wait, on another process for instance; the
+    /// server then filters on a thread of its own.
+    fn may_block(&self) -> bool {
+        false
+    }
+}
+
+/// Wordlist action when a pattern matches.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WordlistAction {
+    /// Replace every match with asterisks.
+    #[default]
+    Censor,
+    /// Drop the whole message.
+    Drop,
+}
+
+/// Settings for the built-in filters, under `[server.filters]`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Drop messages longer than this many characters.
+    pub max_length: Option<usize>,
+    /// Case-insensitive regular expressions; plain words work too.
+    pub wordlist: Vec<String>,
+    pub wordlist_action: WordlistAction,
+    /// External filter command, see the module documentation.
+    pub plugin: Option<String>,
+    /// How long to wait for the plugin before letting a message through.
+    pub plugin_timeout_ms: Option<u64>,
+}
+
+/// Drops messages over a length limit.
+pub struct MaxLength(pub usize);
+
+impl Filter for MaxLength {
+    fn name(&self) -> &str {
+        "max_length"
+    }
+
+    fn check(&self, _username: &str, text: &str) -> Verdict {
+        let len = text.chars().count();
+        if len > self.0 {
+            Verdict::Drop(format!("too long ({} characters, the limit is {})", len, self.0))
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+/// Censors or drops messages matching any of a list of patterns.
+pub struct Wordlist {
+    pattern: Regex,
+    action: WordlistAction,
+}
+
+impl Wordlist {
+    pub fn new(patterns: &[String], action: WordlistAction) -> Result<Self, String> {
+        let alternation: Vec<String> = patterns.iter().map(|p| format!("(?:{})", p)).collect();
+        let pattern = Regex::new(&format!("(?i){}", alternation.join("|"))).map_err(|e| format!("Invalid wordlist pattern: {}", e))?;
+        Ok(Self { pattern, action })
+    }
+}
+
+impl Filter for Wordlist {
+    fn name(&self) -> &str {
+        "wordlist"
+    }
+
+    fn check(&self, _username: &str, text: &str) -> Verdict {
+        if !self.pattern.is_match(text) {
+            return Verdict::Allow;
+        }
+        match self.action {
+            WordlistAction::Drop => Verdict::Drop("matched the wordlist".to_string()),
+            WordlistAction::Censor => Verdict::Censor(self.pattern.replace_all(text, |m: &regex::Captures| "*".repeat(m[0].chars().count())).into_owned()),
+        }
+    }
+}
+
+/// A filter command running for the lifetime of the server.
+pub struct Plugin {
+    command: String,
+    /// The child and its stdin, locked for one request-response at a time.
+    io: Mutex<(Child, ChildStdin)>,
+    answers: Mutex<mpsc::Receiver<String>>,
+    timeout: Duration,
+}
+
+impl Plugin {
+    pub fn spawn(command: &str, timeout: Duration) -> Result<Self, String> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Failed to start filter plugin {}: {}", command, e))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = child.stdout.take().expect("piped stdout");
+        // Read answers on a thread so a stuck plugin cannot block the server
+        let (tx, answers) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { command: command.to_string(), io: Mutex::new((child, stdin)), answers: Mutex::new(answers), timeout })
+    }
+}
+
+impl Filter for Plugin {
+    fn name(&self) -> &str {
+        &self.command
+    }
+
+    fn may_block(&self) -> bool {
+        true
+    }
+
+    /// Messages pass when the plugin fails or does not answer in time.
+    fn check(&self, username: &str, text: &str) -> Verdict {
+        let mut io = self.io.lock().unwrap();
+        let answers = self.answers.lock().unwrap();
+        // Discard answers that arrived after an earlier timeout
+        while answers.try_recv().is_ok() {}
+        let request = serde_json::json!({ "username": username, "text": text });
+        if writeln!(io.1, "{}", request).and_then(|()| io.1.flush()).is_err() {
+            return Verdict::Allow;
+        }
+        match answers.recv_timeout(self.timeout) {
+            Ok(answer) => match answer.split_once(' ').unwrap_or((answer.as_str(), "")) {
+                ("censor", replacement) => Verdict::Censor(replacement.to_string()),
+                ("drop", reason) => Verdict::Drop(if reason.is_empty() { "dropped by plugin".to_string() } else { reason.to_string() }),
+                _ => Verdict::Allow,
+            },
+            Err(_) => Verdict::Allow,
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        let _ = self.io.lock().unwrap().0.kill();
+    }
+}
+
+/// Ordered list of filters applied to each message.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn Filter>>,
+}
+
+/// Result of running a message through the chain.
+pub struct Outcome {
+    /// Text to relay, or why the message was dropped.
+    pub text: Result<String, String>,
+    /// One line per filter that censored or dropped the message.
+    pub log: Vec<String>,
+}
+
+impl FilterChain {
+    /// Build the chain from the config: length limit, wordlist, plugin.
+    pub fn from_config(config: &FilterConfig) -> Result<Self, String> {
+        let mut chain = Self::default();
+        if let Some(max) = config.max_length {
+            chain.push(Box::new(MaxLength(max)));
+        }
+        if !config.wordlist.is_empty() {
+            chain.push(Box::new(Wordlist::new(&config.wordlist, config.wordlist_action)?));
+        }
+        if let Some(command) = &config.plugin {
+            let timeout = Duration::from_millis(config.plugin_timeout_ms.unwrap_or(200));
+            chain.push(Box::new(Plugin::spawn(command, timeout)?));
+        }
+        Ok(chain)
+    }
+
+    /// Whether any filter may wait, see `Filter::may_block`.
+    pub fn may_block(&self) -> bool {
+        self.filters.iter().any(|f| f.may_block())
+    }
+
+    /// Append a filter; it sees the text as censored by earlier ones.
+    pub fn push(&mut self, filter: Box<dyn Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn apply(&self, username: &str, text: &str) -> Outcome {
+        let mut text = text.to_string();
+        let mut log = Vec::new();
+        for filter in &self.filters {
+            match filter.check(username, &text) {
+                Verdict::Allow => {}
+                Verdict::Censor(censored) => {
+                    log.push(format!("Filter {} censored a message from {}", filter.name(), username));
+                    text = censored;
+                }
+                Verdict::Drop(reason) => {
+                    log.push(format!("Filter {} dropped a message from {}: {}", filter.name(), username, reason));
+                    return Outcome { text: Err(reason), log };
+                }
+            }
+        }
+        Outcome { text: Ok(text), log }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(config: FilterConfig) -> FilterChain {
+        FilterChain::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn the_length_limit_counts_characters() {
+        let limit = MaxLength(3);
+        assert_eq!(limit.check("alice", "äöü"), Verdict::Allow);
+        assert_eq!(limit.check("alice", "abcd"), Verdict::Drop("too long (4 characters, the limit is 3)".to_string()));
+    }
+
+    #[test]
+    fn the_wordlist_censors_or_drops_matches() {
+        let words = vec!["darn".to_string(), "h[e3]ck".to_string()];
+        let censor = Wordlist::new(&words, WordlistAction::Censor).unwrap();
+        assert_eq!(censor.check("alice", "DARN it, what the h3ck"), Verdict::Censor("**** it, what the ****".to_string()));
+        assert_eq!(censor.check("alice", "fine"), Verdict::Allow);
+        let drop = Wordlist::new(&words, WordlistAction::Drop).unwrap();
+        assert_eq!(drop.check("alice", "heck"), Verdict::Drop("matched the wordlist".to_string()));
+    }
+
+    #[test]
+    fn the_chain_stops_at_the_first_drop() {
+        let chain = chain(FilterConfig { max_length: Some(10), wordlist: vec!["darn".to_string()], ..Default::default() });
+        let censored = chain.apply("alice", "darn");
+        assert_eq!((censored.text, censored.log.len()), (Ok("****".to_string()), 1));
+        let dropped = chain.apply("alice", "darn it, again and again");
+        assert!(dropped.text.is_err());
+        assert_eq!(dropped.log, ["Filter max_length dropped a message from alice: too long (24 characters, the limit is 10)"]);
+    }
+
+    #[test]
+    fn a_bad_wordlist_pattern_is_an_error() {
+        let config = FilterConfig { wordlist: vec!["(".to_string()], ..Default::default() };
+        assert!(FilterChain::from_config(&config).is_err_and(|e| e.starts_with("Invalid wordlist pattern")));
+    }
+
+    #[test]
+    fn the_plugin_answers_per_message() {
+        let plugin = Plugin::spawn("while read -r line; do echo 'censor [redacted]'; done", Duration::from_secs(5)).unwrap();
+        assert_eq!(plugin.check("alice", "hello"), Verdict::Censor("[redacted]".to_string()));
+        assert_eq!(plugin.check("alice", "again"), Verdict::Censor("[redacted]".to_string()));
+    }
+
+    #[test]
+    fn a_silent_plugin_lets_messages_through() {
+        let plugin = Plugin::spawn("cat > /dev/null", Duration::from_millis(50)).unwrap();
+        assert_eq!(plugin.check("alice", "hello"), Verdict::Allow);
+    }
+}
@@ -0,0 +1,164 @@
+//! Key fingerprints for comparing keys out of band: `/fingerprint`.
+//!
+//! A fingerprint is the first 20 bytes of the SHA-256 of a public key,
+//! shown as hex and as words from the PGP word list, which alternates a
+//! two-syllable word for bytes in even positions with a three-syllable
+//! word for odd ones, so the words can be read out over a phone call and
+//! a swapped or dropped word is noticed.
+
+use sha2::{Digest, Sha256};
+
+/// Bytes of the key digest in a fingerprint.
+const LENGTH: usize = 20;
+
+/// Words for bytes in even positions.
+const EVEN: [&str; 256] = [
+    "aardvark", "absurd", "accrue", "acme", "adrift", "adult", "afflict", "ahead", "aimless",
+    "Algol", "allow", "alone", "ammo", "ancient", "apple", "artist", "assume", "Athens", "atlas",
+    "Aztec", "baboon", "backfield", "backward", "banjo", "beaming", "bedlamp", "beehive", "beeswax",
+    "befriend", "Belfast", "berserk", "billiard", "bison", "blackjack", "blockade", "blowtorch",
+    "bluebird", "bombast", "bookshelf", "brackish", "breadline", "breakup", "brickyard",
+    "briefcase", "Burbank", "button", "buzzard", "cement", "chairlift", "chatter", "checkup",
+    "chisel", "choking", "chopper", "Christmas", "clamshell", "classic", "classroom", "cleanup",
+    "clockwork", "cobra", "commence", "concert", "cowbell", "crackdown", "cranky", "crowfoot",
+    "crucial", "crumpled", "crusade", "cubic", "dashboard", "deadbolt", "deckhand", "dogsled",
+    "dragnet", "drainage", "dreadful", "drifter", "dropper", "drumbeat", "drunken", "Dupont",
+    "dwelling", "eating", "edict", "egghead", "eightball", "endorse", "endow", "enlist", "erase",
+    "escape", "exceed", "eyeglass", "eyetooth", "facial", "fallout", "flagpole", "flatfoot",
+    "flytrap", "fracture", "framework", "freedom", "frighten", "gazelle", "Geiger", "glitter",
+    "glucose", "goggles", "goldfish", "gremlin", "guidance", "hamlet", "highchair", "hockey",
+    "indoors", "indulge", "inverse", "involve", "island", "jawbone", "keyboard", "kickoff", "kiwi",
+    "klaxon", "locale", "lockup", "merit", "minnow", "miser", "Mohawk", "mural", "music",
+    "necklace", "Neptune", "newborn", "nightbird", "Oakland", "obtuse", "offload", "optic", "orca",
+    "payday", "peachy", "pheasant", "physique", "playhouse", "Pluto", "preclude", "prefer",
+    "preshrunk", "printer", "prowler", "pupil", "puppy", "python", "quadrant", "quiver", "quota",
+    "ragtime", "ratchet", "rebirth", "reform", "regain", "reindeer", "rematch", "repay", "retouch",
+    "revenge", "reward", "rhythm", "ribcage", "ringbolt", "robust", "rocker", "ruffled", "sailboat",
+    "sawdust", "scallion", "scenic", "scorecard", "Scotland", "seabird", "select", "sentence",
+    "shadow", "shamrock", "showgirl", "skullcap", "skydive", "slingshot", "slowdown", "snapline",
+    "snapshot", "snowcap", "snowslide", "solo", "southward", "soybean", "spaniel", "spearhead",
+    "spellbind", "spheroid", "spigot", "spindle", "spyglass", "stagehand", "stagnate", "stairway",
+    "standard", "stapler", "steamship", "sterling", "stockman", "stopwatch", "stormy", "sugar",
+    "surmount", "suspense", "sweatband", "swelter", "tactics", "talon", "tapeworm", "tempest",
+    "tiger", "tissue", "tonic", "topmost", "tracker", "transit", "trauma", "treadmill", "Trojan",
+    "trouble", "tumor", "tunnel", "tycoon", "uncut", "unearth", "unwind", "uproot", "upset",
+    "upshot", "vapor", "village", "virus", "Vulcan", "waffle", "wallet", "watchword", "wayside",
+    "willow", "woodlark", "Zulu"
+];
+
+/// Words for bytes in odd positions.
+const ODD: [&str; 256] = [
+    "adroitness", "adviser", "aftermath", "aggregate", "alkali", "almighty", "amulet", "amusement",
+    "antenna", "applicant", "Apollo", "armistice", "article", "asteroid", "Atlantic", "atmosphere",
+    "autopsy", "Babylon", "backwater", "barbecue", "belowground", "bifocals", "bodyguard",
+    "bookseller", "borderline", "bottomless", "Bradbury", "bravado", "Brazilian", "breakaway",
+    "Burlington", "businessman", "butterfat", "Camelot", "candidate", "cannonball", "Capricorn",
+    "caravan", "caretaker", "celebrate", "cellulose", "certify", "chambermaid", "Cherokee",
+    "Chicago", "clergyman", "coherence", "combustion", "commando", "company", "component",
+    "concurrent", "confidence", "conformist", "congregate", "consensus", "consulting", "corporate",
+    "corrosion", "councilman", "crossover", "crucifix", "cumbersome", "customer", "Dakota",
+    "decadence", "December", "decimal", "designing", "detector", "detergent", "determine",
+    "dictator", "dinosaur", "direction", "disable", "disbelief", "disruptive", "distortion",
+    "document", "embezzle", "enchanting", "enrollment", "enterprise", "equation", "equipment",
+    "escapade", "Eskimo", "everyday", "examine", "existence", "exodus", "fascinate", "filament",
+    "finicky", "forever", "fortitude", "frequency", "gadgetry", "Galveston", "getaway", "glossary",
+    "gossamer", "graduate", "gravity", "guitarist", "hamburger", "Hamilton", "handiwork",
+    "hazardous", "headwaters", "hemisphere", "hesitate", "hideaway", "holiness", "hurricane",
+    "hydraulic", "impartial", "impetus", "inception", "indigo", "inertia", "infancy", "inferno",
+    "informant", "insincere", "insurgent", "integrate", "intention", "inventive", "Istanbul",
+    "Jamaica", "Jupiter", "leprosy", "letterhead", "liberty", "maritime", "matchmaker", "maverick",
+    "Medusa", "megaton", "microscope", "microwave", "midsummer", "millionaire", "miracle",
+    "misnomer", "molasses", "molecule", "Montana", "monument", "mosquito", "narrative", "nebula",
+    "newsletter", "Norwegian", "October", "Ohio", "onlooker", "opulent", "Orlando", "outfielder",
+    "Pacific", "pandemic", "Pandora", "paperweight", "paragon", "paragraph", "paramount",
+    "passenger", "pedigree", "Pegasus", "penetrate", "perceptive", "performance", "pharmacy",
+    "phonetic", "photograph", "pioneer", "pocketful", "politeness", "positive", "potato",
+    "processor", "provincial", "proximate", "puberty", "publisher", "pyramid", "quantity",
+    "racketeer", "rebellion", "recipe", "recover", "repellent", "replica", "reproduce", "resistor",
+    "responsive", "retraction", "retrieval", "retrospect", "revenue", "revival", "revolver",
+    "sandalwood", "sardonic", "Saturday", "savagery", "scavenger", "sensation", "sociable",
+    "souvenir", "specialist", "speculate", "stethoscope", "stupendous", "supportive", "surrender",
+    "suspicious", "sympathy", "tambourine", "telephone", "therapist", "tobacco", "tolerance",
+    "tomorrow", "torpedo", "tradition", "travesty", "trombonist", "truncated", "typewriter",
+    "ultimate", "undaunted", "underfoot", "unicorn", "unify", "universe", "unravel", "upcoming",
+    "vacancy", "vagabond", "vertigo", "Virginia", "visitor", "vocalist", "voyager", "warranty",
+    "Waterloo", "whimsical", "Wichita", "Wilmington", "Wyoming", "yesteryear", "Yucatan"
+];
+
+fn digest(key: &[u8]) -> [u8; LENGTH] {
+    let digest = Sha256::digest(key);
+    let mut out = [0u8; LENGTH];
+    out.copy_from_slice(&digest[..LENGTH]);
+    out
+}
+
+/// The fingerprint of `key` as hex in groups of four.
+pub fn as_hex(key: &[u8]) -> String {
+    digest(key).chunks(2).map(hex::encode).collect::<Vec<_>>().join(" ")
+}
+
+/// The fingerprint of `key` as PGP words.
+pub fn as_words(key: &[u8]) -> String {
+    digest(key)
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if i % 2 == 0 { EVEN[b as usize] } else { ODD[b as usize] })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Lines describing the key `key_hex` of `whose`, for command output.
+pub fn describe(whose: &str, key_hex: &str) -> Vec<String> {
+    match hex::decode(key_hex) {
+        Ok(key) => {
+            let words = as_words(&key);
+            let words: Vec<&str> = words.split(' ').collect();
+            // two lines of words, to fit the chat pane
+            let mut lines = vec![format!("{}: {}", whose, as_hex(&key))];
+            lines.extend(words.chunks(LENGTH / 2).map(|chunk| format!("  {}", chunk.join(" "))));
+            lines
+        }
+        Err(_) => vec![format!("{}: malformed key {}", whose, key_hex)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_alternate_between_the_two_lists() {
+        let key = [7u8; 32];
+        let digest = digest(&key);
+        let words = as_words(&key);
+        let words: Vec<&str> = words.split(' ').collect();
+        assert_eq!(words.len(), LENGTH);
+        for (i, (word, byte)) in words.iter().zip(digest).enumerate() {
+            let list = if i % 2 == 0 { &EVEN } else { &ODD };
+            assert_eq!(*word, list[byte as usize]);
+        }
+        assert_eq!((EVEN[0], ODD[0], EVEN[255], ODD[255]), ("aardvark", "adroitness", "Zulu", "Yucatan"));
+    }
+
+    #[test]
+    fn hex_is_the_digest_in_groups_of_four() {
+        let key = b"a public key";
+        let hex = as_hex(key);
+        assert_eq!(hex.split(' ').count(), LENGTH / 2);
+        assert_eq!(hex.replace(' ', ""), hex::encode(digest(key)));
+    }
+
+    #[test]
+    fn describe_puts_the_words_on_two_lines() {
+        let key = [1u8; 32];
+        let lines = describe("Server", &hex::encode(key));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], format!("Server: {}", as_hex(&key)));
+        assert_eq!(format!("{} {}", lines[1].trim(), lines[2].trim()), as_words(&key));
+    }
+
+    #[test]
+    fn describe_reports_a_malformed_key() {
+        assert_eq!(describe("alice", "not hex"), vec!["alice: malformed key not hex".to_string()]);
+    }
+}
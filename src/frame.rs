@@ -0,0 +1,295 @@
+//! Wire frame encoding and decoding.
+//!
+//! A frame is a 4-byte big-endian length followed by a JSON envelope. All
+//! parsing here is pure (no IO, no crypto) and bounds-checked so it can be
+//! fed arbitrary bytes, e.g. from the fuzz target in `fuzz/`.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Serialize, Deserialize};
+use std::borrow::Cow;
+use std::fmt;
+
+/// Largest accepted frame body. Anything bigger is treated as corruption
+/// rather than buffered.
+pub const MAX_FRAME_LEN: usize = 1 << 20;
+/// Longest accepted username (inside the decrypted payload), in bytes.
+pub const MAX_USERNAME_LEN: usize = 256;
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// JSON envelope for encrypted messages sent over TCP. It only carries what
+/// is needed to decrypt; the sender travels inside the ciphertext. Decoding
+/// borrows the encoded fields from the frame where it can.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncryptedMessage<'a> {
+    /// Envelope version: 2 when the fields are base64, for peers that
+    /// offered `base64` in the handshake, or 1, left out on the wire, when
+    /// they are hex.
+    #[serde(default = "hex_version", skip_serializing_if = "is_hex_version")]
+    pub v: u8,
+    /// Per-sender sequence number, authenticated as associated data.
+    pub seq: u64,
+    #[serde(borrow)]
+    pub nonce: Cow<'a, str>,
+    #[serde(borrow)]
+    pub ciphertext: Cow<'a, str>,
+    #[serde(borrow)]
+    pub tag: Cow<'a, str>,
+}
+
+/// Version of the envelope with hex fields.
+const HEX_VERSION: u8 = 1;
+/// Version of the envelope with base64 fields.
+const BASE64_VERSION: u8 = 2;
+
+fn hex_version() -> u8 {
+    HEX_VERSION
+}
+
+fn is_hex_version(v: &u8) -> bool {
+    *v == HEX_VERSION
+}
+
+/// A structurally valid frame, ready for decryption.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u64,
+    pub nonce: [u8; NONCE_LEN],
+    /// Ciphertext with the GCM tag appended, as the AEAD expects it.
+    pub sealed: Vec<u8>,
+}
+
+/// Why a frame could not be read or decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The peer closed the connection between frames.
+    Eof,
+    /// The input ends inside a frame.
+    Truncated,
+    /// The length prefix exceeds `MAX_FRAME_LEN`.
+    TooLarge(usize),
+    /// Bytes follow the frame that the length prefix does not cover.
+    TrailingBytes(usize),
+    /// The body is not a valid envelope.
+    Malformed(String),
+    /// A field has the wrong size or encoding.
+    BadField(&'static str),
+    /// Authentication failed: wrong key or tampered frame.
+    Decrypt,
+    /// The sequence number did not increase: a replayed or reordered frame.
+    Replayed(u64),
+    /// The connection failed while reading.
+    Io(std::io::ErrorKind),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Eof => write!(f, "connection closed"),
+            ProtocolError::Truncated => write!(f, "truncated frame"),
+            ProtocolError::TooLarge(n) => write!(f, "frame of {} bytes exceeds the {} byte limit", n, MAX_FRAME_LEN),
+            ProtocolError::TrailingBytes(n) => write!(f, "{} unexpected bytes after frame", n),
+            ProtocolError::Malformed(e) => write!(f, "malformed frame: {}", e),
+            ProtocolError::BadField(name) => write!(f, "invalid {} field", name),
+            ProtocolError::Decrypt => write!(f, "frame failed authentication"),
+            ProtocolError::Replayed(seq) => write!(f, "replayed frame (sequence {})", seq),
+            ProtocolError::Io(kind) => write!(f, "read failed: {}", kind),
+        }
+    }
+}
+
+/// Parse the length prefix at the start of `buf`.
+///
+/// Returns the total frame size (prefix included) once `buf` holds the whole
+/// prefix, `None` if fewer than 4 bytes are available, and an error if the
+/// declared length is over the limit.
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, ProtocolError> {
+    let Some(prefix) = buf.get(..4) else { return Ok(None) };
+    let body_len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+    if body_len > MAX_FRAME_LEN {
+        return Err(ProtocolError::TooLarge(body_len));
+    }
+    Ok(Some(4 + body_len))
+}
+
+/// Decode one complete wire frame (length prefix included).
+pub fn decode_frame(buf: &[u8]) -> Result<Frame, ProtocolError> {
+    let total = frame_len(buf)?.ok_or(ProtocolError::Truncated)?;
+    if buf.len() < total {
+        return Err(ProtocolError::Truncated);
+    }
+    if buf.len() > total {
+        return Err(ProtocolError::TrailingBytes(buf.len() - total));
+    }
+    let envelope: EncryptedMessage = serde_json::from_slice(&buf[4..])
+        .map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+
+    let mut nonce = [0u8; NONCE_LEN];
+    let sealed = match envelope.v {
+        HEX_VERSION => {
+            decode_hex(envelope.nonce.as_bytes(), &mut nonce).ok_or(ProtocolError::BadField("nonce"))?;
+            if !envelope.ciphertext.len().is_multiple_of(2) {
+                return Err(ProtocolError::BadField("ciphertext"));
+            }
+            let ciphertext_len = envelope.ciphertext.len() / 2;
+            let mut sealed = vec![0u8; ciphertext_len + TAG_LEN];
+            decode_hex(envelope.ciphertext.as_bytes(), &mut sealed[..ciphertext_len]).ok_or(ProtocolError::BadField("ciphertext"))?;
+            decode_hex(envelope.tag.as_bytes(), &mut sealed[ciphertext_len..]).ok_or(ProtocolError::BadField("tag"))?;
+            sealed
+        }
+        BASE64_VERSION => {
+            let decoded = BASE64.decode(envelope.nonce.as_bytes()).ok().filter(|n| n.len() == NONCE_LEN).ok_or(ProtocolError::BadField("nonce"))?;
+            nonce.copy_from_slice(&decoded);
+            let mut sealed = Vec::with_capacity(envelope.ciphertext.len() / 4 * 3 + TAG_LEN);
+            BASE64.decode_vec(envelope.ciphertext.as_bytes(), &mut sealed).map_err(|_| ProtocolError::BadField("ciphertext"))?;
+            let ciphertext_len = sealed.len();
+            BASE64.decode_vec(envelope.tag.as_bytes(), &mut sealed).map_err(|_| ProtocolError::BadField("tag"))?;
+            if sealed.len() - ciphertext_len != TAG_LEN {
+                return Err(ProtocolError::BadField("tag"));
+            }
+            sealed
+        }
+        v => return Err(ProtocolError::Malformed(format!("unknown envelope version {}", v))),
+    };
+    Ok(Frame { seq: envelope.seq, nonce, sealed })
+}
+
+/// Value of each byte as a hex digit, or 0xff.
+const HEX_VALUES: [u8; 256] = {
+    let mut table = [0xff; 256];
+    let mut i = 0;
+    while i < 10 {
+        table[b'0' as usize + i] = i as u8;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 6 {
+        table[b'a' as usize + i] = 10 + i as u8;
+        table[b'A' as usize + i] = 10 + i as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Decode `hex` into `out`, which must be exactly half as long. A table
+/// lookup per digit is several times faster than the `hex` crate here,
+/// which matters for every frame received.
+fn decode_hex(hex: &[u8], out: &mut [u8]) -> Option<()> {
+    if hex.len() != 2 * out.len() {
+        return None;
+    }
+    for (pair, byte) in hex.chunks_exact(2).zip(out) {
+        let (high, low) = (HEX_VALUES[pair[0] as usize], HEX_VALUES[pair[1] as usize]);
+        if (high | low) & 0xf0 != 0 {
+            return None;
+        }
+        *byte = high << 4 | low;
+    }
+    Some(())
+}
+
+/// Encode a sealed message (ciphertext with the GCM tag appended) as a
+/// complete wire frame, with base64 fields or hex ones. The envelope is
+/// written directly, as serde_json would write an `EncryptedMessage`,
+/// without building one. Fails on input too short to end in a tag.
+pub fn encode_frame(seq: u64, nonce: &[u8; NONCE_LEN], sealed: &[u8], base64: bool) -> Result<Bytes, ProtocolError> {
+    let split = sealed.len().checked_sub(TAG_LEN).ok_or(ProtocolError::BadField("tag"))?;
+    let (ciphertext, tag) = sealed.split_at(split);
+    let mut frame = BytesMut::with_capacity(4 + 64 + 2 * (NONCE_LEN + sealed.len()));
+    frame.put_u32(0);
+    if base64 {
+        frame.put_slice(format!("{{\"v\":{},", BASE64_VERSION).as_bytes());
+    } else {
+        frame.put_u8(b'{');
+    }
+    frame.put_slice(b"\"seq\":");
+    frame.put_slice(seq.to_string().as_bytes());
+    for (name, field) in [(&b",\"nonce\":\""[..], &nonce[..]), (b"\",\"ciphertext\":\"", ciphertext), (b"\",\"tag\":\"", tag)] {
+        frame.put_slice(name);
+        let start = frame.len();
+        if base64 {
+            frame.resize(start + field.len().div_ceil(3) * 4, 0);
+            BASE64.encode_slice(field, &mut frame[start..]).expect("sized for the base64 digits");
+        } else {
+            frame.resize(start + 2 * field.len(), 0);
+            hex::encode_to_slice(field, &mut frame[start..]).expect("sized for the hex digits");
+        }
+    }
+    frame.put_slice(b"\"}");
+    let body_len = (frame.len() - 4) as u32;
+    frame[..4].copy_from_slice(&body_len.to_be_bytes());
+    Ok(frame.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sealed() -> Vec<u8> {
+        (0..40).collect()
+    }
+
+    #[test]
+    fn frames_round_trip_in_both_encodings() {
+        for base64 in [false, true] {
+            let frame = encode_frame(7, &[3; NONCE_LEN], &sealed(), base64).unwrap();
+            assert_eq!(frame_len(&frame), Ok(Some(frame.len())));
+            assert_eq!(decode_frame(&frame), Ok(Frame { seq: 7, nonce: [3; NONCE_LEN], sealed: sealed() }));
+        }
+    }
+
+    #[test]
+    fn sealed_data_must_hold_a_tag() {
+        assert_eq!(encode_frame(1, &[0; NONCE_LEN], &[0; TAG_LEN - 1], false), Err(ProtocolError::BadField("tag")));
+        assert_eq!(encode_frame(1, &[0; NONCE_LEN], &[], true), Err(ProtocolError::BadField("tag")));
+        let frame = encode_frame(1, &[0; NONCE_LEN], &[9; TAG_LEN], false).unwrap();
+        assert_eq!(decode_frame(&frame), Ok(Frame { seq: 1, nonce: [0; NONCE_LEN], sealed: vec![9; TAG_LEN] }));
+    }
+
+    #[test]
+    fn the_length_prefix_is_bounded() {
+        assert_eq!(frame_len(&[0, 0, 1]), Ok(None));
+        let limit = (MAX_FRAME_LEN as u32).to_be_bytes();
+        assert_eq!(frame_len(&limit), Ok(Some(4 + MAX_FRAME_LEN)));
+        let over = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        assert_eq!(frame_len(&over), Err(ProtocolError::TooLarge(MAX_FRAME_LEN + 1)));
+        assert_eq!(decode_frame(&u32::MAX.to_be_bytes()), Err(ProtocolError::TooLarge(u32::MAX as usize)));
+    }
+
+    #[test]
+    fn a_frame_must_be_whole_and_alone() {
+        let frame = encode_frame(1, &[0; NONCE_LEN], &sealed(), false).unwrap();
+        assert_eq!(decode_frame(&frame[..2]), Err(ProtocolError::Truncated));
+        assert_eq!(decode_frame(&frame[..frame.len() - 1]), Err(ProtocolError::Truncated));
+        let mut longer = frame.to_vec();
+        longer.extend_from_slice(b"  ");
+        assert_eq!(decode_frame(&longer), Err(ProtocolError::TrailingBytes(2)));
+    }
+
+    /// A frame around `body`, with its length prefix.
+    fn framed(body: &str) -> Vec<u8> {
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(body.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn fields_of_the_wrong_size_are_refused() {
+        let tag = "00".repeat(TAG_LEN);
+        let short_nonce = format!(r#"{{"seq":1,"nonce":"0011","ciphertext":"aa","tag":"{}"}}"#, tag);
+        assert_eq!(decode_frame(&framed(&short_nonce)), Err(ProtocolError::BadField("nonce")));
+        let nonce = "00".repeat(NONCE_LEN);
+        let odd = format!(r#"{{"seq":1,"nonce":"{}","ciphertext":"aab","tag":"{}"}}"#, nonce, tag);
+        assert_eq!(decode_frame(&framed(&odd)), Err(ProtocolError::BadField("ciphertext")));
+        let short_tag = format!(r#"{{"seq":1,"nonce":"{}","ciphertext":"aa","tag":"00"}}"#, nonce);
+        assert_eq!(decode_frame(&framed(&short_tag)), Err(ProtocolError::BadField("tag")));
+        let base64_tag = format!(r#"{{"v":2,"seq":1,"nonce":"{}","ciphertext":"qg==","tag":"AAAA"}}"#, BASE64.encode([0; NONCE_LEN]));
+        assert_eq!(decode_frame(&framed(&base64_tag)), Err(ProtocolError::BadField("tag")));
+        let version = format!(r#"{{"v":3,"seq":1,"nonce":"{}","ciphertext":"aa","tag":"{}"}}"#, nonce, tag);
+        assert!(matches!(decode_frame(&framed(&version)), Err(ProtocolError::Malformed(_))));
+        assert!(matches!(decode_frame(&framed("not json")), Err(ProtocolError::Malformed(_))));
+    }
+}
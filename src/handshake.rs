@@ -0,0 +1,321 @@
+//! Client side of the connection handshake, shared by the `antimpeu`
+//! binary and `ChatClient`: cookie, challenge-response, server identity
+//! check and, on servers that require it, login. See the protocol section
+//! of the README.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::TcpStream;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use rand_core::RngCore;
+use crate::crypto::Framing;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::crypto::{Kind, Payload, SessionCipher};
+
+/// Capabilities the server accepted in its challenge.
+#[derive(Default)]
+pub struct Accepted {
+    /// How to write our frames.
+    pub framing: Framing,
+    /// The server wants a `login` frame first, see `login`.
+    pub login: bool,
+    pub observer: bool,
+    /// The server acknowledges each of our chat messages.
+    pub acks: bool,
+    /// The server takes part in heartbeats, see `net::CAP_PING`.
+    pub ping: bool,
+    /// The server's challenge, which the join signature covers.
+    pub challenge: Vec<u8>,
+    /// This connection's message key, see `auth::derive_session_key`.
+    pub session_key: [u8; 32],
+    /// What the server identity check has to tell the user: that the key
+    /// was pinned for the first time, or that it changed while
+    /// `strict_server_keys` is off. Left to the caller to show.
+    pub notice: Option<String>,
+}
+
+/// How to connect, see `connect`.
+#[derive(Clone)]
+pub struct Options {
+    /// Ask for padded frames, see `net::CAP_PAD`.
+    pub pad: bool,
+    /// Ask to join read-only.
+    pub observe: bool,
+    /// Offer heartbeats, see `net::CAP_PING`; the caller then sends the
+    /// pings and answers the server's.
+    pub heartbeat: bool,
+    /// SOCKS5 proxy to connect through; `.onion` hosts use Tor's default
+    /// one when unset.
+    pub socks_proxy: Option<String>,
+    /// Refuse a server whose identity key differs from the pinned one
+    /// instead of only warning.
+    pub strict_server_keys: bool,
+    /// How long to wait for the server's side of the handshake.
+    pub timeouts: crate::net::Timeouts,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { pad: false, observe: false, heartbeat: false, socks_proxy: None, strict_server_keys: true, timeouts: crate::net::Timeouts::default() }
+    }
+}
+
+/// Connect to `addr` and run the handshake. Returns the authenticated
+/// stream, the accepted capabilities and the group key, which is the one
+/// passed in or, without `dek.bin`, the one the server wrapped to us.
+///
+/// The server answers a HELLO without a valid cookie with `COOKIE:<hex>`
+/// and closes the connection, so the first attempt only fetches a cookie
+/// and the second presents it.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn connect(host: &str, port: u16, dek: Option<[u8; 32]>, options: &Options) -> Result<(TcpStream, Accepted, [u8; 32]), String> {
+    let addr = format!("{}:{}", host, port);
+    let _span = tracing::info_span!("handshake", server = %addr).entered();
+    let mut cookie: Option<String> = None;
+    for _ in 0..2 {
+        let mut stream = open_stream(host, port, options.socks_proxy.as_deref())?;
+        // Send HELLO token immediately so server's HELLO-first check succeeds.
+        let mut hello = crate::net::HELLO.to_string();
+        if let Some(cookie) = &cookie {
+            hello.push_str(" cookie=");
+            hello.push_str(cookie);
+        }
+        if options.pad {
+            hello.push(' ');
+            hello.push_str(crate::net::CAP_PAD);
+        }
+        if options.observe {
+            hello.push(' ');
+            hello.push_str(crate::net::CAP_OBSERVE);
+        }
+        if options.heartbeat {
+            hello.push(' ');
+            hello.push_str(crate::net::CAP_PING);
+        }
+        hello.push(' ');
+        hello.push_str(crate::net::CAP_ACK);
+        hello.push(' ');
+        hello.push_str(crate::net::CAP_BASE64);
+        crate::net::write_plain(&mut stream, hello.as_bytes()).map_err(|e| format!("Failed to send HELLO to server: {}", e))?;
+
+        stream.set_read_timeout(Some(options.timeouts.handshake())).ok();
+        let reply = crate::net::read_plain(&mut stream).map_err(|e| format!("Server did not send a challenge: {}", e))?;
+        let reply = String::from_utf8(reply).map_err(|_| "Server sent an invalid challenge".to_string())?;
+        if let Some(fresh) = reply.strip_prefix("COOKIE:") {
+            tracing::debug!("server asked for a cookie; reconnecting");
+            cookie = Some(fresh.to_string());
+            continue;
+        }
+        let (accepted, dek) = handshake(&mut stream, dek, &reply, &addr, options.strict_server_keys).inspect_err(|e| tracing::info!(error = %e, "handshake failed"))?;
+        tracing::info!(framing = ?accepted.framing, login = accepted.login, acks = accepted.acks, ping = accepted.ping, "connected");
+        return Ok((stream, accepted, dek));
+    }
+    Err("Server rejected the connection cookie".to_string())
+}
+
+/// Open the TCP connection to the server, through the SOCKS proxy when one
+/// is given. `.onion` hosts always go through a proxy, Tor's default
+/// one unless configured otherwise.
+#[cfg(not(target_arch = "wasm32"))]
+fn open_stream(host: &str, port: u16, socks_proxy: Option<&str>) -> Result<TcpStream, String> {
+    let proxy = socks_proxy.or_else(|| host.ends_with(".onion").then_some(crate::tor::DEFAULT_SOCKS_PROXY));
+    let stream = match proxy {
+        Some(proxy) => crate::tor::socks_connect(proxy, host, port)?,
+        None => TcpStream::connect((host, port)).map_err(|e| format!("Could not connect to {}:{}: {}", host, port, e))?,
+    };
+    crate::net::tune(&stream).map_err(|e| format!("Could not set socket options: {}", e))?;
+    Ok(stream)
+}
+
+/// Run the client side of the challenge-response and return the accepted
+/// capabilities and the group key.
+///
+/// The server's challenge carries our address as it sees it and the
+/// capabilities it accepted. We prove knowledge of the DEK with an HMAC over
+/// it, or without a DEK ask for it with a request signed by our personal
+/// key, and send our own challenge, which the server must answer the same
+/// way before we trust it with any message. The answer is signed with the
+/// server's identity key, which is checked against `known_servers`.
+#[cfg(not(target_arch = "wasm32"))]
+fn handshake(stream: &mut TcpStream, dek: Option<[u8; 32]>, chal_str: &str, server: &str, strict_server_keys: bool) -> Result<(Accepted, [u8; 32]), String> {
+    let Some(chal_str) = chal_str.strip_prefix("CHAL:") else {
+        return Err("Server sent an invalid challenge".to_string());
+    };
+    let mut parts = chal_str.split(' ');
+    let challenge = hex::decode(parts.next().unwrap_or_default()).map_err(|_| "Server sent an invalid challenge".to_string())?;
+    let peer = parts.next().unwrap_or_default().to_string();
+    let mut accepted = Accepted { challenge: challenge.clone(), ..Default::default() };
+    for cap in parts {
+        match cap {
+            crate::net::CAP_PAD => accepted.framing.pad = true,
+            crate::net::CAP_BASE64 => accepted.framing.base64 = true,
+            crate::net::CAP_LOGIN => accepted.login = true,
+            crate::net::CAP_OBSERVE => accepted.observer = true,
+            crate::net::CAP_ACK => accepted.acks = true,
+            crate::net::CAP_PING => accepted.ping = true,
+            _ => {}
+        }
+    }
+
+    let mut our_challenge = [0u8; 32];
+    aes_gcm::aead::OsRng.fill_bytes(&mut our_challenge);
+    let mut wrapped_by = None;
+    let dek = match dek {
+        Some(dek) => {
+            let response = crate::auth::handshake_mac(&crate::auth::derive_handshake_key(&dek), &challenge, "client", &peer);
+            let reply = format!("{} {}", hex::encode(response), hex::encode(our_challenge));
+            crate::net::write_plain(stream, reply.as_bytes()).map_err(|e| format!("Handshake reply failed: {}", e))?;
+            dek
+        }
+        None => {
+            let (dek, identity) = request_group_key(stream, &challenge, &peer, &our_challenge)?;
+            wrapped_by = Some(identity);
+            dek
+        }
+    };
+    let handshake_key = crate::auth::derive_handshake_key(&dek);
+    tracing::debug!(%peer, "challenge answered");
+
+    // The server only answers once it accepted our response; a server that
+    // does not know the DEK cannot produce this.
+    let proof = crate::net::read_plain(stream).map_err(|_| "Server refused the handshake (wrong key?)".to_string())?;
+    let proof = String::from_utf8(proof).unwrap_or_default();
+    let mut parts = proof.split(' ');
+    let mac = hex::decode(parts.next().unwrap_or_default()).unwrap_or_default();
+    if !crate::auth::verify_handshake_mac(&handshake_key, &our_challenge, "server", &peer, &mac) {
+        return Err("Server failed to prove it knows the DEK; refusing to connect".to_string());
+    }
+    let identity = crate::identity::verify_handshake(parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), &our_challenge, &peer)?;
+    if wrapped_by.is_some_and(|wrapper| wrapper != identity) {
+        return Err("Server wrapped the group key under another identity key; refusing to connect".to_string());
+    }
+    accepted.notice = check_server_identity(server, &identity, strict_server_keys)?;
+    accepted.session_key = crate::auth::derive_session_key(&dek, &challenge, &our_challenge, &peer);
+    stream.set_read_timeout(None).ok();
+    Ok((accepted, dek))
+}
+
+/// Log in to `account` with `password` on a server that requires it,
+/// waiting up to `timeout` for the answer. The login frame is the first
+/// encrypted frame, sequence 1.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn login(stream: &mut TcpStream, cipher: &SessionCipher, account: &str, framing: Framing, password: &str, timeout: Duration) -> Result<(), String> {
+    crate::crypto::send_encrypted(stream, &Payload::control(Kind::Login, account, password), cipher, 1, framing).map_err(|e| format!("Failed to log in: {}", e))?;
+    stream.set_read_timeout(Some(timeout)).ok();
+    let reply = crate::net::read_plain(stream).unwrap_or_default();
+    stream.set_read_timeout(None).ok();
+    if reply != b"LOGIN-OK" {
+        return Err(format!("Login as {} failed", account));
+    }
+    Ok(())
+}
+
+/// Ask the server for the group key: sign a request for it with our
+/// personal key and unwrap the KEYWRAP answer with the matching ephemeral
+/// X25519 secret. Returns the key and the server identity that signed it.
+#[cfg(not(target_arch = "wasm32"))]
+fn request_group_key(stream: &mut TcpStream, challenge: &[u8], peer: &str, our_challenge: &[u8; 32]) -> Result<([u8; 32], ed25519_dalek::VerifyingKey), String> {
+    let personal = crate::identity::load_or_create(&crate::identity::user_key_path())?;
+    let secret = x25519_dalek::EphemeralSecret::random_from_rng(aes_gcm::aead::OsRng);
+    let wrap_public = x25519_dalek::PublicKey::from(&secret);
+    let signature = crate::identity::sign_key_request(&personal, challenge, peer, wrap_public.as_bytes());
+    let reply = format!(
+        "KEY:{} {} {} {}",
+        hex::encode(personal.verifying_key().as_bytes()),
+        hex::encode(wrap_public.as_bytes()),
+        hex::encode(signature),
+        hex::encode(our_challenge),
+    );
+    crate::net::write_plain(stream, reply.as_bytes()).map_err(|e| format!("Handshake reply failed: {}", e))?;
+
+    let refused = || format!("Server refused to hand out the group key; ask its operator to add this line to authorized_clients:\n{} {}", whoami::username(), hex::encode(personal.verifying_key().as_bytes()));
+    let wrapped = crate::net::read_plain(stream).map_err(|_| refused())?;
+    let wrapped = String::from_utf8(wrapped).unwrap_or_default();
+    let wrapped = wrapped.strip_prefix("KEYWRAP:").ok_or_else(refused)?;
+    open_key_wrap(secret, wrapped, challenge, our_challenge, peer)
+}
+
+/// Check the server's signature over the KEYWRAP body `message`, then
+/// unwrap the group key in it. Returns the key and the signing identity.
+#[cfg(not(target_arch = "wasm32"))]
+fn open_key_wrap(secret: x25519_dalek::EphemeralSecret, message: &str, challenge: &[u8], our_challenge: &[u8], peer: &str) -> Result<([u8; 32], ed25519_dalek::VerifyingKey), String> {
+    let mut fields = message.rsplitn(3, ' ');
+    let (signature, identity) = (fields.next().unwrap_or_default(), fields.next().unwrap_or_default());
+    let wrapped = fields.next().ok_or("Server sent an invalid wrapped key")?;
+    let wrap_public = x25519_dalek::PublicKey::from(&secret);
+    let identity = crate::identity::verify_key_wrap(identity, signature, challenge, our_challenge, peer, wrap_public.as_bytes(), wrapped)?;
+    Ok((crate::crypto::unwrap_key(secret, wrapped)?, identity))
+}
+
+/// Trust-on-first-use check of the server's identity key. Returns the
+/// notice for a newly pinned or, when not `strict`, a changed key.
+#[cfg(not(target_arch = "wasm32"))]
+fn check_server_identity(server: &str, identity: &ed25519_dalek::VerifyingKey, strict: bool) -> Result<Option<String>, String> {
+    let path = crate::identity::known_servers_path();
+    let offered = hex::encode(identity.as_bytes());
+    match crate::identity::check_known_server(&path, server, identity)? {
+        crate::identity::Pin::Match => Ok(None),
+        crate::identity::Pin::New => Ok(Some(format!("Pinned identity key of {} in {}: {}", server, path, offered))),
+        crate::identity::Pin::Changed(pinned) => {
+            let warning = [
+                "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@".to_string(),
+                "@    WARNING: SERVER IDENTITY KEY HAS CHANGED!             @".to_string(),
+                "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@".to_string(),
+                format!("Someone could be impersonating {}.", server),
+                format!("Pinned key:  {}", pinned),
+                format!("Offered key: {}", offered),
+                format!("If the change is expected, remove the line for {} from {}.", server, path),
+            ]
+            .join("\n");
+            if strict {
+                return Err(format!("{}\nRefusing to connect to {}: identity key changed", warning, server));
+            }
+            Ok(Some(format!("{}\nstrict_server_keys is off; connecting anyway.", warning)))
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    /// The KEYWRAP body `server` sends for `dek`, as `server::authenticate`
+    /// builds it.
+    fn key_wrap(server: &SigningKey, dek: &[u8; 32], wrap_public: &[u8; 32], challenges: (&[u8], &[u8])) -> String {
+        let wrapped = crate::crypto::wrap_key(dek, wrap_public);
+        let signature = crate::identity::sign_key_wrap(server, challenges.0, challenges.1, "peer", wrap_public, &wrapped);
+        format!("{} {} {}", wrapped, hex::encode(server.verifying_key().as_bytes()), hex::encode(signature))
+    }
+
+    fn secret() -> (x25519_dalek::EphemeralSecret, [u8; 32]) {
+        let secret = x25519_dalek::EphemeralSecret::random_from_rng(aes_gcm::aead::OsRng);
+        let public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        (secret, public)
+    }
+
+    #[test]
+    fn a_signed_key_wrap_opens() {
+        let server = SigningKey::from_bytes(&[1; 32]);
+        let (secret, public) = secret();
+        let message = key_wrap(&server, &[9; 32], &public, (&[2; 32], &[3; 32]));
+        let (dek, identity) = open_key_wrap(secret, &message, &[2; 32], &[3; 32], "peer").unwrap();
+        assert_eq!(dek, [9; 32]);
+        assert_eq!(identity, server.verifying_key());
+    }
+
+    #[test]
+    fn a_swapped_key_wrap_is_refused() {
+        let server = SigningKey::from_bytes(&[1; 32]);
+        let (secret, public) = secret();
+        let genuine = key_wrap(&server, &[9; 32], &public, (&[2; 32], &[3; 32]));
+        // an attacker wraps a key of its own to the same client key but
+        // cannot sign it as the server
+        let forged = key_wrap(&SigningKey::from_bytes(&[6; 32]), &[6; 32], &public, (&[2; 32], &[3; 32]));
+        let forged_wrap = forged.rsplitn(3, ' ').nth(2).unwrap();
+        let signed_by = genuine.splitn(4, ' ').nth(3).unwrap();
+        let swapped = format!("{} {}", forged_wrap, signed_by);
+        assert_eq!(open_key_wrap(secret, &swapped, &[2; 32], &[3; 32], "peer").unwrap_err(), "Server identity signature does not verify");
+    }
+}
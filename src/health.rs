@@ -0,0 +1,70 @@
+//! Health probe (`[server.health]`): a listener that answers every
+//! connection with `ok`, the server's uptime in seconds and the number of
+//! connected clients, for load balancers and uptime monitors. It needs no
+//! key and tells nothing else about the room.
+//!
+//! A probe that sends an HTTP request gets `200 OK` with the line as its
+//! body; one that sends nothing, or anything else, gets the bare line:
+//!
+//! ```text
+//! ok uptime=3600 clients=4
+//! ```
+
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Largest request line plus headers read from an HTTP probe.
+const MAX_HEAD: usize = 8 * 1024;
+/// How long to wait for a probe to say something before answering with
+/// the bare line.
+const PROBE_WAIT: Duration = Duration::from_millis(500);
+
+/// Settings under `[server.health]`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Address to answer probes on, e.g. `0.0.0.0:7879`; off when unset.
+    pub listen: Option<String>,
+}
+
+/// Answer probes on `config.listen` in the background. `clients` counts
+/// the connected clients.
+pub fn spawn(config: &HealthConfig, clients: impl Fn() -> usize + Send + Sync + 'static) -> Result<Option<String>, String> {
+    let Some(listen) = &config.listen else { return Ok(None) };
+    let listener = TcpListener::bind(listen).map_err(|e| format!("Cannot bind the health probe {}: {}", listen, e))?;
+    let started = Instant::now();
+    let clients = Arc::new(clients);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let clients = clients.clone();
+            std::thread::spawn(move || handle(stream, &format!("ok uptime={} clients={}\n", started.elapsed().as_secs(), clients())));
+        }
+    });
+    Ok(Some(listen.clone()))
+}
+
+/// Answer one probe with `line`; the connection is closed afterwards.
+fn handle(mut stream: TcpStream, line: &str) {
+    stream.set_read_timeout(Some(PROBE_WAIT)).ok();
+    let mut data = Vec::new();
+    // read the whole request of an HTTP probe, or closing the socket on
+    // unread data could reset the connection before the answer arrives
+    while !data.windows(4).any(|w| w == b"\r\n\r\n") && data.len() <= MAX_HEAD {
+        let mut buf = [0u8; 1024];
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    }
+    let request_line = data.split(|&b| b == b'\n').next().unwrap_or_default();
+    let response = if request_line.windows(5).any(|w| w == b"HTTP/") {
+        let body = if request_line.starts_with(b"HEAD ") { "" } else { line };
+        format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}", line.len(), body)
+    } else {
+        line.to_string()
+    };
+    let _ = stream.write_all(response.as_bytes());
+}
@@ -0,0 +1,696 @@
+//! Server-side message history.
+//!
+//! With `[server.history] enabled = true` the server appends every relayed
+//! chat message to `$HOME/key/history` and replays the most recent ones to
+//! clients when they join. Each line is `<unix-time> <index> <hex-nonce>
+//! <hex-ciphertext>`: the payload JSON sealed with the DEK, with the time
+//! and the line's index authenticated as associated data. The time stays
+//! readable so retention can prune by age without decrypting. Indexes count
+//! up by one per line, and `<path>.head`, sealed too, holds the index of
+//! the first line kept and of the next one, so reading the file back shows
+//! lines that were removed, reordered or cut off the end.
+//!
+//! Retention (`max_age_days`, `max_messages`) is applied by a background
+//! task that rewrites the file without the expired lines.
+//!
+//! Clients with `[client] keep_log = true` keep their own log in the same
+//! format in `$HOME/key/local_history`, sealed with a storage key derived
+//! from the DEK rather than the DEK itself (see `History::open_local`),
+//! which `/search` scans,
+//! `antimpeu history export` / `import` move between installs and
+//! `antimpeu history purge` deletes.
+
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{AeadInPlace, OsRng}};
+use rand_core::RngCore;
+use serde::Deserialize;
+use crate::crypto::Payload;
+
+/// Settings under `[server.history]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Keep a history of chat messages on the server.
+    pub enabled: bool,
+    /// Number of recent messages replayed to a client that joins.
+    pub replay: usize,
+    /// Drop messages older than this many days.
+    pub max_age_days: Option<u64>,
+    /// Keep at most this many messages, dropping the oldest.
+    pub max_messages: Option<usize>,
+    /// How often retention is applied.
+    pub prune_interval_secs: u64,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { enabled: false, replay: 50, max_age_days: None, max_messages: None, prune_interval_secs: 3600 }
+    }
+}
+
+/// Location of the history file.
+pub fn history_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/history", home)
+}
+
+/// Location of a client's local log.
+pub fn local_history_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/local_history", home)
+}
+
+/// Delete the history file at `path`, with its head, the lines set aside
+/// as damaged and the temporary files a rewrite may have left, overwriting them with random bytes synced to disk
+/// first so the sealed lines do not linger in freed blocks. Filesystems
+/// that copy on write and SSDs that remap blocks can still keep the old
+/// data, which stays encrypted with the DEK. Returns the bytes wiped, or
+/// None when there was nothing to delete.
+pub fn purge(path: &str) -> Result<Option<u64>, String> {
+    let mut wiped = None;
+    for path in [format!("{}.tmp", path), format!("{}.head.tmp", path), format!("{}.damaged", path), format!("{}.head", path), path.to_string()] {
+        let mut file = match std::fs::OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Failed to open {}: {}", path, e)),
+        };
+        let len = file.metadata().map_err(|e| format!("Failed to read {}: {}", path, e))?.len();
+        let mut chunk = vec![0u8; 64 * 1024];
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(chunk.len() as u64) as usize;
+            OsRng.fill_bytes(&mut chunk[..n]);
+            file.write_all(&chunk[..n]).map_err(|e| format!("Failed to overwrite {}: {}", path, e))?;
+            left -= n as u64;
+        }
+        file.sync_all().map_err(|e| format!("Failed to overwrite {}: {}", path, e))?;
+        drop(file);
+        std::fs::remove_file(&path).map_err(|e| format!("Failed to delete {}: {}", path, e))?;
+        wiped = Some(wiped.unwrap_or(0) + len);
+    }
+    Ok(wiped)
+}
+
+/// Bytes `History::page_before` reads at a time.
+const PAGE_BLOCK: u64 = 64 * 1024;
+
+/// Start of an exported archive, also its associated data.
+const ARCHIVE_MAGIC: &[u8] = b"ANTIMPEU-HISTORY-ARCHIVE-2\n";
+/// Start of an archive from before local logs had their own key, sealed
+/// with the DEK.
+const ARCHIVE_MAGIC_V1: &[u8] = b"ANTIMPEU-HISTORY-ARCHIVE-1\n";
+/// Associated data of the sealed head.
+const HEAD_AAD: &[u8] = b"antimpeu history head v1";
+
+fn associated_data(time: i64, index: u64) -> Vec<u8> {
+    let mut aad = b"antimpeu history v2".to_vec();
+    aad.extend_from_slice(&time.to_be_bytes());
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+/// Time stamp of a history line, without decrypting it.
+fn line_time(line: &str) -> Option<i64> {
+    line.split(' ').next()?.parse().ok()
+}
+
+/// Decrypt and authenticate a history line sealed with `cipher`, and its
+/// place in the chain.
+fn open_line(cipher: &Aes256Gcm, line: &str) -> Option<(u64, Payload<'static>)> {
+    let mut parts = line.split(' ');
+    let time: i64 = parts.next()?.parse().ok()?;
+    let index: u64 = parts.next()?.parse().ok()?;
+    let nonce: [u8; 12] = hex::decode(parts.next()?).ok()?.try_into().ok()?;
+    let mut sealed = hex::decode(parts.next()?).ok()?;
+    cipher
+        .decrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), &associated_data(time, index), &mut sealed)
+        .ok()?;
+    let payload: Payload = serde_json::from_slice(&sealed).ok()?;
+    Some((index, payload.into_owned()))
+}
+
+/// Decrypt a line written before lines were chained: `<time> <nonce>
+/// <ciphertext>`, with only the time authenticated.
+fn open_line_v1(cipher: &Aes256Gcm, line: &str) -> Option<Payload<'static>> {
+    let mut parts = line.split(' ');
+    let time: i64 = parts.next()?.parse().ok()?;
+    let nonce: [u8; 12] = hex::decode(parts.next()?).ok()?.try_into().ok()?;
+    let mut sealed = hex::decode(parts.next()?).ok()?;
+    let mut aad = b"antimpeu history v1".to_vec();
+    aad.extend_from_slice(&time.to_be_bytes());
+    cipher
+        .decrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), &aad, &mut sealed)
+        .ok()?;
+    let payload: Payload = serde_json::from_slice(&sealed).ok()?;
+    Some(payload.into_owned())
+}
+
+/// The indexes of the oldest line kept and of the next one appended.
+#[derive(Clone, Copy, Default)]
+struct Head {
+    first: u64,
+    next: u64,
+}
+
+/// Where `History::page_before` continues: the offset of the oldest line
+/// on the page and its index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    offset: u64,
+    index: u64,
+}
+
+/// Messages read back by `History::page_before`.
+pub struct Page {
+    /// Oldest first.
+    pub messages: Vec<Payload<'static>>,
+    /// Where the page before starts, None once the start of the file was
+    /// reached.
+    pub before: Option<Cursor>,
+    /// Lines that failed the integrity check and were skipped.
+    pub damaged: usize,
+    /// Messages the chain of indexes says belong on the page but are not
+    /// in the file: lines were removed or the file was cut short.
+    pub missing: u64,
+    /// The oldest line on the page, even at the start of the file.
+    oldest: Option<Cursor>,
+}
+
+/// The history file, sealed with the DEK, or for a local log with the key
+/// derived from it.
+pub struct History {
+    path: String,
+    cipher: Arc<Aes256Gcm>,
+    /// The DEK itself for a local log, which older versions sealed with it.
+    legacy: Option<Aes256Gcm>,
+    /// What opening the file found wrong or changed.
+    notices: Vec<String>,
+    config: HistoryConfig,
+    /// Also serializes appends with the rewrites done by `prune` and
+    /// `import`.
+    head: Mutex<Head>,
+}
+
+impl History {
+    pub fn open(path: String, cipher: Arc<Aes256Gcm>, config: HistoryConfig) -> Result<Self, String> {
+        Self::open_with(path, cipher, None, config)
+    }
+
+    /// Open a client's local log, sealed with `auth::derive_storage_key`
+    /// of the DEK. Lines an older version sealed with the DEK itself are
+    /// sealed again with the storage key when the log is brought up to
+    /// the chained format.
+    pub fn open_local(path: String, dek: &[u8; 32], config: HistoryConfig) -> Result<Self, String> {
+        let cipher = Aes256Gcm::new_from_slice(&crate::auth::derive_storage_key(dek)).expect("Invalid storage key");
+        let legacy = Aes256Gcm::new_from_slice(dek).expect("Invalid DEK");
+        Self::open_with(path, Arc::new(cipher), Some(legacy), config)
+    }
+
+    /// Open the file and its head. Only the end of the file is read, except
+    /// the first time, when a file without a head is brought up to date.
+    fn open_with(path: String, cipher: Arc<Aes256Gcm>, legacy: Option<Aes256Gcm>, config: HistoryConfig) -> Result<Self, String> {
+        if let Some(dir) = std::path::Path::new(&path).parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        // Fail at startup rather than on the first message
+        std::fs::OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let mut history = Self { path, cipher, legacy, notices: Vec::new(), config, head: Mutex::new(Head::default()) };
+        let head_path = history.head_path();
+        match std::fs::read_to_string(&head_path) {
+            Ok(text) => match history.open_head(&text) {
+                Some(head) => {
+                    *history.head.get_mut().unwrap() = head;
+                    history.catch_up()?;
+                }
+                None => {
+                    history.notices.push(format!("The head of {} failed the integrity check and was rebuilt; messages removed before now cannot be detected", history.path));
+                    history.migrate()?;
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => history.migrate()?,
+            Err(e) => return Err(format!("Failed to read {}: {}", head_path, e)),
+        }
+        Ok(history)
+    }
+
+    /// What opening the file found wrong or changed, to tell the user.
+    pub fn notices(&self) -> &[String] {
+        &self.notices
+    }
+
+    /// Location of the sealed head, next to the file.
+    fn head_path(&self) -> String {
+        format!("{}.head", self.path)
+    }
+
+    fn open_head(&self, text: &str) -> Option<Head> {
+        let (nonce, sealed) = text.trim().split_once(' ')?;
+        let nonce: [u8; 12] = hex::decode(nonce).ok()?.try_into().ok()?;
+        let mut sealed = hex::decode(sealed).ok()?;
+        self.cipher.decrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), HEAD_AAD, &mut sealed).ok()?;
+        let bytes: [u8; 16] = sealed.try_into().ok()?;
+        let first = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+        let next = u64::from_be_bytes(bytes[8..].try_into().unwrap());
+        Some(Head { first, next }).filter(|_| first <= next)
+    }
+
+    /// Seal `head` and swap it in, so a crash leaves the old one.
+    fn save_head(&self, head: Head) -> Result<(), String> {
+        let mut buffer = [head.first.to_be_bytes(), head.next.to_be_bytes()].concat();
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        self.cipher
+            .encrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), HEAD_AAD, &mut buffer)
+            .expect("encryption failed");
+        let (path, tmp) = (self.head_path(), format!("{}.head.tmp", self.path));
+        std::fs::write(&tmp, format!("{} {}\n", hex::encode(nonce), hex::encode(buffer))).map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+        std::fs::rename(&tmp, &path).map_err(|e| format!("Failed to replace {}: {}", path, e))
+    }
+
+    /// Move the head past a last line written before a crash kept the head
+    /// from following it.
+    fn catch_up(&mut self) -> Result<(), String> {
+        let head = *self.head.get_mut().unwrap();
+        let newest = self.read_back(Head { first: 0, next: u64::MAX }, None, 1)?;
+        if let Some(index) = newest.oldest.map(|c| c.index).filter(|&index| index >= head.next) {
+            let head = Head { next: index + 1, ..head };
+            *self.head.get_mut().unwrap() = head;
+            self.save_head(head)?;
+        }
+        Ok(())
+    }
+
+    /// Bring a file without a head up to the chained format, once: seal
+    /// every line that authenticates again under the next index, and move
+    /// those that do not to `<path>.damaged`.
+    fn migrate(&mut self) -> Result<(), String> {
+        let text = std::fs::read_to_string(&self.path).map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+        let (mut lines, mut damaged, mut chained) = (Vec::new(), Vec::new(), false);
+        for line in text.lines() {
+            let payload = match open_line(&self.cipher, line) {
+                Some((_, payload)) => {
+                    chained = true;
+                    Some(payload)
+                }
+                None => open_line_v1(&self.cipher, line).or_else(|| self.legacy.as_ref().and_then(|legacy| open_line_v1(legacy, line))),
+            };
+            match (payload, line_time(line)) {
+                (Some(payload), Some(time)) => lines.push(self.seal(&payload, time, lines.len() as u64)),
+                _ => damaged.push(line),
+            }
+        }
+        if chained && self.notices.is_empty() {
+            self.notices.push(format!("The head of {} was missing and was rebuilt; messages removed before now cannot be detected", self.path));
+        }
+        if !damaged.is_empty() {
+            self.set_aside(&damaged)?;
+            self.notices.push(format!("{} entries of {} failed the integrity check and were moved to {}.damaged", damaged.len(), self.path, self.path));
+        }
+        if !text.is_empty() {
+            self.rewrite(&lines)?;
+        }
+        let head = Head { first: 0, next: lines.len() as u64 };
+        *self.head.get_mut().unwrap() = head;
+        self.save_head(head)
+    }
+
+    /// Move lines that fail the integrity check out of the chain, to
+    /// `<path>.damaged`.
+    fn set_aside<S: AsRef<str>>(&self, lines: &[S]) -> Result<(), String> {
+        let path = format!("{}.damaged", self.path);
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        for line in lines {
+            writeln!(file, "{}", line.as_ref()).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Replace the file with `lines`, written next to it and swapped in so
+    /// a crash never leaves a half-written history.
+    fn rewrite<S: AsRef<str>>(&self, lines: &[S]) -> Result<(), String> {
+        let tmp = format!("{}.tmp", self.path);
+        let body: String = lines.iter().map(|line| format!("{}\n", line.as_ref())).collect();
+        std::fs::write(&tmp, body).map_err(|e| format!("Failed to write {}: {}", tmp, e))?;
+        std::fs::rename(&tmp, &self.path).map_err(|e| format!("Failed to replace {}: {}", self.path, e))
+    }
+
+    pub fn config(&self) -> &HistoryConfig {
+        &self.config
+    }
+
+    /// Append a chat message, stamped with the current time.
+    pub fn append(&self, payload: &Payload) -> Result<(), String> {
+        let mut head = self.head.lock().unwrap();
+        let line = self.seal(payload, chrono::Utc::now().timestamp(), head.next);
+        let mut file = std::fs::OpenOptions::new().append(true).open(&self.path).map_err(|e| format!("Failed to open {}: {}", self.path, e))?;
+        writeln!(file, "{}", line).map_err(|e| format!("Failed to write {}: {}", self.path, e))?;
+        head.next += 1;
+        self.save_head(*head)
+    }
+
+    /// A history line for `payload`, stamped with `time`, at `index` in the
+    /// chain.
+    fn seal(&self, payload: &Payload, time: i64, index: u64) -> String {
+        let stamped = Payload { time: Some(time), ..payload.clone() };
+        let mut buffer = serde_json::to_vec(&stamped).expect("serialization failed");
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        self.cipher
+            .encrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), &associated_data(time, index), &mut buffer)
+            .expect("encryption failed");
+        format!("{} {} {} {}", time, index, hex::encode(nonce), hex::encode(buffer))
+    }
+
+    fn decrypt(&self, line: &str) -> Option<Payload<'static>> {
+        open_line(&self.cipher, line).map(|(_, payload)| payload)
+    }
+
+    /// The last `n` messages, oldest first, read back from the end of the
+    /// file by `page_before`. Lines that fail to decrypt, e.g. from before a
+    /// DEK rotation, are skipped.
+    pub fn recent(&self, n: usize) -> Vec<Payload<'static>> {
+        self.page_before(None, n).map(|page| page.messages).unwrap_or_default()
+    }
+
+    /// Up to `n` messages before `before`, or the end of the file when
+    /// None. Reads backwards a block at a time, so a page costs the same
+    /// however long the log has grown, and checks the indexes of the lines
+    /// it reads follow on from each other, from the head down to the first
+    /// line kept.
+    pub fn page_before(&self, before: Option<Cursor>, n: usize) -> Result<Page, String> {
+        let head = *self.head.lock().unwrap();
+        self.read_back(head, before, n)
+    }
+
+    fn read_back(&self, head: Head, before: Option<Cursor>, n: usize) -> Result<Page, String> {
+        let mut file = std::fs::File::open(&self.path).map_err(|e| format!("Failed to open {}: {}", self.path, e))?;
+        let len = file.metadata().map_err(|e| format!("Failed to read {}: {}", self.path, e))?.len();
+        let end = before.map_or(len, |c| c.offset.min(len));
+        // the index the next line back should be one below
+        let mut above = before.map_or(head.next, |c| c.index);
+        // damaged lines since the last good one, which may be the ones the
+        // indexes skip
+        let mut skipped = 0;
+        // the bytes from `start` up to the first line taken so far
+        let (mut start, mut buf) = (end, Vec::new());
+        let mut page = Page { messages: Vec::new(), before: None, damaged: 0, missing: 0, oldest: None };
+        loop {
+            while page.messages.len() < n {
+                let body = buf.strip_suffix(b"\n").unwrap_or(&buf);
+                let from = match body.iter().rposition(|&b| b == b'\n') {
+                    Some(i) => i + 1,
+                    None if start == 0 && !body.is_empty() => 0,
+                    None => break,
+                };
+                // a line at or above one already read was copied or moved
+                match std::str::from_utf8(&body[from..]).ok().and_then(|line| open_line(&self.cipher, line)).filter(|(index, _)| *index < above) {
+                    Some((index, payload)) => {
+                        page.missing += (above - 1 - index).saturating_sub(skipped);
+                        (above, skipped) = (index, 0);
+                        page.oldest = Some(Cursor { offset: start + from as u64, index });
+                        page.messages.push(payload);
+                    }
+                    None => {
+                        page.damaged += 1;
+                        skipped += 1;
+                    }
+                }
+                buf.truncate(from);
+            }
+            if page.messages.len() == n || start == 0 {
+                break;
+            }
+            let step = start.min(PAGE_BLOCK);
+            start -= step;
+            let mut block = vec![0u8; step as usize];
+            file.seek(SeekFrom::Start(start)).and_then(|_| file.read_exact(&mut block)).map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+            block.extend_from_slice(&buf);
+            buf = block;
+        }
+        // a short page means everything back to the start was read
+        if page.messages.len() < n || page.oldest.is_some_and(|c| c.offset == 0) {
+            page.missing += above.saturating_sub(head.first).saturating_sub(skipped);
+        } else {
+            page.before = page.oldest;
+        }
+        page.messages.reverse();
+        Ok(page)
+    }
+
+    /// The last `limit` messages whose text or sender contains `term`,
+    /// ignoring case, oldest first. Decrypts one line at a time, so the
+    /// log never has to fit in memory.
+    pub fn search(&self, term: &str, limit: usize) -> Result<Vec<Payload<'static>>, String> {
+        let file = std::fs::File::open(&self.path).map_err(|e| format!("Failed to open {}: {}", self.path, e))?;
+        let term = term.to_lowercase();
+        let mut matches = std::collections::VecDeque::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+            let Some(payload) = self.decrypt(&line) else { continue };
+            if payload.text.to_lowercase().contains(&term) || payload.username.to_lowercase().contains(&term) {
+                if matches.len() == limit {
+                    matches.pop_front();
+                }
+                matches.push_back(payload);
+            }
+        }
+        Ok(matches.into())
+    }
+
+    /// Apply the retention settings and compact the file. Returns the number
+    /// of messages removed.
+    pub fn prune(&self) -> Result<usize, String> {
+        let mut head = self.head.lock().unwrap();
+        let text = std::fs::read_to_string(&self.path).map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+        let mut lines: Vec<&str> = text.lines().collect();
+        // only ever from the front, so the rest of the chain stays whole
+        let mut removed = 0;
+        // An age too large to express in seconds keeps everything.
+        let age = self.config.max_age_days.and_then(|days| days.checked_mul(86_400)).and_then(|secs| i64::try_from(secs).ok());
+        if let Some(age) = age {
+            let cutoff = chrono::Utc::now().timestamp().saturating_sub(age);
+            removed = lines.iter().position(|line| line_time(line).is_some_and(|t| t >= cutoff)).unwrap_or(lines.len());
+        }
+        if let Some(max) = self.config.max_messages {
+            removed = removed.max(lines.len().saturating_sub(max));
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        lines.drain(..removed);
+        self.rewrite(&lines)?;
+        // the index of the first line left, counted back from the first
+        // one that authenticates
+        head.first = lines.iter().enumerate().find_map(|(i, line)| open_line(&self.cipher, line).map(|(index, _)| index.saturating_sub(i as u64))).unwrap_or(head.next);
+        self.save_head(*head)?;
+        Ok(removed)
+    }
+
+    /// Write every message into a single archive at `path`: the messages as
+    /// JSON lines, sealed as a whole with the DEK. Returns how many were
+    /// written.
+    pub fn export(&self, path: &str) -> Result<usize, String> {
+        let text = {
+            let _head = self.head.lock().unwrap();
+            std::fs::read_to_string(&self.path).map_err(|e| format!("Failed to read {}: {}", self.path, e))?
+        };
+        let mut body = Vec::new();
+        let mut count = 0;
+        for payload in text.lines().filter_map(|line| self.decrypt(line)) {
+            serde_json::to_writer(&mut body, &payload).expect("serialization failed");
+            body.push(b'\n');
+            count += 1;
+        }
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+        self.cipher
+            .encrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(&nonce), ARCHIVE_MAGIC, &mut body)
+            .expect("encryption failed");
+        let mut archive = ARCHIVE_MAGIC.to_vec();
+        archive.extend_from_slice(&nonce);
+        archive.extend_from_slice(&body);
+        std::fs::write(path, archive).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(count)
+    }
+
+    /// Merge an archive written by `export` into this history, skipping
+    /// messages it already has, and keep the file in time order, indexed
+    /// again from the first line. Archives from before local logs had their
+    /// own key are read with the DEK. Returns how many messages were added
+    /// and how many lines of the file failed the integrity check and were
+    /// moved to `<path>.damaged`.
+    pub fn import(&self, path: &str) -> Result<(usize, usize), String> {
+        let archive = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let (magic, cipher) = if archive.starts_with(ARCHIVE_MAGIC_V1) {
+            (ARCHIVE_MAGIC_V1, self.legacy.as_ref().unwrap_or(&self.cipher))
+        } else {
+            (ARCHIVE_MAGIC, &*self.cipher)
+        };
+        let rest = archive.strip_prefix(magic).filter(|r| r.len() >= 12).ok_or_else(|| format!("{} is not a history archive", path))?;
+        let (nonce, sealed) = rest.split_at(12);
+        let mut body = sealed.to_vec();
+        cipher
+            .decrypt_in_place(aes_gcm::aead::generic_array::GenericArray::from_slice(nonce), magic, &mut body)
+            .map_err(|_| format!("Failed to decrypt {}: it was exported with a different DEK or is damaged", path))?;
+
+        let mut head = self.head.lock().unwrap();
+        let text = std::fs::read_to_string(&self.path).map_err(|e| format!("Failed to read {}: {}", self.path, e))?;
+        let (mut entries, mut damaged) = (Vec::new(), Vec::new());
+        for line in text.lines() {
+            match self.decrypt(line) {
+                Some(payload) => entries.push(payload),
+                None => damaged.push(line),
+            }
+        }
+        let key = |p: &Payload| (p.time, p.username.to_string(), p.text.to_string());
+        let mut seen: std::collections::HashSet<_> = entries.iter().map(key).collect();
+        let mut added = 0;
+        for record in body.split(|b| *b == b'\n').filter(|r| !r.is_empty()) {
+            let payload: Payload = serde_json::from_slice(record).map_err(|e| format!("Invalid record in {}: {}", path, e))?;
+            if payload.time.is_some() && seen.insert(key(&payload)) {
+                entries.push(payload.into_owned());
+                added += 1;
+            }
+        }
+        if added == 0 {
+            return Ok((0, 0));
+        }
+        if !damaged.is_empty() {
+            self.set_aside(&damaged)?;
+        }
+        entries.sort_by_key(|p| p.time.unwrap_or(i64::MIN));
+        let lines: Vec<String> = entries.iter().zip(head.first..).map(|(p, index)| self.seal(p, p.time.unwrap_or_default(), index)).collect();
+        self.rewrite(&lines)?;
+        head.next = head.first + lines.len() as u64;
+        self.save_head(*head)?;
+        Ok((added, damaged.len()))
+    }
+
+    /// Time between retention runs.
+    pub fn prune_interval(&self) -> Duration {
+        Duration::from_secs(self.config.prune_interval_secs.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh history in its own file under the temp directory.
+    fn scratch(name: &str) -> History {
+        let path = std::env::temp_dir().join(format!("antimpeu-history-{}-{}", name, std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let _ = purge(&path);
+        History::open(path, Arc::new(Aes256Gcm::new_from_slice(&[7; 32]).unwrap()), HistoryConfig::default()).unwrap()
+    }
+
+    fn append(history: &History, n: usize) {
+        for i in 0..n {
+            history.append(&Payload::chat("alice", &format!("message {}", i), None)).unwrap();
+        }
+    }
+
+    fn lines(history: &History) -> Vec<String> {
+        std::fs::read_to_string(&history.path).unwrap().lines().map(str::to_string).collect()
+    }
+
+    fn reopen(history: History) -> History {
+        History::open(history.path.clone(), history.cipher.clone(), HistoryConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn an_intact_file_reads_back_whole() {
+        let history = scratch("intact");
+        append(&history, 5);
+        let history = reopen(history);
+        let page = history.page_before(None, 10).unwrap();
+        assert_eq!(page.messages.len(), 5);
+        assert_eq!(page.messages[4].text, "message 4");
+        assert_eq!((page.damaged, page.missing, page.before), (0, 0, None));
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn a_removed_line_is_missing() {
+        let history = scratch("removed");
+        append(&history, 5);
+        let mut body = lines(&history);
+        body.remove(2);
+        history.rewrite(&body).unwrap();
+        let page = history.page_before(None, 10).unwrap();
+        assert_eq!((page.messages.len(), page.damaged, page.missing), (4, 0, 1));
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn a_file_cut_short_is_missing_its_tail() {
+        let history = scratch("truncated");
+        append(&history, 5);
+        history.rewrite(&lines(&history)[..3]).unwrap();
+        let history = reopen(history);
+        let page = history.page_before(None, 10).unwrap();
+        assert_eq!((page.messages.len(), page.missing), (3, 2));
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn swapped_lines_fail_the_check() {
+        let history = scratch("swapped");
+        append(&history, 3);
+        let mut body = lines(&history);
+        body.swap(0, 1);
+        history.rewrite(&body).unwrap();
+        let page = history.page_before(None, 10).unwrap();
+        // the line out of place is skipped, and its place shows up empty
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!((page.damaged, page.missing), (1, 1));
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn pruning_keeps_the_chain_whole() {
+        let history = scratch("pruned");
+        append(&history, 5);
+        let config = HistoryConfig { max_messages: Some(2), ..HistoryConfig::default() };
+        let history = History::open(history.path.clone(), history.cipher.clone(), config).unwrap();
+        assert_eq!(history.prune().unwrap(), 3);
+        let page = history.page_before(None, 10).unwrap();
+        assert_eq!((page.messages.len(), page.missing), (2, 0));
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn pages_cross_block_boundaries() {
+        let history = scratch("paged");
+        let text = "x".repeat(1000);
+        for i in 0..150 {
+            history.append(&Payload::chat("alice", &format!("{} {}", i, text), None)).unwrap();
+        }
+        assert!(std::fs::metadata(&history.path).unwrap().len() > 2 * PAGE_BLOCK);
+        let (mut read, mut before) = (Vec::new(), None);
+        loop {
+            let page = history.page_before(before, 7).unwrap();
+            assert_eq!((page.damaged, page.missing), (0, 0));
+            read.splice(0..0, page.messages.iter().map(|m| m.text.split(' ').next().unwrap().parse::<usize>().unwrap()));
+            before = page.before;
+            if before.is_none() {
+                break;
+            }
+        }
+        assert_eq!(read, (0..150).collect::<Vec<_>>());
+        purge(&history.path).unwrap();
+    }
+
+    #[test]
+    fn a_deleted_head_is_reported() {
+        let history = scratch("headless");
+        append(&history, 2);
+        std::fs::remove_file(history.head_path()).unwrap();
+        let history = reopen(history);
+        assert_eq!(history.notices().len(), 1);
+        assert_eq!(history.page_before(None, 10).unwrap().messages.len(), 2);
+        purge(&history.path).unwrap();
+    }
+}
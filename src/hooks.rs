@@ -0,0 +1,102 @@
+//! Notification hooks (`[[client.hooks]]`): commands the client runs when
+//! a message matches, so any notifier can be wired up from the config.
+//!
+//! The command runs under `sh -c` with the message in the environment:
+//! `ANTIMPEU_SENDER`, `ANTIMPEU_TEXT` and `ANTIMPEU_HOOK`, the reason it
+//! fired (`mention` or `pattern`, or `message` for a hook without either).
+//! Hooks fire for live messages from others only, never for our own or for
+//! history replayed on join.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::process::{Command, Stdio};
+
+/// One `[[client.hooks]]` entry. Every condition given must hold.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HookConfig {
+    /// Shell command to run.
+    pub command: String,
+    /// Fire when our name is mentioned.
+    pub mention: bool,
+    /// Fire when the text matches this case-insensitive regular expression.
+    pub pattern: Option<String>,
+    /// Fire only for messages from this sender.
+    pub from: Option<String>,
+}
+
+struct Hook {
+    command: String,
+    mention: bool,
+    pattern: Option<Regex>,
+    from: Option<String>,
+}
+
+/// The configured hooks, ready to match messages.
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    /// Our name as a word, for `mention`.
+    name: Regex,
+}
+
+impl Hooks {
+    /// Compile the hooks for a client chatting as `name`.
+    pub fn new(config: &[HookConfig], name: &str) -> Result<Self, String> {
+        let mut hooks = Vec::new();
+        for hook in config {
+            if hook.command.trim().is_empty() {
+                return Err("Every [[client.hooks]] entry needs a command".to_string());
+            }
+            let pattern = match &hook.pattern {
+                Some(p) => Some(Regex::new(&format!("(?i){}", p)).map_err(|e| format!("Invalid hook pattern {:?}: {}", p, e))?),
+                None => None,
+            };
+            hooks.push(Hook { command: hook.command.clone(), mention: hook.mention, pattern, from: hook.from.clone() });
+        }
+        let name = Regex::new(&format!(r"(?i)(^|\W)@?{}(\W|$)", regex::escape(name))).map_err(|e| e.to_string())?;
+        Ok(Self { hooks, name })
+    }
+
+    /// Run every hook that matches a message from `sender`. Commands run in
+    /// the background; their output is discarded so it cannot garble the
+    /// TUI, and a failure to start one is returned for display.
+    pub fn fire(&self, sender: &str, text: &str) -> Vec<String> {
+        let mut errors = Vec::new();
+        for hook in &self.hooks {
+            if hook.from.as_deref().is_some_and(|from| from != sender) {
+                continue;
+            }
+            if hook.mention && !self.name.is_match(text) {
+                continue;
+            }
+            if hook.pattern.as_ref().is_some_and(|p| !p.is_match(text)) {
+                continue;
+            }
+            let reason = if hook.mention {
+                "mention"
+            } else if hook.pattern.is_some() {
+                "pattern"
+            } else {
+                "message"
+            };
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(&hook.command)
+                .env("ANTIMPEU_SENDER", sender)
+                .env("ANTIMPEU_TEXT", text)
+                .env("ANTIMPEU_HOOK", reason)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            match child {
+                // reap it so finished hooks do not linger as zombies
+                Ok(mut child) => {
+                    std::thread::spawn(move || child.wait());
+                }
+                Err(e) => errors.push(format!("Failed to run hook {}: {}", hook.command, e)),
+            }
+        }
+        errors
+    }
+}
@@ -0,0 +1,330 @@
+//! Long-term Ed25519 identities.
+//!
+//! The server keeps a signing key in `$HOME/key/server_identity.key`,
+//! created on first start, and signs every handshake with it. Clients pin
+//! the key per server in `$HOME/key/known_servers` the first time they
+//! connect (trust on first use, like SSH) and refuse a server whose key
+//! changed later.
+//!
+//! Clients without `dek.bin` authenticate with their personal key from
+//! `$HOME/key/identity.key` instead; the server admits the keys listed in
+//! `$HOME/key/authorized_clients` and wraps the group key to them.
+//!
+//! In signed rooms every participant also signs its chat messages with a
+//! personal key from `$HOME/key/identity.key`, and receivers pin each
+//! sender's key in `$HOME/key/known_users` the same way.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::RngCore;
+
+fn key_dir() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key", home)
+}
+
+/// Location of the server's identity key.
+pub fn server_key_path() -> String {
+    format!("{}/server_identity.key", key_dir())
+}
+
+/// Location of the client's pinned server keys.
+pub fn known_servers_path() -> String {
+    format!("{}/known_servers", key_dir())
+}
+
+/// Location of this user's message signing key.
+pub fn user_key_path() -> String {
+    format!("{}/identity.key", key_dir())
+}
+
+/// Location of the server's list of client keys allowed to receive the
+/// group key.
+pub fn authorized_clients_path() -> String {
+    format!("{}/authorized_clients", key_dir())
+}
+
+/// Location of the pinned message signing keys of other users.
+pub fn known_users_path() -> String {
+    format!("{}/known_users", key_dir())
+}
+
+/// Load the signing key at `path`, generating and saving a new one if the
+/// file does not exist yet.
+pub fn load_or_create(path: &str) -> Result<SigningKey, String> {
+    match std::fs::read(path) {
+        Ok(bytes) => {
+            let seed: [u8; 32] = bytes.as_slice().try_into().map_err(|_| format!("Identity key {} is malformed", path))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            aes_gcm::aead::OsRng.fill_bytes(&mut seed);
+            if let Some(dir) = std::path::Path::new(path).parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            write_private(path, &seed).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+    }
+}
+
+/// Write a file readable only by the owner.
+pub fn write_private(path: &str, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
+}
+
+/// Bytes the server signs in the handshake: the client's challenge and the
+/// client address as the server sees it, so a signature is only good for
+/// the connection it was made on.
+fn transcript(challenge: &[u8], peer: &str) -> Vec<u8> {
+    let mut msg = b"antimpeu server identity v1".to_vec();
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(peer.as_bytes());
+    msg
+}
+
+/// Sign the handshake transcript with the server identity.
+pub fn sign_handshake(key: &SigningKey, challenge: &[u8], peer: &str) -> [u8; 64] {
+    key.sign(&transcript(challenge, peer)).to_bytes()
+}
+
+/// Parse a hex public key and check its signature over the transcript.
+pub fn verify_handshake(public_hex: &str, signature_hex: &str, challenge: &[u8], peer: &str) -> Result<VerifyingKey, String> {
+    verify_server(public_hex, signature_hex, &transcript(challenge, peer))
+}
+
+/// Parse the server's hex identity key and check its signature over `msg`.
+fn verify_server(public_hex: &str, signature_hex: &str, msg: &[u8]) -> Result<VerifyingKey, String> {
+    let public: [u8; 32] = hex::decode(public_hex).ok().and_then(|b| b.try_into().ok()).ok_or("Server sent an invalid identity key")?;
+    let public = VerifyingKey::from_bytes(&public).map_err(|_| "Server sent an invalid identity key".to_string())?;
+    let signature: [u8; 64] = hex::decode(signature_hex).ok().and_then(|b| b.try_into().ok()).ok_or("Server sent an invalid identity signature")?;
+    public.verify(msg, &Signature::from_bytes(&signature)).map_err(|_| "Server identity signature does not verify".to_string())?;
+    Ok(public)
+}
+
+/// Bytes the server signs with the group key it wraps for a client: both
+/// challenges, the client address, the client's ephemeral X25519 key and
+/// the KEYWRAP body `wrapped` with the server's ephemeral key and the
+/// sealed DEK. The client checks the signature before unwrapping, so an
+/// on-path attacker cannot swap in a wrap of its own.
+fn key_wrap(challenge: &[u8], client_challenge: &[u8], peer: &str, wrap_public: &[u8; 32], wrapped: &str) -> Vec<u8> {
+    let mut msg = b"antimpeu key wrap v1".to_vec();
+    for part in [challenge, client_challenge, peer.as_bytes(), wrap_public, wrapped.as_bytes()] {
+        msg.extend_from_slice(&(part.len() as u32).to_be_bytes());
+        msg.extend_from_slice(part);
+    }
+    msg
+}
+
+/// Sign a wrapped group key with the server identity.
+pub fn sign_key_wrap(key: &SigningKey, challenge: &[u8], client_challenge: &[u8], peer: &str, wrap_public: &[u8; 32], wrapped: &str) -> [u8; 64] {
+    key.sign(&key_wrap(challenge, client_challenge, peer, wrap_public, wrapped)).to_bytes()
+}
+
+/// Parse a hex public key and check its signature over a wrapped group key.
+pub fn verify_key_wrap(public_hex: &str, signature_hex: &str, challenge: &[u8], client_challenge: &[u8], peer: &str, wrap_public: &[u8; 32], wrapped: &str) -> Result<VerifyingKey, String> {
+    verify_server(public_hex, signature_hex, &key_wrap(challenge, client_challenge, peer, wrap_public, wrapped))
+}
+
+/// Bytes a key-wrap client signs in the handshake: the server's challenge,
+/// the client address as the server sees it and the ephemeral X25519 key
+/// the group key is to be wrapped to.
+fn key_request(challenge: &[u8], peer: &str, wrap_public: &[u8; 32]) -> Vec<u8> {
+    let mut msg = b"antimpeu key request v1".to_vec();
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(peer.as_bytes());
+    msg.extend_from_slice(wrap_public);
+    msg
+}
+
+/// Sign a request for the group key with the personal key.
+pub fn sign_key_request(key: &SigningKey, challenge: &[u8], peer: &str, wrap_public: &[u8; 32]) -> [u8; 64] {
+    key.sign(&key_request(challenge, peer, wrap_public)).to_bytes()
+}
+
+/// Check a group key request against `authorized_clients` and return the
+/// name the key is listed under, and the key in hex.
+pub fn verify_key_request(path: &str, public_hex: &str, signature_hex: &str, challenge: &[u8], peer: &str, wrap_public: &[u8; 32]) -> Result<(String, String), String> {
+    let public: [u8; 32] = hex::decode(public_hex).ok().and_then(|b| b.try_into().ok()).ok_or("invalid client key")?;
+    let public = VerifyingKey::from_bytes(&public).map_err(|_| "invalid client key".to_string())?;
+    let signature: [u8; 64] = hex::decode(signature_hex).ok().and_then(|b| b.try_into().ok()).ok_or("invalid key request signature")?;
+    public
+        .verify(&key_request(challenge, peer, wrap_public), &Signature::from_bytes(&signature))
+        .map_err(|_| "key request signature does not verify".to_string())?;
+    let key_hex = hex::encode(public.as_bytes());
+    read_pins(path)?
+        .into_iter()
+        .find(|(_, key)| *key == key_hex)
+        .map(|(name, _)| (name, key_hex.clone()))
+        .ok_or_else(|| format!("key {} is not in authorized_clients", key_hex))
+}
+
+/// Bytes a client signs in its join frame: the server's challenge and the
+/// name it joins under, so the signature is only good for this connection.
+fn join_bytes(challenge: &[u8], name: &str) -> Vec<u8> {
+    let mut msg = b"antimpeu join v1".to_vec();
+    msg.extend_from_slice(challenge);
+    msg.extend_from_slice(name.as_bytes());
+    msg
+}
+
+/// Sign a join as `name` with the personal key, as
+/// `hex(public key || signature)`.
+pub fn sign_join(key: &SigningKey, challenge: &[u8], name: &str) -> String {
+    let mut out = key.verifying_key().as_bytes().to_vec();
+    out.extend_from_slice(&key.sign(&join_bytes(challenge, name)).to_bytes());
+    hex::encode(out)
+}
+
+/// Check a join signature and return the signer's key in hex.
+pub fn verify_join(signature_hex: &str, challenge: &[u8], name: &str) -> Option<String> {
+    let bytes = hex::decode(signature_hex).ok().filter(|b| b.len() == 32 + 64)?;
+    let (public, signature) = bytes.split_at(32);
+    let public = VerifyingKey::from_bytes(public.try_into().unwrap()).ok()?;
+    public.verify(&join_bytes(challenge, name), &Signature::from_bytes(signature.try_into().unwrap())).ok()?;
+    Some(hex::encode(public.as_bytes()))
+}
+
+/// Result of looking a name up in a pin file.
+pub enum Pin {
+    /// First time this name was seen; its key was pinned.
+    New,
+    /// The key matches the pinned one.
+    Match,
+    /// The key differs from the pinned one, given in hex.
+    Changed(String),
+}
+
+/// Read a pin file of `name <hex-key>` lines. A missing file is empty.
+fn read_pins(path: &str) -> Result<HashMap<String, String>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let mut pins = HashMap::new();
+    for line in text.lines() {
+        let mut parts = line.split_whitespace();
+        if let (Some(name), Some(key)) = (parts.next(), parts.next()) {
+            pins.entry(name.to_string()).or_insert_with(|| key.to_string());
+        }
+    }
+    Ok(pins)
+}
+
+fn append_pin(path: &str, name: &str, key_hex: &str) -> Result<(), String> {
+    use std::io::Write;
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    writeln!(file, "{} {}", name, key_hex).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+/// Compare `key` with the key pinned for `server` (as `host:port`) and pin
+/// it if the server is new.
+pub fn check_known_server(path: &str, server: &str, key: &VerifyingKey) -> Result<Pin, String> {
+    let key_hex = hex::encode(key.as_bytes());
+    match read_pins(path)?.remove(server) {
+        Some(pinned) if pinned == key_hex => Ok(Pin::Match),
+        Some(pinned) => Ok(Pin::Changed(pinned)),
+        None => {
+            append_pin(path, server, &key_hex)?;
+            Ok(Pin::New)
+        }
+    }
+}
+
+/// Pin `key_hex` for `server` ahead of connecting, as given in a join link.
+/// Unlike `check_known_server`, a different pinned key is an error.
+pub fn pin_server(path: &str, server: &str, key_hex: &str) -> Result<Pin, String> {
+    let key: [u8; 32] = hex::decode(key_hex).ok().and_then(|k| k.try_into().ok()).ok_or_else(|| format!("Invalid server key {:?} in the link", key_hex))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|_| format!("Invalid server key {:?} in the link", key_hex))?;
+    match check_known_server(path, server, &key)? {
+        Pin::Changed(pinned) => Err(format!("The link gives {} a different identity key than the one pinned in {} ({}); remove that line if the server's key really changed", server, path, pinned)),
+        pin => Ok(pin),
+    }
+}
+
+/// Key pinned for `name` in the pin file at `path`, in hex.
+pub fn pinned(path: &str, name: &str) -> Result<Option<String>, String> {
+    Ok(read_pins(path)?.remove(name))
+}
+
+/// Bytes a sender signs in a signed room.
+fn message_bytes(username: &str, text: &str) -> Vec<u8> {
+    let mut msg = b"antimpeu message v1".to_vec();
+    msg.extend_from_slice(&(username.len() as u32).to_be_bytes());
+    msg.extend_from_slice(username.as_bytes());
+    msg.extend_from_slice(text.as_bytes());
+    msg
+}
+
+/// Message signing for signed rooms, where the DEK is shared too widely to
+/// tell members apart by it.
+///
+/// A signature travels inside the encrypted payload as
+/// `hex(public key || signature)` over the sender's name and text, and the
+/// server relays it untouched. A message verifies when the signature is
+/// valid and the key is the one pinned for that sender name.
+pub struct SignedRoom {
+    key: SigningKey,
+    known_users: String,
+    /// Cache of `known_users`; new senders are added to both.
+    pins: Mutex<HashMap<String, String>>,
+}
+
+impl SignedRoom {
+    /// Load (or create) this user's signing key and the pinned keys of
+    /// everybody else.
+    pub fn load() -> Result<Self, String> {
+        let key = load_or_create(&user_key_path())?;
+        let known_users = known_users_path();
+        let pins = Mutex::new(read_pins(&known_users)?);
+        Ok(Self { key, known_users, pins })
+    }
+
+    /// Sign a message sent as `username`.
+    pub fn sign(&self, username: &str, text: &str) -> String {
+        let signature = self.key.sign(&message_bytes(username, text));
+        let mut out = self.key.verifying_key().as_bytes().to_vec();
+        out.extend_from_slice(&signature.to_bytes());
+        hex::encode(out)
+    }
+
+    /// Check a received message. Unsigned messages, bad signatures and keys
+    /// that differ from the pinned one all fail.
+    pub fn verify(&self, username: &str, text: &str, signature: Option<&str>) -> bool {
+        let Some(bytes) = signature.and_then(|s| hex::decode(s).ok()) else { return false };
+        if bytes.len() != 32 + 64 {
+            return false;
+        }
+        let (public, signature) = bytes.split_at(32);
+        let Ok(public) = VerifyingKey::from_bytes(public.try_into().unwrap()) else { return false };
+        let signature = Signature::from_bytes(signature.try_into().unwrap());
+        if public.verify(&message_bytes(username, text), &signature).is_err() {
+            return false;
+        }
+        let key_hex = hex::encode(public.as_bytes());
+        let mut pins = self.pins.lock().unwrap();
+        match pins.get(username) {
+            Some(pinned) => *pinned == key_hex,
+            None => {
+                let _ = append_pin(&self.known_users, username, &key_hex);
+                pins.insert(username.to_string(), key_hex);
+                true
+            }
+        }
+    }
+}
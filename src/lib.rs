@@ -0,0 +1,64 @@
+//! Antimpeu as a library: join a room from another Rust program, such as a
+//! bot or a GUI, with `ChatClient`, or host one with `ChatServer`. Depend on
+//! it with `default-features = false` to leave out the `tui` feature, which
+//! only the `antimpeu` binary needs, and with it crossterm and ratatui. The
+//! `async` feature adds `AsyncChatClient`, for tokio applications, and the
+//! `ffi` feature C bindings for `ChatClient` (see `ffi`).
+//!
+//! The modules are the protocol and crypto building blocks these share with
+//! the binary.
+//!
+//! For wasm32 the crate builds without sockets and threads: the frames,
+//! the crypto and `client_state::ClientState`, the client as a state
+//! machine a browser can drive over its own transport. Build it with
+//! `cargo build --lib --target wasm32-unknown-unknown --no-default-features`.
+
+pub mod accounts;
+pub mod auth;
+pub mod client_state;
+pub mod crypto;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
+pub mod filters;
+pub mod fingerprint;
+pub mod frame;
+pub mod handshake;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod health;
+pub mod history;
+pub mod identity;
+pub mod logfile;
+pub mod net;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod nicknames;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod qr;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod queue;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod resolve;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod roles;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tor;
+pub mod transfer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod types;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod webhook;
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+mod async_client;
+#[cfg(not(target_arch = "wasm32"))]
+mod chat_client;
+#[cfg(not(target_arch = "wasm32"))]
+mod chat_server;
+
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub use async_client::AsyncChatClient;
+#[cfg(not(target_arch = "wasm32"))]
+pub use chat_client::{ChatClient, ClientOptions};
+pub use client_state::Message;
+#[cfg(not(target_arch = "wasm32"))]
+pub use chat_server::{ChatServer, ChatServerBuilder, Event};
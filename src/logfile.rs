@@ -0,0 +1,143 @@
+//! Log file (`[log]`): where the `tracing` output goes instead of stderr,
+//! rotated by size and by day so a long-running server does not fill the
+//! disk.
+//!
+//! Rotation renames `file` to `file.1`, `file.1` to `file.2` and so on,
+//! dropping what would go past `keep`, and starts an empty `file`. It
+//! happens before a write that would take the file past `max_size_mb`, on
+//! the first write of a new day with `daily`, and from the server's
+//! maintenance task, which also rotates a file that sat idle over midnight.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+/// Settings under `[log]`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    /// File to write the log to; stderr, and only with `--trace`, when
+    /// unset.
+    pub file: Option<String>,
+    /// Rotate once the file would grow past this many MiB; 0 for no limit.
+    pub max_size_mb: u64,
+    /// Also rotate when the day changes.
+    pub daily: bool,
+    /// Rotated files kept next to the current one.
+    pub keep: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self { file: None, max_size_mb: 10, daily: true, keep: 5 }
+    }
+}
+
+/// The log file, set by `open`.
+static LOG: OnceLock<LogFile> = OnceLock::new();
+
+/// Handle to the log file; clones share it. Writes go straight to the
+/// file, rotating it first when it is due.
+#[derive(Clone)]
+pub struct LogFile {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    path: String,
+    config: LogConfig,
+    file: File,
+    /// Bytes in `file`.
+    size: u64,
+    /// Day the current file was started.
+    day: NaiveDate,
+}
+
+/// Open the log file of `config`, if it names one, and remember it for
+/// `maintain`.
+pub fn open(config: &LogConfig) -> Result<Option<LogFile>, String> {
+    let Some(path) = &config.file else { return Ok(None) };
+    let file = open_file(path).map_err(|e| format!("Failed to open the log file {}: {}", path, e))?;
+    let metadata = file.metadata().map_err(|e| format!("Failed to open the log file {}: {}", path, e))?;
+    // a file left from an earlier day is rotated on the first write
+    let day = metadata.modified().map(|t| chrono::DateTime::<chrono::Local>::from(t).date_naive()).unwrap_or_else(|_| today());
+    let log = LogFile { inner: Arc::new(Mutex::new(Inner { path: path.clone(), config: config.clone(), file, size: metadata.len(), day })) };
+    let _ = LOG.set(log.clone());
+    Ok(Some(log))
+}
+
+/// Rotate the log file if it is due, for the server's maintenance task.
+pub fn maintain() -> Result<(), String> {
+    let Some(log) = LOG.get() else { return Ok(()) };
+    let mut inner = log.inner.lock().unwrap();
+    inner.rotate_if_due(0).map_err(|e| format!("Failed to rotate the log file {}: {}", inner.path, e))
+}
+
+fn open_file(path: &str) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+fn today() -> NaiveDate {
+    chrono::Local::now().date_naive()
+}
+
+impl Inner {
+    /// Rotate if the file is from another day or `incoming` more bytes
+    /// would take it past the size limit. An empty file is kept, so a
+    /// write larger than the limit still lands.
+    fn rotate_if_due(&mut self, incoming: u64) -> io::Result<()> {
+        if self.size == 0 {
+            self.day = today();
+            return Ok(());
+        }
+        let max = self.config.max_size_mb * 1024 * 1024;
+        let full = max > 0 && self.size + incoming > max;
+        let stale = self.config.daily && self.day != today();
+        if full || stale {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let numbered = |n: usize| format!("{}.{}", self.path, n);
+        if self.config.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                match std::fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = open_file(&self.path)?;
+        self.size = 0;
+        self.day = today();
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rotate_if_due(buf.len() as u64)?;
+        inner.file.write_all(buf)?;
+        inner.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().file.flush()
+    }
+}
@@ -2,96 +2,733 @@
 //!
 //! This binary module is intentionally small: it parses CLI arguments,
 //! loads the decrypted data encryption key (DEK) and delegates to the
-//! `server` or `client` modules. Helper modules contain encryption,
-//! network framing, and the terminal UI.
+//! `server` or `client` modules. Helper modules contain the terminal UI
+//! and everything else the library (`lib.rs`) leaves out; encryption,
+//! network framing and the client handshake live there.
 
 mod tui;
-mod crypto;
-mod auth;
-mod net;
 mod utils;
-mod server;
 mod client;
-mod types;
+mod config;
+mod portmap;
+mod peer;
+mod profiles;
+mod session;
+mod hooks;
+mod setup;
+mod backup;
+mod plain;
+mod drafts;
+mod editor;
 
-use clap::{Parser, Subcommand};
-use aes_gcm::Aes256Gcm;
-use aes_gcm::aead::KeyInit;
+// the protocol and crypto building blocks come from the library, see lib.rs
+use antimpeu::{accounts, auth, crypto, fingerprint, frame, handshake, history, identity, logfile, net, qr, resolve, server, tor, transfer, types};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::engine::{ArgValueCandidates, CompletionCandidate};
 use std::sync::{Arc, Mutex, mpsc};
-use types::{SharedMessages, SharedClients};
-use std::collections::HashMap;
+use types::{MessageBuffer, SharedMessages, SharedStatus};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print the chat as plain lines and read input at a prompt, without
+    /// the full-screen TUI, for screen readers and dumb terminals
+    #[arg(long, global = true)]
+    plain: bool,
+    /// Inject network faults for testing, e.g.
+    /// `drop=0.1,reorder=0.05,delay=200,seed=7`; see `net::enable_chaos`
+    #[arg(long, global = true, hide = true, value_name = "SPEC")]
+    chaos: Option<String>,
+    /// Send frames at once instead of coalescing small ones (TCP_NODELAY);
+    /// overrides `[socket] nodelay`
+    #[arg(long, global = true, value_name = "BOOL")]
+    tcp_nodelay: Option<bool>,
+    /// Idle seconds before TCP keepalive probes, 0 for none; overrides
+    /// `[socket] keepalive_secs`
+    #[arg(long, global = true, value_name = "SECS")]
+    tcp_keepalive: Option<u64>,
+    /// Seconds between TCP keepalive probes; overrides
+    /// `[socket] keepalive_interval_secs`
+    #[arg(long, global = true, value_name = "SECS")]
+    tcp_keepalive_interval: Option<u64>,
+    /// Socket send buffer size in bytes; overrides `[socket] send_buffer`
+    #[arg(long, global = true, value_name = "BYTES")]
+    send_buffer: Option<usize>,
+    /// Socket receive buffer size in bytes; overrides `[socket] recv_buffer`
+    #[arg(long, global = true, value_name = "BYTES")]
+    recv_buffer: Option<usize>,
+    /// Write tracing spans and events to stderr, or to `[log] file`, as
+    /// text (`fmt`) or JSON lines (`json`); `RUST_LOG` selects them,
+    /// `antimpeu=debug` by default
+    #[arg(long, global = true, value_name = "FORMAT")]
+    trace: Option<TraceFormat>,
+}
+
+/// Output of `--trace`.
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum TraceFormat {
+    Fmt,
+    Json,
+}
+
+/// Send the spans and events of `tracing` to `file`, or else to stderr.
+/// Spans are reported when they close, with how long they were open.
+fn init_tracing(format: TraceFormat, file: Option<logfile::LogFile>) {
+    use tracing_subscriber::{fmt::{format::FmtSpan, writer::BoxMakeWriter}, EnvFilter};
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("antimpeu=debug"));
+    let ansi = file.is_none() && std::io::IsTerminal::is_terminal(&std::io::stderr());
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(move || file.clone()),
+        None => BoxMakeWriter::new(std::io::stderr),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_writer(writer).with_ansi(ansi).with_span_events(FmtSpan::CLOSE);
+    match format {
+        TraceFormat::Fmt => subscriber.init(),
+        TraceFormat::Json => subscriber.json().init(),
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start the group chat server and wait for incoming connections.
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Server {
     /// Port to listen on
-    #[arg(value_parser)]
-    port: u16,
+    #[arg(value_parser, required = true)]
+    port: Option<u16>,
+    /// Ask the router to forward the port (NAT-PMP or UPnP) and print the public address
+    #[arg(long)]
+    upnp: bool,
+    /// Publish the server as a Tor onion service and print its .onion address
+    #[arg(long)]
+    onion: bool,
+    /// Open the TUI on a QR code others can scan to join, for HOST or else
+    /// the onion, mapped or LAN address
+    #[arg(long, value_name = "HOST", num_args = 0..=1, default_missing_value = "")]
+    show_qr: Option<String>,
+    #[command(subcommand)]
+    command: Option<ServerCommands>,
     },
     /// Connect to a chat server.
     Client {
-    /// Server IP or hostname
+    /// Server IP, hostname, host:port or antimpeu://host[:port]; with
+    /// `remember_session`, defaults to the last server
     #[arg(value_parser)]
-    ip: String,
-    /// Server port
+    ip: Option<String>,
+    /// Server port; looked up in DNS (`_antimpeu._tcp` SRV) when omitted
+    #[arg(value_parser)]
+    port: Option<u16>,
+    /// Join read-only: receive messages without being able to send
+    #[arg(long)]
+    observe: bool,
+    /// Connect with a saved profile (see `antimpeu profile`); arguments
+    /// given on the command line take precedence
+    #[arg(long, add = ArgValueCandidates::new(complete_profiles))]
+    profile: Option<String>,
+    },
+    /// Post a message and exit, for scripts. The KEK and the account
+    /// password are read from ANTIMPEU_KEK and ANTIMPEU_PASSWORD when set.
+    /// Exit status: 0 delivered, 2 local error, 3 connection or handshake
+    /// failed, 4 login failed, 5 not delivered.
+    Send {
+    /// Server as host:port, host (DNS SRV lookup) or antimpeu:// URI
+    #[arg(long)]
+    to: String,
+    /// Message to send; read from stdin when omitted
+    message: Option<String>,
+    /// Send each line of stdin as its own message over one connection,
+    /// until stdin closes
+    #[arg(long, conflicts_with = "message")]
+    stdin: bool,
+    /// Report the outcome as a JSON event on stdout
+    #[arg(long)]
+    json: bool,
+    },
+    /// Print the room's messages to stdout, one per line, for piping into
+    /// other tools. Reads ANTIMPEU_KEK and ANTIMPEU_PASSWORD like `send`.
+    Tail {
+    /// Server as host:port, host (DNS SRV lookup) or antimpeu:// URI
+    #[arg(value_parser)]
+    server: String,
+    /// Print every event, notices and errors included, as one JSON object
+    /// per line
+    #[arg(long)]
+    json: bool,
+    },
+    /// Chat without a server: link directly to other peers on the LAN.
+    Peer {
+    /// Port to listen on for other peers
     #[arg(value_parser)]
     port: u16,
+    /// Peers to link to, as host:port; the rest of the group is found through them
+    #[arg(value_parser)]
+    peers: Vec<String>,
+    },
+    /// Create dek.bin step by step: generate a key for a new room, or
+    /// import the sharing blob of an existing one.
+    Setup {
+    /// Key slot: write dek-<slot>.bin instead of dek.bin
+    #[arg(long)]
+    slot: Option<String>,
+    },
+    /// Back up or restore the DEK as passphrase-protected text, e.g. to
+    /// keep a copy in a password manager.
+    Key {
+    #[command(subcommand)]
+    command: KeyCommands,
     },
     /// Generate dek.bin from dek.key (passphrase)
-    Enc {},
+    Enc {
+    /// Key slot: read dek-<slot>.key and write dek-<slot>.bin
+    #[arg(long, add = ArgValueCandidates::new(complete_key_slots))]
+    slot: Option<String>,
+    },
+    /// Print this user's public key, as a line for a server's authorized_clients
+    Id {},
+    /// Print a shell completion script; e.g. `source <(antimpeu completions bash)`.
+    Completions {
+    /// Shell to complete in
+    #[arg(value_parser = clap_complete::env::Shells::builtins().names().collect::<Vec<_>>())]
+    shell: String,
+    },
+    /// Forget the session remembered with `[client] remember_session`.
+    Logout {},
+    /// Manage the saved connection profiles in the config file.
+    Profile {
+    #[command(subcommand)]
+    command: ProfileCommands,
+    },
+    /// Move the local chat log (`[client] keep_log`) between installs.
+    History {
+    #[command(subcommand)]
+    command: HistoryCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommands {
+    /// Print the DEK encrypted with a backup passphrase, base64-armored.
+    Backup {
+    /// Key slot: back up dek-<slot>.bin instead of dek.bin
+    #[arg(long, add = ArgValueCandidates::new(complete_key_slots))]
+    slot: Option<String>,
+    /// Write the backup to this file instead of stdout
+    #[arg(long)]
+    out: Option<String>,
+    },
+    /// Write dek.bin from a backup, under a new KEK.
+    Restore {
+    /// File holding the backup; read from stdin when omitted
+    path: Option<String>,
+    /// Key slot: write dek-<slot>.bin instead of dek.bin
+    #[arg(long)]
+    slot: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum HistoryCommands {
+    /// Write the local log to an archive encrypted with the DEK.
+    Export {
+    /// Archive to write
+    path: String,
+    },
+    /// Merge an archive into the local log, skipping messages it already has.
+    Import {
+    /// Archive to read
+    path: String,
+    },
+    /// Overwrite the local log with random bytes and delete it.
+    Purge {
+    /// Do not ask for confirmation
+    #[arg(long)]
+    yes: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileCommands {
+    /// Save a profile, replacing one of the same name.
+    Add {
+    /// Profile name
+    name: String,
+    /// Server host, host:port or antimpeu:// URI
+    host: String,
+    /// Server port
+    port: Option<u16>,
+    /// Key slot to use instead of dek.bin
+    #[arg(long, add = ArgValueCandidates::new(complete_key_slots))]
+    key: Option<String>,
+    /// Name to chat under
+    #[arg(long)]
+    nickname: Option<String>,
+    /// Colour scheme
+    #[arg(long)]
+    theme: Option<tui::Theme>,
+    },
+    /// List the saved profiles.
+    List {},
+    /// Delete a profile.
+    Remove {
+    /// Profile name
+    #[arg(add = ArgValueCandidates::new(complete_profiles))]
+    name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServerCommands {
+    /// Manage the accounts clients log in with when `require_login` is set.
+    User {
+    #[command(subcommand)]
+    command: UserCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum UserCommands {
+    /// Create an account, or set a new password for an existing one.
+    Add {
+    /// Account name
+    name: String,
+    },
+    /// Delete an account.
+    Del {
+    /// Account name
+    name: String,
+    },
 }
 
 fn main() {
+    // Answers completion requests from the scripts of `antimpeu completions`
+    clap_complete::CompleteEnv::with_factory(Cli::command).complete();
     let cli = Cli::parse();
+    if let Some(spec) = &cli.chaos {
+        if let Err(e) = net::enable_chaos(spec) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    let mut config = match config::load() {
+        Ok(c) => c,
+        Err(e) => { eprintln!("{}", e); return; }
+    };
+    let socket = &mut config.socket;
+    socket.nodelay = cli.tcp_nodelay.unwrap_or(socket.nodelay);
+    socket.keepalive_secs = cli.tcp_keepalive.unwrap_or(socket.keepalive_secs);
+    socket.keepalive_interval_secs = cli.tcp_keepalive_interval.unwrap_or(socket.keepalive_interval_secs);
+    socket.send_buffer = cli.send_buffer.unwrap_or(socket.send_buffer);
+    socket.recv_buffer = cli.recv_buffer.unwrap_or(socket.recv_buffer);
+    net::set_socket_config(config.socket.clone());
+    config.server.socket = config.socket.clone();
+    // a log file is for keeping, so it is written without --trace too
+    match logfile::open(&config.log) {
+        Ok(Some(file)) => init_tracing(cli.trace.unwrap_or(TraceFormat::Fmt), Some(file)),
+        Ok(None) => {
+            if let Some(format) = cli.trace {
+                init_tracing(format, None);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    // while this is the only thread
+    config.tui.timezone.apply();
+    config.tui.plain |= cli.plain || std::env::var("TERM").is_ok_and(|term| term == "dumb");
     match cli.command {
-        Commands::Server { port } => {
+        Commands::Server { command: Some(ServerCommands::User { command }), .. } => { cmd_user(command); }
+        Commands::Server { port, upnp, onion, show_qr, command: None } => {
+            let port = port.expect("clap requires the port without a subcommand");
             // load dek and prepare shared state
             let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
             let dek_path = format!("{}/key/dek.bin", home);
+            if let Err(e) = setup::ensure_dek(&dek_path) {
+                eprintln!("{}", e);
+                return;
+            }
             let dek_arr = match auth::load_dek_from_encrypted(&dek_path) {
                 Ok(a) => a,
                 Err(e) => { eprintln!("{}", e); return; }
             };
-            let cipher = Arc::new(Aes256Gcm::new_from_slice(&dek_arr).expect("Invalid DEK"));
-            let messages: SharedMessages<tui::Message> = Arc::new(Mutex::new(Vec::new()));
+            let handshake_key = auth::derive_handshake_key(&dek_arr);
+            let identity = match identity::load_or_create(&identity::server_key_path()) {
+                Ok(k) => k,
+                Err(e) => { eprintln!("{}", e); return; }
+            };
+            let key_hex = hex::encode(identity.verifying_key().as_bytes());
+            println!("Server identity key: {}", key_hex);
+            let room = if config.room.signed {
+                match identity::SignedRoom::load() {
+                    Ok(r) => Some(r),
+                    Err(e) => { eprintln!("{}", e); return; }
+                }
+            } else {
+                None
+            };
+            let messages: SharedMessages<tui::Message> = Arc::new(Mutex::new(MessageBuffer::new(config.tui.history_limit)));
             let (tx, rx) = mpsc::channel::<String>();
-            let clients: SharedClients = Arc::new(Mutex::new(HashMap::new()));
+            let status: SharedStatus = Arc::default();
             // spawn server components
-            server::run_server_with_tui(port, cipher.clone(), messages.clone(), rx, clients.clone());
+            if let Err(e) = server::run_server_with_tui(port, server::ServerKeys { dek: dek_arr, handshake: handshake_key, identity, room }, messages.clone(), status.clone(), rx, &config.server, None) {
+                eprintln!("{}", e);
+                return;
+            }
+            // held until the server exits, which removes it from the router
+            let _mapping = if upnp {
+                let (mapping, text) = match portmap::map_port(port) {
+                    Ok(m) => {
+                        let text = format!("Reachable at {} (port mapped with {})", m.external, m.method);
+                        (Some(m), text)
+                    }
+                    Err(e) => (None, format!("Port mapping failed: {}", e)),
+                };
+                println!("{}", text);
+                messages.lock().unwrap().push_event(tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                mapping
+            } else {
+                None
+            };
+            // held until the server exits, which closes the onion service
+            let _onion = if onion {
+                let (onion, text) = match tor::publish_onion(&config.server.tor_control, port, config.server.tor_control_password.as_deref()) {
+                    Ok(o) => {
+                        let text = format!("Onion service: antimpeu client {} {}", o.address, port);
+                        (Some(o), text)
+                    }
+                    Err(e) => (None, format!("Publishing the onion service failed: {}", e)),
+                };
+                println!("{}", text);
+                messages.lock().unwrap().push_event(tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                onion
+            } else {
+                None
+            };
+            {
+                let mut status = status.lock().unwrap();
+                status.join_address = show_qr.clone().filter(|host| !host.is_empty()).map(|host| (host, port))
+                    .or_else(|| _onion.as_ref().map(|o| (o.address.clone(), port)))
+                    .or_else(|| _mapping.as_ref().map(|m| (m.external.ip().to_string(), m.external.port())));
+            }
+            if show_qr.is_some() {
+                match server::join_qr(&status, "", port, &key_hex) {
+                    Ok(overlay) => status.lock().unwrap().overlay = Some(overlay),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
             // start TUI in main thread
             let send_fn = move |m: String| { let _ = tx.send(m); };
             let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
-            let _ = tui::run_tui_with_sender(send_fn, messages.clone(), shutdown.clone());
+            let _ = tui::run_tui_with_sender(send_fn, messages.clone(), status, shutdown.clone(), &config.tui);
             println!("Antimpeu closed, shutting down server.");
         }
-        Commands::Client { ip, port } => {
-            let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
-            let dek_path = format!("{}/key/dek.bin", home);
-            let dek_arr = match auth::load_dek_from_encrypted(&dek_path) {
-                Ok(a) => a,
+        Commands::Client { ip, port, observe, profile } => {
+            let profile = match profile.map(|name| config.use_profile(&name)).transpose() {
+                Ok(p) => p.unwrap_or_default(),
                 Err(e) => { eprintln!("{}", e); return; }
             };
-            let cipher = Aes256Gcm::new_from_slice(&dek_arr).expect("Invalid DEK");
-            client::run_client_with_tui(ip, port, cipher);
+            let session = if config.client.remember_session {
+                session::load().unwrap_or_else(|e| { eprintln!("{}", e); None })
+            } else {
+                None
+            };
+            let Some(ip) = ip.or(profile.host).or_else(|| session.as_ref().map(|s| s.server.clone())) else {
+                eprintln!("No server given; name one, use --profile, or set remember_session to reconnect to the last one");
+                return;
+            };
+            let (ip, port) = match resolve::resolve(&ip, port.or(profile.port)) {
+                Ok(r) => r,
+                Err(e) => { eprintln!("{}", e); return; }
+            };
+            let dek_path = match auth::dek_path(profile.key.as_deref(), "bin") {
+                Ok(p) => p,
+                Err(e) => { eprintln!("{}", e); return; }
+            };
+            let session = session.filter(|s| s.server == format!("{}:{}", ip, port));
+            // Without dek.bin, the server wraps the group key to our personal key
+            let dek = if let Some(session) = &session {
+                println!("Using the remembered session for {}", session.server);
+                Some(session.dek)
+            } else if std::path::Path::new(&dek_path).exists() {
+                match auth::load_dek_from_encrypted(&dek_path) {
+                    Ok(a) => Some(a),
+                    Err(e) => { eprintln!("{}", e); return; }
+                }
+            } else {
+                println!("No {}; requesting the group key from the server", dek_path);
+                None
+            };
+            client::run_client_with_tui(ip, port, dek, observe, session.and_then(|s| s.password), &config);
         }
-    Commands::Enc {} => { cmd_enc(); }
+        Commands::Send { to, message, stdin: false, json } => { cmd_send(&to, message, json, &config); }
+        Commands::Send { to, stdin: true, json, .. } => { cmd_send_lines(&to, json, &config); }
+        Commands::Tail { server, json } => { cmd_tail(&server, json, &config); }
+        Commands::Peer { port, peers } => {
+            let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+            let dek_path = format!("{}/key/dek.bin", home);
+            match setup::ensure_dek(&dek_path).and_then(|()| auth::load_dek_from_encrypted(&dek_path)) {
+                Ok(dek) => peer::run_peer_with_tui(port, peers, dek, &config),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    Commands::Setup { slot } => {
+        if let Err(e) = auth::dek_path(slot.as_deref(), "bin").and_then(|path| setup::run(&path)) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    Commands::Key { command } => { cmd_key(command); }
+    Commands::Enc { slot } => { cmd_enc(slot.as_deref()); }
+    Commands::Id {} => { cmd_id(); }
+    Commands::Logout {} => { cmd_logout(); }
+    Commands::Completions { shell } => { cmd_completions(&shell); }
+    Commands::Profile { command } => { cmd_profile(command, &config); }
+    Commands::History { command } => { cmd_history(command); }
     }
 }
 
-fn cmd_enc() {
-    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
-    let key_in_path = format!("{}/key/dek.key", home);
-    let key_out_path = format!("{}/key/dek.bin", home);
+fn cmd_enc(slot: Option<&str>) {
+    let paths = auth::dek_path(slot, "key").and_then(|key| auth::dek_path(slot, "bin").map(|bin| (key, bin)));
+    let (key_in_path, key_out_path) = match paths {
+        Ok(p) => p,
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    };
     match utils::encrypt_and_write_dek(&key_in_path, &key_out_path) {
         Ok(()) => println!("Wrote encrypted DEK to {}", key_out_path),
         Err(e) => { eprintln!("{}", e); std::process::exit(2); }
     }
-}
\ No newline at end of file
+}
+fn cmd_key(command: KeyCommands) {
+    let result = match command {
+        KeyCommands::Backup { slot, out } => {
+            let armored = auth::dek_path(slot.as_deref(), "bin").and_then(|path| backup::backup(&path));
+            match (armored, out) {
+                (Ok(armored), Some(out)) => identity::write_private(&out, format!("{}\n", armored).as_bytes())
+                    .map(|()| format!("Wrote the key backup to {}", out))
+                    .map_err(|e| format!("Failed to write {}: {}", out, e)),
+                (armored, None) => armored,
+                (Err(e), _) => Err(e),
+            }
+        }
+        KeyCommands::Restore { path, slot } => {
+            let armored = match &path {
+                Some(path) => std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e)),
+                None => {
+                    println!("Paste the key backup, then press Ctrl-D:");
+                    let mut text = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut text).map(|_| text).map_err(|e| format!("Failed to read the backup from stdin: {}", e))
+                }
+            };
+            auth::dek_path(slot.as_deref(), "bin")
+                .and_then(|dek_path| backup::restore(&dek_path, &armored?).map(|()| format!("Wrote encrypted DEK to {}", dek_path)))
+        }
+    };
+    match result {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+fn cmd_id() {
+    match identity::load_or_create(&identity::user_key_path()) {
+        Ok(key) => println!("{} {}", whoami::username(), hex::encode(key.verifying_key().as_bytes())),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+fn cmd_user(command: UserCommands) {
+    let path = accounts::accounts_path();
+    let result = match command {
+        UserCommands::Add { name } => {
+            use std::io::Write;
+            print!("Password for {}: ", name);
+            std::io::stdout().flush().ok();
+            match rpassword::read_password() {
+                Ok(password) => accounts::add(&path, &name, &password).map(|()| format!("Saved account {} in {}", name, path)),
+                Err(_) => Err("Failed to read password".to_string()),
+            }
+        }
+        UserCommands::Del { name } => accounts::remove(&path, &name).map(|()| format!("Deleted account {}", name)),
+    };
+    match result {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+fn cmd_send(to: &str, message: Option<String>, json: bool, config: &config::Config) {
+    let text = match message {
+        Some(text) => Ok(text),
+        None => {
+            let mut text = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)
+                .map(|_| text.trim_end().to_string())
+                .map_err(|e| format!("Failed to read the message from stdin: {}", e))
+        }
+    };
+    let result = text
+        .and_then(|text| if text.trim().is_empty() { Err("Nothing to send".to_string()) } else { Ok(text) })
+        .map_err(|e| (2, e))
+        .and_then(|text| connect_sender(to, config)?.send(&text));
+    match result {
+        Ok(id) => sent(&id, json),
+        Err((code, e)) => headless_exit(code, &e, json),
+    }
+}
+
+/// `antimpeu send --stdin`: relay stdin line by line. A rejected line is
+/// reported and the rest still sent; the exit status then says some were
+/// not delivered.
+fn cmd_send_lines(to: &str, json: bool, config: &config::Config) {
+    let mut sender = connect_sender(to, config).unwrap_or_else(|(code, e)| headless_exit(code, &e, json));
+    let mut undelivered = false;
+    for line in std::io::stdin().lines() {
+        let line = line.unwrap_or_else(|e| headless_exit(2, &format!("Failed to read stdin: {}", e), json));
+        if line.trim().is_empty() {
+            continue;
+        }
+        match sender.send(&line) {
+            Ok(id) => sent(&id, json),
+            Err((client::EXIT_NOT_DELIVERED, e)) => {
+                undelivered = true;
+                report_error(client::EXIT_NOT_DELIVERED, &e, json);
+            }
+            Err((code, e)) => headless_exit(code, &e, json),
+        }
+    }
+    if undelivered {
+        std::process::exit(client::EXIT_NOT_DELIVERED);
+    }
+}
+
+fn connect_sender(to: &str, config: &config::Config) -> Result<client::Sender, (i32, String)> {
+    let (ip, port) = resolve::resolve(to, None).map_err(|e| (2, e))?;
+    let dek = headless_dek().map_err(|e| (2, e))?;
+    client::Sender::connect(&ip, port, dek, std::env::var("ANTIMPEU_PASSWORD").ok(), config)
+}
+
+fn sent(id: &str, json: bool) {
+    if json {
+        client::emit(&mut std::io::stdout(), &client::Event::Sent { time: client::event_time(None), id });
+    }
+}
+
+fn cmd_tail(server: &str, json: bool, config: &config::Config) {
+    let result = resolve::resolve(server, None)
+        .and_then(|(ip, port)| Ok((ip, port, headless_dek()?)))
+        .map_err(|e| (2, e))
+        .and_then(|(ip, port, dek)| client::tail(&ip, port, dek, std::env::var("ANTIMPEU_PASSWORD").ok(), json, config));
+    if let Err((code, e)) = result {
+        headless_exit(code, &e, json);
+    }
+}
+
+/// Report an error of `send` or `tail`, as an event with `json`.
+fn report_error(code: i32, e: &str, json: bool) {
+    if json {
+        client::emit(&mut std::io::stdout(), &client::Event::Error { time: client::event_time(None), code, text: e });
+    } else {
+        eprintln!("{}", e);
+    }
+}
+
+/// Report a failed `send` or `tail` and exit with `code`.
+fn headless_exit(code: i32, e: &str, json: bool) -> ! {
+    report_error(code, e, json);
+    std::process::exit(code);
+}
+
+/// The DEK for `send` and `tail`, unlocked with ANTIMPEU_KEK when set.
+/// Without dek.bin, the server wraps the group key to our personal key.
+fn headless_dek() -> Result<Option<[u8; 32]>, String> {
+    let dek_path = auth::dek_path(None, "bin")?;
+    if !std::path::Path::new(&dek_path).exists() {
+        return Ok(None);
+    }
+    auth::load_dek_with_kek(&dek_path, std::env::var("ANTIMPEU_KEK").ok()).map(Some)
+}
+
+fn cmd_completions(shell: &str) {
+    let shells = clap_complete::env::Shells::builtins();
+    let completer = shells.completer(shell).expect("clap only accepts known shells");
+    // The script calls back into `COMPLETE=<shell> antimpeu`, so profile
+    // and key slot names are read when completing, not when generating
+    if let Err(e) = completer.write_registration("COMPLETE", "antimpeu", "antimpeu", "antimpeu", &mut std::io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+}
+
+fn complete_profiles() -> Vec<CompletionCandidate> {
+    config::load().map(|c| c.profiles.into_keys().map(CompletionCandidate::new).collect()).unwrap_or_default()
+}
+
+fn complete_key_slots() -> Vec<CompletionCandidate> {
+    auth::key_slots().into_iter().map(CompletionCandidate::new).collect()
+}
+
+fn cmd_logout() {
+    match session::forget() {
+        Ok(true) => println!("Forgot the remembered session"),
+        Ok(false) => println!("No session was remembered"),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+fn cmd_profile(command: ProfileCommands, config: &config::Config) {
+    let result = match command {
+        ProfileCommands::Add { name, host, port, key, nickname, theme } => {
+            let profile = config::Profile { host: Some(host), port, key, nickname, theme };
+            profiles::add(&name, &profile).map(|()| format!("Saved profile {} in {}", name, config::config_path()))
+        }
+        ProfileCommands::List {} => {
+            let lines = profiles::list(config);
+            Ok(if lines.is_empty() { "No profiles saved".to_string() } else { lines.join("\n") })
+        }
+        ProfileCommands::Remove { name } => profiles::remove(&name).map(|()| format!("Deleted profile {}", name)),
+    };
+    match result {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+fn cmd_history(command: HistoryCommands) {
+    let log_path = history::local_history_path();
+    let result = match command {
+        HistoryCommands::Export { path } => open_local_log(&log_path).and_then(|log| log.export(&path)).map(|n| format!("Exported {} messages from {} to {}", n, log_path, path)),
+        HistoryCommands::Import { path } => open_local_log(&log_path).and_then(|log| log.import(&path)).map(|(n, damaged)| match damaged {
+            0 => format!("Imported {} new messages into {}", n, log_path),
+            _ => format!("Imported {} new messages into {}; {} entries failed the integrity check and were moved to {}.damaged", n, log_path, damaged, log_path),
+        }),
+        // needs no key, so the log can go even when the KEK is lost
+        HistoryCommands::Purge { yes } => {
+            if yes || matches!(setup::ask_yes(&format!("Delete {} for good?", log_path), false), Ok(true)) {
+                history::purge(&log_path).map(|wiped| match wiped {
+                    Some(bytes) => format!("Wiped and deleted {} ({})", log_path, transfer::format_size(bytes)),
+                    None => format!("No local log at {}", log_path),
+                })
+            } else {
+                Ok(format!("Kept {}", log_path))
+            }
+        }
+    };
+    match result {
+        Ok(msg) => println!("{}", msg),
+        Err(e) => { eprintln!("{}", e); std::process::exit(2); }
+    }
+}
+
+/// The local log at `path`, unsealed with the key derived from the DEK.
+fn open_local_log(path: &str) -> Result<history::History, String> {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    let dek = auth::load_dek_from_encrypted(&format!("{}/key/dek.bin", home))?;
+    let log = history::History::open_local(path.to_string(), &dek, history::HistoryConfig::default())?;
+    for notice in log.notices() {
+        eprintln!("{}", notice);
+    }
+    Ok(log)
+}
@@ -1,22 +1,300 @@
+#[cfg(not(target_arch = "wasm32"))]
 use std::io::{Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
 use std::net::TcpStream;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use bytes::BufMut;
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+#[cfg(not(target_arch = "wasm32"))]
+use socket2::{SockRef, TcpKeepalive};
+use crate::frame::{frame_len, ProtocolError};
+
+/// Plaintext greeting that opens the handshake. The client may append
+/// space-separated capabilities; the server echoes the ones it accepts after
+/// the challenge.
+pub const HELLO: &str = "HELLO-ANTIMPEU";
+/// Capability: pad encrypted payloads to bucket sizes in both directions.
+pub const CAP_PAD: &str = "pad";
+/// Capability, server to client only: log in to an account after the
+/// handshake.
+pub const CAP_LOGIN: &str = "login";
+/// Capability: join read-only. The client receives broadcasts, but the
+/// server does not relay its chat messages.
+pub const CAP_OBSERVE: &str = "observe";
+/// Capability: the server acknowledges every chat message of the client
+/// with an `ack` frame, see `crypto::Kind::Ack`.
+pub const CAP_ACK: &str = "ack";
+/// Capability: write the frame envelope in base64 rather than hex, which
+/// makes frames about a third smaller, see `frame::EncryptedMessage`.
+pub const CAP_BASE64: &str = "base64";
+/// Capability: both sides send a `ping` every `HEARTBEAT_INTERVAL` and
+/// answer the other's with a `pong`, which gives each the round-trip time,
+/// see `crypto::Kind::Ping`.
+pub const CAP_PING: &str = "ping";
+
+/// How often each side pings the other under `CAP_PING`.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reference point of ping tokens.
+static PING_EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+/// `text` of a `ping`: when it was sent, in microseconds since the first
+/// ping. The `pong` carries it back, so nothing is kept while it travels.
+pub fn ping_token() -> String {
+    PING_EPOCH.get_or_init(std::time::Instant::now).elapsed().as_micros().to_string()
+}
+
+/// Round-trip time of a `pong` answering one of our `ping_token`s.
+pub fn pong_rtt(token: &str) -> Option<Duration> {
+    let sent = Duration::from_micros(token.parse().ok()?);
+    PING_EPOCH.get()?.elapsed().checked_sub(sent)
+}
 
 /// Write a length-prefixed plaintext message to `stream`.
 /// The length is a big-endian u32 followed by the raw bytes.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn write_plain(stream: &mut TcpStream, data: &[u8]) -> std::io::Result<()> {
-    let len_bytes = (data.len() as u32).to_be_bytes();
-    stream.write_all(&len_bytes)?;
-    stream.write_all(data)?;
+    let mut frame = BytesMut::with_capacity(4 + data.len());
+    frame.put_u32(data.len() as u32);
+    frame.put_slice(data);
+    stream.write_all(&frame)?;
     stream.flush()?;
     Ok(())
 }
 
 /// Read a length-prefixed plaintext message from `stream`.
+#[cfg(not(target_arch = "wasm32"))]
 pub fn read_plain(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)?;
-    let msg_len = u32::from_be_bytes(len_buf) as usize;
-    let mut buffer = vec![0u8; msg_len];
-    stream.read_exact(&mut buffer)?;
-    Ok(buffer)
+    let mut buf = BytesMut::new();
+    read_frame(stream, &mut buf).map_err(|e| match e {
+        ProtocolError::Io(kind) => kind.into(),
+        ProtocolError::Eof | ProtocolError::Truncated => std::io::ErrorKind::UnexpectedEof.into(),
+        other => std::io::Error::new(std::io::ErrorKind::InvalidData, other.to_string()),
+    })?;
+    Ok(buf[4..].to_vec())
+}
+
+/// Read one complete frame (length prefix included) from `stream` into `buf`.
+///
+/// A close before the first byte is `Eof`; a close mid-frame is `Truncated`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn read_frame(stream: &mut TcpStream, buf: &mut BytesMut) -> Result<(), ProtocolError> {
+    buf.clear();
+    buf.resize(4, 0);
+    fill(stream, &mut buf[..], true)?;
+    let total = frame_len(buf)?.ok_or(ProtocolError::Truncated)?;
+    buf.resize(total, 0);
+    fill(stream, &mut buf[4..], false)
+}
+
+/// `read_exact` that reports where the stream ended.
+#[cfg(not(target_arch = "wasm32"))]
+fn fill(stream: &mut TcpStream, mut out: &mut [u8], at_boundary: bool) -> Result<(), ProtocolError> {
+    let mut first = true;
+    while !out.is_empty() {
+        match stream.read(out) {
+            Ok(0) if first && at_boundary => return Err(ProtocolError::Eof),
+            Ok(0) => return Err(ProtocolError::Truncated),
+            Ok(n) => out = &mut out[n..],
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ProtocolError::Io(e.kind())),
+        }
+        first = false;
+    }
+    Ok(())
+}
+
+/// Split one complete frame (length prefix included) off the front of `buf`
+/// without copying. Returns `Ok(None)` if more bytes are needed and an error
+/// as soon as the length prefix is over the limit, before buffering the body.
+pub fn take_frame(buf: &mut BytesMut) -> Result<Option<Bytes>, ProtocolError> {
+    match frame_len(buf)? {
+        Some(total) if buf.len() >= total => Ok(Some(buf.split_to(total).freeze())),
+        _ => Ok(None),
+    }
+}
+
+/// Fault injection for testing (the hidden `--chaos` flag): frames are
+/// delayed, dropped and, on the way in, delivered out of order, so the
+/// acknowledgement, retry and resend logic can be exercised against a local
+/// server. It applies to the blocking client connections of this process
+/// (`client`, `send`, `tail`), not to the server's event loop. The same
+/// seed gives the same faults for the same traffic.
+struct Chaos {
+    /// Chance that a frame is lost, in each direction.
+    drop: f64,
+    /// Chance that a received frame is held back and delivered after the
+    /// next one.
+    reorder: f64,
+    /// Most latency added to each frame; each gets a random share of it.
+    delay: std::time::Duration,
+    /// State of the splitmix64 generator behind every decision.
+    state: u64,
+}
+
+/// TCP options for chat connections, from `[socket]` in the config file
+/// and the command line. Applied with `tune` to the sockets the client
+/// dials and the server and peers accept.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SocketConfig {
+    /// Send frames as soon as they are written instead of letting Nagle's
+    /// algorithm hold small ones back (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Idle seconds before keepalive probes are sent; 0 turns keepalive
+    /// off.
+    pub keepalive_secs: u64,
+    /// Seconds between keepalive probes; 0 leaves the system default.
+    pub keepalive_interval_secs: u64,
+    /// Send buffer size in bytes (`SO_SNDBUF`); 0 leaves the system
+    /// default.
+    pub send_buffer: usize,
+    /// Receive buffer size in bytes (`SO_RCVBUF`); 0 leaves the system
+    /// default.
+    pub recv_buffer: usize,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self { nodelay: true, keepalive_secs: 0, keepalive_interval_secs: 0, send_buffer: 0, recv_buffer: 0 }
+    }
+}
+
+/// How long to wait for the other side while setting up a connection,
+/// from `[client.timeouts]` and `[server.timeouts]`. Slow links, such as
+/// satellite or Tor, may need more than the defaults.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Timeouts {
+    /// Server: milliseconds a new connection has to send its HELLO.
+    pub hello_ms: u64,
+    /// Seconds to wait for each step of the challenge-response.
+    pub handshake_secs: u64,
+    /// Server: seconds a client has to log in, which may include the user
+    /// typing a password. Client: seconds to wait for the login's answer.
+    pub login_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self { hello_ms: 200, handshake_secs: 5, login_secs: 120 }
+    }
+}
+
+/// The timeouts as durations, never zero: a zero read timeout is an error,
+/// and ignoring it would leave no timeout at all.
+impl Timeouts {
+    pub fn hello(&self) -> Duration {
+        Duration::from_millis(self.hello_ms.max(1))
+    }
+
+    pub fn handshake(&self) -> Duration {
+        Duration::from_secs(self.handshake_secs.max(1))
+    }
+
+    pub fn login(&self) -> Duration {
+        Duration::from_secs(self.login_secs.max(1))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static SOCKET: std::sync::Mutex<Option<SocketConfig>> = std::sync::Mutex::new(None);
+
+/// Use `config` for every connection made or accepted from now on.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_socket_config(config: SocketConfig) {
+    *SOCKET.lock().unwrap() = Some(config);
+}
+
+/// Apply the socket options of `set_socket_config`, or the defaults, to a
+/// chat connection.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn tune(stream: &TcpStream) -> std::io::Result<()> {
+    let config = SOCKET.lock().unwrap().clone().unwrap_or_default();
+    tune_with(stream, &config)
+}
+
+/// Apply the socket options in `config` to a chat connection.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn tune_with(stream: &TcpStream, config: &SocketConfig) -> std::io::Result<()> {
+    let socket = SockRef::from(stream);
+    socket.set_tcp_nodelay(config.nodelay)?;
+    if config.keepalive_secs > 0 {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.keepalive_secs));
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "netbsd", target_os = "windows"))]
+        if config.keepalive_interval_secs > 0 {
+            keepalive = keepalive.with_interval(Duration::from_secs(config.keepalive_interval_secs));
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if config.send_buffer > 0 {
+        socket.set_send_buffer_size(config.send_buffer)?;
+    }
+    if config.recv_buffer > 0 {
+        socket.set_recv_buffer_size(config.recv_buffer)?;
+    }
+    Ok(())
+}
+
+static CHAOS: std::sync::Mutex<Option<Chaos>> = std::sync::Mutex::new(None);
+
+/// What happens to a frame under `--chaos`.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Fate {
+    Deliver,
+    Drop,
+    /// Deliver it after the next frame; only for received frames.
+    Hold,
+}
+
+impl Chaos {
+    fn next(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as f64 / u64::MAX as f64
+    }
+}
+
+/// Turn on fault injection from a `--chaos` spec such as
+/// `drop=0.1,reorder=0.05,delay=200,seed=7`, with the delay in
+/// milliseconds. Unset keys default to no faults and seed 0.
+pub fn enable_chaos(spec: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid --chaos setting {:?}; expected drop=<0-1>,reorder=<0-1>,delay=<ms>,seed=<n>", spec);
+    let mut chaos = Chaos { drop: 0.0, reorder: 0.0, delay: std::time::Duration::ZERO, state: 0 };
+    for setting in spec.split(',').filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=').ok_or_else(invalid)?;
+        match key.trim() {
+            "drop" => chaos.drop = value.trim().parse().ok().filter(|p| (0.0..=1.0).contains(p)).ok_or_else(invalid)?,
+            "reorder" => chaos.reorder = value.trim().parse().ok().filter(|p| (0.0..=1.0).contains(p)).ok_or_else(invalid)?,
+            "delay" => chaos.delay = std::time::Duration::from_millis(value.trim().parse().map_err(|_| invalid())?),
+            "seed" => chaos.state = value.trim().parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        }
+    }
+    *CHAOS.lock().unwrap() = Some(chaos);
+    Ok(())
+}
+
+/// Decide the fate of a frame about to be sent (`inbound` false) or just
+/// received, and sleep for its share of the latency. Always `Deliver`
+/// without `--chaos`.
+pub fn chaos_fate(inbound: bool) -> Fate {
+    let mut guard = CHAOS.lock().unwrap();
+    let Some(chaos) = guard.as_mut() else { return Fate::Deliver };
+    let delay = chaos.delay.mul_f64(chaos.next());
+    let fate = if chaos.next() < chaos.drop {
+        Fate::Drop
+    } else if inbound && chaos.next() < chaos.reorder {
+        Fate::Hold
+    } else {
+        Fate::Deliver
+    };
+    drop(guard);
+    std::thread::sleep(delay);
+    fate
 }
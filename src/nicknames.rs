@@ -0,0 +1,101 @@
+//! Server-side nickname registry, kept in `$HOME/key/nicknames`.
+//!
+//! Clients sign their join frame with their personal key (see
+//! `identity::sign_join`), and the server remembers the name last used
+//! under each key, one `<hex-key> <name>` line per key. A returning client
+//! gets its remembered name back whatever name it announced, so it keeps
+//! the same name across reconnects and machines whose OS usernames differ.
+//! `/nick` changes the remembered name. The server reads the file when it
+//! starts and writes it back from a thread of its own. Logged-in clients speak under their
+//! account name and are not listed.
+//!
+//! Names change, so the server also stamps every relayed message with the
+//! sender's stable user ID (`Payload::user`), which is what to refer to a
+//! user by: `account:<name>` for a logged-in client, `key:<hex>` with the
+//! first 16 hex digits of the personal key for one that signed its join,
+//! and otherwise `conn:<hex>`, random per connection.
+
+use std::sync::{Mutex, mpsc};
+
+/// Location of the nickname registry.
+pub fn nicknames_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/nicknames", home)
+}
+
+fn read_all(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    Ok(text.lines().filter_map(|line| line.split_once(' ')).map(|(key, name)| (key.to_string(), name.to_string())).collect())
+}
+
+/// The registry, read once when the server starts. Names remembered later
+/// reach the file through `roles::write_back`.
+pub struct Registry {
+    names: Mutex<Vec<(String, String)>>,
+    writes: mpsc::Sender<crate::roles::Write>,
+}
+
+impl Registry {
+    /// Read the file, queueing later changes on `writes`.
+    pub fn load(writes: mpsc::Sender<crate::roles::Write>) -> Result<Self, String> {
+        Ok(Registry { names: Mutex::new(read_all(&nicknames_path())?), writes })
+    }
+
+    /// A registry with nothing in it that never writes.
+    #[cfg(test)]
+    pub(crate) fn empty() -> Self {
+        Registry { names: Mutex::default(), writes: mpsc::channel().0 }
+    }
+
+    /// Name remembered for the personal key `key_hex`.
+    pub fn lookup(&self, key_hex: &str) -> Option<String> {
+        self.names.lock().unwrap().iter().find(|(key, _)| key == key_hex).map(|(_, name)| name.clone())
+    }
+
+    /// Remember `name` for `key_hex`, replacing any earlier name.
+    pub fn remember(&self, key_hex: &str, name: &str) {
+        let mut names = self.names.lock().unwrap();
+        names.retain(|(key, _)| key != key_hex);
+        names.push((key_hex.to_string(), name.to_string()));
+        let lines = names.iter().map(|(key, name)| format!("{} {}", key, name));
+        // Nobody writes back in tests
+        let _ = self.writes.send(crate::roles::Write::lines(&nicknames_path(), lines));
+    }
+}
+
+/// Stable ID of the server console, for its own messages.
+pub const SERVER_USER_ID: &str = "server";
+
+/// User ID of a client that logged in to `account` or signed its join with
+/// the key `identity`, or a fresh per-connection ID for one that did
+/// neither.
+pub fn user_id(account: Option<&str>, identity: Option<&str>) -> String {
+    match (account, identity) {
+        (Some(account), _) => format!("account:{}", account),
+        (None, Some(key)) => format!("key:{}", &key[..16.min(key.len())]),
+        (None, None) => {
+            use rand_core::RngCore;
+            let mut id = [0u8; 8];
+            aes_gcm::aead::OsRng.fill_bytes(&mut id);
+            format!("conn:{}", hex::encode(id))
+        }
+    }
+}
+
+/// Check a name asked for with `/nick`.
+pub fn validate(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("Usage: /nick <name>".to_string());
+    }
+    if name.len() > crate::frame::MAX_USERNAME_LEN || name.chars().any(char::is_control) {
+        return Err("That name is too long or contains control characters".to_string());
+    }
+    if ["Server", "System"].contains(&name) {
+        return Err(format!("{} is reserved", name));
+    }
+    Ok(())
+}
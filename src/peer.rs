@@ -0,0 +1,374 @@
+//! Serverless peer mode for a few people on a LAN (`antimpeu peer`).
+//!
+//! Every peer listens on a port and dials the peers it is given; each link
+//! uses the same encrypted envelope as a client connection. After the
+//! handshake both sides send their name (`join`) and the other peers they
+//! are linked to (`peers`), which the receiver dials in turn, so the group
+//! ends up fully meshed. Chat is gossiped: a peer shows a message the first
+//! time its ID arrives and forwards it to every other link, so a message
+//! still gets through while a link is missing.
+//!
+//! The handshake proves the DEK in both directions with the handshake key:
+//!
+//! ```text
+//! dialer                                  listener
+//!   HELLO-ANTIMPEU-PEER ca port node   ->
+//!                                     <-  cb mac(ca, "peer-listener", cb) port node
+//!   mac(cb, "peer-dialer", ca)         ->
+//! ```
+//!
+//! `node` is a random ID per run. It stops a peer from linking to itself
+//! and to a peer it already has a link with: either side then drops the
+//! connection, the listener after answering `KNOWN`.
+
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use rand_core::RngCore;
+use crate::crypto::{Kind, Payload, SessionCipher, Side};
+use crate::identity::SignedRoom;
+use crate::types::{MessageBuffer, SeenIds, SharedMessages, SharedStatus};
+
+/// Plaintext greeting that opens a peer handshake.
+const PEER_HELLO: &str = "HELLO-ANTIMPEU-PEER";
+/// Listener's answer to a node it is already linked to, or itself.
+const KNOWN: &str = "KNOWN";
+
+/// Slash commands of peer mode, for the F1 help.
+const COMMANDS: &[(&str, &str)] = &[
+    ("/who", "list this peer and the ones it is linked to"),
+    ("/connect <host:port>", "link to another peer"),
+    ("/ephemeral <duration> <text>", "send a message that disappears after the duration"),
+];
+
+/// One open link to another peer.
+struct Link {
+    name: Mutex<String>,
+    /// Where the peer listens, for passing on to others.
+    listen: String,
+    /// Whether we dialed; decides which link survives when two peers dial
+    /// each other at once.
+    dialed: bool,
+    /// The link's message key, see `auth::derive_session_key`.
+    cipher: SessionCipher,
+    writer: Mutex<(TcpStream, u64)>,
+}
+
+/// State shared by the listener, the dialers and the link readers.
+struct Mesh {
+    node: String,
+    port: u16,
+    username: String,
+    dek: [u8; 32],
+    handshake_key: [u8; 32],
+    room: Option<SignedRoom>,
+    links: Mutex<HashMap<String, Arc<Link>>>,
+    seen: Mutex<SeenIds>,
+    messages: SharedMessages<crate::tui::Message>,
+    /// How long to wait for each step of a link's handshake.
+    handshake_timeout: Duration,
+}
+
+impl Mesh {
+    fn system(&self, text: String) {
+        self.messages.lock().unwrap().push(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+    }
+
+    /// Show a line in the event pane, for links coming and going.
+    fn event(&self, text: String) {
+        self.messages.lock().unwrap().push_event(crate::tui::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+    }
+
+    /// Send to every link except the one with node ID `skip`.
+    fn gossip(&self, payload: &Payload, skip: Option<&str>) {
+        let links: Vec<Arc<Link>> = self.links.lock().unwrap().iter().filter(|(node, _)| Some(node.as_str()) != skip).map(|(_, link)| link.clone()).collect();
+        for link in links {
+            send(&link, payload);
+        }
+    }
+
+    /// `node address` lines for the links other than `skip`.
+    fn peer_list(&self, skip: &str) -> String {
+        let links = self.links.lock().unwrap();
+        links.iter().filter(|(node, _)| node.as_str() != skip).map(|(node, link)| format!("{} {}", node, link.listen)).collect::<Vec<_>>().join("\n")
+    }
+
+    fn knows(&self, node: &str) -> bool {
+        node == self.node || self.links.lock().unwrap().contains_key(node)
+    }
+}
+
+fn send(link: &Link, payload: &Payload) {
+    if let Ok(mut guard) = link.writer.lock() {
+        let (s, seq) = &mut *guard;
+        *seq += 1;
+        let _ = crate::crypto::send_encrypted(s, payload, &link.cipher, *seq, crate::crypto::Framing::default());
+    }
+}
+
+fn random_hex() -> String {
+    let mut bytes = [0u8; 16];
+    aes_gcm::aead::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Dial a peer in the background; failures are shown in the chat.
+fn dial(mesh: &Arc<Mesh>, addr: String) {
+    let mesh = mesh.clone();
+    thread::spawn(move || {
+        if let Err(e) = dial_handshake(&mesh, &addr) {
+            mesh.event(format!("Could not link to {}: {}", addr, e));
+        }
+    });
+}
+
+fn dial_handshake(mesh: &Arc<Mesh>, addr: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect(addr).map_err(|e| e.to_string())?;
+    crate::net::tune(&stream).map_err(|e| format!("could not set socket options: {}", e))?;
+    stream.set_read_timeout(Some(mesh.handshake_timeout)).ok();
+    let mut challenge = [0u8; 32];
+    aes_gcm::aead::OsRng.fill_bytes(&mut challenge);
+    let ours = hex::encode(challenge);
+    let hello = format!("{} {} {} {}", PEER_HELLO, ours, mesh.port, mesh.node);
+    crate::net::write_plain(&mut stream, hello.as_bytes()).map_err(|e| e.to_string())?;
+
+    let reply = crate::net::read_plain(&mut stream).map_err(|_| "peer refused the link (wrong key?)".to_string())?;
+    let reply = String::from_utf8(reply).unwrap_or_default();
+    if reply == KNOWN {
+        return Ok(());
+    }
+    let mut parts = reply.split(' ');
+    let (theirs, mac, node) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), parts.nth(1).unwrap_or_default());
+    let their_challenge = hex::decode(theirs).map_err(|_| "peer sent an invalid challenge".to_string())?;
+    if !crate::auth::verify_handshake_mac(&mesh.handshake_key, &challenge, "peer-listener", theirs, &hex::decode(mac).unwrap_or_default()) {
+        return Err("the peer has a different DEK".to_string());
+    }
+    if mesh.knows(node) {
+        // ourselves, or a peer we are already linked to
+        return Ok(());
+    }
+    let response = crate::auth::handshake_mac(&mesh.handshake_key, &their_challenge, "peer-dialer", &ours);
+    crate::net::write_plain(&mut stream, hex::encode(response).as_bytes()).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(None).ok();
+    // the dialer counts as the client, and the link is bound to its node ID
+    let key = crate::auth::derive_session_key(&mesh.dek, &their_challenge, &challenge, &mesh.node);
+    add_link(mesh, stream, node.to_string(), addr.to_string(), SessionCipher::new(&key, Side::Client));
+    Ok(())
+}
+
+fn accept_handshake(mesh: &Arc<Mesh>, mut stream: TcpStream) -> Result<(), String> {
+    let peer_ip = stream.peer_addr().map_err(|e| e.to_string())?.ip();
+    stream.set_read_timeout(Some(mesh.handshake_timeout)).ok();
+    let hello = crate::net::read_plain(&mut stream).map_err(|e| e.to_string())?;
+    let hello = String::from_utf8(hello).unwrap_or_default();
+    let mut parts = hello.split(' ');
+    if parts.next() != Some(PEER_HELLO) {
+        return Err("not a peer".to_string());
+    }
+    let (theirs, port, node) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let their_challenge = hex::decode(theirs).map_err(|_| "invalid challenge".to_string())?;
+    let port: u16 = port.parse().map_err(|_| "invalid port".to_string())?;
+    if node.is_empty() || mesh.knows(node) {
+        return crate::net::write_plain(&mut stream, KNOWN.as_bytes()).map_err(|e| e.to_string());
+    }
+    let mut challenge = [0u8; 32];
+    aes_gcm::aead::OsRng.fill_bytes(&mut challenge);
+    let ours = hex::encode(challenge);
+    let mac = crate::auth::handshake_mac(&mesh.handshake_key, &their_challenge, "peer-listener", &ours);
+    let reply = format!("{} {} {} {}", ours, hex::encode(mac), mesh.port, mesh.node);
+    crate::net::write_plain(&mut stream, reply.as_bytes()).map_err(|e| e.to_string())?;
+    let response = match crate::net::read_plain(&mut stream) {
+        Ok(r) => r,
+        // the dialer found it is already linked to us
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    if !crate::auth::verify_handshake_mac(&mesh.handshake_key, &challenge, "peer-dialer", theirs, &hex::decode(response).unwrap_or_default()) {
+        return Err("failed to prove it knows the DEK".to_string());
+    }
+    stream.set_read_timeout(None).ok();
+    let listen = std::net::SocketAddr::new(peer_ip, port).to_string();
+    let key = crate::auth::derive_session_key(&mesh.dek, &challenge, &their_challenge, node);
+    add_link(mesh, stream, node.to_string(), listen, SessionCipher::new(&key, Side::Server));
+    Ok(())
+}
+
+/// Register an authenticated link, introduce ourselves and read from it.
+/// `cipher` writes as the client on a link we dialed.
+fn add_link(mesh: &Arc<Mesh>, stream: TcpStream, node: String, listen: String, cipher: SessionCipher) {
+    let Ok(mut reader) = stream.try_clone() else { return };
+    let dialed = cipher.side() == Side::Client;
+    let link = Arc::new(Link { name: Mutex::new(listen.clone()), listen, dialed, cipher, writer: Mutex::new((stream, 0)) });
+    {
+        let mut links = mesh.links.lock().unwrap();
+        if let Some(existing) = links.get(&node) {
+            // Both sides dialed at once: keep the link dialed by the smaller
+            // node ID, which both sides agree on.
+            let initiator = |l: &Link| if l.dialed { mesh.node.as_str() } else { node.as_str() };
+            if initiator(existing) <= initiator(&link) {
+                return;
+            }
+            let _ = existing.writer.lock().unwrap().0.shutdown(std::net::Shutdown::Both);
+        }
+        links.insert(node.clone(), link.clone());
+    }
+    send(&link, &Payload::control(Kind::Join, &mesh.username, ""));
+    send(&link, &Payload::control(Kind::Peers, &mesh.username, &mesh.peer_list(&node)));
+
+    let mesh = mesh.clone();
+    thread::spawn(move || {
+        let mut frame_buf = bytes::BytesMut::new();
+        let mut last_seq = 0;
+        let error = loop {
+            let m = match crate::crypto::read_one_encrypted(&mut reader, &link.cipher, &mut frame_buf, &mut last_seq) {
+                Ok(m) => m,
+                Err(e) => break e,
+            };
+            match m.kind {
+                Kind::Chat => {
+                    // only messages with an ID can be gossiped safely
+                    let Some(id) = m.id.as_deref().filter(|id| id.len() <= crate::crypto::MAX_ID_LEN) else { continue };
+                    if !mesh.seen.lock().unwrap().insert(id) {
+                        continue;
+                    }
+                    let m = Payload { time: None, file: None, ..m };
+                    mesh.gossip(&m, Some(&node));
+                    let verified = mesh.room.as_ref().map(|r| r.verify(&m.username, &m.text, m.signature.as_deref()));
+                    let message = crate::tui::Message { sender: m.username.into_owned(), text: m.text.into_owned(), time: chrono::Utc::now(), verified, expires: None, user: None, id: None };
+                    match m.ttl {
+                        Some(ttl) => crate::tui::push_ephemeral(&mesh.messages, message, Duration::from_secs(ttl).min(crate::tui::MAX_EPHEMERAL_TTL)),
+                        None => mesh.messages.lock().unwrap().push(message),
+                    }
+                }
+                Kind::Join => {
+                    *link.name.lock().unwrap() = m.username.to_string();
+                    mesh.event(format!("{} linked ({})", m.username, link.listen));
+                }
+                Kind::Peers => {
+                    for line in m.text.lines() {
+                        if let Some((node, addr)) = line.split_once(' ') {
+                            if !mesh.knows(node) {
+                                dial(&mesh, addr.to_string());
+                            }
+                        }
+                    }
+                }
+                // nothing else is used between peers
+                _ => {}
+            }
+        };
+        // A link replaced by a newer one to the same peer goes quietly
+        let mut links = mesh.links.lock().unwrap();
+        if links.get(&node).is_some_and(|l| Arc::ptr_eq(l, &link)) {
+            links.remove(&node);
+            drop(links);
+            let name = link.name.lock().unwrap().clone();
+            let text = match error {
+                crate::frame::ProtocolError::Eof => format!("{} left", name),
+                e => format!("Lost the link to {}: {}", name, e),
+            };
+            mesh.event(text);
+        }
+    });
+}
+
+/// Run peer mode: listen on `port`, link to `peers`, and chat in the TUI
+/// until it exits.
+pub fn run_peer_with_tui(port: u16, peers: Vec<String>, dek: [u8; 32], config: &crate::config::Config) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Could not listen on port {}: {}", port, e);
+            return;
+        }
+    };
+    let room = if config.room.signed {
+        match SignedRoom::load() {
+            Ok(room) => Some(room),
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let messages: SharedMessages<crate::tui::Message> = Arc::new(Mutex::new(MessageBuffer::new(config.tui.history_limit)));
+    let mesh = Arc::new(Mesh {
+        node: random_hex(),
+        port,
+        username: config.client.nickname.clone().unwrap_or_else(whoami::username),
+        dek,
+        handshake_key: crate::auth::derive_handshake_key(&dek),
+        room,
+        links: Mutex::default(),
+        seen: Mutex::default(),
+        messages: messages.clone(),
+        handshake_timeout: config.client.timeouts.handshake(),
+    });
+    mesh.event(format!("Listening for peers on port {}", port));
+
+    let mesh_listener = mesh.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mesh = mesh_listener.clone();
+            thread::spawn(move || {
+                let addr = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                crate::net::tune(&stream).ok();
+                if let Err(e) = accept_handshake(&mesh, stream) {
+                    mesh.event(format!("Refused a link from {}: {}", addr, e));
+                }
+            });
+        }
+    });
+    for addr in peers {
+        dial(&mesh, addr);
+    }
+
+    let status: SharedStatus = Arc::default();
+    {
+        let mut status = status.lock().unwrap();
+        status.name = Some(mesh.username.clone());
+        status.commands = COMMANDS.to_vec();
+    }
+    let shutdown = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let send_closure = move |msg: String| {
+        let (msg, ttl) = match msg.strip_prefix("/ephemeral").filter(|a| a.is_empty() || a.starts_with(char::is_whitespace)) {
+            Some(args) => match crate::tui::parse_ephemeral(args) {
+                Ok((ttl, text)) => {
+                    let echo = crate::tui::Message { sender: mesh.username.clone(), text: text.to_string(), time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None };
+                    crate::tui::push_ephemeral(&mesh.messages, echo, ttl);
+                    (text.to_string(), Some(ttl.as_secs()))
+                }
+                Err(usage) => return mesh.system(usage),
+            },
+            None => (msg, None),
+        };
+        if let Some(command) = msg.strip_prefix('/').filter(|_| ttl.is_none()) {
+            match command.split_once(' ').unwrap_or((command, "")) {
+                ("who", _) => {
+                    let links = mesh.links.lock().unwrap();
+                    let mut lines: Vec<String> = links.values().map(|l| format!("{} ({})", l.name.lock().unwrap(), l.listen)).collect();
+                    drop(links);
+                    lines.sort();
+                    lines.insert(0, format!("{} (you, port {})", mesh.username, mesh.port));
+                    for line in lines {
+                        mesh.system(line);
+                    }
+                }
+                ("connect", addr) if !addr.trim().is_empty() => dial(&mesh, addr.trim().to_string()),
+                _ => mesh.system("Peer mode knows /who, /connect <host:port> and /ephemeral".to_string()),
+            }
+            return;
+        }
+        let signature = mesh.room.as_ref().map(|r| r.sign(&mesh.username, &msg));
+        let payload = Payload { ttl, ..Payload::chat(&mesh.username, &msg, signature.as_deref()) };
+        if let Some(id) = &payload.id {
+            mesh.seen.lock().unwrap().insert(id);
+        }
+        mesh.gossip(&payload, None);
+    };
+    let _ = crate::tui::run_tui_with_sender(send_closure, messages, status, shutdown, &config.tui);
+}
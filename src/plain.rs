@@ -0,0 +1,165 @@
+//! Plain line mode (`--plain`, `[tui] plain`): the chat as lines of text
+//! with a `> ` prompt, for screen readers and dumb terminals.
+//!
+//! There is no alternate screen, box drawing or colour. Messages and events
+//! are printed as they arrive, overlays such as `/search` results as a
+//! block of lines, and input is read a line at a time. `/help` lists the
+//! commands; `/quit` or end of input leaves.
+
+use crate::config::TuiConfig;
+use crate::tui::{Message, Zone};
+use crate::types::{SharedMessages, SharedStatus};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// How often new messages are looked for while waiting for input.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run the chat in plain mode until the user quits or `shutdown` is set.
+/// `send_fn` and the shared state are those of the TUI.
+pub fn run<F>(send_fn: F, messages: SharedMessages<Message>, status: SharedStatus, shutdown: Arc<AtomicBool>, config: &TuiConfig) -> std::io::Result<()>
+where
+    F: Fn(String) + Send + Sync + 'static,
+{
+    let mut username = status.lock().unwrap().name.clone().unwrap_or_else(|| std::env::var("USER").unwrap_or_else(|_| "user".to_string()));
+    let mut user_id = None;
+    // stdin blocks, so it is read on a thread of its own; it ends the
+    // channel at end of input
+    let (line_tx, line_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    // everything already in the buffers is printed first
+    let mut zone = config.timezone.clone();
+    let (mut pushed, mut events_pushed) = (0, 0);
+    let mut topic = None;
+    let mut out = std::io::stdout();
+    prompt(&mut out)?;
+    while !shutdown.load(Ordering::SeqCst) {
+        let mut lines = Vec::new();
+        {
+            let msgs = messages.lock().unwrap();
+            let new = (msgs.pushed() - pushed).min(msgs.len() as u64) as usize;
+            lines.extend(msgs.iter().skip(msgs.len() - new).map(|m| message_line(m, &zone, &username, user_id.as_deref())));
+            pushed = msgs.pushed();
+            let new = (msgs.events_pushed() - events_pushed).min(msgs.events().len() as u64) as usize;
+            lines.extend(msgs.events().skip(msgs.events().len() - new).map(|m| format!("* {}", clean(&m.text))));
+            events_pushed = msgs.events_pushed();
+        }
+        {
+            let mut status = status.lock().unwrap();
+            // the server may rename us, see `Kind::Nick`
+            if let Some(name) = status.name.as_ref().filter(|name| **name != username) {
+                username.clone_from(name);
+            }
+            user_id.clone_from(&status.user_id);
+            if status.topic != topic {
+                topic.clone_from(&status.topic);
+                if let Some(topic) = &topic {
+                    lines.push(format!("Topic: {}", clean(topic)));
+                }
+            }
+            if let Some(overlay) = status.overlay.take() {
+                lines.push(format!("--- {} ---", overlay.title));
+                lines.extend(overlay.lines.iter().map(|l| clean(l)));
+                lines.push("---".to_string());
+            }
+        }
+        if !lines.is_empty() {
+            // the line the prompt was on is overwritten
+            write!(out, "\r")?;
+            for line in lines {
+                writeln!(out, "{}", line)?;
+            }
+            prompt(&mut out)?;
+        }
+
+        let line = match line_rx.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => line,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        let text = crate::config::expand_alias(&config.aliases, line.trim()).into_owned();
+        let text = text.as_str();
+        match text {
+            "" => {}
+            "/quit" => break,
+            _ if text.starts_with("/theme") => writeln!(out, "Plain mode has no colours")?,
+            "/tz" => writeln!(out, "Times are shown in {}", zone)?,
+            _ if text.starts_with("/tz ") => match crate::tui::set_zone(&mut zone, text[4..].trim()) {
+                Ok(()) => writeln!(out, "Times are now shown in {}", zone)?,
+                Err(e) => writeln!(out, "{}", e)?,
+            },
+            "/help" => {
+                let commands = status.lock().unwrap().commands.clone();
+                writeln!(out, "/help  this list")?;
+                writeln!(out, "/quit  leave (or end the input)")?;
+                writeln!(out, "/tz [zone]  show times in local time, utc, an offset or a named zone")?;
+                for (command, what) in commands {
+                    writeln!(out, "{}  {}", command, what)?;
+                }
+                for (name, expansion) in &config.aliases {
+                    writeln!(out, "/{}  {}", name, expansion)?;
+                }
+            }
+            _ => {
+                let next_send = status.lock().unwrap().next_send.filter(|t| *t > Instant::now());
+                match next_send {
+                    Some(t) if !text.starts_with('/') => {
+                        writeln!(out, "Slow mode: send again in {}s", (t - Instant::now()).as_secs() + 1)?;
+                    }
+                    _ => {
+                        send_fn(text.to_string());
+                        let id = status.lock().unwrap().sent_id.take();
+                        // commands are handled by send_fn and not echoed
+                        if !text.starts_with('/') {
+                            let time = chrono::Utc::now();
+                            messages.lock().unwrap().push(Message { sender: username.clone(), text: text.to_string(), time, verified: None, expires: None, user: user_id.clone(), id });
+                        }
+                    }
+                }
+            }
+        }
+        prompt(&mut out)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+fn prompt(out: &mut impl Write) -> std::io::Result<()> {
+    write!(out, "> ")?;
+    out.flush()
+}
+
+/// A chat message as one line, the signature check spelled out and the
+/// user's own messages marked.
+fn message_line(m: &Message, zone: &Zone, username: &str, user_id: Option<&str>) -> String {
+    let mut line = format!("[{}] {}", zone.format(m.time), clean(&m.sender));
+    if crate::tui::is_own(m, username, user_id) {
+        line.push_str(" (you)");
+    }
+    match m.verified {
+        Some(true) => line.push_str(" (signed)"),
+        Some(false) => line.push_str(" (BAD SIGNATURE)"),
+        None => {}
+    }
+    if m.expires.is_some() {
+        line.push_str(" (ephemeral)");
+    }
+    line.push_str(": ");
+    line.push_str(&clean(&m.text));
+    line
+}
+
+/// `text` without control characters, which the terminal would otherwise
+/// act on.
+fn clean(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
@@ -0,0 +1,149 @@
+//! Port mapping on home routers for `antimpeu server --upnp`.
+//!
+//! NAT-PMP (RFC 6886) is tried first: one UDP request to the default gateway
+//! on port 5351, renewed before its lease runs out. Routers without it are
+//! asked over UPnP IGD, found by SSDP multicast. Either way the mapping is
+//! removed again when the server exits.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Lease requested from NAT-PMP routers; renewed at half time.
+const NATPMP_LEASE_SECS: u32 = 7200;
+const NATPMP_PORT: u16 = 5351;
+/// How long to wait for an SSDP answer from a UPnP router.
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A port mapping held on the router until dropped.
+pub struct Mapping {
+    /// Public address under which the server is reachable.
+    pub external: SocketAddr,
+    /// Which protocol made the mapping, for messages.
+    pub method: &'static str,
+    kind: Kind,
+}
+
+enum Kind {
+    NatPmp { gateway: Ipv4Addr, port: u16, stop: Arc<AtomicBool> },
+    Upnp { gateway: Box<igd_next::Gateway>, port: u16 },
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        match &self.kind {
+            Kind::NatPmp { gateway, port, stop } => {
+                stop.store(true, Ordering::SeqCst);
+                // a zero lifetime deletes the mapping
+                let _ = natpmp_map(*gateway, *port, 0);
+            }
+            Kind::Upnp { gateway, port } => {
+                let _ = gateway.remove_port(igd_next::PortMappingProtocol::TCP, *port);
+            }
+        }
+    }
+}
+
+/// Map TCP `port` on the router to the same port on this host.
+pub fn map_port(port: u16) -> Result<Mapping, String> {
+    let natpmp = match default_gateway() {
+        Some(gateway) => match natpmp_mapping(gateway, port) {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => e,
+        },
+        None => "no default gateway found".to_string(),
+    };
+    upnp_mapping(port).map_err(|upnp| format!("NAT-PMP: {}; UPnP: {}", natpmp, upnp))
+}
+
+/// The IPv4 default gateway, read from the kernel routing table.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let table = std::fs::read_to_string("/proc/net/route").ok()?;
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // little-endian hex
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.swap_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+/// Send a NAT-PMP request and wait for the matching answer, retrying with
+/// the back-off of RFC 6886 (shortened to four attempts).
+fn natpmp_request(gateway: Ipv4Addr, request: &[u8]) -> Result<Vec<u8>, String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|e| e.to_string())?;
+    socket.connect((gateway, NATPMP_PORT)).map_err(|e| e.to_string())?;
+    let mut timeout = Duration::from_millis(250);
+    let mut buf = [0u8; 16];
+    for _ in 0..4 {
+        socket.send(request).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        if let Ok(n) = socket.recv(&mut buf) {
+            // answers echo the opcode plus 128
+            if n >= 8 && buf[0] == 0 && buf[1] == request[1] + 128 {
+                return match u16::from_be_bytes([buf[2], buf[3]]) {
+                    0 => Ok(buf[..n].to_vec()),
+                    code => Err(format!("router refused (result code {})", code)),
+                };
+            }
+        }
+        timeout *= 2;
+    }
+    Err(format!("no answer from {}", gateway))
+}
+
+/// Ask for a TCP mapping of `port` with `lifetime` seconds; returns the
+/// external port.
+fn natpmp_map(gateway: Ipv4Addr, port: u16, lifetime: u32) -> Result<u16, String> {
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    let answer = natpmp_request(gateway, &request)?;
+    answer.get(10..12).map(|p| u16::from_be_bytes([p[0], p[1]])).ok_or_else(|| "short answer".to_string())
+}
+
+fn natpmp_mapping(gateway: Ipv4Addr, port: u16) -> Result<Mapping, String> {
+    let answer = natpmp_request(gateway, &[0, 0])?;
+    let ip = answer.get(8..12).map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3])).ok_or_else(|| "short answer".to_string())?;
+    let external_port = natpmp_map(gateway, port, NATPMP_LEASE_SECS)?;
+    // Renew the lease until the mapping is dropped
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_renew = stop.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(u64::from(NATPMP_LEASE_SECS / 2)));
+        if stop_renew.load(Ordering::SeqCst) {
+            break;
+        }
+        if let Err(e) = natpmp_map(gateway, port, NATPMP_LEASE_SECS) {
+            eprintln!("Renewing the NAT-PMP port mapping failed: {}", e);
+        }
+    });
+    Ok(Mapping {
+        external: SocketAddr::V4(SocketAddrV4::new(ip, external_port)),
+        method: "NAT-PMP",
+        kind: Kind::NatPmp { gateway, port, stop },
+    })
+}
+
+fn upnp_mapping(port: u16) -> Result<Mapping, String> {
+    let options = igd_next::SearchOptions { timeout: Some(UPNP_SEARCH_TIMEOUT), ..Default::default() };
+    let gateway = igd_next::search_gateway(options).map_err(|e| e.to_string())?;
+    // The address the router sees us under is the one that routes to it
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|s| s.connect(gateway.addr).map(|()| s)).map_err(|e| e.to_string())?;
+    let local_ip = probe.local_addr().map_err(|e| e.to_string())?.ip();
+    gateway
+        .add_port(igd_next::PortMappingProtocol::TCP, port, SocketAddr::new(local_ip, port), 0, "antimpeu")
+        .map_err(|e| e.to_string())?;
+    let ip: IpAddr = match gateway.get_external_ip() {
+        Ok(ip) => ip,
+        Err(e) => {
+            let _ = gateway.remove_port(igd_next::PortMappingProtocol::TCP, port);
+            return Err(e.to_string());
+        }
+    };
+    Ok(Mapping { external: SocketAddr::new(ip, port), method: "UPnP", kind: Kind::Upnp { gateway: Box::new(gateway), port } })
+}
@@ -0,0 +1,100 @@
+//! `antimpeu profile add|list|remove`: edit the `[profiles.<name>]` tables
+//! of the config file in place, keeping the rest of it (comments included)
+//! as the user wrote it.
+
+use crate::config::{config_path, Profile};
+use toml_edit::{value, DocumentMut, Item, Table};
+
+fn load(path: &str) -> Result<DocumentMut, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    text.parse().map_err(|e| format!("Invalid config {}: {}", path, e))
+}
+
+fn save(path: &str, doc: &DocumentMut) -> Result<(), String> {
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    std::fs::write(path, doc.to_string()).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+/// The `[profiles]` table, created when missing.
+fn profiles(doc: &mut DocumentMut) -> Result<&mut Table, String> {
+    let item = doc.entry("profiles").or_insert_with(|| {
+        let mut table = Table::new();
+        table.set_implicit(true);
+        Item::Table(table)
+    });
+    item.as_table_mut().ok_or_else(|| "profiles in the config is not a table".to_string())
+}
+
+/// Save `profile` as `name`, replacing a profile of that name.
+pub fn add(name: &str, profile: &Profile) -> Result<(), String> {
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c == '.') {
+        return Err("Profile names must be non-empty and contain no whitespace or dots".to_string());
+    }
+    if let Some(slot) = &profile.key {
+        crate::auth::dek_path(Some(slot), "bin")?;
+    }
+    let path = config_path();
+    let mut doc = load(&path)?;
+    let mut table = Table::new();
+    if let Some(host) = &profile.host {
+        table["host"] = value(host.as_str());
+    }
+    if let Some(port) = profile.port {
+        table["port"] = value(i64::from(port));
+    }
+    if let Some(key) = &profile.key {
+        table["key"] = value(key.as_str());
+    }
+    if let Some(nickname) = &profile.nickname {
+        table["nickname"] = value(nickname.as_str());
+    }
+    if let Some(theme) = profile.theme {
+        table["theme"] = value(theme.name());
+    }
+    profiles(&mut doc)?.insert(name, Item::Table(table));
+    save(&path, &doc)
+}
+
+/// Delete the profile `name`.
+pub fn remove(name: &str) -> Result<(), String> {
+    let path = config_path();
+    let mut doc = load(&path)?;
+    if profiles(&mut doc)?.remove(name).is_none() {
+        return Err(format!("No profile {:?} in {}", name, path));
+    }
+    save(&path, &doc)
+}
+
+/// One line per saved profile, as `name  host:port  [key, nickname, theme]`.
+pub fn list(config: &crate::config::Config) -> Vec<String> {
+    config
+        .profiles
+        .iter()
+        .map(|(name, p)| {
+            let mut line = format!("{}  {}", name, p.host.as_deref().unwrap_or("?"));
+            if let Some(port) = p.port {
+                line.push_str(&format!(":{}", port));
+            }
+            let mut extra = Vec::new();
+            if let Some(key) = &p.key {
+                extra.push(format!("key {}", key));
+            }
+            if let Some(nickname) = &p.nickname {
+                extra.push(format!("as {}", nickname));
+            }
+            if let Some(theme) = p.theme {
+                extra.push(format!("{} theme", theme.name()));
+            }
+            if !extra.is_empty() {
+                line.push_str(&format!("  ({})", extra.join(", ")));
+            }
+            line
+        })
+        .collect()
+}
@@ -0,0 +1,43 @@
+//! QR codes for joining a room from a phone or a second machine.
+//!
+//! The code holds an `antimpeu://host:port?key=<hex>` URI. The key is the
+//! server's identity key, which the client pins when given such a URI, so
+//! whoever scans the code does not have to trust the first connection.
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+
+/// Join URI for a server at `host` and `port` with the identity key
+/// `key_hex`.
+pub fn join_uri(host: &str, port: u16, key_hex: &str) -> String {
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    format!("{}{}:{}?key={}", crate::resolve::SCHEME, host, port, key_hex)
+}
+
+/// `data` as a QR code, one string per terminal row, two modules per row.
+///
+/// Light modules are drawn as blocks, like `qrencode -t utf8`, so the code
+/// scans on the usual dark terminal background.
+pub fn render(data: &str) -> Result<Vec<String>, String> {
+    let code = QrCode::new(data.as_bytes()).map_err(|e| format!("Cannot make a QR code: {}", e))?;
+    let text = code.render::<Dense1x2>().dark_color(Dense1x2::Light).light_color(Dense1x2::Dark).build();
+    Ok(text.lines().map(str::to_string).collect())
+}
+
+/// Overlay showing the code to join `host` and `port`, with the server
+/// and its key below it for those who would rather type them.
+pub fn overlay(host: &str, port: u16, key_hex: &str) -> Result<crate::types::Overlay, String> {
+    let mut lines = render(&join_uri(host, port, key_hex))?;
+    lines.push(String::new());
+    lines.push(format!("Server  {}:{}", host, port));
+    lines.push(format!("Key     {}", key_hex));
+    Ok(crate::types::Overlay { title: format!("Join {}", host), lines })
+}
+
+/// Address of this machine on the network it routes outside through, for
+/// a code scanned on the same LAN. Nothing is sent.
+pub fn local_address() -> Option<String> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:9").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
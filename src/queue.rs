@@ -0,0 +1,141 @@
+//! Bounded outbound queue drained by the server's event loop.
+//!
+//! Broadcasting only pushes already-encrypted frames into each client's
+//! queue, so a slow client can no longer stall fan-out to everybody else.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use bytes::Bytes;
+use serde::Deserialize;
+
+/// What to do when a client's queue is full.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued frame to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Close the queue; the event loop then disconnects the client.
+    Disconnect,
+}
+
+/// Result of queueing a frame.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Push {
+    Queued,
+    /// Queued, and the depth just reached the lag warning threshold.
+    Lagging(usize),
+    /// The queue is closed; the client is going away.
+    Closed,
+}
+
+struct QueueState {
+    frames: VecDeque<Bytes>,
+    closed: bool,
+    /// Accept no more frames and close once the queued ones are written.
+    closing: bool,
+    overflowed: bool,
+    /// Set once a lag warning was issued; cleared when the queue drains
+    /// below half the threshold so a recovering client can warn again.
+    lagging: bool,
+}
+
+pub struct SendQueue {
+    state: Mutex<QueueState>,
+    /// Wakes the event loop when the queue has something to write or closed.
+    waker: Arc<mio::Waker>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    lag_warn: usize,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy, lag_warn: usize, waker: Arc<mio::Waker>) -> Self {
+        Self {
+            state: Mutex::new(QueueState { frames: VecDeque::new(), closed: false, closing: false, overflowed: false, lagging: false }),
+            waker,
+            capacity: capacity.max(1),
+            policy,
+            lag_warn: lag_warn.max(1),
+        }
+    }
+
+    /// Queue a frame without blocking. Returns `Closed` if the queue is
+    /// closed, including when this push overflowed it under `Disconnect`.
+    pub fn push(&self, frame: Bytes) -> Push {
+        let mut st = self.state.lock().unwrap();
+        if st.closed || st.closing {
+            return Push::Closed;
+        }
+        if st.frames.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => { st.frames.pop_front(); }
+                OverflowPolicy::Disconnect => {
+                    st.closed = true;
+                    st.overflowed = true;
+                    let _ = self.waker.wake();
+                    return Push::Closed;
+                }
+            }
+        }
+        st.frames.push_back(frame);
+        // the event loop drains whole queues, so only the first frame needs a wake-up
+        if st.frames.len() == 1 {
+            let _ = self.waker.wake();
+        }
+        let depth = st.frames.len();
+        if depth >= self.lag_warn && !st.lagging {
+            st.lagging = true;
+            return Push::Lagging(depth);
+        }
+        Push::Queued
+    }
+
+    /// Take up to `max` frames, oldest first, without blocking, so they can
+    /// go out in one write. Returns nothing when the queue is empty or
+    /// closed.
+    pub fn pop_batch(&self, max: usize) -> Vec<Bytes> {
+        let mut st = self.state.lock().unwrap();
+        if st.closed {
+            return Vec::new();
+        }
+        let n = st.frames.len().min(max);
+        let batch = st.frames.drain(..n).collect();
+        if st.frames.len() < self.lag_warn / 2 {
+            st.lagging = false;
+        }
+        batch
+    }
+
+    /// Close the queue and wake the event loop.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        let _ = self.waker.wake();
+    }
+
+    /// Close the queue once the frames already in it have been written, so
+    /// a final notice still reaches the client.
+    pub fn close_when_drained(&self) {
+        self.state.lock().unwrap().closing = true;
+        let _ = self.waker.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let st = self.state.lock().unwrap();
+        st.closed || (st.closing && st.frames.is_empty())
+    }
+
+    /// Number of frames waiting to be written.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the queue was closed because it overflowed.
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().unwrap().overflowed
+    }
+}
@@ -0,0 +1,78 @@
+//! Finding a server from what the user typed: `host port`, `host:port`,
+//! `antimpeu://host[:port]`, or a bare host name whose port comes from a
+//! `_antimpeu._tcp` DNS SRV record.
+
+/// Port used when neither the user nor DNS names one.
+pub const DEFAULT_PORT: u16 = 5000;
+/// URI scheme accepted in place of a host.
+pub const SCHEME: &str = "antimpeu://";
+
+/// Split `host[:port]`, with IPv6 addresses in brackets.
+fn split_port(target: &str) -> Result<(String, Option<u16>), String> {
+    let invalid = || format!("Invalid server address {:?}", target);
+    if let Some(rest) = target.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        let port = match rest.strip_prefix(':') {
+            Some(port) => Some(port.parse().map_err(|_| invalid())?),
+            None if rest.is_empty() => None,
+            None => return Err(invalid()),
+        };
+        return Ok((host.to_string(), port));
+    }
+    match target.rsplit_once(':') {
+        // a bare IPv6 address has several colons and no port
+        Some((host, port)) if !host.contains(':') => Ok((host.to_string(), Some(port.parse().map_err(|_| invalid())?))),
+        _ => Ok((target.to_string(), None)),
+    }
+}
+
+/// Turn the user's server argument and optional port into a host and port.
+/// Without a port anywhere, ask DNS for `_antimpeu._tcp.<host>` and fall
+/// back to `DEFAULT_PORT`. IP addresses and `.onion` names skip the lookup,
+/// the latter so the name does not leak outside Tor.
+///
+/// A URI may carry the server's identity key as `?key=<hex>`, as in the
+/// QR code of `antimpeu server --show-qr`; it is pinned in `known_servers`
+/// before connecting, and refused if a different key is pinned already.
+pub fn resolve(target: &str, port: Option<u16>) -> Result<(String, u16), String> {
+    let target = target.strip_prefix(SCHEME).unwrap_or(target);
+    let (target, key) = match target.split_once('?') {
+        Some((target, query)) => (target, query.split('&').find_map(|p| p.strip_prefix("key="))),
+        None => (target, None),
+    };
+    let (host, port) = locate(target.trim_end_matches('/'), port)?;
+    if let Some(key) = key {
+        let path = crate::identity::known_servers_path();
+        let server = format!("{}:{}", host, port);
+        if let crate::identity::Pin::New = crate::identity::pin_server(&path, &server, key)? {
+            eprintln!("Pinned identity key of {} in {}: {}", server, path, key);
+        }
+    }
+    Ok((host, port))
+}
+
+fn locate(target: &str, port: Option<u16>) -> Result<(String, u16), String> {
+    let (host, uri_port) = split_port(target)?;
+    if host.is_empty() {
+        return Err("No server host given".to_string());
+    }
+    if let Some(port) = port.or(uri_port) {
+        return Ok((host, port));
+    }
+    if host.parse::<std::net::IpAddr>().is_ok() || host.ends_with(".onion") {
+        return Ok((host, DEFAULT_PORT));
+    }
+    Ok(lookup_srv(&host).unwrap_or((host, DEFAULT_PORT)))
+}
+
+/// The preferred `_antimpeu._tcp` SRV target of `host`: lowest priority
+/// first, then highest weight.
+fn lookup_srv(host: &str) -> Option<(String, u16)> {
+    let resolver = hickory_resolver::Resolver::from_system_conf().ok()?;
+    let records = resolver.srv_lookup(format!("_antimpeu._tcp.{}.", host.trim_end_matches('.'))).ok()?;
+    let best = records.iter().min_by_key(|srv| (srv.priority(), std::cmp::Reverse(srv.weight())))?;
+    // a target of "." means the service is not offered
+    let target = best.target().to_utf8();
+    let target = target.trim_end_matches('.');
+    (!target.is_empty()).then(|| (target.to_string(), best.port()))
+}
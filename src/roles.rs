@@ -0,0 +1,204 @@
+//! Server-side moderation state: roles, bans, the message of the day, the
+//! room topic and slow mode.
+//!
+//! Roles live in `$HOME/key/roles`, one `name role` line per account that
+//! is not a plain member. They attach to account names, so they only take
+//! effect for clients that logged in (`require_login`); a name announced
+//! without logging in proves nothing and always gets `member`. Bans live in
+//! `$HOME/key/banned`, one name per line, the message of the day in
+//! `$HOME/key/motd`, the topic in `$HOME/key/topic` and the slow mode
+//! interval in seconds in `$HOME/key/slowmode`.
+//!
+//! The server reads the files once at startup into a `Store` and writes
+//! changes back from a thread of its own.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Mutex, mpsc};
+
+/// What a connection may do, in increasing order of privilege.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    #[default]
+    Member,
+    /// May kick and ban members and set the message of the day, topic and
+    /// slow mode.
+    Moderator,
+    /// May also promote and demote. The server console is always owner.
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "member" => Ok(Role::Member),
+            "moderator" => Ok(Role::Moderator),
+            "owner" => Ok(Role::Owner),
+            other => Err(format!("Unknown role {} (expected member, moderator or owner)", other)),
+        }
+    }
+}
+
+fn key_dir() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key", home)
+}
+
+/// Location of the role assignments.
+pub fn roles_path() -> String {
+    format!("{}/roles", key_dir())
+}
+
+/// Location of the banned names.
+pub fn banned_path() -> String {
+    format!("{}/banned", key_dir())
+}
+
+/// Location of the message of the day.
+pub fn motd_path() -> String {
+    format!("{}/motd", key_dir())
+}
+
+/// Location of the room topic.
+pub fn topic_path() -> String {
+    format!("{}/topic", key_dir())
+}
+
+/// Location of the slow mode interval.
+pub fn slow_mode_path() -> String {
+    format!("{}/slowmode", key_dir())
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => Ok(text.lines().map(str::to_string).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+    }
+}
+
+/// A text setting such as the message of the day or the topic, if set.
+pub fn read_text(path: &str) -> Result<Option<String>, String> {
+    let text = read_lines(path)?.join("\n");
+    Ok(Some(text).filter(|t| !t.trim().is_empty()))
+}
+
+/// A file to replace, queued for the writer thread, see `write_back`.
+pub struct Write {
+    pub path: String,
+    pub text: String,
+}
+
+impl Write {
+    /// Replace `path` with `lines`, one per line.
+    pub fn lines(path: &str, lines: impl IntoIterator<Item = String>) -> Self {
+        Write { path: path.to_string(), text: lines.into_iter().map(|line| format!("{}\n", line)).collect() }
+    }
+}
+
+/// Do the writes queued on `writes`, in order, until every sender is gone.
+/// Run it on a thread of its own, so the event loop never waits on the
+/// disk; failures go to `on_error`.
+pub fn write_back(writes: mpsc::Receiver<Write>, on_error: impl Fn(String)) {
+    for write in writes {
+        if let Some(dir) = std::path::Path::new(&write.path).parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        if let Err(e) = std::fs::write(&write.path, write.text) {
+            on_error(format!("Failed to write {}: {}", write.path, e));
+        }
+    }
+}
+
+/// Roles, bans and the message of the day, read once when the server
+/// starts. Changes apply at once and reach the files through `write_back`.
+pub struct Store {
+    roles: Mutex<Vec<(String, Role)>>,
+    banned: Mutex<Vec<String>>,
+    motd: Mutex<Option<String>>,
+    writes: mpsc::Sender<Write>,
+}
+
+impl Store {
+    /// Read the files, queueing later changes on `writes`.
+    pub fn load(writes: mpsc::Sender<Write>) -> Result<Self, String> {
+        let mut roles = Vec::new();
+        for line in read_lines(&roles_path())? {
+            let mut parts = line.split_whitespace();
+            if let (Some(name), Some(role)) = (parts.next(), parts.next()) {
+                roles.push((name.to_string(), role.parse()?));
+            }
+        }
+        let banned = read_lines(&banned_path())?.iter().map(|line| line.trim().to_string()).filter(|name| !name.is_empty()).collect();
+        Ok(Store { roles: Mutex::new(roles), banned: Mutex::new(banned), motd: Mutex::new(read_text(&motd_path())?), writes })
+    }
+
+    /// A store with nothing in it that never writes.
+    #[cfg(test)]
+    pub(crate) fn empty() -> Self {
+        Store { roles: Mutex::default(), banned: Mutex::default(), motd: Mutex::default(), writes: mpsc::channel().0 }
+    }
+
+    /// Role of the account `name`; accounts not listed are members.
+    pub fn role_of(&self, name: &str) -> Role {
+        self.roles.lock().unwrap().iter().find(|(n, _)| n == name).map_or(Role::Member, |(_, role)| *role)
+    }
+
+    /// Give the account `name` a role. Members are simply not listed.
+    pub fn set_role(&self, name: &str, role: Role) {
+        let mut roles = self.roles.lock().unwrap();
+        roles.retain(|(n, _)| n != name);
+        if role != Role::Member {
+            roles.push((name.to_string(), role));
+        }
+        self.queue(Write::lines(&roles_path(), roles.iter().map(|(name, role)| format!("{} {}", name, role))));
+    }
+
+    /// Whether `name` is banned.
+    pub fn is_banned(&self, name: &str) -> bool {
+        self.banned.lock().unwrap().iter().any(|n| n == name)
+    }
+
+    /// Ban `name`, or lift the ban.
+    pub fn set_banned(&self, name: &str, banned: bool) {
+        let mut names = self.banned.lock().unwrap();
+        names.retain(|n| n != name);
+        if banned {
+            names.push(name.to_string());
+        }
+        self.queue(Write::lines(&banned_path(), names.iter().cloned()));
+    }
+
+    /// The message of the day, if set.
+    pub fn motd(&self) -> Option<String> {
+        self.motd.lock().unwrap().clone()
+    }
+
+    /// Replace the message of the day; an empty text clears it.
+    pub fn set_motd(&self, text: &str) {
+        *self.motd.lock().unwrap() = Some(text.to_string()).filter(|t| !t.trim().is_empty());
+        self.write_text(&motd_path(), text);
+    }
+
+    /// Replace a text setting kept elsewhere in memory, such as the topic.
+    pub fn write_text(&self, path: &str, text: &str) {
+        self.queue(Write::lines(path, [text.to_string()]));
+    }
+
+    fn queue(&self, write: Write) {
+        // Nobody writes back in tests
+        let _ = self.writes.send(write);
+    }
+}
@@ -1,194 +1,1794 @@
 //! Server responsibilities:
 //! - accept TCP connections
-//! - run a lightweight handshake (plaintext HELLO, challenge-response)
-//! - spawn per-client reader threads
+//! - run a lightweight handshake (plaintext HELLO, stateless cookie, mutual
+//!   HMAC challenge-response)
+//! - serve all authenticated clients from a single mio event loop
 //! - broadcast messages received from the UI via an mpsc Receiver
 
 use std::sync::{Arc, Mutex, mpsc};
-use std::net::TcpListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::io::{self, IoSlice, Read, Write};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::thread;
-use std::time::Duration;
-use aes_gcm::Aes256Gcm;
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use bytes::{Buf, Bytes, BytesMut};
+use crate::frame::ProtocolError;
+use aes_gcm::{Aes256Gcm, KeyInit};
+use ed25519_dalek::SigningKey;
+use crate::identity::SignedRoom;
 use rand_core::RngCore;
-use crate::types::{SharedMessages, SharedClients};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::Deserialize;
+use crate::filters::FilterConfig;
+use crate::history::HistoryConfig;
+use crate::webhook::WebhookConfig;
+use crate::queue::{OverflowPolicy, Push, SendQueue};
+use crate::crypto::{Framing, Kind, Payload, SessionCipher, Side};
+use crate::types::{ClientEntry, SharedMessages, SharedClients, SharedStatus, Traffic, Transfer};
+use crate::transfer::{format_size, FileMeta};
+use crate::roles::Role;
 
-/// Start the server accept loop and internal worker threads.
+/// Settings for `antimpeu server`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Frames buffered per client before `overflow` applies.
+    pub queue_capacity: usize,
+    /// What to do with a client whose queue is full.
+    pub overflow: OverflowPolicy,
+    /// Queue depth at which a client is reported as lagging.
+    pub lag_warn: usize,
+    /// A client that accepts no data for this long is disconnected.
+    pub write_timeout_secs: u64,
+    /// Honour client requests for padded messages.
+    pub allow_padding: bool,
+    /// Make clients log in to an account (see `antimpeu server user`)
+    /// after the handshake.
+    pub require_login: bool,
+    /// Admit read-only observers (`antimpeu client --observe`).
+    pub allow_observers: bool,
+    /// Content filters applied to every chat message.
+    pub filters: FilterConfig,
+    /// Message history kept by the server.
+    pub history: HistoryConfig,
+    /// Largest file clients may share with `/send`, in MiB; 0 disables
+    /// file sharing.
+    pub max_file_mb: u64,
+    /// Tor control port used by `antimpeu server --onion`.
+    pub tor_control: String,
+    /// Password for the control port when Tor uses
+    /// `HashedControlPassword`; cookie authentication needs none.
+    pub tor_control_password: Option<String>,
+    /// Incoming webhook endpoint for integrations.
+    pub webhook: WebhookConfig,
+    /// Health probe for load balancers and uptime monitors.
+    pub health: crate::health::HealthConfig,
+    /// Most clients connected at once; 0 for no limit.
+    pub max_clients: usize,
+    /// How long to wait for clients while they connect and log in.
+    pub timeouts: crate::net::Timeouts,
+    /// Socket options for accepted connections, from the top-level
+    /// `[socket]` section rather than this one.
+    #[serde(skip)]
+    pub socket: crate::net::SocketConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { queue_capacity: 256, overflow: OverflowPolicy::DropOldest, lag_warn: 64, write_timeout_secs: 10, allow_padding: true, require_login: false, allow_observers: false, filters: FilterConfig::default(), history: HistoryConfig::default(), max_file_mb: 100, tor_control: "127.0.0.1:9051".to_string(), tor_control_password: None, webhook: WebhookConfig::default(), health: Default::default(), max_clients: 0, timeouts: crate::net::Timeouts::default(), socket: crate::net::SocketConfig::default() }
+    }
+}
+
+/// Relayed chat messages kept in memory for clients that missed some, see
+/// `Kind::Resend`.
+const RESEND_BACKLOG: usize = 500;
+
+/// Everything the threads of one running server share.
+struct Room {
+    /// What the server TUI shows.
+    messages: SharedMessages<crate::types::Message>,
+    status: SharedStatus,
+    clients: SharedClients,
+    /// Set when the room is signed. Everything the server originates is
+    /// signed with it, notifications included, and relayed messages are
+    /// checked against it for the server TUI.
+    signed: Option<SignedRoom>,
+    /// Content filters from `[server.filters]`.
+    filters: crate::filters::FilterChain,
+    /// Message history, when `[server.history]` is enabled.
+    history: Option<crate::history::History>,
+    /// Roles, bans and the message of the day.
+    store: crate::roles::Store,
+    /// Names remembered for the keys clients sign their joins with.
+    nicknames: crate::nicknames::Registry,
+    /// Sequence number of the next frame the server sends. Broadcast
+    /// frames are shared by all clients, so one counter covers every
+    /// connection.
+    next_seq: AtomicU64,
+    /// Held while frames are numbered and queued, so they reach every
+    /// queue in sequence order. Unlike the clients lock, serving clients
+    /// never waits on it.
+    sending: Mutex<()>,
+    /// Serial number of the next relayed chat message, see
+    /// `Payload::serial`.
+    next_serial: AtomicU64,
+    /// The last `RESEND_BACKLOG` relayed chat messages, oldest first.
+    backlog: Mutex<VecDeque<Payload<'static>>>,
+    /// Gets everything the TUI shows, for a program embedding the server.
+    events: Option<mpsc::Sender<crate::chat_server::Event>>,
+}
+
+impl Room {
+    /// Show a message in the chat pane of the server TUI.
+    fn show(&self, message: crate::types::Message) {
+        self.emit(&message, false);
+        self.messages.lock().unwrap().push(message);
+    }
+
+    /// Show a message in the chat pane for `ttl`.
+    fn show_ephemeral(&self, message: crate::types::Message, ttl: Duration) {
+        self.emit(&message, false);
+        crate::types::push_ephemeral(&self.messages, message, ttl);
+    }
+
+    /// Show a line in the event pane of the server TUI.
+    fn log(&self, text: String) {
+        let message = crate::types::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None };
+        self.emit(&message, true);
+        self.messages.lock().unwrap().push_event(message);
+    }
+
+    fn emit(&self, message: &crate::types::Message, event: bool) {
+        if let Some(events) = &self.events {
+            let _ = events.send(crate::chat_server::Event::from_message(message, event));
+        }
+    }
+}
+
+/// Queue an encrypted frame for every connected client except `skip`.
+/// Chat is numbered with a serial and kept in the backlog first, except
+/// ephemeral messages, which must not outlive their TTL in a resend, and
+/// connection notices.
+///
+/// The frame is encrypted for each client with its own session key,
+/// outside the clients lock; the event loop does the actual socket writes,
+/// so this never blocks on a slow client. Clients whose queue reaches the
+/// lag threshold are reported in the server TUI. A relayed payload keeps
+/// the original sender's signature.
+fn broadcast(room: &Room, payload: &Payload, skip: Option<&str>) {
+    let recipients = recipients(room, |addr| skip != Some(addr));
+    let mut lagging = Vec::new();
+    {
+        let _sending = room.sending.lock().unwrap();
+        let seq = room.next_seq.fetch_add(1, Ordering::SeqCst);
+        let _span = tracing::debug_span!("broadcast", kind = ?payload.kind, seq).entered();
+        let numbered;
+        let kept = payload.kind == Kind::Chat && payload.ttl.is_none() && !is_connection_notice(&payload.username, &payload.text);
+        let payload = if kept {
+            numbered = Payload { serial: Some(room.next_serial.fetch_add(1, Ordering::SeqCst)), ..payload.clone().into_owned() };
+            let mut backlog = room.backlog.lock().unwrap();
+            if backlog.len() == RESEND_BACKLOG {
+                backlog.pop_front();
+            }
+            backlog.push_back(numbered.clone());
+            &numbered
+        } else {
+            payload
+        };
+        for (addr, cipher, framing, queue) in &recipients {
+            let frame = crate::crypto::encrypt_frame(payload, cipher, seq, *framing);
+            if let Push::Lagging(depth) = queue.push(frame) {
+                tracing::warn!(peer = %addr, depth, "client is lagging");
+                lagging.push(format!("{} is lagging ({} frames queued)", addr, depth));
+            }
+        }
+        tracing::trace!(recipients = recipients.len(), "queued");
+    }
+    for text in lagging {
+        room.log(text);
+    }
+}
+
+/// What it takes to queue frames for the clients whose address passes
+/// `filter`, copied so the clients lock is not held while encrypting.
+fn recipients(room: &Room, filter: impl Fn(&str) -> bool) -> Vec<(String, SessionCipher, crate::crypto::Framing, Arc<SendQueue>)> {
+    let conns = room.clients.lock().unwrap();
+    conns.iter().filter(|(addr, _)| filter(addr)).map(|(addr, c)| (addr.clone(), c.cipher.clone(), c.framing, c.queue.clone())).collect()
+}
+
+/// Start of the notice broadcast when a client connects, followed by its
+/// address; `tail --json` turns it into a join event.
+pub const JOIN_NOTICE: &str = "New connection from ";
+/// Start of the notice broadcast when a client disconnects.
+pub const LEAVE_NOTICE: &str = "Disconnected from ";
+/// Start of the notice broadcast when a client fails the handshake or login.
+pub const REFUSED_NOTICE: &str = "Refused connection from ";
+
+/// Whether a chat message from `sender` is one of the server's join, leave
+/// or refusal notices, which clients show as system events.
+pub fn is_connection_notice(sender: &str, text: &str) -> bool {
+    sender == "Server" && [JOIN_NOTICE, LEAVE_NOTICE, REFUSED_NOTICE].iter().any(|n| text.starts_with(n))
+}
+
+/// Show a System line in the server TUI and broadcast it to all clients.
+fn notify(room: &Room, text: &str) {
+    room.log(text.to_string());
+    let signature = room.signed.as_ref().map(|r| r.sign("Server", text));
+    broadcast(room, &Payload::chat("Server", text, signature.as_deref()), None);
+}
+
+/// Queue a frame for a single client.
+fn send_to(room: &Room, addr: &str, payload: &Payload) {
+    if let Some((_, cipher, framing, queue)) = recipients(room, |a| a == addr).pop() {
+        let _sending = room.sending.lock().unwrap();
+        let seq = room.next_seq.fetch_add(1, Ordering::SeqCst);
+        queue.push(crate::crypto::encrypt_frame(payload, &cipher, seq, framing));
+    }
+}
+
+/// Format a duration as a short idle time such as `42s`, `5m` or `3h`.
+fn format_idle(idle: Duration) -> String {
+    let secs = idle.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+/// Current member list, one line per connected client, sorted by name.
+fn roster(clients: &SharedClients) -> Vec<String> {
+    let conns = clients.lock().unwrap();
+    let mut members: Vec<_> = conns
+        .iter()
+        .map(|(addr, c)| (c.username.clone().unwrap_or_else(|| addr.clone()), c.joined, c.last_active.elapsed(), c.observer, c.rtt, c.traffic.totals()))
+        .collect();
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut lines = vec![format!("{} connected:", members.len())];
+    for (name, joined, idle, observer, rtt, (sent, received)) in members {
+        let rtt = rtt.map(|rtt| format!("  {}{}", rtt.label(), if rtt.spiking() { " (spiking)" } else { "" })).unwrap_or_default();
+        let mode = if observer { "  (read-only)" } else { "" };
+        let traffic = format!("  in {}  out {}", format_size(received), format_size(sent));
+        lines.push(format!("  {}  joined {}  idle {}{}{}{}", name, joined.format("%H:%M"), format_idle(idle), rtt, traffic, mode));
+    }
+    lines
+}
+
+/// Bytes exchanged with each connected client, busiest first, with the
+/// average rate since it joined, and the chat messages it sent.
+fn stats(clients: &SharedClients, status: &SharedStatus) -> Vec<String> {
+    let conns = clients.lock().unwrap();
+    let mut members: Vec<_> = conns
+        .iter()
+        .map(|(addr, c)| (c.username.clone().unwrap_or_else(|| addr.clone()), c.traffic.totals(), (chrono::Local::now() - c.joined).num_seconds().max(1) as u64, &c.messages))
+        .collect();
+    members.sort_by_key(|(_, (sent, received), _, _)| std::cmp::Reverse(sent + received));
+    let (sent, received) = members.iter().fold((0, 0), |(s, r), (_, (sent, received), _, _)| (s + sent, r + received));
+    let total: u64 = members.iter().map(|m| m.3.total).sum();
+    let per_minute: usize = members.iter().map(|m| m.3.per_minute()).sum();
+    let peak = members.iter().map(|m| m.3.peak).max().unwrap_or(0);
+    let slow_mode = match status.lock().unwrap().slow_mode {
+        Some(slow) => format!("slow mode allows one every {}s", slow.as_secs()),
+        None => "slow mode is off".to_string(),
+    };
+    let mut lines = vec![
+        format!("Traffic of {} connected: in {}  out {}", members.len(), format_size(received), format_size(sent)),
+        format!("Messages: {} in total, {} in the last minute; the busiest member peaked at {} a minute, {}", total, per_minute, peak, slow_mode),
+    ];
+    for (name, (sent, received), secs, messages) in members {
+        lines.push(format!(
+            "  {}  in {} ({}/s)  out {} ({}/s)  {} messages, {} in the last minute, peak {}/min",
+            name, format_size(received), format_size(received / secs), format_size(sent), format_size(sent / secs), messages.total, messages.per_minute(), messages.peak,
+        ));
+    }
+    lines
+}
+
+/// Lowest role allowed to run each gated command.
+fn required_role(command: &str) -> Role {
+    match command {
+        "kick" | "ban" | "unban" | "stats" => Role::Moderator,
+        "promote" | "demote" => Role::Owner,
+        _ => Role::Member,
+    }
+}
+
+/// What follows the name of `command`, trimmed.
+fn command_args(command: &str) -> &str {
+    command.trim_start().split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim())
+}
+
+/// Slash commands of `run_command`, for the F1 help.
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("/who", "list connected members with join, idle, traffic and round-trip time"),
+    ("/stats", "traffic and message rates of each member, busiest first (moderators)"),
+    ("/nick <name>", "change the name you are known by; remembered for your key"),
+    ("/motd [text]", "show the message of the day; moderators set it"),
+    ("/topic [text]", "show the room topic; moderators set it"),
+    ("/slow [seconds|off]", "show slow mode; moderators set it"),
+    ("/kick <name>", "disconnect a member (moderators)"),
+    ("/ban <name>, /unban <name>", "keep a member out, or let them back (moderators)"),
+    ("/promote <name> [role]", "make an account moderator or owner (owners)"),
+    ("/demote <name>", "make an account a member again (owners)"),
+];
+
+/// Run a slash command (without the slash) for `actor`, which holds `role`;
+/// the server console runs commands as `Server` with the owner role.
+/// Returns the lines to show the actor; effects on the room are announced
+/// with `notify`.
+fn run_command(room: &Room, actor: &str, role: Role, command: &str) -> Vec<String> {
+    let (status, clients) = (&room.status, &room.clients);
+    let mut args = command.split_whitespace();
+    let name = args.next().unwrap_or_default();
+    if role < required_role(name) {
+        return vec![format!("/{} needs the {} role", name, required_role(name))];
+    }
+    let result = match name {
+        "who" => return roster(clients),
+        "stats" => return stats(clients, status),
+        "nick" => Err("The server console always speaks under its own name".to_string()),
+        "motd" => {
+            let text = command_args(command);
+            if text.is_empty() {
+                Ok(room.store.motd().unwrap_or_else(|| "No message of the day".to_string()))
+            } else if role < Role::Moderator {
+                Err("Setting the message of the day needs the moderator role".to_string())
+            } else {
+                room.store.set_motd(text);
+                notify(room, &format!("{} set the message of the day: {}", actor, text));
+                Ok("Message of the day updated".to_string())
+            }
+        }
+        "topic" => {
+            let text = command_args(command);
+            if text.is_empty() {
+                Ok(status.lock().unwrap().topic.clone().unwrap_or_else(|| "No topic set".to_string()))
+            } else if role < Role::Moderator {
+                Err("Setting the topic needs the moderator role".to_string())
+            } else {
+                room.store.write_text(&crate::roles::topic_path(), text);
+                status.lock().unwrap().topic = Some(text.to_string());
+                let line = format!("{} changed the topic to: {}", actor, text);
+                room.show(crate::types::Message { sender: "System".to_string(), text: line, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                broadcast(room, &Payload::control(Kind::Topic, actor, text), None);
+                Ok("Topic updated".to_string())
+            }
+        }
+        "slow" => match args.next() {
+            None => Ok(match status.lock().unwrap().slow_mode {
+                Some(slow) => format!("Slow mode: one message every {}s", slow.as_secs()),
+                None => "Slow mode is off".to_string(),
+            }),
+            Some(_) if role < Role::Moderator => Err("Setting slow mode needs the moderator role".to_string()),
+            Some(arg) => match if arg == "off" { Ok(0) } else { arg.parse::<u64>() } {
+                Err(_) => Err("Usage: /slow <seconds|off>".to_string()),
+                Ok(secs) => {
+                    room.store.write_text(&crate::roles::slow_mode_path(), &secs.to_string());
+                    status.lock().unwrap().slow_mode = Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+                    let line = if secs == 0 { format!("{} turned slow mode off", actor) } else { format!("{} set slow mode to one message every {}s", actor, secs) };
+                    room.show(crate::types::Message { sender: "System".to_string(), text: line, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                    broadcast(room, &Payload::control(Kind::SlowMode, actor, &secs.to_string()), None);
+                    Ok("Slow mode updated".to_string())
+                }
+            },
+        },
+        "kick" | "ban" | "unban" => match args.next() {
+            None => Err(format!("Usage: /{} <name>", name)),
+            Some(target) => moderate(room, actor, role, name, target),
+        },
+        "promote" | "demote" => match args.next() {
+            None => Err(format!("Usage: /{} <name>{}", name, if name == "promote" { " [moderator|owner]" } else { "" })),
+            Some(target) => {
+                let new_role = match (name, args.next()) {
+                    ("demote", _) => Ok(Role::Member),
+                    (_, None) => Ok(Role::Moderator),
+                    (_, Some(r)) => r.parse(),
+                };
+                new_role.map(|new_role| {
+                    room.store.set_role(target, new_role);
+                    for client in clients.lock().unwrap().values_mut() {
+                        if client.account.as_deref() == Some(target) {
+                            client.role = new_role;
+                        }
+                    }
+                    notify(room, &format!("{} made {} {}", actor, target, new_role));
+                    format!("{} is now {}", target, new_role)
+                })
+            }
+        },
+        other => Err(format!("Unknown command /{}", other)),
+    };
+    vec![result.unwrap_or_else(|e| e)]
+}
+
+/// The join frame of the client at `peer`, announcing `claimed` with the
+/// `signature`, if any: settle its name and send it the message of the
+/// day, the room settings and the recent history. Only the first join of a
+/// connection counts; later ones are ignored, so they cannot replay the
+/// history again and again.
+fn join(room: &Room, peer: &str, claimed: &str, signature: Option<&str>) {
+    let local_name = room.status.lock().unwrap().name.clone().unwrap_or_default();
+    let mut conns = room.clients.lock().unwrap();
+    match conns.get_mut(peer) {
+        Some(client) if !client.greeted => client.greeted = true,
+        _ => return,
+    }
+    let Some(renamed) = assign_name(room, &mut conns, peer, &local_name, claimed, signature) else { return };
+    let Some(client) = conns.get_mut(peer) else { return };
+    let name = client.username.clone().unwrap_or_default();
+    if room.store.is_banned(&name) {
+        client.queue.close();
+        return;
+    }
+    let (signed, user_id) = (client.identity.is_some(), client.user_id.clone());
+    drop(conns);
+    // clients that signed their join learn their name and ID
+    match renamed {
+        _ if signed => send_to(room, peer, &Payload { user: Some(user_id.into()), ..Payload::control(Kind::Nick, &name, &renamed.unwrap_or_default()) }),
+        Some(notice) if !notice.is_empty() => tell(room, peer, &[notice]),
+        _ => {}
+    }
+    if let Some(motd) = room.store.motd() {
+        tell(room, peer, &[format!("Message of the day: {}", motd)]);
+    }
+    if let Some(topic) = room.status.lock().unwrap().topic.clone() {
+        send_to(room, peer, &Payload::control(Kind::Topic, "", &topic));
+    }
+    if let Some(slow) = room.status.lock().unwrap().slow_mode {
+        send_to(room, peer, &Payload::control(Kind::SlowMode, "", &slow.as_secs().to_string()));
+    }
+    if let Some(history) = room.history.as_ref() {
+        for past in history.recent(history.config().replay) {
+            send_to(room, peer, &past);
+        }
+    }
+}
+
+/// A slash command (without the slash) from the client at `peer`.
+fn command(room: &Room, peer: &str, command: &str) {
+    if command.split_whitespace().next() == Some("nick") {
+        return nick(room, peer, command_args(command));
+    }
+    let (actor, role) = match room.clients.lock().unwrap().get(peer) {
+        Some(c) => (c.username.clone().unwrap_or_else(|| peer.to_string()), c.role),
+        None => return,
+    };
+    let lines = run_command(room, &actor, role, command);
+    tell(room, peer, &lines);
+}
+
+/// `/nick` from the client at `peer`: rename it and tell the room.
+fn nick(room: &Room, peer: &str, name: &str) {
+    let local_name = room.status.lock().unwrap().name.clone().unwrap_or_default();
+    match rename(room, peer, &local_name, name) {
+        Ok((old, remembered)) => {
+            if remembered {
+                let user = room.clients.lock().unwrap().get(peer).map(|c| Cow::Owned(c.user_id.clone()));
+                send_to(room, peer, &Payload { user, ..Payload::control(Kind::Nick, name, "") });
+            } else {
+                tell(room, peer, &[format!("You are now known as {}; this client does not sign its join, so the name is not remembered", name)]);
+            }
+            notify(room, &format!("{} is now known as {}", old, name));
+        }
+        Err(e) => tell(room, peer, &[e]),
+    }
+}
+
+/// Give the client at `peer` the name `name`, remembering it for its key if
+/// it signed its join. Returns the old name and whether the new one was
+/// remembered.
+fn rename(room: &Room, peer: &str, local_name: &str, name: &str) -> Result<(String, bool), String> {
+    crate::nicknames::validate(name)?;
+    if room.store.is_banned(name) {
+        return Err(format!("{} is banned", name));
+    }
+    let mut conns = room.clients.lock().unwrap();
+    let client = conns.get_mut(peer).ok_or("Not connected")?;
+    if client.account.is_some() {
+        return Err("Logged-in members speak under their account name".to_string());
+    }
+    if client.username.as_deref() == Some(name) {
+        return Err(format!("You are already known as {}", name));
+    }
+    let identity = client.identity.clone();
+    if free_name(&conns, peer, identity.as_deref(), local_name, name) != name {
+        return Err(format!("{} is taken", name));
+    }
+    let client = conns.get_mut(peer).ok_or("Not connected")?;
+    if let Some(key) = &client.identity {
+        room.nicknames.remember(key, name);
+    }
+    let old = client.username.replace(name.to_string()).unwrap_or_else(|| peer.to_string());
+    Ok((old, client.identity.is_some()))
+}
+
+/// Settle the name of the client at `peer`, which announced `claimed` with
+/// the join `signature`, if any: the account name for a logged-in client;
+/// for one known by its key, from the handshake or the signature, the name
+/// remembered for that key, else the name `authorized_clients` lists it
+/// under or the announced one, which is then remembered; for the rest the
+/// announced name. A name somebody else is using gets a number. Returns
+/// None if the client is gone, else, if the name differs from the announced
+/// one, why (empty when the server simply remembers another).
+fn assign_name(room: &Room, conns: &mut HashMap<String, ClientEntry>, peer: &str, local_name: &str, claimed: &str, signature: Option<&str>) -> Option<Option<String>> {
+    let client = conns.get_mut(peer)?;
+    if let Some(account) = &client.account {
+        client.username = Some(account.clone());
+        return Some(None);
+    }
+    if client.identity.is_none() {
+        client.identity = signature.and_then(|s| crate::identity::verify_join(s, &client.challenge, claimed));
+    }
+    let identity = client.identity.clone();
+    if let Some(key) = &identity {
+        client.user_id = crate::nicknames::user_id(None, Some(key));
+    }
+    let mut name = client.username.clone().unwrap_or_else(|| claimed.to_string());
+    if let Some(key) = &identity {
+        match room.nicknames.lookup(key) {
+            Some(known) => name = known,
+            None => room.nicknames.remember(key, &name),
+        }
+    }
+    let mut renamed = Some(String::new()).filter(|_| name != claimed);
+    let free = free_name(conns, peer, identity.as_deref(), local_name, &name);
+    if free != name {
+        renamed = Some(format!("{} is taken here, so you are {} for now; /nick changes it", name, free));
+        name = free;
+    }
+    conns.get_mut(peer)?.username = Some(name);
+    Some(renamed)
+}
+
+/// `name`, or if somebody else uses it, the first of `name_2`, `name_3`...
+/// that nobody does, for the client at `peer` signed in with `identity`.
+/// Other sessions of the same key share a name; the server console's name
+/// `local_name` and the names the server speaks under are always taken.
+fn free_name(conns: &HashMap<String, ClientEntry>, peer: &str, identity: Option<&str>, local_name: &str, name: &str) -> String {
+    let taken = |candidate: &str| {
+        [local_name, "Server", "System"].contains(&candidate)
+            || conns.iter().any(|(addr, c)| addr != peer && c.username.as_deref() == Some(candidate) && (identity.is_none() || c.identity.as_deref() != identity))
+    };
+    if !taken(name) {
+        return name.to_string();
+    }
+    (2..).map(|n| format!("{}_{}", name, n)).find(|candidate| !taken(candidate)).unwrap()
+}
+
+/// Kick, ban or unban `target`. Moderators may only act on members; owners
+/// on anybody.
+fn moderate(room: &Room, actor: &str, role: Role, action: &str, target: &str) -> Result<String, String> {
+    let target_role = room.store.role_of(target);
+    if target_role >= role && role != Role::Owner {
+        return Err(format!("You cannot {} {} ({})", action, target, target_role));
+    }
+    match action {
+        "unban" => {
+            room.store.set_banned(target, false);
+            notify(room, &format!("{} unbanned {}", actor, target));
+            return Ok(format!("Unbanned {}", target));
+        }
+        "ban" => room.store.set_banned(target, true),
+        _ => {}
+    }
+    let online = room.clients.lock().unwrap().values().any(|c| c.username.as_deref() == Some(target));
+    if action == "kick" && !online {
+        return Err(format!("{} is not connected", target));
+    }
+    // Announce first, so the target still gets the notice before its queue closes
+    let (verb, done) = if action == "ban" { ("banned", "Banned") } else { ("kicked", "Kicked") };
+    notify(room, &format!("{} was {} by {}", target, verb, actor));
+    for client in room.clients.lock().unwrap().values() {
+        if client.username.as_deref() == Some(target) {
+            client.queue.close_when_drained();
+        }
+    }
+    Ok(format!("{} {}", done, target))
+}
+
+/// Send the client at `peer` the relayed chat it missed, given as the first
+/// and last serial in `range`, from the backlog, and say how many of them
+/// are no longer there.
+fn resend(room: &Room, peer: &str, range: &str) {
+    let newest = room.next_serial.load(Ordering::SeqCst).saturating_sub(1);
+    let Some(range) = resend_range(range, newest) else { return };
+    let found: Vec<_> = room.backlog.lock().unwrap().iter().filter(|p| p.serial.is_some_and(|s| range.contains(&s))).cloned().collect();
+    for payload in &found {
+        send_to(room, peer, payload);
+    }
+    let lost = (range.end() - range.start() + 1) as usize - found.len();
+    if lost > 0 {
+        tell(room, peer, &[format!("{} missed messages are no longer available", lost)]);
+    }
+}
+
+/// The serials a `Kind::Resend` asks for, given as the first and last in
+/// `range`: None when it is malformed, reversed or entirely past `newest`,
+/// the last serial relayed. At most `RESEND_BACKLOG` serials ending no later
+/// than `newest`, since nothing else can be in the backlog.
+fn resend_range(range: &str, newest: u64) -> Option<std::ops::RangeInclusive<u64>> {
+    let (first, last) = range.split_once(' ')?;
+    let (first, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+    if last < first || first > newest {
+        return None;
+    }
+    let last = last.min(newest).min(first.saturating_add(RESEND_BACKLOG as u64 - 1));
+    Some(first..=last)
+}
+
+/// Run a chat message from the client at `peer` through the content
+/// filters, then show, record and relay it and acknowledge it to the
+/// sender. In a signed room a filter that would change the text drops the
+/// message instead, since the sender's signature would no longer match.
+fn relay(room: &Room, peer: &str, mut m: Payload<'static>) {
+    let id = m.id.clone().unwrap_or_default();
+    let outcome = room.filters.apply(&m.username, &m.text);
+    for line in outcome.log {
+        room.log(line);
+    }
+    match outcome.text {
+        Ok(text) if text != m.text && room.signed.is_some() => {
+            room.log(format!("Dropped the message from {} instead, as censoring it would break its signature", m.username));
+            acknowledge(room, peer, &id, Some("Your message was not delivered: a filter would have changed it, which signed rooms do not allow"));
+            return;
+        }
+        Ok(text) => m.text = text.into(),
+        Err(reason) => {
+            acknowledge(room, peer, &id, Some(&format!("Your message was not delivered: {}", reason)));
+            return;
+        }
+    }
+
+    // push into server TUI
+    let verified = room.signed.as_ref().map(|r| r.verify(&m.username, &m.text, m.signature.as_deref()));
+    let message = crate::types::Message { sender: m.username.to_string(), text: m.text.to_string(), time: chrono::Utc::now(), verified, expires: None, user: m.user.as_ref().map(|u| u.to_string()), id: m.id.as_ref().map(|id| id.to_string()) };
+    match m.ttl {
+        Some(ttl) => room.show_ephemeral(message, Duration::from_secs(ttl)),
+        None => room.show(message),
+    }
+    record(room, &m);
+
+    // broadcast to everybody, the sender too: its other sessions
+    // need the message and the sending one drops it by its ID
+    broadcast(room, &m, None);
+    acknowledge(room, peer, &id, None);
+}
+
+/// Append a relayed chat message to the history, if it is kept. Ephemeral
+/// messages never are.
+fn record(room: &Room, payload: &Payload) {
+    if payload.ttl.is_some() {
+        return;
+    }
+    if let Some(Err(e)) = room.history.as_ref().map(|h| h.append(payload)) {
+        room.log(e);
+    }
+}
+
+/// Relay `text` from the webhook integration `name` like a client's chat
+/// message; the error says why the filters dropped it.
+fn post_integration(room: &Room, name: &str, text: &str) -> Result<(), String> {
+    let outcome = room.filters.apply(name, text);
+    for line in outcome.log {
+        room.log(line);
+    }
+    let text = outcome.text?;
+    let signature = room.signed.as_ref().map(|r| r.sign(name, &text));
+    let payload = Payload::chat(name, &text, signature.as_deref());
+    room.show(crate::types::Message { sender: name.to_string(), text: text.clone(), time: chrono::Utc::now(), verified: signature.as_ref().map(|_| true), expires: None, user: None, id: None });
+    record(room, &payload);
+    broadcast(room, &payload, None);
+    Ok(())
+}
+
+/// Queue a server notice for a single client.
+fn tell(room: &Room, addr: &str, lines: &[String]) {
+    send_to(room, addr, &Payload::control(Kind::Reply, "Server", &lines.join("\n")));
+}
+
+/// Answer a chat message with ID `id` from the client at `addr`: with an
+/// `ack` if it asked for them, else only a refusal, as a reply.
+fn acknowledge(room: &Room, addr: &str, id: &str, refusal: Option<&str>) {
+    let acks = room.clients.lock().unwrap().get(addr).is_some_and(|c| c.acks);
+    match refusal {
+        _ if acks => send_to(room, addr, &Payload { id: Some(id.into()), ..Payload::control(Kind::Ack, "Server", refusal.unwrap_or_default()) }),
+        Some(reason) => tell(room, addr, &[reason.to_string()]),
+        None => {}
+    }
+}
+
+/// Files shared through the server, see `transfer`. Complete files are
+/// stored by hash in `transfer::server_dir()`; uploads in progress live
+/// next to them as `<hash>.part` and survive a dropped connection.
+struct Files {
+    /// Uploads in progress by peer and hash.
+    uploads: HashMap<(String, String), FileMeta>,
+    /// Largest file accepted, in bytes; 0 disables sharing.
+    max_size: u64,
+}
+
+impl Files {
+    fn new(config: &ServerConfig) -> Self {
+        Self { uploads: HashMap::new(), max_size: config.max_file_mb.saturating_mul(1 << 20) }
+    }
+
+    /// Handle a `file-*` frame from `peer`.
+    fn handle(&mut self, room: &Room, peer: &str, m: Payload) {
+        let status = &room.status;
+        let Some(meta) = m.file.clone() else { return };
+        if let Err(e) = meta.validate() {
+            tell(room, peer, &[format!("File transfer refused: {}", e)]);
+            return;
+        }
+        let (name, observer) = match room.clients.lock().unwrap().get(peer) {
+            Some(c) => (c.username.clone().unwrap_or_else(|| peer.to_string()), c.observer),
+            None => return,
+        };
+        let key = format!("{} {}", peer, meta.hash);
+        let stored = crate::transfer::server_dir().join(&meta.hash);
+        let part = crate::transfer::server_dir().join(format!("{}.part", meta.hash));
+        match m.kind {
+            Kind::FileOffer => {
+                if observer {
+                    tell(room, peer, &["This connection is read-only; your file was not shared.".to_string()]);
+                } else if self.max_size == 0 {
+                    tell(room, peer, &["File sharing is disabled on this server.".to_string()]);
+                } else if meta.size == 0 || meta.size > self.max_size {
+                    tell(room, peer, &[format!("{} was not shared: files must be 1 B to {}.", meta.name, crate::transfer::format_size(self.max_size))]);
+                } else if stored.exists() {
+                    self.announce(room, &name, &meta);
+                } else if self.uploads.keys().any(|(p, hash)| *hash == meta.hash && p != peer) {
+                    tell(room, peer, &[format!("{} is already being uploaded; try again later.", meta.name)]);
+                } else {
+                    let done = crate::transfer::received(&part);
+                    room.log(format!("Receiving {} ({}) from {}", meta.name, crate::transfer::format_size(meta.size), name));
+                    status.lock().unwrap().transfers.insert(key, Transfer { name: meta.name.clone(), size: meta.size, done, upload: false });
+                    self.uploads.insert((peer.to_string(), meta.hash.clone()), meta.clone());
+                    send_to(room, peer, &Payload { file: Some(meta.at(done)), ..Payload::control(Kind::FileRequest, "Server", "") });
+                }
+            }
+            Kind::FileChunk => {
+                let Some(upload) = self.uploads.get(&(peer.to_string(), meta.hash.clone())).cloned() else { return };
+                let data = hex::decode(m.text.as_bytes()).map_err(|_| "invalid chunk encoding".to_string());
+                let result = data.and_then(|data| crate::transfer::append_chunk(&part, &upload.at(meta.offset), &data));
+                let done = match result {
+                    Ok(done) => done,
+                    Err(e) => {
+                        self.uploads.remove(&(peer.to_string(), meta.hash.clone()));
+                        status.lock().unwrap().transfers.remove(&key);
+                        room.log(format!("Upload of {} from {} failed: {}", upload.name, name, e));
+                        tell(room, peer, &[format!("Upload of {} failed: {}", upload.name, e)]);
+                        return;
+                    }
+                };
+                if done < upload.size {
+                    if let Some(t) = status.lock().unwrap().transfers.get_mut(&key) {
+                        t.done = done;
+                    }
+                    send_to(room, peer, &Payload { file: Some(upload.at(done)), ..Payload::control(Kind::FileRequest, "Server", "") });
+                    return;
+                }
+                self.uploads.remove(&(peer.to_string(), meta.hash.clone()));
+                status.lock().unwrap().transfers.remove(&key);
+                match crate::transfer::finish(&part, &upload, &stored) {
+                    Ok(()) => self.announce(room, &name, &upload),
+                    Err(e) => {
+                        room.log(format!("Upload from {} failed: {}", name, e));
+                        tell(room, peer, &[format!("Upload failed: {}", e)]);
+                    }
+                }
+            }
+            Kind::FileRequest => {
+                let Ok(size) = std::fs::metadata(&stored).map(|m| m.len()) else {
+                    tell(room, peer, &[format!("{} is not available on this server.", meta.name)]);
+                    return;
+                };
+                let meta = FileMeta { size, ..meta };
+                if meta.offset >= size {
+                    return;
+                }
+                let data = match crate::transfer::read_chunk(&stored, &meta) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        room.log(e);
+                        return;
+                    }
+                };
+                let done = meta.offset + data.len() as u64;
+                let text = hex::encode(&data);
+                send_to(room, peer, &Payload { file: Some(meta.clone()), ..Payload::control(Kind::FileChunk, "Server", &text) });
+                let mut status = status.lock().unwrap();
+                if done < size {
+                    status.transfers.insert(key, Transfer { name: meta.name.clone(), size, done, upload: true });
+                } else {
+                    status.transfers.remove(&key);
+                    drop(status);
+                    room.log(format!("Sent {} to {}", meta.name, name));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Tell everybody, the uploader included, that a file is available.
+    fn announce(&self, room: &Room, by: &str, meta: &FileMeta) {
+        room.log(format!("{} shared {} ({})", by, meta.name, crate::transfer::format_size(meta.size)));
+        broadcast(room, &Payload { file: Some(meta.at(0)), ..Payload::control(Kind::FileOffer, by, "") }, None);
+    }
+
+    /// Forget the uploads of a client that disconnected; their `.part`
+    /// files stay for when it offers the file again.
+    fn disconnected(&mut self, status: &SharedStatus, peer: &str) {
+        self.uploads.retain(|(p, _), _| p != peer);
+        let prefix = format!("{} ", peer);
+        status.lock().unwrap().transfers.retain(|key, _| !key.starts_with(&prefix));
+    }
+}
+
+/// Window in which a connection cookie stays valid. A cookie is accepted in
+/// the window it was issued in and the following one.
+const COOKIE_WINDOW_SECS: u64 = 30;
+/// Cookie size in bytes (a truncated HMAC-SHA256).
+const COOKIE_LEN: usize = 16;
+
+/// Stateless connection cookies, in the spirit of DTLS HelloVerifyRequest.
+///
+/// A HELLO without a valid cookie gets `COOKIE:<hex>` and the connection is
+/// closed straight away, so a flood of connections costs one HMAC each and
+/// never reaches the challenge-response or the server TUI.
+struct Cookies {
+    secret: [u8; 32],
+}
+
+impl Cookies {
+    fn new() -> Self {
+        let mut secret = [0u8; 32];
+        aes_gcm::aead::OsRng.fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    fn mac(&self, ip: IpAddr, window: u64) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(ip.to_string().as_bytes());
+        mac.update(&window.to_be_bytes());
+        mac
+    }
+
+    fn window() -> u64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) / COOKIE_WINDOW_SECS
+    }
+
+    /// Cookie for a client connecting from `ip`. The port is left out since
+    /// the client reconnects from a new one.
+    fn issue(&self, ip: IpAddr) -> String {
+        hex::encode(&self.mac(ip, Self::window()).finalize().into_bytes()[..COOKIE_LEN])
+    }
+
+    fn check(&self, ip: IpAddr, cookie: &str) -> bool {
+        let Ok(cookie) = hex::decode(cookie) else { return false };
+        if cookie.len() != COOKIE_LEN {
+            return false;
+        }
+        let now = Self::window();
+        [now, now.saturating_sub(1)].iter().any(|&w| self.mac(ip, w).verify_truncated_left(&cookie).is_ok())
+    }
+}
+
+/// Long-term secrets the server authenticates itself with.
+pub struct ServerKeys {
+    /// The group key, wrapped to clients that join without `dek.bin`.
+    pub dek: [u8; 32],
+    /// HMAC key for the challenge-response, derived from the DEK.
+    pub handshake: [u8; 32],
+    /// Identity key clients pin on first connect.
+    pub identity: SigningKey,
+    /// Message signing key when the room is signed.
+    pub room: Option<SignedRoom>,
+}
+
+/// What the challenge-response established about a client.
+struct Authenticated {
+    /// Our challenge, which the client's join signature covers.
+    challenge: [u8; 32],
+    /// For a client that asked for the group key: the name its key is
+    /// listed under in `authorized_clients`, and the key in hex.
+    authorized: Option<(String, String)>,
+    /// The connection's message key, see `auth::derive_session_key`.
+    session_key: [u8; 32],
+}
+
+/// Run the challenge-response with a client that presented a valid cookie.
+///
+/// `caps` are the capabilities accepted for this connection, echoed after
+/// the challenge. A client holding the DEK answers with an HMAC; a client
+/// without it answers with `KEY:` and a request signed by its personal key,
+/// and if that key is authorized the group key is wrapped to it in a
+/// KEYWRAP message signed with the server identity. Either way the server
+/// then proves itself in turn.
+/// Returns the reason a client was refused.
+fn authenticate(stream: &mut TcpStream, peer: &str, keys: &ServerKeys, caps: &[&str], timeout: Duration) -> Result<Authenticated, String> {
+    let mut challenge = [0u8; 32];
+    aes_gcm::aead::OsRng.fill_bytes(&mut challenge);
+    let mut challenge_msg = format!("CHAL:{} {}", hex::encode(challenge), peer);
+    for cap in caps {
+        challenge_msg.push(' ');
+        challenge_msg.push_str(cap);
+    }
+    // send plaintext length-prefixed challenge
+    stream.set_read_timeout(None).ok();
+    crate::net::write_plain(stream, challenge_msg.as_bytes()).map_err(|_| "handshake write failed".to_string())?;
+    tracing::debug!(caps = ?caps, "challenge sent");
+
+    // wait for the response and the client's own challenge within timeout
+    stream.set_read_timeout(Some(timeout)).ok();
+    let reply = crate::net::read_plain(stream).map_err(|e| format!("handshake failed: {}", e))?;
+    let reply = String::from_utf8(reply).unwrap_or_default();
+    let mut authorized = None;
+    let client_challenge = if let Some(request) = reply.strip_prefix("KEY:") {
+        let mut parts = request.split(' ');
+        let public = parts.next().unwrap_or_default();
+        let wrap_public: [u8; 32] = hex::decode(parts.next().unwrap_or_default()).ok().and_then(|b| b.try_into().ok()).ok_or("invalid key request")?;
+        let signature = parts.next().unwrap_or_default();
+        let client_challenge = hex::decode(parts.next().unwrap_or_default()).unwrap_or_default();
+        if client_challenge.len() < 16 {
+            return Err("missing client challenge".to_string());
+        }
+        authorized = Some(crate::identity::verify_key_request(&crate::identity::authorized_clients_path(), public, signature, &challenge, peer, &wrap_public)?);
+        tracing::debug!(client = ?authorized.as_ref().map(|(name, _)| name), "group key requested by an authorized client");
+        // sign the wrap, so the client can tell it came from us before it
+        // trusts the key inside
+        let wrapped = crate::crypto::wrap_key(&keys.dek, &wrap_public);
+        let signature = crate::identity::sign_key_wrap(&keys.identity, &challenge, &client_challenge, peer, &wrap_public, &wrapped);
+        let message = format!("KEYWRAP:{} {} {}", wrapped, hex::encode(keys.identity.verifying_key().as_bytes()), hex::encode(signature));
+        crate::net::write_plain(stream, message.as_bytes()).map_err(|_| "handshake write failed".to_string())?;
+        client_challenge
+    } else {
+        let mut parts = reply.split(' ');
+        let response = hex::decode(parts.next().unwrap_or_default()).unwrap_or_default();
+        if !crate::auth::verify_handshake_mac(&keys.handshake, &challenge, "client", peer, &response) {
+            return Err("handshake mismatch".to_string());
+        }
+        hex::decode(parts.next().unwrap_or_default()).unwrap_or_default()
+    };
+    if client_challenge.len() < 16 {
+        return Err("missing client challenge".to_string());
+    }
+
+    // prove our own knowledge of the DEK to the client and sign the
+    // exchange with our identity key so it can be pinned
+    let proof = crate::auth::handshake_mac(&keys.handshake, &client_challenge, "server", peer);
+    let signature = crate::identity::sign_handshake(&keys.identity, &client_challenge, peer);
+    let proof_msg = format!("{} {} {}", hex::encode(proof), hex::encode(keys.identity.verifying_key().as_bytes()), hex::encode(signature));
+    crate::net::write_plain(stream, proof_msg.as_bytes()).map_err(|_| "handshake write failed".to_string())?;
+    stream.set_read_timeout(None).ok();
+    tracing::debug!("client proved the key");
+    let session_key = crate::auth::derive_session_key(&keys.dek, &challenge, &client_challenge, peer);
+    Ok(Authenticated { challenge, authorized, session_key })
+}
+
+/// Allowance for network jitter when enforcing slow mode, since clients time
+/// their messages on their side of the connection.
+const SLOW_MODE_SLACK: Duration = Duration::from_millis(500);
+
+/// Wait up to `timeout` for the client's login frame, which may include the
+/// user typing a password, and check it against the account store. Returns
+/// the account name, or the reason the login was refused.
+fn login(stream: &mut TcpStream, cipher: &SessionCipher, last_seq: &mut u64, timeout: Duration, store: &crate::roles::Store) -> Result<String, String> {
+    stream.set_read_timeout(Some(timeout)).ok();
+    let m = crate::crypto::read_one_encrypted(stream, cipher, &mut BytesMut::new(), last_seq).map_err(|e| format!("login failed: {}", e))?;
+    stream.set_read_timeout(None).ok();
+    if m.kind != Kind::Login {
+        return Err("did not log in".to_string());
+    }
+    let refusal = if !crate::accounts::verify(&crate::accounts::accounts_path(), &m.username, &m.text)? {
+        Some(format!("wrong password for account {}", m.username))
+    } else if store.is_banned(&m.username) {
+        Some(format!("account {} is banned", m.username))
+    } else {
+        None
+    };
+    let reply: &[u8] = if refusal.is_none() { b"LOGIN-OK" } else { b"LOGIN-FAILED" };
+    crate::net::write_plain(stream, reply).map_err(|_| "login reply failed".to_string())?;
+    match refusal {
+        None => Ok(m.username.into_owned()),
+        Some(reason) => Err(reason),
+    }
+}
+
+/// How often the maintenance task wakes up to see what is due.
+const MAINTENANCE_TICK: Duration = Duration::from_secs(60);
+
+/// Event loop token reserved for the waker; connections count up from 1.
+const WAKER: Token = Token(0);
+
+/// Most queued frames written to a client in one vectored write.
+const WRITE_BATCH: usize = 64;
+
+/// An authenticated client owned by the event loop.
+struct Conn {
+    stream: mio::net::TcpStream,
+    peer: String,
+    queue: Arc<SendQueue>,
+    /// The client's message key, see `auth::derive_session_key`.
+    cipher: SessionCipher,
+    /// Bytes received but not yet forming a complete frame. Complete frames
+    /// are split off without copying and the allocation is reused.
+    read_buf: BytesMut,
+    /// Frames taken from the queue and not written yet; the first may have
+    /// been written in part, and holds only what is left of it.
+    pending: VecDeque<Bytes>,
+    /// Last time a write made progress or the client had nothing to send.
+    last_progress: Instant,
+    /// Highest sequence number received from this client.
+    last_seq: u64,
+    /// Byte counters shared with the client's `ClientEntry`.
+    traffic: Arc<Traffic>,
+    /// Span of everything the event loop does for this client.
+    span: tracing::Span,
+}
+
+impl Conn {
+    /// Read everything the socket has and return the complete frames.
+    fn read_frames(&mut self) -> Result<Vec<Bytes>, ProtocolError> {
+        loop {
+            // read straight into the spare tail of the buffer
+            let filled = self.read_buf.len();
+            self.read_buf.resize(filled + 4096, 0);
+            let res = self.stream.read(&mut self.read_buf[filled..]);
+            self.read_buf.truncate(filled + *res.as_ref().unwrap_or(&0));
+            match res {
+                Ok(0) if self.read_buf.is_empty() => return Err(ProtocolError::Eof),
+                Ok(0) => return Err(ProtocolError::Truncated),
+                Ok(n) => {
+                    self.traffic.received.fetch_add(n as u64, Ordering::Relaxed);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ProtocolError::Io(e.kind())),
+            }
+        }
+        let mut frames = Vec::new();
+        while let Some(frame) = crate::net::take_frame(&mut self.read_buf)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    /// Write queued frames until the queue is empty or the socket would block.
+    /// A burst of frames goes out in one vectored write instead of one
+    /// write per frame.
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            if self.pending.is_empty() {
+                self.pending.extend(self.queue.pop_batch(WRITE_BATCH));
+                if self.pending.is_empty() {
+                    self.last_progress = Instant::now();
+                    return Ok(());
+                }
+            }
+            let slices: Vec<IoSlice> = self.pending.iter().map(|frame| IoSlice::new(frame)).collect();
+            match self.stream.write_vectored(&slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(mut n) => {
+                    self.last_progress = Instant::now();
+                    self.traffic.sent.fetch_add(n as u64, Ordering::Relaxed);
+                    tracing::trace!(written = n, frames = slices.len(), "write");
+                    while let Some(frame) = self.pending.front_mut() {
+                        if n < frame.len() {
+                            frame.advance(n);
+                            break;
+                        }
+                        n -= frame.len();
+                        self.pending.pop_front();
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    tracing::debug!(pending = self.pending.len(), queued = self.queue.len(), stalled_ms = self.last_progress.elapsed().as_millis() as u64, "socket full");
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Single-threaded loop serving every authenticated client: reads and relays
+/// incoming frames, drains send queues when sockets are writable and drops
+/// clients that overflow or stop reading.
+fn run_event_loop(mut poll: Poll, conn_rx: mpsc::Receiver<(String, std::net::TcpStream, Arc<SendQueue>, SessionCipher, u64)>, room: Arc<Room>, config: ServerConfig) {
+    let (status, clients) = (&room.status, &room.clients);
+    let mut events = Events::with_capacity(256);
+    let mut conns: HashMap<Token, Conn> = HashMap::new();
+    let mut next_token = 1;
+    let write_timeout = Duration::from_secs(config.write_timeout_secs.max(1));
+    let mut files = Files::new(&config);
+    let mut last_ping = Instant::now();
+    // chat goes through filters that may wait, such as a plugin, on a
+    // thread of its own, so they never stall the other clients
+    let relay_tx = room.filters.may_block().then(|| {
+        let (tx, rx) = mpsc::channel::<(String, Payload<'static>)>();
+        let room = room.clone();
+        thread::spawn(move || {
+            while let Ok((peer, m)) = rx.recv() {
+                relay(&room, &peer, m);
+            }
+        });
+        tx
+    });
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(Duration::from_secs(1))) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            room.log(format!("Event loop failed: {}", e));
+            return;
+        }
+        let mut dropped: Vec<(Token, Option<String>)> = Vec::new();
+
+        // Register connections that finished the handshake
+        while let Ok((peer, stream, queue, cipher, last_seq)) = conn_rx.try_recv() {
+            let token = Token(next_token);
+            next_token += 1;
+            let mut stream = mio::net::TcpStream::from_std(stream);
+            let traffic = clients.lock().unwrap().get(&peer).map(|c| c.traffic.clone()).unwrap_or_default();
+            let span = tracing::info_span!("client", %peer);
+            if poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE).is_err() {
+                queue.close();
+                clients.lock().unwrap().remove(&peer);
+                continue;
+            }
+            conns.insert(token, Conn { stream, peer, queue, cipher, read_buf: BytesMut::with_capacity(4096), pending: VecDeque::new(), last_progress: Instant::now(), last_seq, traffic, span });
+        }
+
+        for event in events.iter() {
+            if event.token() == WAKER || !event.is_readable() {
+                continue;
+            }
+            let Some(conn) = conns.get_mut(&event.token()) else { continue };
+            let _span = conn.span.clone().entered();
+            let frames = match conn.read_frames() {
+                Ok(frames) => frames,
+                Err(e @ (ProtocolError::Eof | ProtocolError::Io(_))) => {
+                    tracing::debug!(error = %e, "connection closed");
+                    dropped.push((event.token(), None));
+                    continue;
+                }
+                Err(e) => {
+                    dropped.push((event.token(), Some(format!("protocol error: {}", e))));
+                    continue;
+                }
+            };
+            tracing::trace!(frames = frames.len(), buffered = conn.read_buf.len(), "read");
+            let peer = conn.peer.clone();
+            for frame in frames {
+                let m = match crate::crypto::decrypt_frame(&frame, &conn.cipher, &mut conn.last_seq) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        dropped.push((event.token(), Some(format!("protocol error: {}", e))));
+                        break;
+                    }
+                };
+                tracing::trace!(kind = ?m.kind, seq = conn.last_seq, "frame");
+                match m.kind {
+                    Kind::Chat => {}
+                    Kind::Join => {
+                        join(&room, &peer, &m.username, m.signature.as_deref());
+                        continue;
+                    }
+                    Kind::Command => {
+                        command(&room, &peer, &m.text);
+                        continue;
+                    }
+                    Kind::Resend => {
+                        resend(&room, &peer, &m.text);
+                        continue;
+                    }
+                    Kind::Who => {
+                        let lines = roster(clients).join("\n");
+                        send_to(&room, &peer, &Payload::control(Kind::Roster, "Server", &lines));
+                        continue;
+                    }
+                    Kind::FileOffer | Kind::FileRequest | Kind::FileChunk => {
+                        files.handle(&room, &peer, m);
+                        continue;
+                    }
+                    Kind::Ping => {
+                        send_to(&room, &peer, &Payload::control(Kind::Pong, "", &m.text));
+                        continue;
+                    }
+                    Kind::Pong => {
+                        if let (Some(rtt), Some(client)) = (crate::net::pong_rtt(&m.text), clients.lock().unwrap().get_mut(&peer)) {
+                            crate::types::sample_rtt(&mut client.rtt, rtt);
+                        }
+                        continue;
+                    }
+                    // server-to-client only, only valid during login, or peer mode only
+                    Kind::Roster | Kind::Reply | Kind::Topic | Kind::SlowMode | Kind::Nick | Kind::Ack | Kind::Login | Kind::Peers => continue,
+                }
+                let mut m = m;
+                // only the server stamps times, on replayed history
+                m.time = None;
+                m.file = None;
+                if m.id.as_ref().is_none_or(|id| id.is_empty() || id.len() > crate::crypto::MAX_ID_LEN) {
+                    m.id = Some(crate::crypto::new_message_id().into());
+                }
+                let (observer, user_id) = clients.lock().unwrap().get(&peer).map(|c| (c.observer, c.user_id.clone())).unwrap_or_default();
+                let id = m.id.clone().unwrap_or_default();
+                if observer {
+                    acknowledge(&room, &peer, &id, Some("This connection is read-only; your message was not delivered."));
+                    continue;
+                }
+                // a client sending again because our ack got lost; an ID
+                // another user's message already has is not theirs to resend
+                let sent_by = room.backlog.lock().unwrap().iter().find(|b| b.id.as_deref() == Some(&id)).map(|b| b.user.clone().unwrap_or_default());
+                match sent_by {
+                    Some(user) if user == user_id => {
+                        acknowledge(&room, &peer, &id, None);
+                        continue;
+                    }
+                    Some(_) => {
+                        acknowledge(&room, &peer, &id, Some("Another message already has this ID; your message was not delivered."));
+                        continue;
+                    }
+                    None => {}
+                }
+                let slow_mode = status.lock().unwrap().slow_mode;
+                let mut wait = None;
+                let mut misnamed = None;
+                // a client that never joined, such as `antimpeu send`, is named
+                // by its first message as if it had; it would take a notice
+                // about the name for a rejection, so it gets none
+                let local_name = status.lock().unwrap().name.clone().unwrap_or_default();
+                let mut conns = clients.lock().unwrap();
+                if conns.get(&peer).is_some_and(|c| c.username.is_none()) {
+                    assign_name(&room, &mut conns, &peer, &local_name, &m.username, None);
+                }
+                if let Some(client) = conns.get_mut(&peer) {
+                    client.messages.record();
+                    let elapsed = client.last_message.map(|t| t.elapsed());
+                    match (slow_mode, elapsed) {
+                        (Some(slow), Some(elapsed)) if elapsed + SLOW_MODE_SLACK < slow => wait = Some(slow - elapsed),
+                        _ => {
+                            client.last_active = Instant::now();
+                            client.last_message = Some(Instant::now());
+                        }
+                    }
+                    // clients speak under the name the server knows them by,
+                    // whatever they put in the payload; in a signed room the
+                    // signature covers the name, so it has to be that one
+                    let name = client.username.clone().unwrap_or_default();
+                    if room.signed.is_some() && name != m.username {
+                        misnamed = Some(name.clone());
+                    }
+                    m.username = name.into();
+                    m.user = Some(client.user_id.clone().into());
+                }
+                drop(conns);
+                if let Some(name) = misnamed {
+                    acknowledge(&room, &peer, &id, Some(&format!("You are {} here, and signed rooms only relay messages signed under that name; your message was not delivered.", name)));
+                    continue;
+                }
+                if let Some(wait) = wait {
+                    acknowledge(&room, &peer, &id, Some(&format!("Slow mode: wait {}s before sending again; your message was not delivered.", wait.as_secs_f64().ceil())));
+                    continue;
+                }
+
+                match &relay_tx {
+                    Some(relay_tx) => {
+                        let _ = relay_tx.send((peer.clone(), m));
+                    }
+                    None => relay(&room, &peer, m),
+                }
+            }
+        }
+
+        // Drain queues: a wake-up, a writable socket or a relayed frame may all
+        // have made data available, and flushing an idle queue is cheap.
+        for (token, conn) in conns.iter_mut() {
+            let _span = conn.span.clone().entered();
+            if conn.queue.overflowed() {
+                dropped.push((*token, Some("send queue full".to_string())));
+            } else if conn.flush().is_err() || (conn.queue.is_closed() && conn.pending.is_empty()) {
+                dropped.push((*token, None));
+            } else if !conn.pending.is_empty() && conn.last_progress.elapsed() > write_timeout {
+                dropped.push((*token, Some(format!("not reading ({} frames queued)", conn.queue.len() + conn.pending.len()))));
+            }
+        }
+
+        if last_ping.elapsed() >= crate::net::HEARTBEAT_INTERVAL {
+            last_ping = Instant::now();
+            let pinged: Vec<String> = clients.lock().unwrap().iter().filter(|(_, c)| c.ping).map(|(addr, _)| addr.clone()).collect();
+            for addr in pinged {
+                send_to(&room, &addr, &Payload::control(Kind::Ping, "", &crate::net::ping_token()));
+            }
+        }
+
+        for (token, reason) in dropped {
+            let Some(mut conn) = conns.remove(&token) else { continue };
+            let (sent, received) = conn.traffic.totals();
+            tracing::info!(parent: &conn.span, reason = reason.as_deref().unwrap_or("disconnected"), sent, received, "dropped");
+            let _ = poll.registry().deregister(&mut conn.stream);
+            conn.queue.close();
+            clients.lock().unwrap().remove(&conn.peer);
+            files.disconnected(status, &conn.peer);
+            if let Some(reason) = reason {
+                room.log(format!("Dropping {}: {}", conn.peer, reason));
+            }
+            notify(&room, &format!("{}{}", LEAVE_NOTICE, conn.peer));
+        }
+    }
+}
+
+/// Overlay with a QR code for joining at `host`, the status address or the LAN address.
+pub fn join_qr(status: &SharedStatus, host: &str, port: u16, key_hex: &str) -> Result<crate::types::Overlay, String> {
+    let (host, port) = match host {
+        "" => status
+            .lock()
+            .unwrap()
+            .join_address
+            .clone()
+            .or_else(|| crate::qr::local_address().map(|host| (host, port)))
+            .ok_or_else(|| "No address to put in the QR code; give one, as in /qr chat.example.com".to_string())?,
+        host => (host.to_string(), port),
+    };
+    crate::qr::overlay(&host, port, key_hex)
+}
+
+/// Start the server accept loop and internal worker threads. Everything
+/// the TUI shows also goes to `events`, if given.
 ///
 /// This function returns quickly — the TUI runs in the caller's thread.
-pub fn run_server_with_tui(port: u16, cipher: Arc<Aes256Gcm>, messages: SharedMessages<crate::tui::Message>, rx: mpsc::Receiver<String>, clients: SharedClients) {
+pub fn run_server_with_tui(port: u16, mut keys: ServerKeys, messages: SharedMessages<crate::types::Message>, status: SharedStatus, rx: mpsc::Receiver<String>, config: &ServerConfig, events: Option<mpsc::Sender<crate::chat_server::Event>>) -> Result<(), String> {
+    let history = match config.history.enabled {
+        true => {
+            let cipher = Aes256Gcm::new_from_slice(&keys.dek).map_err(|_| "Invalid DEK".to_string())?;
+            Some(crate::history::History::open(crate::history::history_path(), Arc::new(cipher), config.history.clone())?)
+        }
+        false => None,
+    };
+    let (writes, written) = mpsc::channel();
+    let room = Arc::new(Room {
+        messages,
+        status,
+        clients: Arc::new(Mutex::new(HashMap::new())),
+        signed: keys.room.take(),
+        filters: crate::filters::FilterChain::from_config(&config.filters)?,
+        history,
+        store: crate::roles::Store::load(writes.clone())?,
+        nicknames: crate::nicknames::Registry::load(writes)?,
+        next_seq: AtomicU64::new(1),
+        sending: Mutex::new(()),
+        next_serial: AtomicU64::new(1),
+        backlog: Mutex::new(VecDeque::new()),
+        events,
+    });
+    for notice in room.history.iter().flat_map(|h| h.notices()) {
+        room.log(notice.clone());
+    }
+    // Writer thread: saves roles, bans, settings and nicknames as they
+    // change, off the event loop; it ends with the room, which holds the
+    // last senders
+    let room_writes = Arc::downgrade(&room);
+    thread::spawn(move || {
+        crate::roles::write_back(written, |e| {
+            if let Some(room) = room_writes.upgrade() {
+                room.log(e);
+            }
+        })
+    });
+    // Maintenance task: history retention at startup and then every prune
+    // interval, and log rotation once the day changes
+    let room_maintain = room.clone();
+    thread::spawn(move || {
+        let room = room_maintain;
+        let mut last_prune: Option<Instant> = None;
+        loop {
+            let mut notices = Vec::new();
+            if let Some(history) = room.history.as_ref().filter(|h| last_prune.is_none_or(|t| t.elapsed() >= h.prune_interval())) {
+                last_prune = Some(Instant::now());
+                match history.prune() {
+                    Ok(0) => {}
+                    Ok(n) => notices.push(format!("History: removed {} expired messages", n)),
+                    Err(e) => notices.push(e),
+                }
+            }
+            if let Err(e) = crate::logfile::maintain() {
+                notices.push(e);
+            }
+            for text in notices {
+                room.log(text);
+            }
+            thread::sleep(MAINTENANCE_TICK);
+        }
+    });
+    let key_hex = hex::encode(keys.identity.verifying_key().as_bytes());
+    let keys = Arc::new(keys);
+    {
+        let mut status = room.status.lock().unwrap();
+        // the name the server's own messages go out under
+        status.name = Some(whoami::username());
+        status.user_id = Some(crate::nicknames::SERVER_USER_ID.to_string());
+        status.commands = [
+            ("/ephemeral <duration> <text>", "send a message that disappears after the duration"),
+            ("/qr [host]", "show a QR code others can scan to join"),
+            ("/fingerprint", "show the fingerprint of the server's identity key"),
+        ]
+        .into_iter()
+        .chain(COMMANDS.iter().copied())
+        .collect();
+        status.topic = crate::roles::read_text(&crate::roles::topic_path()).unwrap_or_default();
+        let slow = crate::roles::read_text(&crate::roles::slow_mode_path()).unwrap_or_default();
+        status.slow_mode = slow.and_then(|s| s.trim().parse().ok()).map(Duration::from_secs).filter(|d| !d.is_zero());
+    }
     let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).expect("Cannot bind");
-    println!("Server running on {}", addr);
+    let listener = TcpListener::bind(&addr).map_err(|e| format!("Cannot bind {}: {}", addr, e))?;
+    let poll = Poll::new().map_err(|e| format!("Cannot create the event loop: {}", e))?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).map_err(|e| format!("Cannot create the event loop waker: {}", e))?);
+    room.log(format!("Server running on {}", addr));
+
+    // Event loop thread: serves every client that completed the handshake
+    let (conn_tx, conn_rx) = mpsc::channel();
+    let (room_loop, config_loop) = (room.clone(), config.clone());
+    thread::spawn(move || run_event_loop(poll, conn_rx, room_loop, config_loop));
+
+    // Webhook endpoint: integrations post like clients, through the filters
+    let room_hook = room.clone();
+    if let Some(listen) = crate::webhook::spawn(&config.webhook, move |name, text| post_integration(&room_hook, name, text))? {
+        room.log(format!("Webhook endpoint on http://{}/message", listen));
+    }
+    let clients_health = room.clients.clone();
+    if let Some(listen) = crate::health::spawn(&config.health, move || clients_health.lock().unwrap().len())? {
+        room.log(format!("Health probe on {}", listen));
+    }
 
     // Accept thread: listen for incoming TCP connections and handle handshake
-    let clients_accept = clients.clone();
-    let messages_accept = messages.clone();
-    let cipher_accept = cipher.clone();
+    let room_accept = room.clone();
+    let config = config.clone();
     thread::spawn(move || {
+        let cookies = Cookies::new();
         for stream in listener.incoming() {
             match stream {
                 Ok(mut stream) => {
-                    let peer = stream.peer_addr().unwrap().to_string();
-                    {
-                        let mut msgs = messages_accept.lock().unwrap();
-                        let sys_text = format!("New connection from {}", peer);
-                        msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                        // broadcast to clients
-                        let conns = clients_accept.lock().unwrap();
-                        for (_addr, client) in conns.iter() {
-                            if let Ok(mut s) = client.lock() {
-                                let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_accept, "Server");
-                            }
-                        }
-                    }
-                    // Create a separate writer (stored in clients map) and a reader stream used by the reader thread.
-                    let mut stream_read = match stream.try_clone() {
-                        Ok(s) => s,
-                        Err(_) => continue,
+                    let Ok(peer_addr) = stream.peer_addr() else { continue };
+                    let peer = peer_addr.to_string();
+                    crate::net::tune_with(&stream, &config.socket).ok();
+                    // Expect a plaintext HELLO token first; if missing or incorrect, drop the
+                    // connection without spending anything else on it.
+                    stream.set_read_timeout(Some(config.timeouts.hello())).ok();
+                    let caps = match crate::net::read_plain(&mut stream) {
+                        Ok(buf) => String::from_utf8(buf).ok().and_then(|s| {
+                            let mut parts = s.split(' ');
+                            (parts.next() == Some(crate::net::HELLO)).then(|| parts.map(str::to_string).collect::<Vec<_>>())
+                        }),
+                        Err(_) => None,
                     };
-                    // Expect a plaintext HELLO token first; if missing or incorrect, refuse immediately.
-                    stream_read.set_read_timeout(Some(Duration::from_millis(200))).ok();
-                    let hello_ok = match crate::net::read_plain(&mut stream_read) {
-                        Ok(buf) => {
-                            if let Ok(s) = String::from_utf8(buf) {
-                                s == "HELLO-ANTIMPEU"
-                            } else { false }
-                        }
-                        Err(_) => false,
-                    };
-                    if !hello_ok {
-                        let mut msgs = messages_accept.lock().unwrap();
-                        let sys_text = format!("Refused connection from {}.", peer);
-                        msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                        let conns = clients_accept.lock().unwrap();
-                        for (_addr, client) in conns.iter() {
-                            if let Ok(mut s) = client.lock() {
-                                let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_accept, "Server");
-                            }
-                        }
+                    let Some(caps) = caps else { continue };
+                    let cookie_ok = caps.iter().filter_map(|c| c.strip_prefix("cookie=")).any(|c| cookies.check(peer_addr.ip(), c));
+                    if !cookie_ok {
+                        let _ = crate::net::write_plain(&mut stream, format!("COOKIE:{}", cookies.issue(peer_addr.ip())).as_bytes());
                         continue;
                     }
-                    // client said HELLO; now send challenge
-                    stream_read.set_read_timeout(None).ok();
-                    let mut rand_bytes = [0u8; 12];
-                    let mut rng = aes_gcm::aead::OsRng;
-                    rng.fill_bytes(&mut rand_bytes);
-                    let challenge = hex::encode(rand_bytes);
-                    let challenge_msg = format!("CHAL:{}", challenge);
-                    // send plaintext length-prefixed challenge
-                    if crate::net::write_plain(&mut stream, challenge_msg.as_bytes()).is_err() {
-                        let mut msgs = messages_accept.lock().unwrap();
-                        let sys_text = format!("Refused connection from {} (handshake write failed)", peer);
-                        msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                        let conns = clients_accept.lock().unwrap();
-                        for (_addr, client) in conns.iter() {
-                            if let Ok(mut s) = client.lock() {
-                                let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_accept, "Server");
-                            }
-                        }
+                    if config.max_clients > 0 && room_accept.clients.lock().unwrap().len() >= config.max_clients {
+                        notify(&room_accept, &format!("{}{} (server full)", REFUSED_NOTICE, peer));
                         continue;
                     }
-                    // wait for encrypted reply within timeout
-                    stream_read.set_read_timeout(Some(Duration::from_secs(5))).ok();
-                    match crate::crypto::read_one_encrypted(&mut stream_read, &cipher_accept) {
-                        Some((_username, reply)) => {
-                            if reply != challenge {
-                                let mut msgs = messages_accept.lock().unwrap();
-                                let sys_text = format!("Refused connection from {} (handshake mismatch)", peer);
-                                msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                                let conns = clients_accept.lock().unwrap();
-                                for (_addr, client) in conns.iter() {
-                                    if let Ok(mut s) = client.lock() {
-                                        let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_accept, "Server");
-                                    }
-                                }
-                                continue;
-                            }
-                            // handshake ok
-                            stream_read.set_read_timeout(None).ok();
-                        }
-                        _ => {
-                            let mut msgs = messages_accept.lock().unwrap();
-                            let sys_text = format!("Refused connection from {} (no handshake reply)", peer);
-                            msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                            let conns = clients_accept.lock().unwrap();
-                                for (_addr, client) in conns.iter() {
-                                    if let Ok(mut s) = client.lock() {
-                                        let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_accept, "Server");
-                                    }
-                                }
-                            continue;
-                        }
+                    notify(&room_accept, &format!("{}{}", JOIN_NOTICE, peer));
+                    let framing = Framing {
+                        pad: config.allow_padding && caps.iter().any(|c| c == crate::net::CAP_PAD),
+                        base64: caps.iter().any(|c| c == crate::net::CAP_BASE64),
+                    };
+                    let mut accepted = Vec::new();
+                    if framing.pad {
+                        accepted.push(crate::net::CAP_PAD);
+                    }
+                    if framing.base64 {
+                        accepted.push(crate::net::CAP_BASE64);
+                    }
+                    if config.require_login {
+                        accepted.push(crate::net::CAP_LOGIN);
+                    }
+                    let observer = config.allow_observers && caps.iter().any(|c| c == crate::net::CAP_OBSERVE);
+                    if observer {
+                        accepted.push(crate::net::CAP_OBSERVE);
+                    }
+                    let acks = caps.iter().any(|c| c == crate::net::CAP_ACK);
+                    if acks {
+                        accepted.push(crate::net::CAP_ACK);
+                    }
+                    let ping = caps.iter().any(|c| c == crate::net::CAP_PING);
+                    if ping {
+                        accepted.push(crate::net::CAP_PING);
                     }
 
-                    let stream_write = Arc::new(Mutex::new(stream));
-                    clients_accept.lock().unwrap().insert(peer.clone(), stream_write.clone());
-
-                    // Reader thread for this client uses the dedicated read clone (no mutex) so that
-                    // the writer mutex in `clients` is not held while blocking on reads.
-                    let messages_in = messages_accept.clone();
-                    let clients_in = clients_accept.clone();
-                    let cipher_in = cipher_accept.clone();
-                    let peer_clone = peer.clone();
+                    // The client presented a valid cookie, so it is worth a thread: the
+                    // challenge-response and login may wait on the network and on a
+                    // user typing a password, which must not stall other connections.
+                    let (keys, config, room, waker, conn_tx) = (keys.clone(), config.clone(), room_accept.clone(), waker.clone(), conn_tx.clone());
                     thread::spawn(move || {
-                        let mut reader = stream_read;
-                        loop {
-                            match crate::crypto::read_one_encrypted(&mut reader, &cipher_in) {
-                                        Some((username, msg)) => {
-                                    // push into server TUI
-                                    let mut msgs = messages_in.lock().unwrap();
-                                    msgs.push(crate::tui::Message { sender: username.clone(), text: msg.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                                    drop(msgs);
-
-                                    // broadcast to all other clients (collect targets while holding lock, then send)
-                                    let conns = clients_in.lock().unwrap();
-                                    let targets: Vec<_> = conns.iter()
-                                        .filter(|(k, _)| *k != &peer_clone)
-                                        .map(|(_, v)| v.clone())
-                                        .collect();
-                                    drop(conns);
-                                    for target in targets {
-                                        if let Ok(mut s) = target.lock() {
-                                            let _ = crate::crypto::send_encrypted(&mut s, &msg, &cipher_in, &username);
-                                        }
-                                    }
+                        let _span = tracing::info_span!("handshake", %peer).entered();
+                        let Authenticated { challenge, authorized, session_key } = match authenticate(&mut stream, &peer, &keys, &accepted, config.timeouts.handshake()) {
+                            Ok(authenticated) => authenticated,
+                            Err(reason) => {
+                                tracing::info!(%reason, "refused");
+                                notify(&room, &format!("{}{} ({})", REFUSED_NOTICE, peer, reason));
+                                return;
+                            }
+                        };
+                        let cipher = SessionCipher::new(&session_key, Side::Server);
+                        let mut last_seq = 0;
+                        let account = if config.require_login {
+                            match login(&mut stream, &cipher, &mut last_seq, config.timeouts.login(), &room.store) {
+                                Ok(account) => {
+                                    tracing::debug!(%account, "logged in");
+                                    Some(account)
                                 }
-                                _ => {
-                                    clients_in.lock().unwrap().remove(&peer_clone);
-                                    let mut msgs = messages_in.lock().unwrap();
-                                    let sys_text = format!("Disconnected from {}", peer_clone);
-                                    msgs.push(crate::tui::Message { sender: "System".to_string(), text: sys_text.clone(), time: chrono::Local::now().format("%H:%M").to_string() });
-                                    let conns = clients_in.lock().unwrap();
-                                    for (_addr, client) in conns.iter() {
-                                        if let Ok(mut s) = client.lock() {
-                                            let _ = crate::crypto::send_encrypted(&mut s, &sys_text, &cipher_in, "Server");
-                                        }
-                                    }
-                                    break;
+                                Err(reason) => {
+                                    tracing::info!(%reason, "login refused");
+                                    notify(&room, &format!("{}{} ({})", REFUSED_NOTICE, peer, reason));
+                                    return;
                                 }
                             }
+                        } else {
+                            None
+                        };
+
+                        // Hand the authenticated socket over to the event loop
+                        if stream.set_nonblocking(true).is_err() {
+                            return;
+                        }
+                        let queue = Arc::new(SendQueue::new(config.queue_capacity, config.overflow, config.lag_warn, waker.clone()));
+                        let role = account.as_deref().map(|a| room.store.role_of(a)).unwrap_or_default();
+                        // the handshake already proved the key of a client that asked
+                        // for the group key, and the name it is listed under
+                        let (listed, identity) = authorized.unzip();
+                        let user_id = crate::nicknames::user_id(account.as_deref(), identity.as_deref());
+                        let entry = ClientEntry { queue: queue.clone(), cipher: cipher.clone(), framing, acks, ping, rtt: None, traffic: Arc::default(), messages: Default::default(), username: account.clone().or(listed), user_id, account, identity, challenge, observer, role, joined: chrono::Local::now(), greeted: false, last_active: Instant::now(), last_message: None };
+                        room.clients.lock().unwrap().insert(peer.clone(), entry);
+                        tracing::info!(?framing, acks, ping, observer, "joined");
+                        if conn_tx.send((peer, stream, queue, cipher, last_seq)).is_ok() {
+                            let _ = waker.wake();
                         }
                     });
                 }
-                Err(e) => eprintln!("Error accepting connection: {}", e),
+                Err(e) => room_accept.log(format!("Error accepting connection: {}", e)),
             }
         }
     });
 
     // Broadcast thread: take messages from TUI and forward to all clients
-    let clients_broadcast = clients.clone();
     let local_username = whoami::username();
-    let cipher_broadcast = cipher.clone();
     thread::spawn(move || {
         while let Ok(msg) = rx.recv() {
-            let conns = clients_broadcast.lock().unwrap();
-            for (_addr, client) in conns.iter() {
-                if let Ok(mut s) = client.lock() {
-                    let _ = crate::crypto::send_encrypted(&mut s, &msg, &cipher_broadcast, &local_username);
+            if msg == "/fingerprint" {
+                for text in crate::fingerprint::describe("Server", &key_hex) {
+                    room.show(crate::types::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
                 }
+                continue;
             }
+            if let Some(host) = msg.strip_prefix("/qr").filter(|a| a.is_empty() || a.starts_with(' ')) {
+                match join_qr(&room.status, host.trim(), port, &key_hex) {
+                    Ok(overlay) => room.status.lock().unwrap().overlay = Some(overlay),
+                    Err(e) => room.show(crate::types::Message { sender: "System".to_string(), text: e, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None }),
+                }
+                continue;
+            }
+            if let Some(args) = msg.strip_prefix("/ephemeral").filter(|a| a.is_empty() || a.starts_with(char::is_whitespace)) {
+                match crate::types::parse_ephemeral(args) {
+                    Ok((ttl, text)) => {
+                        let echo = crate::types::Message { sender: local_username.clone(), text: text.to_string(), time: chrono::Utc::now(), verified: None, expires: None, user: Some(crate::nicknames::SERVER_USER_ID.to_string()), id: None };
+                        room.show_ephemeral(echo, ttl);
+                        let signature = room.signed.as_ref().map(|r| r.sign(&local_username, text));
+                        let payload = Payload { ttl: Some(ttl.as_secs()), user: Some(crate::nicknames::SERVER_USER_ID.into()), ..Payload::chat(&local_username, text, signature.as_deref()) };
+                        broadcast(&room, &payload, None);
+                    }
+                    Err(usage) => room.show(crate::types::Message { sender: "System".to_string(), text: usage, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None }),
+                }
+                continue;
+            }
+            if let Some(command) = msg.strip_prefix('/') {
+                for text in run_command(&room, "Server", Role::Owner, command) {
+                    room.show(crate::types::Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                }
+                continue;
+            }
+            let signature = room.signed.as_ref().map(|r| r.sign(&local_username, &msg));
+            let payload = Payload { user: Some(crate::nicknames::SERVER_USER_ID.into()), ..Payload::chat(&local_username, &msg, signature.as_deref()) };
+            record(&room, &payload);
+            broadcast(&room, &payload, None);
         }
     });
 
     // Keep this function returning quickly; actual TUI is driven from main which holds handles.
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A room nobody is connected to, with nothing on disk.
+    fn room() -> Room {
+        Room {
+            messages: Arc::new(Mutex::new(crate::types::MessageBuffer::new(100))),
+            status: SharedStatus::default(),
+            clients: SharedClients::default(),
+            signed: None,
+            filters: crate::filters::FilterChain::default(),
+            history: None,
+            store: crate::roles::Store::empty(),
+            nicknames: crate::nicknames::Registry::empty(),
+            next_seq: AtomicU64::new(1),
+            sending: Mutex::new(()),
+            next_serial: AtomicU64::new(1),
+            backlog: Mutex::new(VecDeque::new()),
+            events: None,
+        }
+    }
+
+    /// Connect a client named `name` at `peer` that signed its join with
+    /// `identity`. The returned poll wakes on its queue.
+    fn connect(room: &Room, peer: &str, name: &str, identity: Option<&str>) -> Poll {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+        let entry = ClientEntry {
+            queue: Arc::new(SendQueue::new(16, OverflowPolicy::DropOldest, 16, waker)),
+            cipher: SessionCipher::new(&[7; 32], crate::crypto::Side::Server),
+            framing: Default::default(),
+            acks: false,
+            ping: false,
+            rtt: None,
+            traffic: Arc::default(),
+            messages: Default::default(),
+            username: Some(name.to_string()),
+            user_id: crate::nicknames::user_id(None, identity),
+            account: None,
+            identity: identity.map(str::to_string),
+            challenge: [0; 32],
+            observer: false,
+            role: Role::Member,
+            joined: chrono::Local::now(),
+            greeted: false,
+            last_active: Instant::now(),
+            last_message: None,
+        };
+        room.clients.lock().unwrap().insert(peer.to_string(), entry);
+        poll
+    }
+
+    fn name_of(room: &Room, peer: &str) -> Option<String> {
+        room.clients.lock().unwrap().get(peer).and_then(|c| c.username.clone())
+    }
+
+    #[test]
+    fn broadcasts_reach_everybody_but_the_sender_in_order() {
+        let room = room();
+        let _polls = [connect(&room, "a", "alice", None), connect(&room, "b", "bob", None)];
+        broadcast(&room, &Payload::chat("alice", "one", None), Some("a"));
+        send_to(&room, "b", &Payload::control(Kind::Reply, "Server", "two"));
+        broadcast(&room, &Payload::chat("Server", "three", None), None);
+        let frames = |peer: &str| room.clients.lock().unwrap()[peer].queue.pop_batch(10);
+        assert_eq!(frames("a").len(), 1);
+        let cipher = SessionCipher::new(&[7; 32], crate::crypto::Side::Client);
+        let mut last_seq = 0;
+        let texts: Vec<_> = frames("b").iter().map(|frame| crate::crypto::decrypt_frame(frame, &cipher, &mut last_seq).unwrap().text.into_owned()).collect();
+        assert_eq!(texts, ["one", "two", "three"]);
+        assert_eq!(room.backlog.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn nick_survives_multibyte_commands() {
+        let room = room();
+        let key = "ab".repeat(32);
+        let _poll = connect(&room, "peer", "bob", Some(&key));
+        command(&room, "peer", "\u{3000}\u{3000}nick\u{3000}zoë ");
+        assert_eq!(name_of(&room, "peer").as_deref(), Some("zoë"));
+        assert_eq!(room.nicknames.lookup(&key).as_deref(), Some("zoë"));
+        // the server is still serving the client afterwards
+        command(&room, "peer", "\u{3000}\u{3000}nick");
+        command(&room, "peer", "nick\u{85}\u{3000}bob");
+        assert_eq!(name_of(&room, "peer").as_deref(), Some("bob"));
+        command(&room, "peer", "\u{3000}\u{3000}nick Server");
+        assert_eq!(name_of(&room, "peer").as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn only_the_first_join_counts() {
+        let room = room();
+        room.store.set_motd("welcome");
+        let _poll = connect(&room, "peer", "bob", None);
+        let queued = || room.clients.lock().unwrap()["peer"].queue.len();
+        join(&room, "peer", "bob", None);
+        assert_eq!(queued(), 1);
+        join(&room, "peer", "mallory", None);
+        assert_eq!(queued(), 1);
+        assert_eq!(name_of(&room, "peer").as_deref(), Some("bob"));
+        // nor does a join from a connection that is gone
+        join(&room, "gone", "eve", None);
+        assert!(!room.clients.lock().unwrap().contains_key("gone"));
+    }
+
+    fn run(room: &Room, role: Role, command: &str) -> String {
+        run_command(room, "alice", role, command).concat()
+    }
+
+    #[test]
+    fn moderators_act_on_members_only() {
+        let room = room();
+        room.store.set_role("mod", Role::Moderator);
+        assert_eq!(run(&room, Role::Member, "kick bob"), "/kick needs the moderator role");
+        assert_eq!(run(&room, Role::Moderator, "kick bob"), "bob is not connected");
+        assert_eq!(run(&room, Role::Moderator, "kick mod"), "You cannot kick mod (moderator)");
+        assert_eq!(run(&room, Role::Moderator, "ban mod"), "You cannot ban mod (moderator)");
+        assert!(!room.store.is_banned("mod"));
+
+        assert_eq!(run(&room, Role::Moderator, "ban bob"), "Banned bob");
+        assert!(room.store.is_banned("bob"));
+        assert_eq!(run(&room, Role::Moderator, "unban bob"), "Unbanned bob");
+        assert!(!room.store.is_banned("bob"));
+        assert_eq!(run(&room, Role::Owner, "ban mod"), "Banned mod");
+        assert_eq!(run(&room, Role::Moderator, "ban"), "Usage: /ban <name>");
+    }
+
+    #[test]
+    fn only_moderators_set_the_motd() {
+        let room = room();
+        assert_eq!(run(&room, Role::Member, "motd"), "No message of the day");
+        assert_eq!(run(&room, Role::Member, "motd hello"), "Setting the message of the day needs the moderator role");
+        assert_eq!(room.store.motd(), None);
+        assert_eq!(run(&room, Role::Moderator, "motd  be nice "), "Message of the day updated");
+        assert_eq!(run(&room, Role::Member, "motd"), "be nice");
+        // the name is found by whitespace, not by its length in bytes
+        assert_eq!(run(&room, Role::Moderator, "\u{3000}\u{3000}motd\u{3000}café"), "Message of the day updated");
+        assert_eq!(room.store.motd().as_deref(), Some("café"));
+    }
+
+    #[test]
+    fn topics_may_follow_multibyte_whitespace() {
+        let room = room();
+        assert_eq!(run(&room, Role::Member, "\u{3000}\u{3000}topic"), "No topic set");
+        assert_eq!(run(&room, Role::Member, "topic\u{3000}ünïcode"), "Setting the topic needs the moderator role");
+        assert_eq!(run(&room, Role::Moderator, "\u{3000}\u{3000}topic\u{3000}ünïcode "), "Topic updated");
+        assert_eq!(room.status.lock().unwrap().topic.as_deref(), Some("ünïcode"));
+        assert_eq!(run(&room, Role::Member, "topic"), "ünïcode");
+    }
+
+    #[test]
+    fn only_owners_promote_and_demote() {
+        let room = room();
+        assert_eq!(run(&room, Role::Moderator, "promote bob"), "/promote needs the owner role");
+        assert_eq!(run(&room, Role::Moderator, "demote bob"), "/demote needs the owner role");
+        assert_eq!(run(&room, Role::Owner, "promote bob"), "bob is now moderator");
+        assert_eq!(room.store.role_of("bob"), Role::Moderator);
+        assert_eq!(run(&room, Role::Owner, "promote bob owner"), "bob is now owner");
+        assert_eq!(room.store.role_of("bob"), Role::Owner);
+        assert_eq!(run(&room, Role::Owner, "promote bob admin"), "Unknown role admin (expected member, moderator or owner)");
+        assert_eq!(room.store.role_of("bob"), Role::Owner);
+        assert_eq!(run(&room, Role::Owner, "demote bob"), "bob is now member");
+        assert_eq!(room.store.role_of("bob"), Role::Member);
+    }
+
+    #[test]
+    fn resend_ranges_are_checked() {
+        assert_eq!(resend_range("3 5", 10), Some(3..=5));
+        assert_eq!(resend_range("10 10", 10), Some(10..=10));
+        // cut at the last serial relayed
+        assert_eq!(resend_range("8 20", 10), Some(8..=10));
+        assert_eq!(resend_range("11 12", 10), None);
+        assert_eq!(resend_range("5 3", 10), None);
+        for malformed in ["", "3", "3 x", "-1 3", "3  5"] {
+            assert_eq!(resend_range(malformed, 10), None, "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn resend_ranges_stay_within_the_backlog() {
+        let backlog = RESEND_BACKLOG as u64;
+        assert_eq!(resend_range(&format!("1 {}", u64::MAX), u64::MAX), Some(1..=backlog));
+        assert_eq!(resend_range(&format!("{} {}", u64::MAX, u64::MAX), u64::MAX), Some(u64::MAX..=u64::MAX));
+    }
 }
@@ -0,0 +1,116 @@
+//! Remembered client session (`[client] remember_session`).
+//!
+//! After connecting, the client seals the server address, the DEK and the
+//! login password with a random key kept in the OS keyring and writes the
+//! result to `$HOME/key/session`. The next `antimpeu client` to the same
+//! server, or with no server at all, uses it instead of asking for the KEK
+//! and the password. The file is useless without the keyring entry, which
+//! the OS ties to the user's login; `antimpeu logout` deletes both.
+
+use aes_gcm::{Aes256Gcm, KeyInit, aead::{Aead, OsRng, Payload}};
+use rand_core::RngCore;
+use serde::{Serialize, Deserialize};
+
+/// Keyring service the sealing key is stored under.
+const KEYRING_SERVICE: &str = "antimpeu";
+/// Associated data of the sealed session.
+const SESSION_AAD: &[u8] = b"antimpeu session v1";
+
+/// What a remembered session holds.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    /// `host:port` the session belongs to.
+    pub server: String,
+    #[serde(with = "hex::serde")]
+    pub dek: [u8; 32],
+    /// Account password, when the server asked for one.
+    pub password: Option<String>,
+}
+
+/// Location of the sealed session.
+pub fn session_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/session", home)
+}
+
+fn entry() -> Result<keyring::Entry, String> {
+    keyring::Entry::new(KEYRING_SERVICE, &whoami::username()).map_err(|e| match e {
+        // keyutils reports a missing session keyring like a missing entry
+        keyring::Error::NoEntry => "no OS keyring is available in this session".to_string(),
+        e => format!("OS keyring unavailable: {}", e),
+    })
+}
+
+/// The sealing key from the keyring, or None when there is none yet.
+fn sealing_key(entry: &keyring::Entry) -> Result<Option<Aes256Gcm>, String> {
+    match entry.get_secret() {
+        Ok(key) => Aes256Gcm::new_from_slice(&key).map(Some).map_err(|_| "The keyring holds a malformed session key".to_string()),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(format!("OS keyring unavailable: {}", e)),
+    }
+}
+
+/// Seal `session` and replace the remembered one.
+pub fn save(session: &Session) -> Result<(), String> {
+    let entry = entry()?;
+    let cipher = match sealing_key(&entry)? {
+        Some(cipher) => cipher,
+        None => {
+            let mut key = [0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            entry.set_secret(&key).map_err(|e| format!("OS keyring unavailable: {}", e))?;
+            Aes256Gcm::new_from_slice(&key).expect("32-byte key")
+        }
+    };
+    let plain = serde_json::to_vec(session).map_err(|e| e.to_string())?;
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let sealed = cipher
+        .encrypt((&nonce).into(), Payload { msg: &plain, aad: SESSION_AAD })
+        .map_err(|_| "Failed to seal the session".to_string())?;
+    let mut data = nonce.to_vec();
+    data.extend_from_slice(&sealed);
+    let path = session_path();
+    let _ = std::fs::remove_file(&path);
+    crate::identity::write_private(&path, hex::encode(data).as_bytes()).map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+/// The remembered session, or None when there is none or its key is gone
+/// from the keyring.
+pub fn load() -> Result<Option<Session>, String> {
+    let path = session_path();
+    let text = match std::fs::read_to_string(&path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let Some(cipher) = sealing_key(&entry()?)? else { return Ok(None) };
+    let data = hex::decode(text.trim()).map_err(|_| format!("{} is malformed", path))?;
+    if data.len() < 12 {
+        return Err(format!("{} is malformed", path));
+    }
+    let (nonce, sealed) = data.split_at(12);
+    let plain = cipher
+        .decrypt(nonce.into(), Payload { msg: sealed, aad: SESSION_AAD })
+        .map_err(|_| format!("{} does not match the key in the OS keyring; run antimpeu logout", path))?;
+    serde_json::from_slice(&plain).map(Some).map_err(|_| format!("{} is malformed", path))
+}
+
+/// Delete the remembered session and its keyring entry; false when there
+/// was nothing to delete.
+pub fn forget() -> Result<bool, String> {
+    let path = session_path();
+    let had_file = match std::fs::remove_file(&path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(format!("Failed to delete {}: {}", path, e)),
+    };
+    // without a keyring there is no key to delete
+    let Ok(entry) = entry() else { return Ok(had_file) };
+    let had_key = match entry.delete_credential() {
+        Ok(()) => true,
+        Err(keyring::Error::NoEntry) => false,
+        Err(e) => return Err(format!("OS keyring unavailable: {}", e)),
+    };
+    Ok(had_file || had_key)
+}
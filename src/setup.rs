@@ -0,0 +1,119 @@
+//! First-run setup: create `dek.bin` interactively instead of failing when
+//! it is missing.
+//!
+//! The first member of a room generates the key; the others import it from
+//! a sharing blob, which is the hex of the generating member's `dek.bin`
+//! behind a prefix. The blob is useless without the KEK, so it can travel
+//! by a different channel than the KEK itself.
+
+use rand_core::RngCore;
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Prefix of a sharing blob.
+const BLOB_PREFIX: &str = "antimpeu-key:";
+
+/// Run the wizard when `path` is missing and someone is at the terminal to
+/// answer it. Otherwise leave the missing file for the caller to report.
+pub fn ensure_dek(path: &str) -> Result<(), String> {
+    if std::path::Path::new(path).exists() || !std::io::stdin().is_terminal() {
+        return Ok(());
+    }
+    println!("No key found at {}.", path);
+    if !ask_yes("Set one up now?", true)? {
+        return Ok(());
+    }
+    run(path)
+}
+
+/// Create `path` by generating a new DEK or importing a sharing blob, and
+/// offer to print the blob for the other members.
+pub fn run(path: &str) -> Result<(), String> {
+    if std::path::Path::new(path).exists() {
+        return Err(format!("{} already exists; delete it first to set up a new key", path));
+    }
+    println!("Is this a new room, or are you joining one whose key someone gave you?");
+    let blob = loop {
+        match ask("[n]ew room / [j]oin with a sharing blob: ")?.to_lowercase().as_str() {
+            "n" | "new" => break generate()?,
+            "j" | "join" => break import()?,
+            _ => {}
+        }
+    };
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    crate::identity::write_private(path, &blob).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    println!("Wrote encrypted DEK to {}", path);
+    if ask_yes("Print a sharing blob for the other members?", false)? {
+        println!();
+        println!("{}{}", BLOB_PREFIX, hex::encode(&blob));
+        println!();
+        println!("They run `antimpeu setup` and paste it. Give them the KEK over a different channel.");
+    }
+    Ok(())
+}
+
+/// A fresh DEK, encrypted with a KEK typed twice.
+fn generate() -> Result<Vec<u8>, String> {
+    let mut dek = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut dek);
+    let kek = choose_secret("Choose a KEK (password) to protect the key: ")?;
+    crate::utils::encrypt_dek(&dek, &kek)
+}
+
+/// A new non-empty passphrase, typed twice.
+pub fn choose_secret(prompt: &str) -> Result<String, String> {
+    loop {
+        let secret = ask_secret(prompt)?;
+        if secret.is_empty() {
+            println!("It must not be empty.");
+        } else if ask_secret("Repeat it: ")? != secret {
+            println!("The two do not match; try again.");
+        } else {
+            return Ok(secret);
+        }
+    }
+}
+
+/// A pasted sharing blob, checked against the KEK it was made with.
+fn import() -> Result<Vec<u8>, String> {
+    let blob = loop {
+        let line = ask("Paste the sharing blob: ")?;
+        match line.strip_prefix(BLOB_PREFIX).map(hex::decode) {
+            Some(Ok(blob)) => break blob,
+            _ => println!("That is not a sharing blob; it starts with {}", BLOB_PREFIX),
+        }
+    };
+    loop {
+        let kek = ask_secret("KEK of the room: ")?;
+        match crate::auth::decrypt_dek(&blob, &kek) {
+            Ok(_) => return Ok(blob),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+fn ask(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    std::io::stdout().flush().ok();
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) => Err("Setup cancelled".to_string()),
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(e) => Err(format!("Failed to read the answer: {}", e)),
+    }
+}
+
+pub fn ask_secret(prompt: &str) -> Result<String, String> {
+    print!("{}", prompt);
+    std::io::stdout().flush().ok();
+    rpassword::read_password().map_err(|_| "Failed to read the KEK".to_string())
+}
+
+pub fn ask_yes(question: &str, default: bool) -> Result<bool, String> {
+    let answer = ask(&format!("{} [{}] ", question, if default { "Y/n" } else { "y/N" }))?;
+    Ok(match answer.to_lowercase().as_str() {
+        "" => default,
+        a => a.starts_with('y'),
+    })
+}
@@ -0,0 +1,147 @@
+//! Tor support: publishing the server as an onion service
+//! (`antimpeu server --onion`) and reaching servers through a SOCKS5 proxy.
+//!
+//! The onion service is added over Tor's control port with `ADD_ONION` and
+//! lives as long as the control connection, so it disappears when the
+//! server exits. Its key is kept in `$HOME/key/onion_key` so the address
+//! stays the same across restarts.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Proxy used for `.onion` hosts when `[client] socks_proxy` is not set.
+pub const DEFAULT_SOCKS_PROXY: &str = "127.0.0.1:9050";
+
+/// Location of the onion service key.
+pub fn onion_key_path() -> String {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    format!("{}/key/onion_key", home)
+}
+
+/// An onion service published until dropped.
+pub struct Onion {
+    /// The `.onion` host name, without port.
+    pub address: String,
+    /// Tor removes the service when this connection closes.
+    _control: TcpStream,
+}
+
+/// One line-based conversation with the control port.
+struct Control {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Control {
+    /// Send `command` and return the reply lines without their status
+    /// code; any status but 250 is an error.
+    fn command(&mut self, command: &str) -> Result<Vec<String>, String> {
+        self.stream.write_all(format!("{}\r\n", command).as_bytes()).map_err(|e| e.to_string())?;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Err("Tor closed the control connection".to_string());
+            }
+            let line = line.trim_end();
+            if line.len() < 4 {
+                return Err(format!("unexpected reply from Tor: {}", line));
+            }
+            let (code, rest) = line.split_at(3);
+            if code != "250" {
+                return Err(format!("Tor answered {}", line));
+            }
+            lines.push(rest[1..].to_string());
+            // "250 text" ends the reply, "250-text" continues it
+            if rest.starts_with(' ') {
+                return Ok(lines);
+            }
+        }
+    }
+
+    /// Authenticate with the first method Tor offers that we can use:
+    /// none, the cookie file, or the configured password.
+    fn authenticate(&mut self, password: Option<&str>) -> Result<(), String> {
+        let info = self.command("PROTOCOLINFO 1")?;
+        let auth = info.iter().find_map(|l| l.strip_prefix("AUTH ")).ok_or_else(|| "Tor did not list its authentication methods".to_string())?;
+        let methods = auth.split(' ').find_map(|f| f.strip_prefix("METHODS=")).unwrap_or_default();
+        let methods: Vec<&str> = methods.split(',').collect();
+        let token = if methods.contains(&"NULL") {
+            String::new()
+        } else if let Some(password) = password.filter(|_| methods.contains(&"HASHEDPASSWORD")) {
+            format!("\"{}\"", password.replace('\\', "\\\\").replace('"', "\\\""))
+        } else if methods.contains(&"COOKIE") {
+            let file = auth.split_once("COOKIEFILE=\"").and_then(|(_, f)| f.split_once('"')).map(|(f, _)| f).ok_or_else(|| "Tor did not name its cookie file".to_string())?;
+            let cookie = std::fs::read(file).map_err(|e| format!("Failed to read the Tor cookie {}: {}", file, e))?;
+            hex::encode(cookie)
+        } else {
+            return Err(format!("no usable Tor authentication method among {}; set tor_control_password", methods.join(", ")));
+        };
+        self.command(&format!("AUTHENTICATE {}", token)).map(|_| ())
+    }
+}
+
+/// Publish local TCP `port` as an onion service on the same port, through
+/// the Tor control port at `control`.
+pub fn publish_onion(control: &str, port: u16, password: Option<&str>) -> Result<Onion, String> {
+    let stream = TcpStream::connect(control).map_err(|e| format!("could not reach the Tor control port {}: {}", control, e))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
+    let reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut tor = Control { stream, reader };
+    tor.authenticate(password)?;
+
+    let key_path = onion_key_path();
+    let key = match std::fs::read_to_string(&key_path) {
+        Ok(key) => key.trim().to_string(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "NEW:ED25519-V3".to_string(),
+        Err(e) => return Err(format!("Failed to read {}: {}", key_path, e)),
+    };
+    let reply = tor.command(&format!("ADD_ONION {} Port={},127.0.0.1:{}", key, port, port))?;
+    let service = reply.iter().find_map(|l| l.strip_prefix("ServiceID=")).ok_or_else(|| "Tor did not return the service ID".to_string())?;
+    if let Some(new_key) = reply.iter().find_map(|l| l.strip_prefix("PrivateKey=")) {
+        crate::identity::write_private(&key_path, new_key.as_bytes()).map_err(|e| format!("Failed to write {}: {}", key_path, e))?;
+    }
+    Ok(Onion { address: format!("{}.onion", service), _control: tor.stream })
+}
+
+/// Open a connection to `host:port` through the SOCKS5 proxy at `proxy`.
+/// The proxy resolves the host name, which `.onion` names need.
+pub fn socks_connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream, String> {
+    let fail = |e: std::io::Error| format!("SOCKS proxy {}: {}", proxy, e);
+    let mut stream = TcpStream::connect(proxy).map_err(|e| format!("Could not connect to the SOCKS proxy {}: {}", proxy, e))?;
+    // greeting: version 5, one method, no authentication
+    stream.write_all(&[5, 1, 0]).map_err(fail)?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).map_err(fail)?;
+    if choice != [5, 0] {
+        return Err(format!("SOCKS proxy {} wants authentication", proxy));
+    }
+    let name = host.as_bytes();
+    if name.len() > 255 {
+        return Err(format!("host name {} is too long", host));
+    }
+    let mut request = vec![5, 1, 0, 3, name.len() as u8];
+    request.extend_from_slice(name);
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).map_err(fail)?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).map_err(fail)?;
+    if reply[1] != 0 {
+        return Err(format!("SOCKS proxy {} could not reach {}:{} (error {})", proxy, host, port, reply[1]));
+    }
+    // skip the bound address
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut n = [0u8; 1];
+            stream.read_exact(&mut n).map_err(fail)?;
+            n[0] as usize
+        }
+        t => return Err(format!("SOCKS proxy {} sent address type {}", proxy, t)),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).map_err(fail)?;
+    Ok(stream)
+}
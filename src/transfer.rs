@@ -0,0 +1,160 @@
+//! File transfer in resumable chunks.
+//!
+//! A transfer is pulled by the receiving side, one chunk at a time:
+//!
+//! ```text
+//! sender                        receiver
+//!   file-offer {name, size, hash}  ->
+//!                               <-  file-request {offset}
+//!   file-chunk {offset} + data     ->
+//!                               <-  file-request {offset + len}
+//!   ...
+//! ```
+//!
+//! The receiver appends chunks to a `.part` file named after the SHA-256 of
+//! the whole file and always asks for the chunk at the length of that file.
+//! An interrupted transfer therefore resumes where it stopped once the two
+//! sides talk again: the server after the client offers the same file again,
+//! the client by asking for every `.part` in its download directory when it
+//! connects. Pulling also keeps a large file from flooding the sender's
+//! queue. The receiver checks the hash before it keeps the file.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+/// Bytes of file data carried by one chunk frame.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Identifies a file and, in requests and chunks, a position in it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileMeta {
+    pub name: String,
+    pub size: u64,
+    /// Hex SHA-256 of the whole file.
+    pub hash: String,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+impl FileMeta {
+    pub fn at(&self, offset: u64) -> Self {
+        Self { offset, ..self.clone() }
+    }
+
+    /// Rejects hashes that are not 64 hex digits, since they name files on
+    /// disk, and names that are not a plain file name.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hash.len() != 64 || !self.hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("invalid file hash".to_string());
+        }
+        if Path::new(&self.name).file_name().and_then(|n| n.to_str()) != Some(self.name.as_str()) {
+            return Err(format!("invalid file name {:?}", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Describe the file at `path` for an offer.
+pub fn describe(path: &Path) -> Result<FileMeta, String> {
+    let mut file = std::fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let name = path.file_name().and_then(|n| n.to_str()).ok_or_else(|| format!("{} has no file name", path.display()))?.to_string();
+    let hash = hash_reader(&mut file).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let size = file.metadata().map_err(|e| format!("Failed to read {}: {}", path.display(), e))?.len();
+    Ok(FileMeta { name, size, hash, offset: 0 })
+}
+
+fn hash_reader(reader: &mut impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Bytes already received into a `.part` file.
+pub fn received(part: &Path) -> u64 {
+    std::fs::metadata(part).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Read the chunk of `meta` at `meta.offset` from the complete file at `path`.
+pub fn read_chunk(path: &Path, meta: &FileMeta) -> Result<Vec<u8>, String> {
+    let mut file = std::fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let len = meta.size.saturating_sub(meta.offset).min(CHUNK_SIZE as u64);
+    let mut data = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(meta.offset))
+        .and_then(|_| file.read_exact(&mut data))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(data)
+}
+
+/// Append a received chunk to `part` and return the bytes now received.
+/// Chunks must arrive in order and stay within the announced size.
+pub fn append_chunk(part: &Path, meta: &FileMeta, data: &[u8]) -> Result<u64, String> {
+    let have = received(part);
+    if meta.offset != have {
+        return Err(format!("chunk at {} but {} bytes received", meta.offset, have));
+    }
+    if data.is_empty() || data.len() > CHUNK_SIZE || have + data.len() as u64 > meta.size {
+        return Err("chunk out of bounds".to_string());
+    }
+    if let Some(dir) = part.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(part).map_err(|e| format!("Failed to open {}: {}", part.display(), e))?;
+    file.write_all(data).map_err(|e| format!("Failed to write {}: {}", part.display(), e))?;
+    Ok(have + data.len() as u64)
+}
+
+/// Check a complete `.part` file against the hash and move it to `dest`.
+/// A file that does not match is deleted so the next attempt starts over.
+pub fn finish(part: &Path, meta: &FileMeta, dest: &Path) -> Result<(), String> {
+    let mut file = std::fs::File::open(part).map_err(|e| format!("Failed to open {}: {}", part.display(), e))?;
+    let hash = hash_reader(&mut file).map_err(|e| format!("Failed to read {}: {}", part.display(), e))?;
+    if hash != meta.hash {
+        let _ = std::fs::remove_file(part);
+        return Err(format!("{} does not match its hash and was discarded", meta.name));
+    }
+    std::fs::rename(part, dest).map_err(|e| format!("Failed to move {} to {}: {}", part.display(), dest.display(), e))
+}
+
+/// Files shared on the server, stored by hash in `$HOME/key/files`.
+pub fn server_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| "~".to_string());
+    PathBuf::from(home).join("key").join("files")
+}
+
+/// Where the client keeps a partial download; the name and hash in the
+/// file name let `pending_downloads` resume it after a restart.
+pub fn download_part(dir: &Path, meta: &FileMeta) -> PathBuf {
+    dir.join(format!(".{}.{}.part", meta.name, meta.hash))
+}
+
+/// Partial downloads in `dir`, positioned at what was already received.
+/// The size is not known until the first chunk arrives.
+pub fn pending_downloads(dir: &Path) -> Vec<FileMeta> {
+    let Ok(entries) = std::fs::read_dir(dir) else { return Vec::new() };
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name().into_string().ok()?;
+            let (name, hash) = file_name.strip_prefix('.')?.strip_suffix(".part")?.rsplit_once('.')?;
+            let meta = FileMeta { name: name.to_string(), size: 0, hash: hash.to_string(), offset: received(&entry.path()) };
+            meta.validate().ok().map(|()| meta)
+        })
+        .collect()
+}
+
+/// Human-readable size.
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
@@ -10,148 +10,1170 @@ use std::io::stdout;
 use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
 use ratatui::{prelude::*, widgets::*};
-use std::sync::{Arc, Mutex};
+use std::rc::Rc;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use crate::config::TuiConfig;
+use crate::types::{Delivery, Overlay, SharedMessages, SharedStatus};
+pub use crate::types::{parse_ephemeral, push_ephemeral, Message, MAX_EPHEMERAL_TTL};
+use std::collections::VecDeque;
+use clap::ValueEnum;
+use crate::editor::Editor;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-#[derive(Clone)]
-pub struct Message {
-    pub sender: String,
-    pub text: String,
-    pub time: String,
+/// Timezone message times are shown in, chosen with `[tui] timezone` or
+/// `/tz`.
+#[derive(serde::Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+#[serde(try_from = "String")]
+pub enum Zone {
+    /// The system's timezone.
+    #[default]
+    Local,
+    Utc,
+    /// A fixed offset from UTC such as `+05:30`.
+    Offset(chrono::FixedOffset),
+    /// A tz database name such as `Europe/Berlin`. The process runs with
+    /// `TZ` set to it, see `Zone::apply`, so it is shown as local time.
+    Named(String),
+}
+
+impl std::str::FromStr for Zone {
+    type Err = String;
+
+    /// `local`, `utc`, an offset such as `+05:30`, `-3` or `UTC+1`, or the
+    /// name of a zone in the system's tz database.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let unknown = || format!("Unknown timezone {:?}; use local, utc, an offset such as +05:30 or a name such as Europe/Berlin", s);
+        match s.to_lowercase().as_str() {
+            "local" => return Ok(Zone::Local),
+            "utc" | "gmt" | "z" => return Ok(Zone::Utc),
+            _ => {}
+        }
+        let offset = s.get(..3).filter(|p| p.eq_ignore_ascii_case("utc")).map_or(s, |_| &s[3..]);
+        if let Some(sign) = offset.chars().next().filter(|c| matches!(c, '+' | '-')) {
+            let (hours, minutes) = offset[1..].split_once(':').unwrap_or((&offset[1..], "0"));
+            let (Ok(hours), Ok(minutes)) = (hours.parse::<i32>(), minutes.parse::<i32>()) else { return Err(unknown()) };
+            let seconds = (hours * 60 + minutes) * 60 * if sign == '-' { -1 } else { 1 };
+            return chrono::FixedOffset::east_opt(seconds).filter(|_| minutes < 60).map(Zone::Offset).ok_or_else(unknown);
+        }
+        let tzdir = std::env::var("TZDIR").unwrap_or_else(|_| "/usr/share/zoneinfo".to_string());
+        if !s.is_empty() && !s.split('/').any(|part| part.is_empty() || part.starts_with('.')) && std::path::Path::new(&tzdir).join(s).is_file() {
+            return Ok(Zone::Named(s.to_string()));
+        }
+        Err(unknown())
+    }
+}
+
+impl TryFrom<String> for Zone {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl std::fmt::Display for Zone {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Zone::Local => write!(f, "local time"),
+            Zone::Utc => write!(f, "UTC"),
+            Zone::Offset(offset) => write!(f, "UTC{}", offset),
+            Zone::Named(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Zone {
+    /// Point local time at a named zone by setting `TZ`. Only call this
+    /// before any other thread starts.
+    pub fn apply(&self) {
+        if let Zone::Named(name) = self {
+            std::env::set_var("TZ", name);
+        }
+    }
+
+    /// `time` as `HH:MM` in this zone, with the date in front when that
+    /// is not today there.
+    pub fn format(&self, time: chrono::DateTime<chrono::Utc>) -> String {
+        let (time, today) = (self.at(time), self.at(chrono::Utc::now()).date_naive());
+        let format = if time.date_naive() == today { "%H:%M" } else { "%m-%d %H:%M" };
+        time.format(format).to_string()
+    }
+
+    /// `time` in full, to the millisecond and with the UTC offset, for
+    /// the details of a message.
+    pub fn format_exact(&self, time: chrono::DateTime<chrono::Utc>) -> String {
+        self.at(time).format("%Y-%m-%d %H:%M:%S%.3f %:z").to_string()
+    }
+
+    /// `time` relative to now: `just now`, `5m ago`, `3h ago` for earlier
+    /// today, then `yesterday 14:30` and the date and time before that,
+    /// the days counted in this zone.
+    pub fn format_relative(&self, time: chrono::DateTime<chrono::Utc>) -> String {
+        let now = chrono::Utc::now();
+        let age = now - time;
+        let (time, today) = (self.at(time), self.at(now).date_naive());
+        if age < chrono::TimeDelta::minutes(1) {
+            "just now".to_string()
+        } else if age < chrono::TimeDelta::hours(1) {
+            format!("{}m ago", age.num_minutes())
+        } else if time.date_naive() == today {
+            format!("{}h ago", age.num_hours())
+        } else if today.pred_opt() == Some(time.date_naive()) {
+            time.format("yesterday %H:%M").to_string()
+        } else {
+            time.format("%m-%d %H:%M").to_string()
+        }
+    }
+
+    /// `time` in this zone.
+    fn at(&self, time: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::FixedOffset> {
+        match self {
+            Zone::Local | Zone::Named(_) => time.with_timezone(&chrono::Local).fixed_offset(),
+            Zone::Utc => time.fixed_offset(),
+            Zone::Offset(offset) => time.with_timezone(offset),
+        }
+    }
+}
+
+/// Colour scheme of the TUI, chosen with `[tui] theme`.
+#[derive(serde::Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Theme {
+    /// Bright colours on a dark, purple-tinged background, like gotop.
+    #[default]
+    Dark,
+    /// Dark colours on an off-white background.
+    Light,
+    /// White and saturated colours on black, every colour at a contrast
+    /// ratio of 7:1 or more against the background (WCAG AAA).
+    HighContrast,
+}
+
+/// How many colours the terminal can show, set with `[tui] colors` when
+/// detection gets it wrong.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorSupport {
+    /// Detect from the environment, see `ColorSupport::detect`.
+    #[default]
+    Auto,
+    /// 24-bit colour: the themes as designed.
+    Truecolor,
+    /// The xterm 256-colour palette.
+    #[serde(rename = "256")]
+    Ansi256,
+    /// The 16 ANSI colours, whose exact shades the terminal decides.
+    #[serde(rename = "16")]
+    Ansi16,
+}
+
+impl ColorSupport {
+    /// Resolve `Auto`: `COLORTERM` announces truecolor, otherwise ask
+    /// terminfo for the `colors` capability, falling back to `TERM`.
+    pub fn detect(self) -> Self {
+        if self != ColorSupport::Auto {
+            return self;
+        }
+        // Windows Terminal does not set COLORTERM
+        if matches!(std::env::var("COLORTERM").as_deref(), Ok("truecolor" | "24bit")) || std::env::var_os("WT_SESSION").is_some() {
+            return ColorSupport::Truecolor;
+        }
+        let colors = std::process::Command::new("tput")
+            .arg("colors")
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .and_then(|out| String::from_utf8(out.stdout).ok()?.trim().parse::<u32>().ok())
+            .or_else(|| std::env::var("TERM").ok().map(|term| if term.contains("256color") { 256 } else { 16 }));
+        match colors {
+            Some(n) if n >= 1 << 24 => ColorSupport::Truecolor,
+            Some(n) if n >= 256 => ColorSupport::Ansi256,
+            _ => ColorSupport::Ansi16,
+        }
+    }
+}
+
+/// Colours used when drawing, see `Theme::palette`.
+#[derive(Clone, Copy)]
+pub struct Palette {
+    time: Color,
+    /// Sender names; each name always gets the same one, see
+    /// `Palette::sender`.
+    senders: [Color; 6],
+    /// Message arrows, warnings and the overlay border.
+    warm: Color,
+    text: Color,
+    bad: Color,
+    /// Titles, borders and the mode indicator.
+    accent: Color,
+    background: Color,
+    /// Background of the overlay box.
+    panel: Color,
+    /// Key hints.
+    dim: Color,
+}
+
+impl Theme {
+    /// Name as written in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+            Theme::HighContrast => "high-contrast",
+        }
+    }
+
+    pub fn palette(self) -> Palette {
+        match self {
+            Theme::Dark => Palette {
+                time: Color::Rgb(80, 250, 123),
+                senders: [Color::Rgb(198, 120, 221), Color::Rgb(97, 175, 239), Color::Rgb(229, 192, 123), Color::Rgb(224, 108, 117), Color::Rgb(86, 182, 194), Color::Rgb(152, 195, 121)],
+                warm: Color::Rgb(255, 168, 64),
+                text: Color::Rgb(200, 200, 210),
+                bad: Color::Rgb(255, 85, 85),
+                accent: Color::Rgb(50, 230, 230),
+                background: Color::Rgb(20, 18, 28),
+                panel: Color::Rgb(30, 27, 40),
+                dim: Color::Rgb(120, 120, 140),
+            },
+            Theme::Light => Palette {
+                time: Color::Rgb(0, 128, 64),
+                senders: [Color::Rgb(136, 48, 168), Color::Rgb(0, 90, 180), Color::Rgb(150, 90, 0), Color::Rgb(180, 30, 80), Color::Rgb(0, 120, 120), Color::Rgb(70, 110, 20)],
+                warm: Color::Rgb(196, 96, 0),
+                text: Color::Rgb(40, 40, 52),
+                bad: Color::Rgb(200, 30, 30),
+                accent: Color::Rgb(0, 112, 140),
+                background: Color::Rgb(250, 250, 244),
+                panel: Color::Rgb(232, 232, 224),
+                dim: Color::Rgb(128, 128, 140),
+            },
+            Theme::HighContrast => Palette {
+                time: Color::Rgb(255, 255, 0),
+                senders: [Color::Rgb(255, 128, 255), Color::Rgb(128, 200, 255), Color::Rgb(255, 210, 80), Color::Rgb(255, 150, 150), Color::Rgb(100, 255, 220), Color::Rgb(180, 255, 120)],
+                warm: Color::Rgb(255, 176, 0),
+                text: Color::Rgb(255, 255, 255),
+                bad: Color::Rgb(255, 110, 110),
+                accent: Color::Rgb(0, 255, 255),
+                background: Color::Rgb(0, 0, 0),
+                panel: Color::Rgb(0, 0, 0),
+                dim: Color::Rgb(210, 210, 210),
+            },
+        }
+    }
+
+    /// The palette for a terminal with `colors`: the truecolor one, its
+    /// nearest xterm colours, or a hand-picked ANSI set, since the nearest
+    /// of 16 colours is mostly grey.
+    pub fn palette_for(self, colors: ColorSupport) -> Palette {
+        match colors.detect() {
+            ColorSupport::Auto | ColorSupport::Truecolor => self.palette(),
+            ColorSupport::Ansi256 => self.palette().map(nearest_256),
+            ColorSupport::Ansi16 => match self {
+                Theme::Dark => Palette {
+                    time: Color::LightGreen,
+                    senders: [Color::LightMagenta, Color::LightBlue, Color::Yellow, Color::LightCyan, Color::LightGreen, Color::Magenta],
+                    warm: Color::Yellow,
+                    text: Color::Gray,
+                    bad: Color::LightRed,
+                    accent: Color::LightCyan,
+                    background: Color::Black,
+                    panel: Color::Black,
+                    dim: Color::DarkGray,
+                },
+                Theme::Light => Palette {
+                    time: Color::Green,
+                    senders: [Color::Magenta, Color::Blue, Color::Cyan, Color::Green, Color::Red, Color::DarkGray],
+                    warm: Color::Yellow,
+                    text: Color::Black,
+                    bad: Color::Red,
+                    accent: Color::Blue,
+                    background: Color::White,
+                    panel: Color::Gray,
+                    dim: Color::DarkGray,
+                },
+                Theme::HighContrast => Palette {
+                    time: Color::LightYellow,
+                    senders: [Color::LightMagenta, Color::LightBlue, Color::LightYellow, Color::LightCyan, Color::LightGreen, Color::White],
+                    warm: Color::Yellow,
+                    text: Color::White,
+                    bad: Color::LightRed,
+                    accent: Color::LightCyan,
+                    background: Color::Black,
+                    panel: Color::Black,
+                    dim: Color::Gray,
+                },
+            },
+        }
+    }
+}
+
+impl Palette {
+    /// Colour of the name `sender`, picked by a hash of it so it stays the
+    /// same across messages, restarts and machines.
+    fn sender(&self, sender: &str) -> Color {
+        // FNV-1a, as `DefaultHasher` may change between Rust releases
+        let hash = sender.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+        self.senders[(hash % self.senders.len() as u64) as usize]
+    }
+
+    fn map(self, f: fn(Color) -> Color) -> Palette {
+        Palette {
+            time: f(self.time),
+            senders: self.senders.map(f),
+            warm: f(self.warm),
+            text: f(self.text),
+            bad: f(self.bad),
+            accent: f(self.accent),
+            background: f(self.background),
+            panel: f(self.panel),
+            dim: f(self.dim),
+        }
+    }
+}
+
+/// The xterm 256-colour index closest to an RGB colour: the nearest point
+/// of the 6×6×6 cube or of the grey ramp, whichever is closer.
+fn nearest_256(color: Color) -> Color {
+    let Color::Rgb(r, g, b) = color else { return color };
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |c: u8| (0..6).min_by_key(|&i| LEVELS[i].abs_diff(c)).unwrap_or(0);
+    let distance = |(r2, g2, b2): (u8, u8, u8)| {
+        let d = |a: u8, b: u8| u32::from(a.abs_diff(b)).pow(2);
+        d(r, r2) + d(g, g2) + d(b, b2)
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (LEVELS[ri], LEVELS[gi], LEVELS[bi]);
+    let grey_step = ((u32::from(r) + u32::from(g) + u32::from(b)) / 3).saturating_sub(3) / 10;
+    let grey_step = grey_step.min(23) as u8;
+    let grey = 8 + 10 * grey_step;
+    if distance((grey, grey, grey)) < distance(cube) {
+        Color::Indexed(232 + grey_step)
+    } else {
+        Color::Indexed(16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8)
+    }
+}
+
+/// Slash commands the TUI handles itself, for the F1 help.
+const COMMANDS: &[(&str, &str)] = &[
+    ("/theme [name]", "switch the colour scheme: dark, light or high-contrast"),
+    ("/times [clock|relative]", "show times as 14:30 or as 5m ago, yesterday 14:30"),
+    ("/tz [zone]", "show times in local time, utc, an offset like +05:30 or a zone like Europe/Berlin"),
+    ("/clear", "empty the chat pane; the local log is kept"),
+];
+
+/// Height of the input box (including borders) for an empty or short draft.
+const INPUT_MIN_HEIGHT: u16 = 3;
+/// The input box stops growing at this height and scrolls its content instead.
+const INPUT_MAX_HEIGHT: u16 = 8;
+/// Height of the system event pane (including borders) when it is shown.
+const EVENT_PANE_HEIGHT: u16 = 8;
+/// Below this width or height the panes lose their borders and titles, the
+/// key hints go and the event pane shrinks, see `is_compact`.
+const COMPACT_WIDTH: u16 = 40;
+const COMPACT_HEIGHT: u16 = 12;
+/// Below this size only a "terminal too small" notice is drawn.
+const MIN_WIDTH: u16 = 20;
+const MIN_HEIGHT: u16 = 4;
+
+/// Whether `m` is one of ours: by user ID when both sides have one, which
+/// holds across renames, else by name.
+pub fn is_own(m: &Message, username: &str, user_id: Option<&str>) -> bool {
+    match (m.user.as_deref(), user_id) {
+        (Some(user), Some(id)) => user == id,
+        _ => m.sender == username,
+    }
+}
+
+/// Parse `name` for `/tz` into `zone`. A named zone other than the one
+/// given at start is refused: `TZ` cannot safely change once threads run.
+pub fn set_zone(zone: &mut Zone, name: &str) -> Result<(), String> {
+    let new: Zone = name.parse()?;
+    if let Zone::Named(name) = &new {
+        if std::env::var("TZ").ok().as_deref() != Some(name) {
+            return Err(format!("Set timezone = {:?} under [tui] in the config and restart to use a named zone", name));
+        }
+    }
+    *zone = new;
+    Ok(())
+}
+
+/// Modes of the optional vi-style keybindings.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ViMode {
+    /// Keys scroll and search the chat pane.
+    Normal,
+    /// Keys edit the draft, like the regular input focus.
+    Insert,
+    /// Keys edit the search query started with `/`.
+    Search,
 }
 
 pub struct ChatState {
-    pub messages: Vec<Message>,
-    pub input: String,
+    pub messages: VecDeque<Message>,
+    /// `MessageBuffer::pushed` at the last sync, used to detect new messages
+    /// even once the buffer is full and its length stops growing.
+    pub messages_pushed: u64,
+    /// `MessageBuffer::prepended` at the last sync.
+    pub messages_prepended: u64,
+    /// Set once `RoomStatus::scrollback` has nothing older to load, or
+    /// after `/clear`.
+    pub scrollback_done: bool,
+    /// `MessageBuffer::generation` at the last sync.
+    pub messages_generation: u64,
+    pub input: Editor,
     pub input_focused: bool,
     pub vertical_scroll: usize,
+    /// Rows of messages the chat pane showed in the last frame, the page
+    /// PageUp and PageDown scroll by.
+    pub chat_rows: usize,
+    /// Current vi mode, or `None` when vi keybindings are disabled.
+    pub vi_mode: Option<ViMode>,
+    /// Query being typed in search mode.
+    pub search: String,
+    /// Last submitted query, reused by `n` / `N`.
+    pub last_search: String,
+    /// Index of the message matched by the last search.
+    pub search_hit: Option<usize>,
+    /// Message under the selection cursor, an index into `messages`;
+    /// Enter shows its details.
+    pub selected: Option<usize>,
+    /// Set after a first `g` so that `gg` jumps to the top.
+    pending_g: bool,
+    /// Room topic, copied from the shared status every frame.
+    pub topic: Option<String>,
+    /// Set when a message was held back by slow mode; the status bar counts
+    /// down to this time.
+    pub held_until: Option<std::time::Instant>,
+    /// Progress of file transfers, one label each, for the status bar.
+    pub transfers: Vec<String>,
+    /// Round-trip time to the server, from the shared status.
+    pub rtt: Option<crate::types::Rtt>,
+    /// Box shown over the chat pane, such as `/search` results, and how far
+    /// it is scrolled.
+    pub overlay: Option<Overlay>,
+    pub overlay_scroll: usize,
+    pub palette: Palette,
+    /// What the terminal can show, detected once at start for `/theme`.
+    pub colors: ColorSupport,
+    /// Reorder right-to-left text for display, see `visual_order`.
+    pub bidi: bool,
+    /// Name of the local user, whose messages are marked `(you)`.
+    pub username: String,
+    /// Our stable ID once the server gave it, to recognise our own messages
+    /// across renames.
+    pub user_id: Option<String>,
+    /// How far our own messages got, from the shared status.
+    pub delivery: std::collections::HashMap<String, Delivery>,
+    /// Timezone message times are shown in.
+    pub zone: Zone,
+    /// Show times as `5m ago` rather than `14:30`; they are worked out at
+    /// every frame, so they age on screen.
+    pub relative_times: bool,
+    /// System events, copied from the shared buffer when new ones arrive.
+    pub events: Vec<Message>,
+    /// `MessageBuffer::events_pushed` at the last sync.
+    pub events_pushed: u64,
+    /// The event pane is shown below the chat.
+    pub show_events: bool,
+    /// `events_pushed` when the event pane was last shown, to count the
+    /// events that arrived while it was hidden.
+    pub events_seen: u64,
+    /// `[tui.aliases]`, for the help overlay.
+    pub aliases: std::collections::BTreeMap<String, String>,
+    /// Slash commands for the help overlay, from the shared status.
+    pub commands: Vec<(&'static str, &'static str)>,
 }
 
 impl ChatState {
     pub fn new() -> Self {
         Self {
-            messages: vec![],
-            input: String::new(),
+            messages: VecDeque::new(),
+            messages_pushed: 0,
+            messages_prepended: 0,
+            scrollback_done: false,
+            messages_generation: 0,
+            input: Editor::default(),
             input_focused: false,
             vertical_scroll: 0,
+            chat_rows: 0,
+            vi_mode: None,
+            search: String::new(),
+            last_search: String::new(),
+            search_hit: None,
+            selected: None,
+            pending_g: false,
+            topic: None,
+            held_until: None,
+            transfers: Vec::new(),
+            rtt: None,
+            overlay: None,
+            overlay_scroll: 0,
+            palette: Theme::default().palette(),
+            colors: ColorSupport::Truecolor,
+            bidi: true,
+            username: String::new(),
+            user_id: None,
+            delivery: Default::default(),
+            zone: Zone::Local,
+            relative_times: false,
+            events: Vec::new(),
+            events_pushed: 0,
+            show_events: true,
+            events_seen: 0,
+            aliases: Default::default(),
+            commands: Vec::new(),
+        }
+    }
+
+    fn set_vi_mode(&mut self, mode: ViMode) {
+        self.vi_mode = Some(mode);
+        self.input_focused = mode == ViMode::Insert;
+        self.pending_g = false;
+    }
+
+    /// Text shown in the input box: the search query in search mode,
+    /// otherwise the draft.
+    pub fn input_box_text(&self) -> &str {
+        if self.vi_mode == Some(ViMode::Search) { &self.search } else { self.input.text() }
+    }
+
+    /// Find the next message containing `last_search` (case-insensitive),
+    /// starting next to the current hit and wrapping around. `older` searches
+    /// towards the top of the history.
+    fn find_next(&self, older: bool) -> Option<usize> {
+        let query = self.last_search.to_lowercase();
+        let len = self.messages.len();
+        if query.is_empty() || len == 0 {
+            return None;
+        }
+        let start = self.search_hit.unwrap_or(len);
+        (1..=len)
+            .map(|step| if older { (start + len - step % len) % len } else { (start + step) % len })
+            .find(|&i| self.messages[i].text.to_lowercase().contains(&query) || self.messages[i].sender.to_lowercase().contains(&query))
+    }
+
+    /// Open the F1 overlay listing the keys of the current keybindings and
+    /// the slash commands.
+    /// Scroll the chat a page up or down, keeping one line of the last
+    /// page in view.
+    fn scroll_page(&mut self, down: bool) {
+        let page = self.chat_rows.saturating_sub(1).max(1);
+        self.vertical_scroll = if down { self.vertical_scroll.saturating_add(page) } else { self.vertical_scroll.saturating_sub(page) };
+    }
+
+    fn show_help(&mut self) {
+        let keys: &[(&str, &str)] = if self.vi_mode.is_some() {
+            &[
+                ("i, a, Tab", "insert mode: type a message"),
+                ("Esc", "back to normal mode"),
+                ("Enter", "send (insert mode)"),
+                ("j/k, ↑/↓, wheel", "scroll"),
+                ("J/K, click", "select a message; Enter or a second click shows its details"),
+                ("gg / G, Home/End", "jump to the top / bottom"),
+                ("Ctrl+B/F, PgUp/PgDn", "scroll a page up / down"),
+                ("/", "search the messages on screen; n/N next/previous"),
+                ("u / Ctrl+R", "undo / redo an edit of the draft"),
+                ("Ctrl+Z / Ctrl+Y", "the same in insert mode, which has the readline keys too"),
+                ("e, F2", "show or hide the event pane"),
+                ("F1", "this help"),
+                ("q", "quit"),
+            ]
+        } else {
+            &[
+                ("Tab", "switch between the chat and the input box"),
+                ("Enter", "send (input box)"),
+                ("Ctrl+Z / Ctrl+Y", "undo / redo an edit of the draft"),
+                ("←/→, Ctrl+A/E", "move in the draft, to its start / end"),
+                ("Alt+B/F", "move a word back / forward"),
+                ("Ctrl+W/U/K", "delete the word before, to the start, to the end"),
+                ("↑/↓, wheel", "scroll; in the chat ↑/↓ select a message"),
+                ("Enter, click", "show the selected message's details (chat)"),
+                ("PgUp/PgDn", "scroll a page up / down"),
+                ("Ctrl+Home/End", "jump to the top / bottom; Home/End in the chat"),
+                ("F2", "show or hide the event pane"),
+                ("F1", "this help"),
+                ("Esc", "quit"),
+            ]
+        };
+        let row = |(key, what): &(&str, &str)| format!("  {}{}", pad(key, 30), what);
+        let mut lines = vec!["Keys".to_string()];
+        lines.extend(keys.iter().map(row));
+        lines.push(String::new());
+        lines.push("Commands".to_string());
+        lines.extend(COMMANDS.iter().chain(&self.commands).map(row));
+        if !self.aliases.is_empty() {
+            lines.push(String::new());
+            lines.push("Aliases".to_string());
+            lines.extend(self.aliases.iter().map(|(name, expansion)| row(&(&format!("/{}", name), expansion))));
+        }
+        self.overlay = Some(Overlay { title: "Help".to_string(), lines });
+        self.overlay_scroll = 0;
+    }
+
+    /// Insert pasted or committed text into the draft, or into the query in
+    /// search mode, focusing the input box first. Line breaks become
+    /// spaces, as Enter sends.
+    fn paste(&mut self, text: &str) {
+        let text: String = text.replace("\r\n", "\n").chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        match self.vi_mode {
+            Some(ViMode::Search) => return self.search.push_str(&text),
+            Some(ViMode::Normal) => self.set_vi_mode(ViMode::Insert),
+            Some(ViMode::Insert) => {}
+            None => self.input_focused = true,
+        }
+        self.input.paste(&text);
+    }
+
+    /// `/theme`: switch to the theme called `name`, and say so or list the
+    /// themes.
+    fn set_theme(&mut self, name: &str) -> String {
+        let names = || Theme::value_variants().iter().map(|t| t.name()).collect::<Vec<_>>().join(", ");
+        if name.is_empty() {
+            return format!("Themes: {}", names());
+        }
+        match Theme::from_str(name, true) {
+            Ok(theme) => {
+                self.palette = theme.palette_for(self.colors);
+                format!("Theme: {}", theme.name())
+            }
+            Err(_) => format!("No theme {:?}; choose one of {}", name, names()),
         }
     }
+
+    /// `/tz`: show times in the zone `name`, and say so or name the current
+    /// one.
+    fn set_zone(&mut self, name: &str) -> String {
+        if name.is_empty() {
+            return format!("Times are shown in {}", self.zone);
+        }
+        match set_zone(&mut self.zone, name) {
+            Ok(()) => format!("Times are now shown in {}", self.zone),
+            Err(e) => e,
+        }
+    }
+
+    /// `/times`: show times as a clock or relative to now.
+    fn set_time_style(&mut self, style: &str) -> String {
+        match style {
+            "" => {}
+            "clock" => self.relative_times = false,
+            "relative" => self.relative_times = true,
+            _ => return format!("No time style {:?}; choose clock or relative", style),
+        }
+        format!("Times: {}", if self.relative_times { "relative" } else { "clock" })
+    }
+
+    /// How the time of a message is shown, in the current zone and style.
+    fn time_label(&self, time: chrono::DateTime<chrono::Utc>) -> String {
+        if self.relative_times { self.zone.format_relative(time) } else { self.zone.format(time) }
+    }
+
+    fn toggle_events(&mut self) {
+        self.show_events = !self.show_events;
+        self.events_seen = self.events_pushed;
+    }
+
+    fn jump_to_next_match(&mut self, older: bool) {
+        if let Some(i) = self.find_next(older) {
+            self.search_hit = Some(i);
+            self.selected = Some(i);
+            self.vertical_scroll = i;
+        }
+    }
+
+    /// Move the selection cursor to the next older or newer message,
+    /// starting from the newest one on screen, and scroll it into view.
+    fn move_selection(&mut self, older: bool) {
+        let Some(last) = self.messages.len().checked_sub(1) else { return };
+        let i = match self.selected {
+            Some(i) if older => i.saturating_sub(1),
+            Some(i) => (i + 1).min(last),
+            None => self.vertical_scroll.saturating_add(self.chat_rows).saturating_sub(1).min(last),
+        };
+        self.selected = Some(i);
+        if i < self.vertical_scroll {
+            self.vertical_scroll = i;
+        } else if i >= self.vertical_scroll.saturating_add(self.chat_rows) {
+            self.vertical_scroll = i + 1 - self.chat_rows.max(1);
+        }
+    }
+
+    /// Show the selected message in an overlay: its whole text, the exact
+    /// time, the sender's ID, the message ID and whether it was delivered.
+    fn show_selected(&mut self) {
+        let Some(m) = self.selected.and_then(|i| self.messages.get(i)) else { return };
+        let delivery = match m.id.as_ref().and_then(|id| self.delivery.get(id)) {
+            Some(Delivery::Sending) => "sent, waiting for the server",
+            Some(Delivery::Delivered) => "delivered",
+            Some(Delivery::Failed) => "not delivered",
+            None if m.id.is_some() => "received",
+            None => "local, never sent",
+        };
+        let row = |label: &str, value: &str| format!("{}{}", pad(label, 13), value);
+        let mut lines = vec![
+            row("From", &m.sender),
+            row("Sender ID", m.user.as_deref().unwrap_or("none")),
+            row("Time", &self.zone.format_exact(m.time)),
+            row("Message ID", m.id.as_deref().unwrap_or("none")),
+            row("Delivery", delivery),
+        ];
+        match m.verified {
+            Some(true) => lines.push(row("Signature", "verified")),
+            Some(false) => lines.push(row("Signature", "does not verify")),
+            None => {}
+        }
+        if let Some(expires) = m.expires {
+            lines.push(row("Expires", &format!("in {}s", expires.saturating_duration_since(std::time::Instant::now()).as_secs())));
+        }
+        lines.push(String::new());
+        lines.extend(m.text.lines().map(str::to_string));
+        self.overlay = Some(Overlay { title: "Message".to_string(), lines });
+        self.overlay_scroll = 0;
+    }
+}
+
+/// Outcome of a key press that the event loop has to act on.
+enum KeyAction {
+    None,
+    Submit,
+    Quit,
 }
 
-pub fn run_tui_with_sender<F>(send_fn: F, messages: Arc<Mutex<Vec<Message>>>, shutdown: Arc<AtomicBool>) -> std::io::Result<()>
+/// Handle a key press when vi keybindings are enabled.
+fn handle_vi_key(state: &mut ChatState, mode: ViMode, key: event::KeyEvent) -> KeyAction {
+    match key.code {
+        event::KeyCode::F(1) => {
+            state.show_help();
+            return KeyAction::None;
+        }
+        event::KeyCode::F(2) => {
+            state.toggle_events();
+            return KeyAction::None;
+        }
+        _ => {}
+    }
+    match mode {
+        ViMode::Normal => {
+            let pending_g = std::mem::take(&mut state.pending_g);
+            match key.code {
+                event::KeyCode::Char('q') => return KeyAction::Quit,
+                event::KeyCode::Char('j') | event::KeyCode::Down => state.vertical_scroll += 1,
+                event::KeyCode::Char('k') | event::KeyCode::Up => state.vertical_scroll = state.vertical_scroll.saturating_sub(1),
+                event::KeyCode::Char('g') if pending_g => state.vertical_scroll = 0,
+                event::KeyCode::Char('g') => state.pending_g = true,
+                // clamped to the last page when drawing
+                event::KeyCode::Char('G') | event::KeyCode::End => state.vertical_scroll = usize::MAX,
+                event::KeyCode::Home => state.vertical_scroll = 0,
+                event::KeyCode::Char('b') if key.modifiers.contains(event::KeyModifiers::CONTROL) => state.scroll_page(false),
+                event::KeyCode::Char('f') if key.modifiers.contains(event::KeyModifiers::CONTROL) => state.scroll_page(true),
+                event::KeyCode::PageUp => state.scroll_page(false),
+                event::KeyCode::PageDown => state.scroll_page(true),
+                event::KeyCode::Char('/') => {
+                    state.search.clear();
+                    state.set_vi_mode(ViMode::Search);
+                }
+                event::KeyCode::Char('K') => state.move_selection(true),
+                event::KeyCode::Char('J') => state.move_selection(false),
+                event::KeyCode::Enter => state.show_selected(),
+                event::KeyCode::Esc => state.selected = None,
+                event::KeyCode::Char('n') => state.jump_to_next_match(true),
+                event::KeyCode::Char('N') => state.jump_to_next_match(false),
+                event::KeyCode::Char('i') | event::KeyCode::Char('a') | event::KeyCode::Tab => state.set_vi_mode(ViMode::Insert),
+                event::KeyCode::Char('e') => state.toggle_events(),
+                event::KeyCode::Char('r') if key.modifiers.contains(event::KeyModifiers::CONTROL) => state.input.redo(),
+                event::KeyCode::Char('u') => state.input.undo(),
+                _ => {}
+            }
+        }
+        ViMode::Insert => match key.code {
+            event::KeyCode::Esc | event::KeyCode::Tab => state.set_vi_mode(ViMode::Normal),
+            event::KeyCode::Enter => return KeyAction::Submit,
+            event::KeyCode::PageUp => state.scroll_page(false),
+            event::KeyCode::PageDown => state.scroll_page(true),
+            _ => handle_edit_key(state, key),
+        },
+        ViMode::Search => match key.code {
+            event::KeyCode::Esc => {
+                state.search.clear();
+                state.set_vi_mode(ViMode::Normal);
+            }
+            event::KeyCode::Enter => {
+                state.last_search = std::mem::take(&mut state.search);
+                state.search_hit = None;
+                state.set_vi_mode(ViMode::Normal);
+                state.jump_to_next_match(true);
+            }
+            event::KeyCode::Char(c) => state.search.push(c),
+            event::KeyCode::Backspace => pop_grapheme(&mut state.search),
+            _ => {}
+        },
+    }
+    KeyAction::None
+}
+
+/// Handle a key press while an overlay is open: scroll it or close it.
+fn handle_overlay_key(state: &mut ChatState, key: event::KeyEvent) {
+    match key.code {
+        event::KeyCode::Esc | event::KeyCode::Enter | event::KeyCode::Char('q') | event::KeyCode::F(1) => state.overlay = None,
+        event::KeyCode::Up | event::KeyCode::Char('k') => state.overlay_scroll = state.overlay_scroll.saturating_sub(1),
+        event::KeyCode::Down | event::KeyCode::Char('j') => state.overlay_scroll += 1,
+        _ => {}
+    }
+}
+
+/// Handle a key press with the default keybindings.
+fn handle_key(state: &mut ChatState, key: event::KeyEvent) -> KeyAction {
+    match key.code {
+        // the first Esc drops the selection
+        event::KeyCode::Esc if state.selected.is_some() => state.selected = None,
+        event::KeyCode::Esc => return KeyAction::Quit,
+        event::KeyCode::Up if !state.input_focused => state.move_selection(true),
+        event::KeyCode::Down if !state.input_focused => state.move_selection(false),
+        event::KeyCode::Enter if !state.input_focused => state.show_selected(),
+        event::KeyCode::Up if state.vertical_scroll > 0 => {
+            state.vertical_scroll -= 1;
+        }
+        event::KeyCode::Down => {
+            state.vertical_scroll += 1;
+        }
+        event::KeyCode::PageUp => state.scroll_page(false),
+        event::KeyCode::PageDown => state.scroll_page(true),
+        // in the input box Home and End move in the draft
+        event::KeyCode::Home if !state.input_focused || key.modifiers.contains(event::KeyModifiers::CONTROL) => state.vertical_scroll = 0,
+        // clamped to the last page when drawing
+        event::KeyCode::End if !state.input_focused || key.modifiers.contains(event::KeyModifiers::CONTROL) => state.vertical_scroll = usize::MAX,
+        event::KeyCode::Tab => {
+            state.input_focused = !state.input_focused;
+        }
+        event::KeyCode::F(1) => state.show_help(),
+        event::KeyCode::F(2) => state.toggle_events(),
+        event::KeyCode::Enter if state.input_focused => return KeyAction::Submit,
+        _ if state.input_focused => handle_edit_key(state, key),
+        _ => {}
+    }
+    KeyAction::None
+}
+
+/// Handle a key that edits the draft or moves in it, with the readline
+/// shortcuts: Ctrl+A/E start and end, Alt+B/F word motion, Ctrl+W/U/K
+/// delete the word before, to the start and to the end, Ctrl+Z or Ctrl+_
+/// undo and Ctrl+Y redo.
+fn handle_edit_key(state: &mut ChatState, key: event::KeyEvent) {
+    let ctrl = key.modifiers.contains(event::KeyModifiers::CONTROL);
+    let alt = key.modifiers.contains(event::KeyModifiers::ALT);
+    let input = &mut state.input;
+    match key.code {
+        // terminals send Ctrl+_ as the byte crossterm reads as Ctrl+7
+        event::KeyCode::Char('z' | '_' | '7') if ctrl => input.undo(),
+        event::KeyCode::Char('y') if ctrl => input.redo(),
+        event::KeyCode::Char('a') if ctrl => input.home(),
+        event::KeyCode::Char('e') if ctrl => input.end(),
+        event::KeyCode::Char('b') if ctrl => input.left(),
+        event::KeyCode::Char('f') if ctrl => input.right(),
+        event::KeyCode::Char('d') if ctrl => input.delete(),
+        event::KeyCode::Char('w') if ctrl => input.delete_word_before(),
+        event::KeyCode::Char('u') if ctrl => input.kill_to_start(),
+        event::KeyCode::Char('k') if ctrl => input.kill_to_end(),
+        event::KeyCode::Char('b') if alt => input.word_left(),
+        event::KeyCode::Char('f') if alt => input.word_right(),
+        event::KeyCode::Left if ctrl || alt => input.word_left(),
+        event::KeyCode::Right if ctrl || alt => input.word_right(),
+        // other control combinations are not text
+        event::KeyCode::Char(_) if ctrl || alt => {}
+        event::KeyCode::Char(c) => input.type_char(c),
+        event::KeyCode::Backspace => input.backspace(),
+        event::KeyCode::Delete => input.delete(),
+        event::KeyCode::Left => input.left(),
+        event::KeyCode::Right => input.right(),
+        event::KeyCode::Home => input.home(),
+        event::KeyCode::End => input.end(),
+        _ => {}
+    }
+}
+
+/// The full-screen TUI has the terminal, see `restore_terminal`.
+static TERMINAL_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Give the terminal back the way the shell had it: raw mode off, mouse
+/// capture and bracketed paste off, the main screen and the cursor back.
+/// Does nothing unless the TUI has the terminal, so it can run from the
+/// panic hook, a drop guard and the normal exit alike.
+fn restore_terminal() {
+    if TERMINAL_TAKEN.swap(false, Ordering::SeqCst) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), event::DisableMouseCapture, event::DisableBracketedPaste, LeaveAlternateScreen, crossterm::cursor::Show);
+    }
+}
+
+/// Restores the terminal when the TUI returns, whether it finished, failed
+/// or is unwinding from a panic.
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Restore the terminal before any panic message is printed, on whichever
+/// thread the panic happens, so the message lands on the main screen and
+/// the shell is usable afterwards.
+fn install_panic_hook() {
+    static INSTALLED: std::sync::Once = std::sync::Once::new();
+    INSTALLED.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            previous(info);
+        }));
+    });
+}
+
+pub fn run_tui_with_sender<F>(send_fn: F, messages: SharedMessages<Message>, status: SharedStatus, shutdown: Arc<AtomicBool>, config: &TuiConfig) -> std::io::Result<()>
 where
     F: Fn(String) + Send + Sync + 'static,
 {
+    if config.plain {
+        return crate::plain::run(send_fn, messages, status, shutdown, config);
+    }
+    // before raw mode, as detecting may run tput
+    let colors = config.colors.detect();
+    install_panic_hook();
+    TERMINAL_TAKEN.store(true, Ordering::SeqCst);
+    let _guard = TerminalGuard;
     enable_raw_mode()?;
     let mut stdout = stdout();
     execute!(stdout, EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    let username = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
     let mut state = ChatState::new();
-    let mut frame_count: usize = 0;
-    execute!(terminal.backend_mut(), crossterm::event::EnableMouseCapture)?;
+    state.username = status.lock().unwrap().name.clone().unwrap_or_else(|| std::env::var("USER").unwrap_or_else(|_| "user".to_string()));
+    state.colors = colors;
+    state.palette = config.theme.palette_for(colors);
+    state.show_events = config.event_pane;
+    state.bidi = config.bidi;
+    state.zone = config.timezone.clone();
+    state.relative_times = config.relative_times;
+    state.aliases.clone_from(&config.aliases);
+    if config.vi_mode {
+        state.set_vi_mode(ViMode::Normal);
+    }
+    if let Some(draft) = status.lock().unwrap().draft.take() {
+        state.input.set(draft);
+        match state.vi_mode {
+            Some(_) => state.set_vi_mode(ViMode::Insert),
+            None => state.input_focused = true,
+        }
+    }
+    execute!(terminal.backend_mut(), event::EnableMouseCapture, event::EnableBracketedPaste)?;
     loop {
+        // kept up to date in the status, so the client can save the draft
+        // as soon as the connection drops
+        {
+            let mut status = status.lock().unwrap();
+            let draft = Some(state.input.text()).filter(|draft| !draft.trim().is_empty());
+            if status.draft.as_deref() != draft {
+                status.draft = draft.map(str::to_string);
+            }
+        }
         if shutdown.load(Ordering::SeqCst) {
             break;
         }
-        frame_count += 1;
-        // Synchronize messages from network
+        // With the oldest message on screen, load the page of the local
+        // log before it; the sync below picks it up
+        if state.vertical_scroll == 0 && !state.messages.is_empty() && !state.scrollback_done {
+            let scrollback = status.lock().unwrap().scrollback.clone();
+            if let Some(load) = scrollback {
+                state.scrollback_done = !load();
+            }
+        }
+        // Synchronize messages from network, copying only what changed
         {
             let msgs = messages.lock().unwrap();
-            let new_len = msgs.len();
-            // Autoscroll: Always scroll to bottom when new messages arrive
-            if new_len > state.messages.len() {
-                let chat_area_height = terminal.size()?.height as usize - 5;
-                state.vertical_scroll = new_len.saturating_sub(chat_area_height);
+            if msgs.generation() != state.messages_generation {
+                let new_len = msgs.len();
+                let new_count = msgs.pushed() - state.messages_pushed;
+                let prepended = (msgs.prepended() - state.messages_prepended) as usize;
+                // Messages evicted from the front shift every index down,
+                // older ones loaded in front of them up
+                let evicted = (state.messages.len() as u64 + new_count + prepended as u64).saturating_sub(new_len as u64) as usize;
+                let shift = |i: usize| (i + prepended).checked_sub(evicted);
+                state.search_hit = state.search_hit.and_then(shift);
+                state.selected = state.selected.and_then(shift).filter(|i| *i < new_len);
+                // Autoscroll: follow new messages while the newest one is in
+                // view; scrolled up, the same messages stay on screen
+                if new_count > 0 || prepended > 0 {
+                    let size = terminal.size()?;
+                    let chat_area_height = chat_rows(Rect::new(0, 0, size.width, size.height), state.input_box_text(), state.show_events);
+                    if new_count > 0 && state.vertical_scroll >= state.messages.len().saturating_sub(chat_area_height) {
+                        state.vertical_scroll = new_len.saturating_sub(chat_area_height);
+                    } else {
+                        state.vertical_scroll = state.vertical_scroll.saturating_add(prepended).saturating_sub(evicted);
+                    }
+                }
+                let appends_only = msgs.generation() - state.messages_generation == new_count;
+                if appends_only && (new_count as usize) < new_len {
+                    state.messages.extend(msgs.iter().skip(new_len - new_count as usize).cloned());
+                    let excess = state.messages.len().saturating_sub(new_len);
+                    state.messages.drain(..excess);
+                } else {
+                    state.messages = msgs.iter().cloned().collect();
+                }
+                state.messages_pushed = msgs.pushed();
+                state.messages_prepended = msgs.prepended();
+                state.messages_generation = msgs.generation();
+            }
+            if msgs.events_pushed() != state.events_pushed {
+                state.events = msgs.events().cloned().collect();
+                state.events_pushed = msgs.events_pushed();
+            }
+            if state.show_events {
+                state.events_seen = state.events_pushed;
+            }
+        }
+        {
+            let mut status = status.lock().unwrap();
+            state.topic.clone_from(&status.topic);
+            // the server may rename us, see `Kind::Nick`
+            if let Some(name) = status.name.as_ref().filter(|name| **name != state.username) {
+                state.username.clone_from(name);
+            }
+            if status.user_id != state.user_id {
+                state.user_id.clone_from(&status.user_id);
+            }
+            state.delivery.clone_from(&status.delivery);
+            state.transfers = status.transfers.values().map(crate::types::Transfer::label).collect();
+            state.rtt = status.rtt;
+            if state.commands.len() != status.commands.len() {
+                state.commands.clone_from(&status.commands);
+            }
+            if let Some(overlay) = status.overlay.take() {
+                // start at the bottom, where the most recent lines are;
+                // clamped to the last page when drawing
+                state.overlay_scroll = usize::MAX;
+                state.overlay = Some(overlay);
             }
-            state.messages = msgs.clone();
         }
         terminal.draw(|f| {
-            draw_chat_scrollbar_minimal(f, &mut state, frame_count);
+            draw_chat_scrollbar_minimal(f, &mut state);
         })?;
 
     if event::poll(std::time::Duration::from_millis(100))? {
             match event::read()? {
+                // terminals that report releases (Windows, the kitty protocol)
+                // would otherwise type every character twice
+                event::Event::Key(key) if key.kind == event::KeyEventKind::Release => {}
+                event::Event::Key(key) if state.overlay.is_some() => handle_overlay_key(&mut state, key),
                 event::Event::Key(key) => {
-                    if key.code == event::KeyCode::Esc {
-                        break;
-                    }
-                    match key.code {
-                        event::KeyCode::Up => {
-                            if state.vertical_scroll > 0 {
-                                state.vertical_scroll -= 1;
-                            }
-                        }
-                        event::KeyCode::Down => {
-                            state.vertical_scroll += 1;
-                        }
-                        event::KeyCode::Tab => {
-                            state.input_focused = !state.input_focused;
-                        }
-                        event::KeyCode::Char(c) => {
-                            if state.input_focused {
-                                state.input.push(c);
-                            }
-                        }
-                        event::KeyCode::Enter => {
-                            if state.input_focused {
-                                let trimmed = state.input.trim();
-                                if trimmed.is_empty() {
-                                    state.input.clear();
-                                } else {
-                                    let time = chrono::Local::now().format("%H:%M").to_string();
-                                    let msg = Message {
-                                        sender: username.clone(),
-                                        text: trimmed.to_string(),
-                                        time,
-                                    };
-                                    send_fn(trimmed.to_string());
-                                    {
-                                        let mut msgs = messages.lock().unwrap();
-                                        msgs.push(msg);
-                                    }
-                                    state.input.clear();
+                    let action = match state.vi_mode {
+                        Some(mode) => handle_vi_key(&mut state, mode, key),
+                        None => handle_key(&mut state, key),
+                    };
+                    match action {
+                        KeyAction::Quit => break,
+                        KeyAction::Submit => {
+                            let text = crate::config::expand_alias(&config.aliases, state.input.text().trim()).into_owned();
+                            let trimmed = text.as_str();
+                            let next_send = status.lock().unwrap().next_send.filter(|t| *t > std::time::Instant::now());
+                            if trimmed.is_empty() {
+                                state.input.clear();
+                            } else if let Some(name) = trimmed.strip_prefix("/theme").filter(|a| a.is_empty() || a.starts_with(' ')) {
+                                let name = name.trim().to_string();
+                                let text = state.set_theme(&name);
+                                messages.lock().unwrap().push(Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                                state.input.clear();
+                            } else if let Some(style) = trimmed.strip_prefix("/times").filter(|a| a.is_empty() || a.starts_with(' ')) {
+                                let style = style.trim().to_string();
+                                let text = state.set_time_style(&style);
+                                messages.lock().unwrap().push(Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                                state.input.clear();
+                            } else if let Some(name) = trimmed.strip_prefix("/tz").filter(|a| a.is_empty() || a.starts_with(' ')) {
+                                let name = name.trim().to_string();
+                                let text = state.set_zone(&name);
+                                messages.lock().unwrap().push(Message { sender: "System".to_string(), text, time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None });
+                                state.input.clear();
+                            } else if trimmed == "/clear" {
+                                messages.lock().unwrap().retain(|_| false);
+                                // and do not load the log back in
+                                state.scrollback_done = true;
+                                state.selected = None;
+                                state.search_hit = None;
+                                state.vertical_scroll = 0;
+                                state.input.clear();
+                            } else if next_send.is_some() && !trimmed.starts_with('/') {
+                                // slow mode: keep the draft until it may be sent
+                                state.held_until = next_send;
+                            } else {
+                                let time = chrono::Utc::now();
+                                send_fn(trimmed.to_string());
+                                let msg = Message {
+                                    sender: state.username.clone(),
+                                    text: trimmed.to_string(),
+                                    time,
+                                    verified: None,
+                                    expires: None,
+                                    user: state.user_id.clone(),
+                                    id: status.lock().unwrap().sent_id.take(),
+                                };
+                                // commands are handled by send_fn and not echoed
+                                if !trimmed.starts_with('/') {
+                                    let mut msgs = messages.lock().unwrap();
+                                    msgs.push(msg);
                                 }
+                                state.input.clear();
                             }
                         }
-                        event::KeyCode::Backspace => {
-                            if state.input_focused {
-                                state.input.pop();
-                            }
-                        }
-                        _ => {}
+                        KeyAction::None => {}
                     }
                 }
+                // pasted text, and what input methods commit in one go
+                event::Event::Paste(text) if state.overlay.is_none() => state.paste(&text),
+                // redraw at the new size right away rather than on the next
+                // tick, which would leave the old frame's cells in the wrong
+                // places; a view that showed the newest message still does
+                event::Event::Resize(width, height) => {
+                    let before = terminal.get_frame().area();
+                    let len = state.messages.len();
+                    let at_bottom = state.vertical_scroll >= len.saturating_sub(chat_rows(before, state.input_box_text(), state.show_events));
+                    let area = Rect::new(0, 0, width, height);
+                    let max_scroll = len.saturating_sub(chat_rows(area, state.input_box_text(), state.show_events));
+                    state.vertical_scroll = if at_bottom { max_scroll } else { state.vertical_scroll.min(max_scroll) };
+                    terminal.resize(area)?;
+                    terminal.draw(|f| draw_chat_scrollbar_minimal(f, &mut state))?;
+                }
                 event::Event::Mouse(me) => {
                     match me.kind {
                         event::MouseEventKind::ScrollDown => {
                             state.vertical_scroll += 1;
                         }
-                        event::MouseEventKind::ScrollUp => {
-                            if state.vertical_scroll > 0 {
-                                state.vertical_scroll -= 1;
-                            }
+                        event::MouseEventKind::ScrollUp if state.vertical_scroll > 0 => {
+                            state.vertical_scroll -= 1;
                         }
                         event::MouseEventKind::Down(event::MouseButton::Left) => {
                             let area = terminal.get_frame().area();
-                            let chat_chunks = Layout::default()
-                                .direction(Direction::Vertical)
-                                .constraints([
-                                    Constraint::Min(20),
-                                    Constraint::Length(3),
-                                ])
-                                .split(area);
+                            let chat_chunks = chat_layout(area, state.input_box_text(), state.show_events);
                             // me.column and me.row are already u16
                             let x = me.column;
                             let y = me.row;
-                            let mut input_clicked = false;
-                            if x >= chat_chunks[1].x && x < chat_chunks[1].x + chat_chunks[1].width && y >= chat_chunks[1].y && y < chat_chunks[1].y + chat_chunks[1].height {
-                                state.input_focused = true;
-                                input_clicked = true;
+                            let input_clicked = x >= chat_chunks[1].x && x < chat_chunks[1].x + chat_chunks[1].width && y >= chat_chunks[1].y && y < chat_chunks[1].y + chat_chunks[1].height;
+                            // a click selects the message on that row, a
+                            // second one shows its details
+                            let chat = pane_inner(chat_chunks[0], is_compact(area));
+                            if chat.contains(Position { x, y }) {
+                                let i = state.vertical_scroll + (y - chat.y) as usize;
+                                if i < state.messages.len() {
+                                    if state.selected == Some(i) {
+                                        state.show_selected();
+                                    }
+                                    state.selected = Some(i);
+                                }
                             }
-                            if !input_clicked {
-                                state.input_focused = false;
+                            match state.vi_mode {
+                                Some(ViMode::Search) => {}
+                                Some(_) => state.set_vi_mode(if input_clicked { ViMode::Insert } else { ViMode::Normal }),
+                                None => state.input_focused = input_clicked,
                             }
                         }
                         _ => {}
@@ -161,76 +1183,245 @@ where
             }
         }
     }
-    execute!(terminal.backend_mut(), crossterm::event::DisableMouseCapture)?;
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    status.lock().unwrap().draft = Some(state.input.text().to_string()).filter(|draft| !draft.trim().is_empty());
+    restore_terminal();
     Ok(())
 }
 
-pub fn draw_chat_scrollbar_minimal(f: &mut Frame, state: &mut ChatState, frame_count: usize) {
-    let chat_chunks = Layout::default()
+/// Whether the terminal is too small for borders and titles; the panes
+/// then use every cell for their content.
+fn is_compact(area: Rect) -> bool {
+    area.width < COMPACT_WIDTH || area.height < COMPACT_HEIGHT
+}
+
+/// What a pane of the chat layout has inside its borders, if it has any.
+fn pane_inner(pane: Rect, compact: bool) -> Rect {
+    if compact { pane } else { pane.inner(Margin { horizontal: 1, vertical: 1 }) }
+}
+
+/// Split the frame into the message pane, the input bar, the status bar
+/// and, below the messages, the event pane, which is empty when hidden.
+///
+/// The input bar grows with the draft (one row per wrapped line) between
+/// `INPUT_MIN_HEIGHT` and `INPUT_MAX_HEIGHT`, less the borders in a compact
+/// layout; the event pane takes at most a third of the height and the chat
+/// pane the rest.
+pub fn chat_layout(area: Rect, input: &str, show_events: bool) -> Rc<[Rect]> {
+    let borders = if is_compact(area) { 0 } else { 2 };
+    let input_rows = wrap_input(input, area.width.saturating_sub(borders)).len() as u16;
+    let input_height = (input_rows + borders).clamp(INPUT_MIN_HEIGHT - 2 + borders, INPUT_MAX_HEIGHT - 2 + borders);
+    let events_height = if show_events { (EVENT_PANE_HEIGHT - 2 + borders).min(area.height / 3) } else { 0 };
+    let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Min(20),   // Messages
-            Constraint::Length(3), // Input bar
+            Constraint::Min(0),               // Messages
+            Constraint::Length(events_height), // Events
+            Constraint::Length(input_height), // Input bar
+            Constraint::Length(1),            // Status bar
         ])
-        .split(f.area());
+        .split(area);
+    // the event pane goes last so the other indices stay as they were
+    Rc::from([chunks[0], chunks[2], chunks[3], chunks[1]])
+}
 
-    // Messages
-    let msg_lines: Vec<Line> = state.messages.iter().map(|m| {
-        // Format: [time] <user> ➢ <message>
-        let time = Span::styled(
-            format!("[{}]", m.time),
+/// Rows of messages the chat pane shows in a frame of `area`.
+fn chat_rows(area: Rect, input: &str, show_events: bool) -> usize {
+    pane_inner(chat_layout(area, input, show_events)[0], is_compact(area)).height as usize
+}
+
+/// Drawn instead of the chat below `MIN_WIDTH` by `MIN_HEIGHT`.
+fn draw_too_small(f: &mut Frame, palette: &Palette) {
+    let area = f.area();
+    let lines = vec![
+        Line::from(Span::styled("Terminal too small", Style::default().fg(palette.warm).add_modifier(Modifier::BOLD))),
+        Line::from(Span::styled(format!("{}×{}, need {}×{}", area.width, area.height, MIN_WIDTH, MIN_HEIGHT), Style::default().fg(palette.dim))),
+    ];
+    let top = area.height.saturating_sub(lines.len() as u16) / 2;
+    let text = Rect { y: area.y + top, height: area.height - top, ..area };
+    f.render_widget(Block::default().style(Style::default().bg(palette.background)), area);
+    f.render_widget(Paragraph::new(lines).alignment(Alignment::Center).wrap(Wrap { trim: true }), text);
+}
+
+/// Hard-wrap the draft into rows of at most `width` terminal columns; CJK
+/// and other wide characters take two, and one that does not fit starts
+/// the next row. Emoji sequences joined with ZWJ, flags and letters with
+/// combining marks are kept whole and measured as the terminal draws them.
+///
+/// One extra column is reserved after the last character for the cursor, so a
+/// draft that exactly fills a row already grows the box by one line.
+fn wrap_input(input: &str, width: u16) -> Vec<String> {
+    let width = width.max(1) as usize;
+    let mut rows = vec![String::new()];
+    let mut used = 0;
+    for g in input.graphemes(true) {
+        let w = g.width();
+        if used + w > width {
+            rows.push(String::new());
+            used = 0;
+        }
+        rows.last_mut().expect("rows is never empty").push_str(g);
+        used += w;
+    }
+    if used >= width {
+        rows.push(String::new());
+    }
+    rows
+}
+
+/// `text` padded with spaces to `width` terminal columns, counting wide
+/// characters as the terminal does, unlike `format!`'s `{:<width}`.
+fn pad(text: &str, width: usize) -> String {
+    format!("{}{}", text, " ".repeat(width.saturating_sub(text.width())))
+}
+
+/// Remove the last character the user sees from `text`: a whole emoji
+/// sequence or letter with its accents, not just its last code point.
+fn pop_grapheme(text: &mut String) {
+    if let Some((i, _)) = text.grapheme_indices(true).next_back() {
+        text.truncate(i);
+    }
+}
+
+/// `text` in display order when `bidi` is on: runs of Arabic, Hebrew and
+/// other right-to-left scripts reordered by the Unicode bidi algorithm,
+/// since the terminal draws every cell left to right. Text without such
+/// characters is returned as is.
+fn visual_order(text: &str, bidi: bool) -> std::borrow::Cow<'_, str> {
+    // runs every frame for every message, so skip the analysis for ASCII
+    if !bidi || text.is_ascii() {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    let info = unicode_bidi::BidiInfo::new(text, None);
+    if !info.has_rtl() {
+        return std::borrow::Cow::Borrowed(text);
+    }
+    info.paragraphs.iter().map(|para| info.reorder_line(para, para.range.clone())).collect::<String>().into()
+}
+
+/// Messages closer together than this are grouped under one heading.
+const GROUP_WINDOW: chrono::TimeDelta = chrono::TimeDelta::minutes(1);
+
+/// `next` follows `prev` closely enough to be shown without its own time and
+/// sender: same sender, same signature check, within `GROUP_WINDOW`.
+fn continues(prev: &Message, next: &Message) -> bool {
+    prev.sender == next.sender && prev.verified == next.verified && next.time - prev.time < GROUP_WINDOW
+}
+
+/// The start of a message line, up to its text.
+/// Format: [time] <user> ➢ <message>
+fn message_prefix(state: &ChatState, m: &Message) -> Vec<Span<'static>> {
+    let palette = state.palette;
+    let time = Span::styled(
+        format!("[{}]", state.time_label(m.time)),
         // bright green time accent (keep similar to gotop green)
-        Style::default().fg(Color::Rgb(80, 250, 123)),
-        );
-        let spacer = Span::raw(" ");
-        // render username without angle brackets
-        let sender = Span::styled(
-            m.sender.to_string(),
-            // magenta-like user color (gotop-inspired)
-            Style::default().fg(Color::Rgb(198, 120, 221)).add_modifier(Modifier::BOLD),
-        );
-        // arrow with no surrounding spaces; we keep spacer spans around fields
-        let arrow = Span::styled(
-            "➢",
-            // warm accent for arrow
-            Style::default().fg(Color::Rgb(255, 168, 64)).add_modifier(Modifier::BOLD),
-        );
+        Style::default().fg(palette.time),
+    );
+    let spacer = Span::raw(" ");
+    // the user's own messages stand out in the accent colour
+    let own = is_own(m, &state.username, state.user_id.as_deref());
+    // render username without angle brackets
+    let sender = Span::styled(
+        visual_order(&m.sender, state.bidi).into_owned(),
+        // one colour per name, so speakers tell apart at a glance
+        Style::default().fg(if own { palette.accent } else { palette.sender(&m.sender) }).add_modifier(Modifier::BOLD),
+    );
+    // arrow with no surrounding spaces; we keep spacer spans around fields
+    let arrow = Span::styled(
+        "➢",
+        // warm accent for arrow
+        Style::default().fg(if own { palette.accent } else { palette.warm }).add_modifier(Modifier::BOLD),
+    );
+    let mut spans = vec![time, spacer.clone(), sender];
+    if own {
+        spans.push(Span::styled(" (you)", Style::default().fg(palette.dim)));
+    }
+    match m.verified {
+        Some(true) => spans.push(Span::styled(" ✔", Style::default().fg(palette.time))),
+        Some(false) => spans.push(Span::styled(" ✖", Style::default().fg(palette.bad).add_modifier(Modifier::BOLD))),
+        None => {}
+    }
+    spans.extend([spacer.clone(), arrow, spacer]);
+    spans
+}
+
+pub fn draw_chat_scrollbar_minimal(f: &mut Frame, state: &mut ChatState) {
+    let palette = state.palette;
+    if f.area().width < MIN_WIDTH || f.area().height < MIN_HEIGHT {
+        draw_too_small(f, &palette);
+        return;
+    }
+    let compact = is_compact(f.area());
+    let borders = if compact { Borders::NONE } else { Borders::ALL };
+    let chat_chunks = chat_layout(f.area(), state.input_box_text(), state.show_events);
+    let bidi = state.bidi;
+
+    // Messages
+    // Width of the prefix of the message heading the current group
+    let mut indent = 0;
+    let msg_lines: Vec<Line> = state.messages.iter().enumerate().map(|(i, m)| {
         let text = Span::styled(
-            m.text.to_string(),
+            visual_order(&m.text, bidi).into_owned(),
             // softer 'normal' foreground color
-            Style::default().fg(Color::Rgb(200, 200, 210)),
+            Style::default().fg(palette.text),
         );
-        Line::from(vec![time, spacer.clone(), sender, spacer.clone(), arrow, spacer.clone(), text])
+        // a quick follow-up by the same sender is aligned under the
+        // message before it instead of repeating the time and name
+        let mut line = if i > 0 && continues(&state.messages[i - 1], m) {
+            Line::from(vec![Span::raw(" ".repeat(indent)), text])
+        } else {
+            let spans = message_prefix(state, m);
+            indent = spans.iter().map(Span::width).sum();
+            Line::from([spans, vec![text]].concat())
+        };
+        // whether the server got our message, see `Kind::Ack`
+        match m.id.as_ref().and_then(|id| state.delivery.get(id)) {
+            Some(Delivery::Sending) => line.push_span(Span::styled(" ⏳", Style::default().fg(palette.dim))),
+            Some(Delivery::Delivered) => line.push_span(Span::styled(" ✓", Style::default().fg(palette.time))),
+            Some(Delivery::Failed) => line.push_span(Span::styled(" ✗ not delivered, send it again", Style::default().fg(palette.bad))),
+            None => {}
+        }
+        // highlight the selected message and the one found by the last
+        // vi search
+        if state.selected == Some(i) || state.search_hit == Some(i) {
+            line.add_modifier(Modifier::REVERSED)
+        } else {
+            line
+        }
     }).collect();
 
     // Ensure scroll position is valid
-    let max_scroll = msg_lines.len().saturating_sub(chat_chunks[0].height as usize - 2);
+    let line_count = msg_lines.len();
+    let chat_inner = pane_inner(chat_chunks[0], compact);
+    let max_scroll = line_count.saturating_sub(chat_inner.height as usize);
     state.vertical_scroll = state.vertical_scroll.min(max_scroll);
+    state.chat_rows = chat_inner.height as usize;
 
     // gotop-like palette: cyan titles, darker background
     let chat_title_style = Style::default()
-        .fg(Color::Rgb(50, 230, 230))
+        .fg(palette.accent)
         .add_modifier(Modifier::BOLD);
-    let chat_border_style = Style::default().fg(Color::Rgb(50, 230, 230)).add_modifier(Modifier::BOLD);
-    let msg_paragraph = Paragraph::new(msg_lines.clone())
+    let chat_border_style = Style::default().fg(palette.accent).add_modifier(Modifier::BOLD);
+    let chat_title = match &state.topic {
+        Some(topic) => format!(" Chat · {} ", topic),
+        None => " Chat ".to_string(),
+    };
+    let msg_paragraph = Paragraph::new(msg_lines)
         .block(Block::default()
-            .borders(Borders::ALL)
-            .title(Span::styled(" Chat ", chat_title_style))
+            .borders(borders)
+            .title(if compact { Span::raw("") } else { Span::styled(chat_title, chat_title_style) })
             .title_alignment(Alignment::Center)
             .border_style(chat_border_style)
         )
         .style(Style::default()
-            .fg(Color::Rgb(200, 200, 210))
-            .bg(Color::Rgb(20, 18, 28)) // darker, purple-tinged background like gotop
+            .fg(palette.text)
+            .bg(palette.background) // darker, purple-tinged background like gotop
         )
         .scroll((state.vertical_scroll as u16, 0));
     f.render_widget(msg_paragraph, chat_chunks[0]);
 
     // Scrollbar
-    let mut scrollbar_state = ScrollbarState::new(msg_lines.len())
-        .viewport_content_length(chat_chunks[0].height.saturating_sub(2) as usize)
+    let mut scrollbar_state = ScrollbarState::new(line_count)
+        .viewport_content_length(chat_inner.height as usize)
         .position(state.vertical_scroll);
     let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight)
         .begin_symbol(Some("↑"))
@@ -240,29 +1431,135 @@ pub fn draw_chat_scrollbar_minimal(f: &mut Frame, state: &mut ChatState, frame_c
     // Input bar
     // input title/border: use cyan to match gotop-style panels
     let input_title_style = Style::default()
-        .fg(Color::Rgb(50, 230, 230))
+        .fg(palette.accent)
         .add_modifier(Modifier::BOLD);
-    let input_border_style = Style::default().fg(Color::Rgb(50, 230, 230)).add_modifier(Modifier::BOLD);
-    let blink_on = (frame_count / 10) % 2 == 0;
-    let input_text = if state.input_focused {
-        if blink_on {
-            format!("{}|", state.input)
-        } else {
-            format!("{} ", state.input)
-        }
-    } else {
-        state.input.clone()
-    };
-    let input = Paragraph::new(input_text)
+    let input_border_style = Style::default().fg(palette.accent).add_modifier(Modifier::BOLD);
+    let searching = state.vi_mode == Some(ViMode::Search);
+    let input_inner = pane_inner(chat_chunks[1], compact);
+    let input_width = input_inner.width;
+    let input_text = state.input_box_text();
+    let input_rows = wrap_input(input_text, input_width);
+    // The text before the cursor wraps the same way, so its last row is
+    // where the cursor is
+    let cursor = if searching { input_text.len() } else { state.input.cursor() };
+    let before_cursor = wrap_input(&input_text[..cursor], input_width);
+    let cursor_row = before_cursor.len() - 1;
+    // Keep the row with the cursor visible once the box has stopped growing
+    let visible_rows = input_inner.height as usize;
+    let input_scroll = (cursor_row + 1).saturating_sub(visible_rows);
+    // The terminal's own cursor marks the insertion point; an input method
+    // draws the text being composed there, in its own style, until it is
+    // committed
+    if (state.input_focused || searching) && visible_rows > 0 {
+        let column = before_cursor[cursor_row].width() as u16;
+        let row = (cursor_row - input_scroll) as u16;
+        f.set_cursor_position((input_inner.x + column, input_inner.y + row));
+    }
+    let input_lines: Vec<Line> = input_rows.into_iter().map(Line::from).collect();
+    let input = Paragraph::new(input_lines)
         .block(Block::default()
-            .borders(Borders::ALL)
-            .title(Span::styled(" Enter Message ", input_title_style))
+            .borders(borders)
+            .title(if compact { Span::raw("") } else { Span::styled(if searching { " Search " } else { " Enter Message " }, input_title_style) })
             .title_alignment(Alignment::Center)
             .border_style(input_border_style)
         )
         .style(Style::default()
-            .fg(Color::Rgb(200, 200, 210))
-            .bg(Color::Rgb(20, 18, 28)) // match main chat background
-        );
+            .fg(palette.text)
+            // match main chat background; without borders the panel
+            // colour sets the input apart
+            .bg(if compact { palette.panel } else { palette.background })
+        )
+        .scroll((input_scroll as u16, 0));
     f.render_widget(input, chat_chunks[1]);
+
+    // Status bar: mode indicator on the left, key hints on the right
+    let (mode, hints) = match state.vi_mode {
+        Some(ViMode::Normal) => (" NORMAL ", "j/k scroll · J/K select · / search · i insert · e events · F1 help · q quit "),
+        Some(ViMode::Insert) => (" INSERT ", "Enter send · Esc normal mode "),
+        Some(ViMode::Search) => (" SEARCH ", "Enter find · n/N next/previous · Esc cancel "),
+        None if state.input_focused => (" INPUT ", "Enter send · Tab chat · Esc quit "),
+        None => (" CHAT ", "↑/↓ select · Enter details · Tab input · F2 events · F1 help · Esc quit "),
+    };
+    let mode_span = Span::styled(
+        mode,
+        Style::default().fg(palette.background).bg(palette.accent).add_modifier(Modifier::BOLD),
+    );
+    let mut status_spans = vec![mode_span];
+    // Slow mode countdown after a message was held back
+    let wait = state.held_until.map(|t| t.saturating_duration_since(std::time::Instant::now())).filter(|d| !d.is_zero());
+    match wait {
+        Some(wait) => status_spans.push(Span::styled(
+            format!(" slow mode: wait {}s ", wait.as_secs_f64().ceil()),
+            Style::default().fg(palette.warm).add_modifier(Modifier::BOLD),
+        )),
+        None => state.held_until = None,
+    }
+    for label in &state.transfers {
+        status_spans.push(Span::styled(format!(" {} ", label), Style::default().fg(palette.accent)));
+    }
+    if let Some(rtt) = state.rtt {
+        // warn while the round trip is well above its average
+        let style = if rtt.spiking() { Style::default().fg(palette.warm).add_modifier(Modifier::BOLD) } else { Style::default().fg(palette.dim) };
+        status_spans.push(Span::styled(format!(" {} ", rtt.label()), style));
+    }
+    let unseen = state.events_pushed - state.events_seen;
+    if unseen > 0 {
+        status_spans.push(Span::styled(format!(" {} new events ", unseen), Style::default().fg(palette.warm)));
+    }
+    let status = Paragraph::new(Line::from(status_spans))
+        .style(Style::default().fg(palette.text).bg(palette.background));
+    f.render_widget(status, chat_chunks[2]);
+    // the mode and the indicators matter more than the hints
+    if !compact {
+        let hints = Paragraph::new(Line::from(hints).alignment(Alignment::Right))
+            .style(Style::default().fg(palette.dim));
+        f.render_widget(hints, chat_chunks[2]);
+    }
+
+    // System events, newest at the bottom
+    if state.show_events {
+        let area = chat_chunks[3];
+        // a compact event pane keeps a rule above it to set it apart
+        let visible = area.height.saturating_sub(if compact { 1 } else { 2 }) as usize;
+        let lines: Vec<Line> = state.events[state.events.len().saturating_sub(visible)..]
+            .iter()
+            .map(|m| Line::from(vec![Span::styled(format!("[{}] ", state.time_label(m.time)), Style::default().fg(palette.time)), Span::styled(visual_order(&m.text, bidi), Style::default().fg(palette.dim))]))
+            .collect();
+        let events = Paragraph::new(lines)
+            .block(Block::default()
+                .borders(if compact { Borders::TOP } else { Borders::ALL })
+                .title(if compact { Span::raw("") } else { Span::styled(" Events ", Style::default().fg(palette.accent).add_modifier(Modifier::BOLD)) })
+                .title_alignment(Alignment::Center)
+                .border_style(Style::default().fg(palette.dim))
+            )
+            .style(Style::default().fg(palette.text).bg(palette.background));
+        f.render_widget(events, area);
+    }
+
+    if let Some(overlay) = &state.overlay {
+        // a small terminal gives the overlay all of it
+        let area = if compact { f.area() } else { chat_chunks[0].inner(Margin { horizontal: 4, vertical: 2 }) };
+        let visible = area.height.saturating_sub(2) as usize;
+        // long lines wrap rather than run past the border
+        let width = area.width.saturating_sub(2);
+        let lines: Vec<Line> = overlay.lines.iter().flat_map(|l| {
+            let mut rows = wrap_input(l, width);
+            if rows.len() > 1 && rows.last().is_some_and(String::is_empty) {
+                rows.pop();
+            }
+            rows
+        }).map(Line::from).collect();
+        state.overlay_scroll = state.overlay_scroll.min(lines.len().saturating_sub(visible));
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" {} ", overlay.title))
+            .title_bottom(Line::from(" ↑/↓ scroll · Esc close ").alignment(Alignment::Right))
+            .border_style(Style::default().fg(palette.warm).add_modifier(Modifier::BOLD));
+        let paragraph = Paragraph::new(lines)
+            .block(block)
+            .style(Style::default().fg(palette.text).bg(palette.panel))
+            .scroll((state.overlay_scroll as u16, 0));
+        f.render_widget(Clear, area);
+        f.render_widget(paragraph, area);
+    }
 }
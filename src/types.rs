@@ -1,9 +1,480 @@
 //! Shared type aliases used across the project to keep signatures concise.
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-/// A shared, thread-safe message vector used by the TUI and networking code.
-pub type SharedMessages<T> = Arc<Mutex<Vec<T>>>;
+/// A chat message or system event as the TUIs show it.
+#[derive(Clone)]
+pub struct Message {
+    pub sender: String,
+    pub text: String,
+    /// When it was sent: the server's time for messages replayed from its
+    /// history, otherwise when it arrived.
+    pub time: chrono::DateTime<chrono::Utc>,
+    /// Signature check result in signed rooms; None where there is nothing
+    /// to check (unsigned rooms, local and system messages).
+    pub verified: Option<bool>,
+    /// When an ephemeral message disappears; None for ordinary messages.
+    pub expires: Option<std::time::Instant>,
+    /// Sender's stable ID, see `Payload::user`; None for system messages
+    /// and where the server did not stamp one.
+    pub user: Option<String>,
+    /// Message ID, see `Payload::id`; None for local and system messages.
+    /// Our own messages are shown with their `RoomStatus::delivery` by it.
+    pub id: Option<String>,
+}
 
-/// A map of peer address -> writer stream protected by a mutex and shared across threads.
-pub type SharedClients = Arc<Mutex<HashMap<String, Arc<Mutex<std::net::TcpStream>>>>>;
+/// Longest lifetime of an ephemeral message; longer TTLs are cut to this.
+pub const MAX_EPHEMERAL_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// Split the arguments of `/ephemeral` into the lifetime and the text. The
+/// lifetime is a number with an optional unit: `30s`, `5m`, `2h`.
+pub fn parse_ephemeral(args: &str) -> Result<(std::time::Duration, &str), String> {
+    let usage = || "Usage: /ephemeral <duration> <text>, e.g. /ephemeral 5m see you".to_string();
+    let (ttl, text) = args.trim().split_once(' ').ok_or_else(usage)?;
+    let (number, unit) = ttl.split_at(ttl.find(|c: char| !c.is_ascii_digit()).unwrap_or(ttl.len()));
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(usage()),
+    };
+    let secs = number.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(usage)?.saturating_mul(scale);
+    Ok((std::time::Duration::from_secs(secs).min(MAX_EPHEMERAL_TTL), text.trim()))
+}
+
+/// How often expired ephemeral messages are removed.
+const EXPIRY_TICK: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Buffers holding ephemeral messages, for the expiry sweep.
+static EPHEMERAL: Mutex<Vec<Weak<Mutex<MessageBuffer<Message>>>>> = Mutex::new(Vec::new());
+
+/// Show an ephemeral message and remove it again once its lifetime is up.
+/// One thread, started with the first ephemeral message, sweeps every
+/// buffer that holds some.
+pub fn push_ephemeral(messages: &SharedMessages<Message>, mut message: Message, ttl: std::time::Duration) {
+    static SWEEPER: std::sync::Once = std::sync::Once::new();
+    let ttl = ttl.min(MAX_EPHEMERAL_TTL);
+    message.expires = Some(std::time::Instant::now() + ttl);
+    messages.lock().unwrap().push(message);
+    let mut buffers = EPHEMERAL.lock().unwrap();
+    if !buffers.iter().any(|b| b.as_ptr() == Arc::as_ptr(messages)) {
+        buffers.push(Arc::downgrade(messages));
+    }
+    SWEEPER.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(EXPIRY_TICK);
+            sweep(&mut EPHEMERAL.lock().unwrap(), std::time::Instant::now());
+        });
+    });
+}
+
+/// Remove the messages that expired by `now` from `buffers`, and forget the
+/// buffers that are gone or hold no more ephemeral messages.
+fn sweep(buffers: &mut Vec<Weak<Mutex<MessageBuffer<Message>>>>, now: std::time::Instant) {
+    buffers.retain(|buffer| {
+        let Some(buffer) = buffer.upgrade() else { return false };
+        let mut buffer = buffer.lock().unwrap();
+        if buffer.iter().any(|m| m.expires.is_some_and(|t| t <= now)) {
+            buffer.retain(|m| m.expires.is_none_or(|t| t > now));
+        }
+        buffer.iter().any(|m| m.expires.is_some())
+    });
+}
+
+/// System events kept at most; the oldest are dropped first.
+const EVENT_LIMIT: usize = 500;
+
+/// A capped message history. Once `limit` entries are stored, pushing a new
+/// one evicts the oldest, so long sessions use bounded memory.
+///
+/// System events such as joins, leaves and refusals go to a second, smaller
+/// buffer so the TUI can show them apart from the chat.
+pub struct MessageBuffer<T> {
+    items: VecDeque<T>,
+    limit: usize,
+    /// Total number of entries ever pushed; `pushed + prepended - len()`
+    /// were evicted.
+    pushed: u64,
+    /// Total number of older entries put in front with `prepend`.
+    prepended: u64,
+    /// Bumped on every mutation so readers can skip unchanged buffers.
+    generation: u64,
+    events: VecDeque<T>,
+    /// Total number of events ever pushed.
+    events_pushed: u64,
+}
+
+impl<T> MessageBuffer<T> {
+    pub fn new(limit: usize) -> Self {
+        Self { items: VecDeque::new(), limit: limit.max(1), pushed: 0, prepended: 0, generation: 0, events: VecDeque::new(), events_pushed: 0 }
+    }
+
+    /// Append a system event, evicting the oldest one past `EVENT_LIMIT`.
+    pub fn push_event(&mut self, item: T) {
+        if self.events.len() == EVENT_LIMIT {
+            self.events.pop_front();
+        }
+        self.events.push_back(item);
+        self.events_pushed += 1;
+    }
+
+    pub fn events(&self) -> std::collections::vec_deque::Iter<'_, T> {
+        self.events.iter()
+    }
+
+    /// Total number of events pushed; events are only ever appended, so a
+    /// change means new ones arrived.
+    pub fn events_pushed(&self) -> u64 {
+        self.events_pushed
+    }
+
+    /// Append an entry, evicting the oldest one when the buffer is full.
+    pub fn push(&mut self, item: T) {
+        if self.items.len() == self.limit {
+            self.items.pop_front();
+        }
+        self.items.push_back(item);
+        self.pushed += 1;
+        self.generation += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Total number of entries pushed since the buffer was created.
+    pub fn pushed(&self) -> u64 {
+        self.pushed
+    }
+
+    /// Mutation counter. When it advanced by exactly as much as `pushed`, the
+    /// only changes since the last look were appends.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Put older entries, oldest first, in front of the oldest one, as
+    /// many of the newest of them as fit under the limit, and return how
+    /// many that was. Never evicts.
+    pub fn prepend(&mut self, items: Vec<T>) -> usize {
+        let skip = items.len().saturating_sub(self.limit - self.items.len());
+        let added = items.len() - skip;
+        for item in items.into_iter().skip(skip).rev() {
+            self.items.push_front(item);
+        }
+        if added > 0 {
+            self.prepended += added as u64;
+            self.generation += 1;
+        }
+        added
+    }
+
+    /// Total number of older entries put in front since the buffer was
+    /// created.
+    pub fn prepended(&self) -> u64 {
+        self.prepended
+    }
+
+    /// Whether the buffer holds `limit` entries, so `prepend` adds none.
+    pub fn is_full(&self) -> bool {
+        self.items.len() >= self.limit
+    }
+
+    /// Remove the entries `keep` rejects, such as expired ephemeral messages.
+    pub fn retain(&mut self, keep: impl FnMut(&T) -> bool) {
+        let before = self.items.len();
+        self.items.retain(keep);
+        if self.items.len() != before {
+            self.generation += 1;
+        }
+    }
+
+    pub fn iter(&self) -> std::collections::vec_deque::Iter<'_, T> {
+        self.items.iter()
+    }
+}
+
+/// A shared, thread-safe message history used by the TUI and networking code.
+pub type SharedMessages<T> = Arc<Mutex<MessageBuffer<T>>>;
+
+/// Room state the network side reports to the TUI besides messages.
+#[derive(Default)]
+pub struct RoomStatus {
+    /// Name our own messages are echoed under; `$USER` when unset.
+    pub name: Option<String>,
+    /// Our stable ID as the server gave it, see `Payload::user`.
+    pub user_id: Option<String>,
+    /// Current topic, shown in the chat pane title.
+    pub topic: Option<String>,
+    /// Minimum interval between two chat messages of one client.
+    pub slow_mode: Option<std::time::Duration>,
+    /// Earliest time this side may send again under slow mode.
+    pub next_send: Option<std::time::Instant>,
+    /// File transfers in progress, by a key unique to each transfer.
+    pub transfers: std::collections::BTreeMap<String, Transfer>,
+    /// Lines to show over the chat pane, such as search results. The TUI
+    /// takes it from here and closes it on Esc.
+    pub overlay: Option<Overlay>,
+    /// Slash commands understood here and what they do, for the F1 help.
+    pub commands: Vec<(&'static str, &'static str)>,
+    /// Host and port others reach this server at, when known from the
+    /// command line, the port mapping or the onion service; for `/qr`.
+    pub join_address: Option<(String, u16)>,
+    /// Draft for the input box. Set before the TUI starts to restore one;
+    /// the TUI leaves what was in the box here when it exits.
+    pub draft: Option<String>,
+    /// ID of the chat message the send function just sent, which the TUI
+    /// gives the echo it shows for it.
+    pub sent_id: Option<String>,
+    /// How far our chat messages got, by ID, for the servers that
+    /// acknowledge them.
+    pub delivery: std::collections::HashMap<String, Delivery>,
+    /// Round-trip time to the server, for servers that answer pings.
+    pub rtt: Option<Rtt>,
+    /// Loads the page of the local log before the oldest message shown,
+    /// for the TUI to call once that message is on screen; false when
+    /// there is nothing older left to load.
+    pub scrollback: Option<Scrollback>,
+}
+
+/// See `RoomStatus::scrollback`.
+pub type Scrollback = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// Round-trip time to the other side, from `ping`/`pong` heartbeats.
+#[derive(Clone, Copy, Debug)]
+pub struct Rtt {
+    /// The latest round trip.
+    pub last: std::time::Duration,
+    /// Moving average of the round trips, weighting the latest by 1/8.
+    pub smoothed: std::time::Duration,
+}
+
+impl Rtt {
+    pub fn new(rtt: std::time::Duration) -> Self {
+        Self { last: rtt, smoothed: rtt }
+    }
+
+    pub fn sample(&mut self, rtt: std::time::Duration) {
+        self.last = rtt;
+        self.smoothed = (self.smoothed * 7 + rtt) / 8;
+    }
+
+    /// The latest round trip took over twice as long as usual, and long
+    /// enough to notice.
+    pub fn spiking(&self) -> bool {
+        self.last > self.smoothed * 2 && self.last >= std::time::Duration::from_millis(100)
+    }
+
+    /// Short label such as `rtt 42ms`, with the latest round trip while it
+    /// spikes.
+    pub fn label(&self) -> String {
+        let shown = if self.spiking() { self.last } else { self.smoothed };
+        format!("rtt {}ms", shown.as_millis())
+    }
+}
+
+/// Record a round trip in `rtt`.
+pub fn sample_rtt(rtt: &mut Option<Rtt>, sample: std::time::Duration) {
+    match rtt {
+        Some(rtt) => rtt.sample(sample),
+        None => *rtt = Some(Rtt::new(sample)),
+    }
+}
+
+/// Bytes sent to and received from one connection.
+#[derive(Default, Debug)]
+pub struct Traffic {
+    pub sent: std::sync::atomic::AtomicU64,
+    pub received: std::sync::atomic::AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes sent and received so far.
+    pub fn totals(&self) -> (u64, u64) {
+        use std::sync::atomic::Ordering::Relaxed;
+        (self.sent.load(Relaxed), self.received.load(Relaxed))
+    }
+}
+
+/// Chat messages one client sent, counted whether or not they were
+/// relayed, so that slow mode can be set from what members really send.
+#[derive(Clone, Default, Debug)]
+pub struct MessageStats {
+    pub total: u64,
+    /// Arrival times of the messages of the last minute.
+    recent: VecDeque<std::time::Instant>,
+    /// Most messages within any one minute.
+    pub peak: usize,
+}
+
+impl MessageStats {
+    const WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+    pub fn record(&mut self) {
+        let now = std::time::Instant::now();
+        while self.recent.front().is_some_and(|t| now.duration_since(*t) >= Self::WINDOW) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        self.total += 1;
+        self.peak = self.peak.max(self.recent.len());
+    }
+
+    /// Messages within the last minute.
+    pub fn per_minute(&self) -> usize {
+        self.recent.iter().filter(|t| t.elapsed() < Self::WINDOW).count()
+    }
+}
+
+/// Delivery state of one of our chat messages, see `crypto::Kind::Ack`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Delivery {
+    /// Sent, not acknowledged yet.
+    Sending,
+    /// The server relayed it.
+    Delivered,
+    /// The server refused it or never acknowledged it.
+    Failed,
+}
+
+/// A scrollable box of lines drawn over the chat pane.
+pub struct Overlay {
+    pub title: String,
+    pub lines: Vec<String>,
+}
+
+/// Progress of one file transfer, shown in the status bar.
+pub struct Transfer {
+    pub name: String,
+    pub size: u64,
+    /// Bytes transferred so far.
+    pub done: u64,
+    /// This side is sending the file.
+    pub upload: bool,
+}
+
+impl Transfer {
+    pub fn label(&self) -> String {
+        let percent = (self.done * 100).checked_div(self.size).unwrap_or(0);
+        format!("{} {} {}%", if self.upload { "↑" } else { "↓" }, self.name, percent)
+    }
+}
+
+/// IDs of the most recent chat messages, so each is shown once.
+#[derive(Default)]
+pub struct SeenIds {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl SeenIds {
+    /// Remembered IDs; the oldest are forgotten first.
+    const CAPACITY: usize = 4096;
+
+    /// Remember `id`; false when it was already known.
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > Self::CAPACITY {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        true
+    }
+}
+
+/// Room status shared between the network threads and the TUI.
+pub type SharedStatus = Arc<Mutex<RoomStatus>>;
+
+/// An authenticated client as seen by `broadcast`.
+pub struct ClientEntry {
+    /// Outbound queue drained by the server's event loop.
+    pub queue: Arc<crate::queue::SendQueue>,
+    /// The client's message key, see `auth::derive_session_key`.
+    pub cipher: crate::crypto::SessionCipher,
+    /// How the client's frames are written, as negotiated during the
+    /// handshake.
+    pub framing: crate::crypto::Framing,
+    /// The client negotiated acknowledgements of its chat messages.
+    pub acks: bool,
+    /// The client negotiated heartbeats, see `net::CAP_PING`.
+    pub ping: bool,
+    /// Round-trip time to the client, from its answers to our pings.
+    pub rtt: Option<Rtt>,
+    /// Bytes exchanged with the client since it joined, counted by the
+    /// event loop.
+    pub traffic: Arc<Traffic>,
+    /// Chat messages the client sent since it joined.
+    pub messages: MessageStats,
+    /// Name the client announced after the handshake, or its account name.
+    pub username: Option<String>,
+    /// Account the client logged in with, when the server requires login.
+    pub account: Option<String>,
+    /// Personal key the client signed its join with, in hex; names are
+    /// remembered for it in `nicknames`.
+    pub identity: Option<String>,
+    /// Stable ID the server stamps on the client's messages, see
+    /// `nicknames::user_id`.
+    pub user_id: String,
+    /// The server's handshake challenge, which the join signature covers.
+    pub challenge: [u8; 32],
+    /// Read-only observer: receives broadcasts, may not chat.
+    pub observer: bool,
+    /// Role of the account; always `Member` without one.
+    pub role: crate::roles::Role,
+    /// When the client completed the handshake.
+    pub joined: chrono::DateTime<chrono::Local>,
+    /// Whether the client sent its join frame; later ones are ignored.
+    pub greeted: bool,
+    /// Last time the client sent a chat message, or joined.
+    pub last_active: std::time::Instant,
+    /// Last chat message that was relayed, for slow mode.
+    pub last_message: Option<std::time::Instant>,
+}
+
+/// A map of peer address -> connected client.
+pub type SharedClients = Arc<Mutex<HashMap<String, ClientEntry>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn message(text: &str) -> Message {
+        Message { sender: "alice".to_string(), text: text.to_string(), time: chrono::Utc::now(), verified: None, expires: None, user: None, id: None }
+    }
+
+    #[test]
+    fn the_sweep_removes_expired_messages_only() {
+        let messages: SharedMessages<Message> = Arc::new(Mutex::new(MessageBuffer::new(10)));
+        messages.lock().unwrap().push(message("stays"));
+        let expires = Instant::now() + Duration::from_secs(60);
+        messages.lock().unwrap().push(Message { expires: Some(expires), ..message("goes") });
+        let mut buffers = vec![Arc::downgrade(&messages)];
+
+        sweep(&mut buffers, expires - Duration::from_secs(1));
+        assert_eq!(messages.lock().unwrap().len(), 2);
+        assert_eq!(buffers.len(), 1);
+        sweep(&mut buffers, expires);
+        let texts: Vec<_> = messages.lock().unwrap().iter().map(|m| m.text.clone()).collect();
+        assert_eq!(texts, ["stays"]);
+        // nothing left to expire, so the buffer is no longer swept
+        assert!(buffers.is_empty());
+    }
+
+    #[test]
+    fn the_sweep_forgets_dropped_buffers() {
+        let messages: SharedMessages<Message> = Arc::new(Mutex::new(MessageBuffer::new(10)));
+        let mut buffers = vec![Arc::downgrade(&messages)];
+        drop(messages);
+        sweep(&mut buffers, Instant::now());
+        assert!(buffers.is_empty());
+    }
+}
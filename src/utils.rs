@@ -18,7 +18,19 @@ pub fn encrypt_and_write_dek(input_path: &str, output_path: &str) -> Result<(),
     print!("Enter KEK (password) to encrypt DEK: ");
     io::stdout().flush().ok();
     let kek = read_password().map_err(|_| "Failed to read KEK".to_string())?;
+    let out_blob = encrypt_dek(&dek_bytes, &kek)?;
 
+    if let Some(dir) = std::path::Path::new(output_path).parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+
+    std::fs::write(output_path, &out_blob).map_err(|e| format!("Failed to write {}: {}", output_path, e))?;
+    Ok(())
+}
+
+/// Encrypt a DEK with the KEK into the `dek.bin` layout: salt || nonce ||
+/// ciphertext.
+pub fn encrypt_dek(dek_bytes: &[u8], kek: &str) -> Result<Vec<u8>, String> {
     let mut salt = [0u8; 16];
     let mut rng = rand::rngs::OsRng;
     rng.fill_bytes(&mut salt);
@@ -29,16 +41,11 @@ pub fn encrypt_and_write_dek(input_path: &str, output_path: &str) -> Result<(),
     let mut nonce = [0u8; 12];
     rng.fill_bytes(&mut nonce);
     let nonce_ga = aes_gcm::aead::generic_array::GenericArray::<u8, typenum::U12>::from_slice(&nonce);
-    let ciphertext = kek_cipher.encrypt(nonce_ga, dek_bytes.as_ref()).map_err(|_| "Encryption failed".to_string())?;
-
-    if let Some(dir) = std::path::Path::new(output_path).parent() {
-        let _ = std::fs::create_dir_all(dir);
-    }
+    let ciphertext = kek_cipher.encrypt(nonce_ga, dek_bytes).map_err(|_| "Encryption failed".to_string())?;
 
     let mut out_blob = Vec::with_capacity(16 + 12 + ciphertext.len());
     out_blob.extend_from_slice(&salt);
     out_blob.extend_from_slice(&nonce);
     out_blob.extend_from_slice(&ciphertext);
-    std::fs::write(output_path, &out_blob).map_err(|e| format!("Failed to write {}: {}", output_path, e))?;
-    Ok(())
+    Ok(out_blob)
 }
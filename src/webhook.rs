@@ -0,0 +1,156 @@
+//! Incoming webhooks (`[server.webhook]`): a small HTTP endpoint through
+//! which CI systems and other integrations post into the room.
+//!
+//! An integration sends `POST /message` with `Authorization: Bearer
+//! <token>` and a JSON body `{"text": "..."}`; the message appears in the
+//! room under the name the token is configured for. The endpoint speaks
+//! plain HTTP, so it should listen on localhost or sit behind a TLS proxy.
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest request body accepted.
+const MAX_BODY: usize = 64 * 1024;
+/// Largest request line plus headers accepted.
+const MAX_HEAD: usize = 8 * 1024;
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings under `[server.webhook]`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Address to serve the endpoint on, e.g. `127.0.0.1:5080`; off when
+    /// unset.
+    pub listen: Option<String>,
+    /// Integration name to token; messages posted with a token appear
+    /// under its name.
+    pub tokens: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Body {
+    text: String,
+}
+
+/// Serve the endpoint on `config.listen` in the background. `post` puts a
+/// message from an integration into the room, or says why it was not.
+pub fn spawn(config: &WebhookConfig, post: impl Fn(&str, &str) -> Result<(), String> + Send + Sync + 'static) -> Result<Option<String>, String> {
+    let Some(listen) = &config.listen else { return Ok(None) };
+    if config.tokens.is_empty() {
+        return Err("[server.webhook] needs at least one entry under tokens".to_string());
+    }
+    if let Some((name, _)) = config.tokens.iter().find(|(_, token)| token.len() < 16) {
+        return Err(format!("The webhook token of {} is too short; use at least 16 characters", name));
+    }
+    let listener = TcpListener::bind(listen).map_err(|e| format!("Cannot bind the webhook endpoint {}: {}", listen, e))?;
+    let tokens = Arc::new(config.tokens.clone());
+    let post = Arc::new(post);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (tokens, post) = (tokens.clone(), post.clone());
+            std::thread::spawn(move || handle(stream, &tokens, &*post));
+        }
+    });
+    Ok(Some(listen.clone()))
+}
+
+/// Answer one request; the connection is closed afterwards.
+fn handle(mut stream: TcpStream, tokens: &BTreeMap<String, String>, post: &dyn Fn(&str, &str) -> Result<(), String>) {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT)).ok();
+    let (status, reason) = match serve(&mut stream, tokens, post) {
+        Ok(()) => (204, String::new()),
+        Err(e) => e,
+    };
+    let text = match status {
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Unprocessable Entity",
+    };
+    let mut response = format!("HTTP/1.1 {} {}\r\nConnection: close\r\n", status, text);
+    if status == 401 {
+        response.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    if !reason.is_empty() {
+        response.push_str(&format!("Content-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}\n", reason.len() + 1, reason));
+    } else {
+        response.push_str("\r\n");
+    }
+    let _ = stream.write_all(response.as_bytes());
+}
+
+/// Read the request and post its message; errors are an HTTP status and
+/// the reason given to the client.
+fn serve(stream: &mut TcpStream, tokens: &BTreeMap<String, String>, post: &dyn Fn(&str, &str) -> Result<(), String>) -> Result<(), (u16, String)> {
+    let bad = |status: u16, reason: &str| (status, reason.to_string());
+    let mut data = Vec::new();
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HEAD {
+            return Err(bad(413, "request headers too large"));
+        }
+        let mut buf = [0u8; 4096];
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => return Err(bad(400, "incomplete request")),
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+        }
+    };
+    let head = std::str::from_utf8(&data[..head_end]).map_err(|_| bad(400, "malformed request"))?;
+    let mut lines = head.split("\r\n");
+    let mut request = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (request.next().unwrap_or_default(), request.next().unwrap_or_default());
+    if path != "/message" {
+        return Err(bad(404, "post to /message"));
+    }
+    if method != "POST" {
+        return Err(bad(405, "use POST"));
+    }
+    let headers: Vec<(String, &str)> = lines.filter_map(|l| l.split_once(':')).map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim())).collect();
+    let header = |name: &str| headers.iter().find(|(k, _)| k == name).map(|(_, v)| *v);
+
+    let token = header("authorization").and_then(|v| v.strip_prefix("Bearer ")).unwrap_or_default();
+    let name = integration(tokens, token).ok_or_else(|| bad(401, "unknown token"))?;
+
+    let length: usize = header("content-length").and_then(|v| v.parse().ok()).ok_or_else(|| bad(400, "Content-Length required"))?;
+    if length > MAX_BODY {
+        return Err(bad(413, "body too large"));
+    }
+    let mut body = data.split_off(head_end + 4);
+    if body.len() < length {
+        let start = body.len();
+        body.resize(length, 0);
+        stream.read_exact(&mut body[start..]).map_err(|_| bad(400, "incomplete body"))?;
+    }
+    body.truncate(length);
+    let body: Body = serde_json::from_slice(&body).map_err(|e| (400, format!("invalid JSON body: {}", e)))?;
+    if body.text.trim().is_empty() {
+        return Err(bad(400, "text is empty"));
+    }
+    post(name, &body.text).map_err(|e| (422, e))
+}
+
+/// The integration `token` belongs to. Tokens are compared by digest so
+/// the comparison takes the same time wherever they differ.
+fn integration<'a>(tokens: &'a BTreeMap<String, String>, token: &str) -> Option<&'a str> {
+    let given = Sha256::digest(token.as_bytes());
+    let mut found = None;
+    for (name, expected) in tokens {
+        let expected = Sha256::digest(expected.as_bytes());
+        let diff = given.iter().zip(expected.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff == 0 {
+            found = Some(name.as_str());
+        }
+    }
+    found
+}